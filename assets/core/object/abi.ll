@@ -1,8 +1,8 @@
 ; This file contains implementations of the ABI-dependent functionality of the Keid reflection API.
 source_filename = "abi.ll"
 
-; heap data for a class: ptr (classinfo pointer), i64 (ref count)
-%KeidAbiClassData = type { ptr, i64 }
+; heap data for a class: ptr (classinfo pointer), i64 (ref count), ptr (weak reference, or null if there are no weak references)
+%KeidAbiClassData = type { ptr, i64, ptr }
 
 ; constant class metadata:
 ;  ptr (pointer to class destructor),
@@ -14,7 +14,10 @@ source_filename = "abi.ll"
 ;    TYPE_CLASS  = 0x00
 ;    TYPE_STRUCT = 0x01
 ;  ptr to %KeidAbiClassInfo (superclass pointer, or null if this represents the core::object::Object type)
-%KeidAbiClassInfo = type { ptr, ptr, i32, ptr, ptr, i32, ptr }
+;  i32 (length of reference fields)
+;  ptr to [%KeidAbiReferenceField] (n-length reference fields, or null if there are none)
+;  i64 (size of the heap data of an instance, or 0 for structs)
+%KeidAbiClassInfo = type { ptr, ptr, i32, ptr, ptr, i32, ptr, i32, ptr, i64 }
 
 ; const char* keid_reflect_get_object_class_name(KeidAbiClass*)
 define ptr @keid_reflect_get_object_class_name(ptr %object) {
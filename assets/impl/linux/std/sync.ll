@@ -0,0 +1,76 @@
+; This file contains the atomic operations used by the Keid `std::sync` module.
+source_filename = "sync.ll"
+
+; i32 keid_atomic_load_i32(i32*)
+define i32 @keid_atomic_load_i32(ptr %ptr) {
+  %value = load atomic i32, ptr %ptr seq_cst, align 4
+  ret i32 %value
+}
+
+; void keid_atomic_store_i32(i32*, i32)
+define void @keid_atomic_store_i32(ptr %ptr, i32 %value) {
+  store atomic i32 %value, ptr %ptr seq_cst, align 4
+  ret void
+}
+
+; i32 keid_atomic_swap_i32(i32*, i32) - returns the previous value
+define i32 @keid_atomic_swap_i32(ptr %ptr, i32 %value) {
+  %previous = atomicrmw xchg ptr %ptr, i32 %value seq_cst
+  ret i32 %previous
+}
+
+; i32 keid_atomic_fetch_add_i32(i32*, i32) - returns the previous value
+define i32 @keid_atomic_fetch_add_i32(ptr %ptr, i32 %value) {
+  %previous = atomicrmw add ptr %ptr, i32 %value seq_cst
+  ret i32 %previous
+}
+
+; i32 keid_atomic_fetch_sub_i32(i32*, i32) - returns the previous value
+define i32 @keid_atomic_fetch_sub_i32(ptr %ptr, i32 %value) {
+  %previous = atomicrmw sub ptr %ptr, i32 %value seq_cst
+  ret i32 %previous
+}
+
+; bool keid_atomic_compare_exchange_i32(i32*, i32 expected, i32 desired) - returns whether the value was exchanged
+define i1 @keid_atomic_compare_exchange_i32(ptr %ptr, i32 %expected, i32 %desired) {
+  %result = cmpxchg ptr %ptr, i32 %expected, i32 %desired seq_cst seq_cst
+  %exchanged = extractvalue { i32, i1 } %result, 1
+  ret i1 %exchanged
+}
+
+; i64 keid_atomic_load_i64(i64*)
+define i64 @keid_atomic_load_i64(ptr %ptr) {
+  %value = load atomic i64, ptr %ptr seq_cst, align 8
+  ret i64 %value
+}
+
+; void keid_atomic_store_i64(i64*, i64)
+define void @keid_atomic_store_i64(ptr %ptr, i64 %value) {
+  store atomic i64 %value, ptr %ptr seq_cst, align 8
+  ret void
+}
+
+; i64 keid_atomic_swap_i64(i64*, i64) - returns the previous value
+define i64 @keid_atomic_swap_i64(ptr %ptr, i64 %value) {
+  %previous = atomicrmw xchg ptr %ptr, i64 %value seq_cst
+  ret i64 %previous
+}
+
+; i64 keid_atomic_fetch_add_i64(i64*, i64) - returns the previous value
+define i64 @keid_atomic_fetch_add_i64(ptr %ptr, i64 %value) {
+  %previous = atomicrmw add ptr %ptr, i64 %value seq_cst
+  ret i64 %previous
+}
+
+; i64 keid_atomic_fetch_sub_i64(i64*, i64) - returns the previous value
+define i64 @keid_atomic_fetch_sub_i64(ptr %ptr, i64 %value) {
+  %previous = atomicrmw sub ptr %ptr, i64 %value seq_cst
+  ret i64 %previous
+}
+
+; bool keid_atomic_compare_exchange_i64(i64*, i64 expected, i64 desired) - returns whether the value was exchanged
+define i1 @keid_atomic_compare_exchange_i64(ptr %ptr, i64 %expected, i64 %desired) {
+  %result = cmpxchg ptr %ptr, i64 %expected, i64 %desired seq_cst seq_cst
+  %exchanged = extractvalue { i64, i1 } %result, 1
+  ret i1 %exchanged
+}
@@ -0,0 +1,455 @@
+//! Native backtraces of Keid code.
+//!
+//! The stack is walked with `_Unwind_Backtrace` from libunwind, and every return address is symbolized with the symbol
+//! table and the DWARF line table (`.debug_line`) of the running executable, which the compiler emits for every Keid
+//! function. Frames that are not in Keid code (the runtime, libc) are left out of the backtrace.
+
+use lazy_static::lazy_static;
+use std::ffi::{c_char, c_int, c_void, CString};
+use std::fmt::{self, Write};
+
+use crate::unwind::UnwindContext;
+
+const URC_NO_REASON: c_int = 0;
+const URC_END_OF_STACK: c_int = 5;
+
+const SHT_SYMTAB: u32 = 2;
+const STT_FUNC: u8 = 2;
+
+const DW_LNS_COPY: u8 = 1;
+const DW_LNS_ADVANCE_PC: u8 = 2;
+const DW_LNS_ADVANCE_LINE: u8 = 3;
+const DW_LNS_SET_FILE: u8 = 4;
+const DW_LNS_CONST_ADD_PC: u8 = 8;
+const DW_LNS_FIXED_ADVANCE_PC: u8 = 9;
+const DW_LNE_END_SEQUENCE: u8 = 1;
+const DW_LNE_SET_ADDRESS: u8 = 2;
+const DW_LNE_DEFINE_FILE: u8 = 3;
+
+extern "C" {
+    fn _Unwind_Backtrace(trace: extern "C" fn(*mut UnwindContext, *mut c_void) -> c_int, arg: *mut c_void) -> c_int;
+    fn _Unwind_GetIPInfo(context: *mut UnwindContext, ip_before_insn: *mut c_int) -> usize;
+}
+
+struct Symbol {
+    address: usize,
+    size: usize,
+    name: String,
+}
+
+/// A row of the line table: the instructions from `address` up to the next row belong to `line` of `files[file]`.
+struct LineRow {
+    address: usize,
+    file: usize,
+    line: u32,
+    /// Marks the first address after a sequence of rows; it belongs to no line.
+    is_end_sequence: bool,
+}
+
+/// The symbols and line table of the running executable.
+struct DebugInfo {
+    /// Sorted by address.
+    symbols: Vec<Symbol>,
+    /// Sorted by address; the end of a sequence comes before a sequence that starts at the same address.
+    rows: Vec<LineRow>,
+    /// The names of the source files, without their directories.
+    files: Vec<String>,
+    /// The difference between the runtime addresses and the addresses in the executable.
+    load_bias: usize,
+}
+
+/// A frame of Keid code.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub function: String,
+    /// The name of the source file, without its directory.
+    pub file: String,
+    pub line: u32,
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({}:{})", self.function, self.file, self.line)
+    }
+}
+
+lazy_static! {
+    // loaded when the first backtrace is symbolized
+    static ref DEBUG_INFO: Option<DebugInfo> = DebugInfo::load();
+}
+
+/// A little-endian reader of ELF and DWARF data.
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], offset: usize) -> Reader<'a> {
+        Reader {
+            data,
+            offset,
+        }
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.offset..self.offset.checked_add(len)?)?;
+        self.offset += len;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.bytes(2)?.try_into().ok()?))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.bytes(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.bytes(8)?.try_into().ok()?))
+    }
+
+    fn uleb128(&mut self) -> Option<u64> {
+        let mut result = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                result |= ((byte & 0x7f) as u64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Some(result);
+            }
+        }
+    }
+
+    fn sleb128(&mut self) -> Option<i64> {
+        let mut result = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                result |= ((byte & 0x7f) as i64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    result |= -1 << shift;
+                }
+                return Some(result);
+            }
+        }
+    }
+
+    fn cstr(&mut self) -> Option<&'a str> {
+        let len = self.data.get(self.offset..)?.iter().position(|byte| *byte == 0)?;
+        let str = std::str::from_utf8(self.bytes(len)?).ok();
+        self.offset += 1;
+        str
+    }
+}
+
+struct Section<'a> {
+    name: &'a str,
+    ty: u32,
+    data: &'a [u8],
+    link: usize,
+}
+
+/// Reads the section headers of a 64-bit little-endian ELF file.
+fn read_sections(elf: &[u8]) -> Option<Vec<Section<'_>>> {
+    if elf.get(0..4)? != b"\x7fELF" || *elf.get(4)? != 2 || *elf.get(5)? != 1 {
+        return None;
+    }
+    let mut header = Reader::new(elf, 0x28);
+    let section_headers = header.u64()? as usize;
+    header.offset = 0x3a;
+    let section_header_size = header.u16()? as usize;
+    let section_count = header.u16()? as usize;
+    let section_names_index = header.u16()? as usize;
+
+    let mut headers = Vec::with_capacity(section_count);
+    for i in 0..section_count {
+        let mut reader = Reader::new(elf, section_headers + i * section_header_size);
+        let name = reader.u32()? as usize;
+        let ty = reader.u32()?;
+        reader.offset += 16; // flags and address
+        let offset = reader.u64()? as usize;
+        let size = reader.u64()? as usize;
+        let link = reader.u32()? as usize;
+        headers.push((name, ty, offset, size, link));
+    }
+
+    let (_, _, names_offset, names_size, _) = *headers.get(section_names_index)?;
+    let names = elf.get(names_offset..names_offset + names_size)?;
+    headers
+        .into_iter()
+        .map(|(name, ty, offset, size, link)| {
+            Some(Section {
+                name: Reader::new(names, name).cstr()?,
+                ty,
+                // sections without contents in the file (`.bss`) are empty
+                data: elf.get(offset..offset + size).unwrap_or(&[]),
+                link,
+            })
+        })
+        .collect()
+}
+
+fn read_symbols(sections: &[Section]) -> Option<Vec<Symbol>> {
+    let symtab = sections.iter().find(|section| section.ty == SHT_SYMTAB)?;
+    let strtab = sections.get(symtab.link)?;
+
+    let mut symbols = Vec::new();
+    let mut reader = Reader::new(symtab.data, 0);
+    while reader.offset < symtab.data.len() {
+        let name = reader.u32()? as usize;
+        let info = reader.u8()?;
+        reader.offset += 3; // other and section index
+        let address = reader.u64()? as usize;
+        let size = reader.u64()? as usize;
+        if info & 0xf == STT_FUNC && address != 0 {
+            if let Some(name) = Reader::new(strtab.data, name).cstr() {
+                symbols.push(Symbol {
+                    address,
+                    size,
+                    name: name.to_owned(),
+                });
+            }
+        }
+    }
+    symbols.sort_by_key(|symbol| symbol.address);
+    Some(symbols)
+}
+
+/// Runs the line number program of a unit of `.debug_line`. Only DWARF versions 2 to 4 are supported; the compiler emits
+/// version 4 line tables, and the units of other versions are skipped.
+fn read_line_program(reader: &mut Reader, files: &mut Vec<String>, rows: &mut Vec<LineRow>) -> Option<()> {
+    let mut unit_length = reader.u32()? as usize;
+    let is_dwarf64 = unit_length == 0xffff_ffff;
+    if is_dwarf64 {
+        unit_length = reader.u64()? as usize;
+    }
+    let unit_end = reader.offset + unit_length;
+
+    let version = reader.u16()?;
+    if !(2..=4).contains(&version) {
+        reader.offset = unit_end;
+        return Some(());
+    }
+    let header_length = if is_dwarf64 { reader.u64()? as usize } else { reader.u32()? as usize };
+    let program_start = reader.offset + header_length;
+    let min_instruction_length = reader.u8()? as usize;
+    if version >= 4 {
+        reader.u8()?; // maximum operations per instruction
+    }
+    reader.u8()?; // default is_stmt
+    let line_base = reader.u8()? as i8 as i64;
+    let line_range = reader.u8()?;
+    let opcode_base = reader.u8()?;
+    let standard_opcode_lengths = reader.bytes(opcode_base as usize - 1)?;
+
+    // the directories aren't needed, since backtraces only show the names of the files
+    while !reader.cstr()?.is_empty() {}
+
+    // maps the file numbers of the unit (which start at 1) to indices of `files`
+    let mut unit_files = vec![0];
+    loop {
+        let name = reader.cstr()?;
+        if name.is_empty() {
+            break;
+        }
+        reader.uleb128()?; // directory
+        reader.uleb128()?; // modification time
+        reader.uleb128()?; // length
+        unit_files.push(files.len());
+        files.push(name.rsplit('/').next().unwrap().to_owned());
+    }
+
+    reader.offset = program_start;
+    let mut address = 0;
+    let mut file = 1;
+    let mut line: i64 = 1;
+    let row = |unit_files: &[usize], address: usize, file: usize, line: i64, is_end_sequence: bool| LineRow {
+        address,
+        file: unit_files.get(file).copied().unwrap_or(usize::MAX),
+        line: line as u32,
+        is_end_sequence,
+    };
+    while reader.offset < unit_end {
+        let opcode = reader.u8()?;
+        if opcode >= opcode_base {
+            let adjusted = opcode - opcode_base;
+            address += (adjusted / line_range) as usize * min_instruction_length;
+            line += line_base + (adjusted % line_range) as i64;
+            rows.push(row(&unit_files, address, file, line, false));
+            continue;
+        }
+        match opcode {
+            0 => {
+                let len = reader.uleb128()? as usize;
+                let end = reader.offset + len;
+                match reader.u8()? {
+                    DW_LNE_END_SEQUENCE => {
+                        rows.push(row(&unit_files, address, file, line, true));
+                        address = 0;
+                        file = 1;
+                        line = 1;
+                    }
+                    DW_LNE_SET_ADDRESS => address = reader.u64()? as usize,
+                    DW_LNE_DEFINE_FILE => {
+                        let name = reader.cstr()?;
+                        unit_files.push(files.len());
+                        files.push(name.rsplit('/').next().unwrap().to_owned());
+                    }
+                    _ => (),
+                }
+                reader.offset = end;
+            }
+            DW_LNS_COPY => rows.push(row(&unit_files, address, file, line, false)),
+            DW_LNS_ADVANCE_PC => address += reader.uleb128()? as usize * min_instruction_length,
+            DW_LNS_ADVANCE_LINE => line += reader.sleb128()?,
+            DW_LNS_SET_FILE => file = reader.uleb128()? as usize,
+            DW_LNS_CONST_ADD_PC => address += ((255 - opcode_base) / line_range) as usize * min_instruction_length,
+            DW_LNS_FIXED_ADVANCE_PC => address += reader.u16()? as usize,
+            _ => {
+                // other standard opcodes don't affect the rows, but their operands still need to be skipped
+                for _ in 0..standard_opcode_lengths[opcode as usize - 1] {
+                    reader.uleb128()?;
+                }
+            }
+        }
+    }
+
+    reader.offset = unit_end;
+    Some(())
+}
+
+impl DebugInfo {
+    fn load() -> Option<DebugInfo> {
+        let elf = std::fs::read("/proc/self/exe").ok()?;
+        let sections = read_sections(&elf)?;
+        let symbols = read_symbols(&sections)?;
+
+        let mut files = Vec::new();
+        let mut rows = Vec::new();
+        if let Some(debug_line) = sections.iter().find(|section| section.name == ".debug_line") {
+            let mut reader = Reader::new(debug_line.data, 0);
+            while reader.offset < debug_line.data.len() {
+                if read_line_program(&mut reader, &mut files, &mut rows).is_none() {
+                    break;
+                }
+            }
+        }
+        rows.sort_by_key(|row| (row.address, !row.is_end_sequence));
+
+        // the executable may have been loaded at a different address than it was linked at
+        let backtrace_fn = keid_core_runtime_get_backtrace as *const () as usize;
+        let load_bias = match symbols.iter().find(|symbol| symbol.name == "keid_core_runtime_get_backtrace") {
+            Some(symbol) => backtrace_fn.wrapping_sub(symbol.address),
+            None => 0,
+        };
+
+        Some(DebugInfo {
+            symbols,
+            rows,
+            files,
+            load_bias,
+        })
+    }
+
+    fn find_symbol(&self, address: usize) -> Option<&Symbol> {
+        let index = self.symbols.partition_point(|symbol| symbol.address <= address);
+        let symbol = &self.symbols[index.checked_sub(1)?];
+        if address < symbol.address + symbol.size.max(1) {
+            Some(symbol)
+        } else {
+            None
+        }
+    }
+
+    fn find_line(&self, address: usize) -> Option<(&str, u32)> {
+        let index = self.rows.partition_point(|row| row.address <= address);
+        let row = &self.rows[index.checked_sub(1)?];
+        if row.is_end_sequence {
+            return None;
+        }
+        Some((self.files.get(row.file)?, row.line))
+    }
+
+    /// Returns `None` for frames outside of Keid code.
+    fn symbolize(&self, address: usize) -> Option<Frame> {
+        let address = address.wrapping_sub(self.load_bias);
+        let symbol = self.find_symbol(address)?;
+        let (file, line) = self.find_line(address)?;
+        if !file.ends_with(".keid") || symbol.name.starts_with("core::runtime::") {
+            return None;
+        }
+        Some(Frame {
+            function: symbol.name.clone(),
+            file: file.to_owned(),
+            line,
+        })
+    }
+}
+
+extern "C" fn trace_frame(context: *mut UnwindContext, addresses: *mut c_void) -> c_int {
+    let addresses = unsafe { &mut *(addresses as *mut Vec<usize>) };
+    let mut ip_before_insn = 0;
+    let ip = unsafe { _Unwind_GetIPInfo(context, &mut ip_before_insn) };
+    if ip == 0 {
+        return URC_END_OF_STACK;
+    }
+    // the return address belongs to the instruction after the call
+    addresses.push(if ip_before_insn == 0 { ip - 1 } else { ip });
+    URC_NO_REASON
+}
+
+/// Returns the return addresses on the current thread's stack, innermost first. Walking the stack is much cheaper than
+/// symbolizing it, so addresses that may never be reported are only symbolized when needed.
+pub fn capture_addresses() -> Vec<usize> {
+    let mut addresses: Vec<usize> = Vec::new();
+    unsafe {
+        _Unwind_Backtrace(trace_frame, &mut addresses as *mut Vec<usize> as *mut c_void);
+    }
+    addresses
+}
+
+/// Returns the Keid frame of an address returned by [`capture_addresses`], or `None` if it's outside of Keid code.
+pub fn symbolize_frame(address: usize) -> Option<Frame> {
+    DEBUG_INFO.as_ref()?.symbolize(address)
+}
+
+/// Formats the Keid frames of addresses returned by [`capture_addresses`] like the frames that
+/// `keid_core_runtime_push_stack_frame` tracks, e.g. `main (main.keid:3)`.
+pub fn symbolize(addresses: &[usize]) -> Vec<String> {
+    addresses.iter().filter_map(|address| symbolize_frame(*address)).map(|frame| frame.to_string()).collect()
+}
+
+/// Returns the Keid frames of the current thread's stack, innermost first.
+pub fn capture() -> Vec<String> {
+    symbolize(&capture_addresses())
+}
+
+/// Captures a backtrace for `Error.create`; the result is freed with `keid_core_runtime_free_stack_frames`.
+#[no_mangle]
+pub extern "C" fn keid_core_runtime_get_backtrace() -> *mut c_char {
+    let mut backtrace = String::new();
+    for frame in capture() {
+        writeln!(&mut backtrace, "  at {}", frame).unwrap();
+    }
+    CString::new(backtrace).unwrap().into_raw()
+}
+
+#[no_mangle]
+pub extern "C" fn keid_core_runtime_print_backtrace() {
+    for frame in capture() {
+        println!("  at {}", frame);
+    }
+}
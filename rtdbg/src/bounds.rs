@@ -0,0 +1,166 @@
+//! Bounds checks of the memory accesses that programs compiled for librtdbg emit before every slice element access,
+//! `Pointer` dereference and `core::mem.copy`.
+//!
+//! `keid_malloc` records the size of every allocation and pads it with a red zone, so an access that starts past the end
+//! of an allocation is still attributed to it. Freed allocations are kept as tombstones until their memory is allocated
+//! again, like the quarantine of deallocated objects, so accesses of freed memory are reported as well. Memory that
+//! wasn't allocated by `keid_malloc`, like the stack, globals and the memory of the runtime, has no recorded size, so
+//! accesses of it aren't checked.
+
+use lazy_static::lazy_static;
+use std::{
+    collections::BTreeMap,
+    ffi::{c_char, c_void, CStr},
+    sync::Mutex,
+};
+
+use crate::report::Value;
+
+/// The amount of bytes after every allocation in which accesses are reported as out of bounds of the allocation.
+const RED_ZONE_SIZE: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+struct Allocation {
+    size: usize,
+    /// Whether the allocation has been freed, but its memory hasn't been allocated again yet.
+    is_freed: bool,
+}
+
+lazy_static! {
+    /// The live and freed allocations, by their address.
+    static ref ALLOCATIONS: Mutex<BTreeMap<usize, Allocation>> = Mutex::new(BTreeMap::new());
+}
+
+/// Returns the amount of bytes that `keid_malloc` allocates for an allocation of `size` bytes.
+#[no_mangle]
+pub extern "C" fn rtdbg_padded_allocation_size(size: usize) -> usize {
+    size + RED_ZONE_SIZE
+}
+
+#[no_mangle]
+pub extern "C" fn rtdbg_record_allocation(address: *mut c_void, size: usize) {
+    if address.is_null() {
+        return;
+    }
+    let address = address as usize;
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    // the memory of the tombstones that overlap the new allocation has been reused
+    let end = address + rtdbg_padded_allocation_size(size);
+    let reused: Vec<usize> = allocations
+        .range(..end)
+        .rev()
+        .take_while(|(start, allocation)| *start + rtdbg_padded_allocation_size(allocation.size) > address)
+        .map(|(start, _)| *start)
+        .collect();
+    for start in reused {
+        allocations.remove(&start);
+    }
+    allocations.insert(address, Allocation { size, is_freed: false });
+}
+
+#[no_mangle]
+pub extern "C" fn rtdbg_record_deallocation(address: *mut c_void) {
+    if let Some(allocation) = ALLOCATIONS.lock().unwrap().get_mut(&(address as usize)) {
+        allocation.is_freed = true;
+    }
+}
+
+/// Checks that the `size` bytes at `address` lie within the allocation that `address` points into. `location` is the
+/// source location of the access, e.g. `main.keid:3:5`.
+///
+/// # Safety
+///
+/// `location` must point to a null-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn rtdbg_check_access(address: *const c_void, size: usize, location: *const c_char) {
+    let address = address as usize;
+    if size == 0 {
+        return;
+    }
+    let location = CStr::from_ptr(location).to_string_lossy();
+    if address == 0 {
+        crate::fatal(
+            "null_access",
+            &format!("attempted access of {} bytes through a null pointer at {}", size, location),
+            &[("location", Value::Str(&location)), ("size", Value::Int(size as i64))],
+        );
+    }
+
+    let allocation = ALLOCATIONS.lock().unwrap().range(..=address).next_back().map(|(start, allocation)| (*start, *allocation));
+    let (start, length, is_freed) = match allocation {
+        Some((start, allocation)) if address < start + rtdbg_padded_allocation_size(allocation.size) => {
+            (start, allocation.size, allocation.is_freed)
+        }
+        _ => return,
+    };
+    if is_freed {
+        crate::fatal(
+            "use_after_free",
+            &format!(
+                "access of {} bytes at {:#x} (offset {}) of the freed allocation of {} bytes at {:#x}, at {}",
+                size,
+                address,
+                address - start,
+                length,
+                start,
+                location
+            ),
+            &[
+                ("location", Value::Str(&location)),
+                ("size", Value::Int(size as i64)),
+                ("offset", Value::Int((address - start) as i64)),
+                ("allocation_size", Value::Int(length as i64)),
+            ],
+        );
+    }
+    if address + size <= start + length {
+        return;
+    }
+    crate::fatal(
+        "out_of_bounds",
+        &format!(
+            "access of {} bytes at {:#x} (offset {}) is out of bounds of the allocation of {} bytes at {:#x}, at {}",
+            size,
+            address,
+            address - start,
+            length,
+            start,
+            location
+        ),
+        &[
+            ("location", Value::Str(&location)),
+            ("size", Value::Int(size as i64)),
+            ("offset", Value::Int((address - start) as i64)),
+            ("allocation_size", Value::Int(length as i64)),
+        ],
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::is_fatal_in_child;
+
+    // the addresses are only recorded and never dereferenced, so each test uses its own range of made up addresses
+
+    #[test]
+    fn access_of_freed_allocation_is_fatal() {
+        if !is_fatal_in_child("bounds::tests::access_of_freed_allocation_is_fatal", "use_after_free") {
+            return;
+        }
+        let address = 0x10000 as *mut c_void;
+        rtdbg_record_allocation(address, 16);
+        rtdbg_record_deallocation(address);
+        unsafe { rtdbg_check_access(address.wrapping_add(8), 8, c"test.keid:1:1".as_ptr()) };
+    }
+
+    #[test]
+    fn reallocated_memory_is_accessible() {
+        let address = 0x20000 as *mut c_void;
+        rtdbg_record_allocation(address, 16);
+        rtdbg_record_deallocation(address);
+        rtdbg_record_allocation(address.wrapping_add(8), 32);
+        unsafe { rtdbg_check_access(address.wrapping_add(16), 8, c"test.keid:1:1".as_ptr()) };
+        assert!(!ALLOCATIONS.lock().unwrap().contains_key(&(address as usize)));
+    }
+}
@@ -0,0 +1,60 @@
+//! Dump of the coverage counters of programs compiled with coverage, which `keid_exit` calls before the program exits.
+//!
+//! Every dump is appended to the file in `KEID_COVERAGE_OUTPUT` (`keid.coverage` by default), so the counts of several
+//! runs can be combined. Each dump starts with a `# keid coverage v1` line, followed by a tab-separated line per point:
+//!   - `F <file> <line> <column> <count> <function>` for the entry of a function
+//!   - `S <file> <line> <column> <count>` for a statement
+
+use std::{
+    ffi::{c_char, CStr},
+    fmt::Write as _,
+    fs::OpenOptions,
+    io::Write as _,
+};
+
+use crate::report::{self, Level, Value};
+
+const DEFAULT_OUTPUT_PATH: &str = "keid.coverage";
+
+/// The `KeidCoveragePoint` type that the compiler emits into the metadata module.
+#[repr(C)]
+pub struct KeidCoveragePoint {
+    file: *const c_char,
+    /// Null for statements.
+    function_name: *const c_char,
+    line: u32,
+    column: u32,
+}
+
+/// Appends the counts of the `count` coverage points to the coverage output.
+///
+/// # Safety
+///
+/// `points` and `counters` must point to `count` coverage points and counters, whose strings are null-terminated.
+#[no_mangle]
+pub unsafe extern "C" fn keid_coverage_dump(points: *const KeidCoveragePoint, counters: *const u64, count: u64) {
+    let path = std::env::var("KEID_COVERAGE_OUTPUT").ok().filter(|path| !path.is_empty());
+    let path = path.as_deref().unwrap_or(DEFAULT_OUTPUT_PATH);
+
+    let mut dump = String::from("# keid coverage v1\n");
+    for i in 0..count as usize {
+        let (point, count) = (&*points.add(i), *counters.add(i));
+        let file = CStr::from_ptr(point.file).to_string_lossy();
+        if point.function_name.is_null() {
+            writeln!(dump, "S\t{}\t{}\t{}\t{}", file, point.line, point.column, count).unwrap();
+        } else {
+            let function_name = CStr::from_ptr(point.function_name).to_string_lossy();
+            writeln!(dump, "F\t{}\t{}\t{}\t{}\t{}", file, point.line, point.column, count, function_name).unwrap();
+        }
+    }
+
+    let result = OpenOptions::new().create(true).append(true).open(path).and_then(|mut file| file.write_all(dump.as_bytes()));
+    if let Err(e) = result {
+        report::report(
+            Level::Error,
+            "coverage_error",
+            &format!("Cannot write coverage to {}: {}", path, e),
+            &[("path", Value::Str(path))],
+        );
+    }
+}
@@ -0,0 +1,308 @@
+//! A synchronous trial-deletion cycle collector for reference counted objects, based on
+//! "Concurrent Cycle Collection in Reference Counted Systems" by Bacon and Rajan.
+//!
+//! Whenever the reference count of an object is decreased without reaching zero, the object is buffered as a
+//! possible root of a garbage cycle. Collecting the buffered roots subtracts the references that come from inside
+//! the object graph reachable from them; every object whose count drops to zero is only referenced by garbage.
+
+use lazy_static::lazy_static;
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::c_void,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Mutex,
+    },
+};
+
+use crate::{KeidAbiClassData, TYPE_STRUCT};
+
+const FIELD_NULLABLE: u32 = 0x01;
+
+/// The amount of buffered roots that triggers a collection, unless changed by `keid_gc_set_threshold`.
+const DEFAULT_THRESHOLD: usize = 1024;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct KeidAbiReferenceField {
+    offset: u32,
+    field_bitflags: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Color {
+    /// In use, or free. Objects without a color are black.
+    Black,
+    /// A possible member of a garbage cycle.
+    Gray,
+    /// A member of a garbage cycle.
+    White,
+    /// A possible root of a garbage cycle.
+    Purple,
+}
+
+/// Objects are stored by their address so that the state can be shared between threads.
+#[derive(Debug)]
+struct CycleCollector {
+    roots: Vec<usize>,
+    buffered: HashSet<usize>,
+    colors: HashMap<usize, Color>,
+    /// Objects that are being freed by a collection, which must not be buffered again by their destructors.
+    garbage: HashSet<usize>,
+    threshold: usize,
+    is_collecting: bool,
+}
+
+lazy_static! {
+    static ref COLLECTOR: Mutex<CycleCollector> = Mutex::new(CycleCollector {
+        roots: Vec::new(),
+        buffered: HashSet::new(),
+        colors: HashMap::new(),
+        garbage: HashSet::new(),
+        threshold: DEFAULT_THRESHOLD,
+        is_collecting: false,
+    });
+}
+
+extern "C" {
+    /// Scopes an object like the program does, which librtdbg keeps track of.
+    #[link_name = "keid.scope"]
+    fn keid_scope(object: *mut KeidAbiClassData);
+    /// Releases the reference taken with `keid.scope` and frees the object through the same path as `keid.unscope`,
+    /// without running its destructor again; under librtdbg, the memory is quarantined.
+    #[link_name = "keid.free_garbage"]
+    fn keid_free_garbage(object: *mut KeidAbiClassData);
+}
+
+/// Returns the reference count of an object, which the program changes with atomic operations as well.
+fn ref_count(object: usize) -> &'static AtomicI64 {
+    unsafe { AtomicI64::from_ptr(std::ptr::addr_of_mut!((*(object as *mut KeidAbiClassData)).ref_count)) }
+}
+
+/// Returns the reference counted objects that the reference fields of `object` point to.
+fn children(object: usize) -> Vec<usize> {
+    let mut children = Vec::new();
+    unsafe {
+        let class_info = (*(object as *mut KeidAbiClassData)).class_info;
+        if class_info.is_null() || (*class_info).reference_fields.is_null() {
+            return children;
+        }
+        for i in 0..(*class_info).reference_fields_count as usize {
+            let field = *(*class_info).reference_fields.add(i);
+            let field_ptr = (object as *const u8).add(field.offset as usize);
+            if (field.field_bitflags & FIELD_NULLABLE) == FIELD_NULLABLE {
+                // nullable values are laid out as { ptr, i8 }, where the i8 is 0 if the value is null
+                let nullability = *field_ptr.add(std::mem::size_of::<*const c_void>());
+                if nullability == 0 {
+                    continue;
+                }
+            }
+            let child = *(field_ptr as *const *mut KeidAbiClassData);
+            if child.is_null() || is_struct(child) {
+                continue;
+            }
+            children.push(child as usize);
+        }
+    }
+    children
+}
+
+/// Fields of interface types can hold structs, which aren't reference counted.
+unsafe fn is_struct(object: *mut KeidAbiClassData) -> bool {
+    let class_info = (*object).class_info;
+    class_info.is_null() || ((*class_info).class_bitflags & TYPE_STRUCT) == TYPE_STRUCT
+}
+
+impl CycleCollector {
+    fn color(&self, object: usize) -> Color {
+        self.colors.get(&object).copied().unwrap_or(Color::Black)
+    }
+
+    fn set_color(&mut self, object: usize, color: Color) {
+        if color == Color::Black {
+            self.colors.remove(&object);
+        } else {
+            self.colors.insert(object, color);
+        }
+    }
+
+    fn possible_root(&mut self, object: usize) {
+        if self.garbage.contains(&object) {
+            return;
+        }
+        self.set_color(object, Color::Purple);
+        if self.buffered.insert(object) {
+            self.roots.push(object);
+        }
+    }
+
+    fn release(&mut self, object: usize) {
+        if self.buffered.remove(&object) {
+            self.roots.retain(|root| *root != object);
+        }
+        self.colors.remove(&object);
+    }
+
+    /// Subtracts the internal references of the object graph reachable from `object`.
+    fn mark_gray(&mut self, object: usize) {
+        let mut stack = vec![object];
+        while let Some(object) = stack.pop() {
+            if self.color(object) == Color::Gray {
+                continue;
+            }
+            self.set_color(object, Color::Gray);
+            for child in children(object) {
+                ref_count(child).fetch_sub(1, Ordering::AcqRel);
+                stack.push(child);
+            }
+        }
+    }
+
+    /// Marks the gray objects that are still referenced from outside of the graph as black,
+    /// and the remaining ones as white.
+    fn scan(&mut self, object: usize) {
+        let mut stack = vec![object];
+        while let Some(object) = stack.pop() {
+            if self.color(object) != Color::Gray {
+                continue;
+            }
+            if ref_count(object).load(Ordering::Acquire) > 0 {
+                self.scan_black(object);
+            } else {
+                self.set_color(object, Color::White);
+                stack.extend(children(object));
+            }
+        }
+    }
+
+    /// Restores the internal references of an object that is still in use, and of everything reachable from it.
+    fn scan_black(&mut self, object: usize) {
+        self.set_color(object, Color::Black);
+        let mut stack = vec![object];
+        while let Some(object) = stack.pop() {
+            for child in children(object) {
+                ref_count(child).fetch_add(1, Ordering::Relaxed);
+                if self.color(child) != Color::Black {
+                    self.set_color(child, Color::Black);
+                    stack.push(child);
+                }
+            }
+        }
+    }
+
+    fn collect_white(&mut self, object: usize, garbage: &mut Vec<usize>) {
+        let mut stack = vec![object];
+        while let Some(object) = stack.pop() {
+            if self.color(object) != Color::White || self.buffered.contains(&object) {
+                continue;
+            }
+            self.set_color(object, Color::Black);
+            garbage.push(object);
+            stack.extend(children(object));
+        }
+    }
+
+    /// Finds the objects that are only referenced by garbage cycles.
+    fn find_garbage(&mut self) -> Vec<usize> {
+        let roots = std::mem::take(&mut self.roots);
+        let mut candidates = Vec::new();
+        for root in roots {
+            if self.color(root) == Color::Purple {
+                self.mark_gray(root);
+                candidates.push(root);
+            } else {
+                self.buffered.remove(&root);
+            }
+        }
+        for root in &candidates {
+            self.scan(*root);
+        }
+
+        let mut garbage = Vec::new();
+        for root in candidates {
+            self.buffered.remove(&root);
+            self.collect_white(root, &mut garbage);
+        }
+        garbage
+    }
+}
+
+/// Frees every object that is only referenced by garbage cycles, and returns how many objects were freed.
+pub fn collect_cycles() -> usize {
+    let garbage = {
+        let mut collector = COLLECTOR.lock().unwrap();
+        if collector.is_collecting {
+            return 0;
+        }
+        collector.is_collecting = true;
+        let garbage = collector.find_garbage();
+        collector.garbage.extend(garbage.iter().copied());
+        garbage
+    };
+
+    // the destructors release the fields of the garbage, which decrease the reference counts again,
+    // so the references subtracted by the trial deletion are added back first
+    for object in &garbage {
+        for child in children(*object) {
+            ref_count(child).fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    // the extra reference keeps the garbage alive while the destructors of the other objects in the cycle run
+    for object in &garbage {
+        unsafe {
+            keid_scope(*object as *mut KeidAbiClassData);
+        }
+    }
+    for object in &garbage {
+        unsafe {
+            let destructor = (*(*(*object as *mut KeidAbiClassData)).class_info).destructor;
+            if !destructor.is_null() {
+                let destructor: extern "C" fn(*mut KeidAbiClassData) = std::mem::transmute(destructor);
+                destructor(*object as *mut KeidAbiClassData);
+            }
+        }
+    }
+    for object in &garbage {
+        unsafe {
+            keid_free_garbage(*object as *mut KeidAbiClassData);
+        }
+    }
+
+    let mut collector = COLLECTOR.lock().unwrap();
+    for object in &garbage {
+        collector.garbage.remove(object);
+        collector.release(*object);
+    }
+    collector.is_collecting = false;
+    garbage.len()
+}
+
+/// Invoked when the reference count of an object is decreased to a value other than zero.
+#[no_mangle]
+pub extern "C" fn keid_gc_possible_root(object: *mut KeidAbiClassData) {
+    let should_collect = {
+        let mut collector = COLLECTOR.lock().unwrap();
+        collector.possible_root(object as usize);
+        !collector.is_collecting && collector.roots.len() >= collector.threshold
+    };
+    if should_collect {
+        collect_cycles();
+    }
+}
+
+/// Invoked right before an object is freed because its reference count reached zero.
+#[no_mangle]
+pub extern "C" fn keid_gc_release(object: *mut KeidAbiClassData) {
+    COLLECTOR.lock().unwrap().release(object as usize);
+}
+
+#[no_mangle]
+pub extern "C" fn keid_gc_collect_cycles() -> usize {
+    collect_cycles()
+}
+
+/// Sets the amount of possible roots that triggers a collection; a threshold of 0 disables automatic collection.
+#[no_mangle]
+pub extern "C" fn keid_gc_set_threshold(threshold: usize) {
+    COLLECTOR.lock().unwrap().threshold = if threshold == 0 { usize::MAX } else { threshold };
+}
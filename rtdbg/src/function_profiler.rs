@@ -0,0 +1,170 @@
+//! Function-level profile of programs compiled with `Compiler::profile_functions`.
+//!
+//! Every function calls `keid_core_runtime_enter_profiled_frame` when it's entered and
+//! `keid_core_runtime_exit_profiled_frame` when it returns, and functions that are unwound by a thrown error are exited
+//! when it's caught. When the program exits, the folded stacks are written to the path in `KEID_PROFILE_OUTPUT`
+//! (`keid.folded` by default), with the nanoseconds spent in each stack excluding its callees, which flamegraph tools
+//! like `flamegraph.pl` and `inferno-flamegraph` read. The call counts and inclusive and exclusive times of every
+//! function are written next to it, to the same path with `.txt` appended.
+
+use lazy_static::lazy_static;
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    ffi::{c_char, CStr},
+    fmt::Write as _,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::report::{self, Level, Value};
+
+const DEFAULT_OUTPUT_PATH: &str = "keid.folded";
+
+/// A function that hasn't returned yet.
+struct OpenFrame {
+    /// The address of the external name of the function, which is a constant string.
+    function: usize,
+    entered: Instant,
+    /// The time spent in the callees of the function that have returned.
+    callee_time: Duration,
+}
+
+#[derive(Default)]
+struct ThreadProfile {
+    frames: Vec<OpenFrame>,
+    /// How often each function is on the stack, so the time of recursive calls is only counted once.
+    active: HashMap<usize, usize>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct FunctionStats {
+    calls: u64,
+    inclusive: Duration,
+    exclusive: Duration,
+}
+
+#[derive(Default)]
+struct FunctionProfile {
+    functions: HashMap<usize, FunctionStats>,
+    /// The exclusive time of every stack, by the functions on it, outermost first.
+    stacks: HashMap<Vec<usize>, Duration>,
+}
+
+thread_local! {
+    static THREAD_PROFILE: RefCell<ThreadProfile> = RefCell::new(ThreadProfile::default());
+}
+
+lazy_static! {
+    static ref PROFILE: Mutex<FunctionProfile> = Mutex::new(FunctionProfile::default());
+}
+
+pub fn enter(function_name: *const c_char) {
+    THREAD_PROFILE.with(|thread| {
+        let mut thread = thread.borrow_mut();
+        *thread.active.entry(function_name as usize).or_default() += 1;
+        thread.frames.push(OpenFrame {
+            function: function_name as usize,
+            entered: Instant::now(),
+            callee_time: Duration::ZERO,
+        });
+    });
+}
+
+pub fn exit() {
+    THREAD_PROFILE.with(|thread| exit_frame(&mut thread.borrow_mut(), Instant::now()));
+}
+
+/// Exits the frames above `depth`, which have been unwound by a thrown error.
+pub fn truncate(depth: usize) {
+    THREAD_PROFILE.with(|thread| {
+        let mut thread = thread.borrow_mut();
+        let now = Instant::now();
+        while thread.frames.len() > depth {
+            exit_frame(&mut thread, now);
+        }
+    });
+}
+
+fn exit_frame(thread: &mut ThreadProfile, now: Instant) {
+    let stack: Vec<usize> = thread.frames.iter().map(|frame| frame.function).collect();
+    let frame = match thread.frames.pop() {
+        Some(frame) => frame,
+        None => return,
+    };
+    let elapsed = now - frame.entered;
+    let exclusive = elapsed.saturating_sub(frame.callee_time);
+    if let Some(caller) = thread.frames.last_mut() {
+        caller.callee_time += elapsed;
+    }
+    let active = thread.active.get_mut(&frame.function).unwrap();
+    *active -= 1;
+    let is_outermost = *active == 0;
+
+    let mut profile = PROFILE.lock().unwrap();
+    let stats = profile.functions.entry(frame.function).or_default();
+    stats.calls += 1;
+    stats.exclusive += exclusive;
+    if is_outermost {
+        stats.inclusive += elapsed;
+    }
+    *profile.stacks.entry(stack).or_default() += exclusive;
+}
+
+/// Returns the name of a function from the address of its name.
+fn function_name(function: usize) -> String {
+    unsafe { CStr::from_ptr(function as *const c_char) }.to_string_lossy().into_owned()
+}
+
+/// Writes the profile of all threads, after exiting the frames of the current thread that haven't returned yet.
+pub fn write() {
+    truncate(0);
+
+    let path = std::env::var("KEID_PROFILE_OUTPUT").ok().filter(|path| !path.is_empty());
+    let path = path.as_deref().unwrap_or(DEFAULT_OUTPUT_PATH);
+    let summary_path = format!("{}.txt", path);
+
+    let profile = PROFILE.lock().unwrap();
+    let mut stacks: HashMap<String, Duration> = HashMap::new();
+    for (stack, time) in &profile.stacks {
+        let stack: Vec<String> = stack.iter().map(|function| function_name(*function)).collect();
+        *stacks.entry(stack.join(";")).or_default() += *time;
+    }
+    let mut functions: HashMap<String, FunctionStats> = HashMap::new();
+    for (function, stats) in &profile.functions {
+        let merged = functions.entry(function_name(*function)).or_default();
+        merged.calls += stats.calls;
+        merged.inclusive += stats.inclusive;
+        merged.exclusive += stats.exclusive;
+    }
+
+    let mut stacks: Vec<(String, Duration)> = stacks.into_iter().collect();
+    stacks.sort();
+    let mut folded = String::new();
+    for (stack, time) in stacks {
+        writeln!(folded, "{} {}", stack, time.as_nanos()).unwrap();
+    }
+
+    let mut functions: Vec<(String, FunctionStats)> = functions.into_iter().collect();
+    functions.sort_by(|(a_name, a), (b_name, b)| b.exclusive.cmp(&a.exclusive).then(a_name.cmp(b_name)));
+    let mut summary = format!("{:>10} {:>14} {:>14}  function\n", "calls", "inclusive ns", "exclusive ns");
+    for (function, stats) in functions {
+        writeln!(summary, "{:>10} {:>14} {:>14}  {}", stats.calls, stats.inclusive.as_nanos(), stats.exclusive.as_nanos(), function)
+            .unwrap();
+    }
+
+    match std::fs::write(path, folded).and_then(|_| std::fs::write(&summary_path, summary)) {
+        Ok(()) => report::report(
+            Level::Info,
+            "function_profile",
+            &format!("Wrote function profile to {} and {}", path, summary_path),
+            &[("path", Value::Str(path)), ("summary_path", Value::Str(&summary_path))],
+        ),
+        Err(e) => report::report(
+            Level::Error,
+            "function_profile_error",
+            &format!("Cannot write function profile to {}: {}", path, e),
+            &[("path", Value::Str(path))],
+        ),
+    }
+}
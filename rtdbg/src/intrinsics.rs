@@ -1,32 +1,67 @@
-use lazy_static::lazy_static;
+use std::cell::RefCell;
 use std::fmt::Write;
-use std::{ffi::*, sync::Mutex};
+use std::ffi::*;
 
-lazy_static! {
-    static ref STACK_FRAMES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+thread_local! {
+    // every thread tracks its own stack frames
+    static STACK_FRAMES: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
 }
 
 #[no_mangle]
 extern "C" fn keid_core_runtime_push_stack_frame(frame: *const c_char) {
     let frame = unsafe { CStr::from_ptr(frame) };
     let frame = frame.to_str().unwrap().to_owned();
-    let mut frames = STACK_FRAMES.lock().unwrap();
-    frames.push(frame);
+    STACK_FRAMES.with(|frames| frames.borrow_mut().push(frame));
 }
 
 #[no_mangle]
 extern "C" fn keid_core_runtime_pop_stack_frame() {
-    let mut frames = STACK_FRAMES.lock().unwrap();
-    frames.pop().expect("keid_core_runtime_pop_stack_frame: stack underflow");
+    STACK_FRAMES.with(|frames| {
+        frames.borrow_mut().pop().expect("keid_core_runtime_pop_stack_frame: stack underflow");
+    });
+}
+
+/// Pushes the stack frame of a function of a program compiled with `Compiler::profile_functions`, and starts timing
+/// the function.
+#[no_mangle]
+extern "C" fn keid_core_runtime_enter_profiled_frame(frame: *const c_char, function_name: *const c_char) {
+    keid_core_runtime_push_stack_frame(frame);
+    crate::function_profiler::enter(function_name);
+}
+
+#[no_mangle]
+extern "C" fn keid_core_runtime_exit_profiled_frame() {
+    crate::function_profiler::exit();
+    keid_core_runtime_pop_stack_frame();
+}
+
+#[no_mangle]
+extern "C" fn keid_core_runtime_write_function_profile() {
+    crate::function_profiler::write();
+}
+
+/// Returns the amount of stack frames of the current thread; saved when entering a `try` block.
+#[no_mangle]
+extern "C" fn keid_core_runtime_get_stack_depth() -> usize {
+    STACK_FRAMES.with(|frames| frames.borrow().len())
+}
+
+/// Discards the stack frames of the functions that were unwound by a thrown error.
+#[no_mangle]
+extern "C" fn keid_core_runtime_truncate_stack_frames(depth: usize) {
+    STACK_FRAMES.with(|frames| frames.borrow_mut().truncate(depth));
+    // profiled functions push a stack frame each, so their depths are the same
+    crate::function_profiler::truncate(depth);
 }
 
 #[no_mangle]
 extern "C" fn keid_core_runtime_get_stack_frames() -> *mut c_char {
     let mut stacktrace = String::new();
-    let frames = STACK_FRAMES.lock().unwrap();
-    for frame in frames.iter().rev() {
-        write!(&mut stacktrace, "  at {}\n", frame).unwrap();
-    }
+    STACK_FRAMES.with(|frames| {
+        for frame in frames.borrow().iter().rev() {
+            write!(&mut stacktrace, "  at {}\n", frame).unwrap();
+        }
+    });
     let cstring = CString::new(stacktrace).unwrap();
     cstring.into_raw()
 }
@@ -40,8 +75,9 @@ extern "C" fn keid_core_runtime_free_stack_frames(frames: *mut c_char) {
 
 #[no_mangle]
 extern "C" fn keid_core_runtime_print_stack_frames() {
-    let frames = STACK_FRAMES.lock().unwrap();
-    for frame in frames.iter().rev() {
-        println!("  at {}", frame);
-    }
+    STACK_FRAMES.with(|frames| {
+        for frame in frames.borrow().iter().rev() {
+            println!("  at {}", frame);
+        }
+    });
 }
@@ -1,19 +1,48 @@
+use lazy_static::lazy_static;
+use report::{Level, Value};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     ffi::{c_char, c_void, CStr},
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Mutex,
+    },
 };
 
+pub mod backtrace;
+pub mod bounds;
+pub mod coverage;
+pub mod cycles;
+pub mod function_profiler;
 pub mod intrinsics;
+pub mod profiler;
+pub mod report;
+pub mod unwind;
+pub mod weak;
 
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
 pub struct KeidAbiClassData {
     class_info: *const KeidAbiClassInfo,
     ref_count: i64,
+    weak_ref: *mut weak::KeidWeakRef,
 }
 
 const TYPE_STRUCT: u32 = 0x01;
 
+/// The amount of deallocated objects whose memory is held back from the allocator, unless changed with
+/// `KEID_RTDBG_QUARANTINE`.
+const DEFAULT_QUARANTINE_SIZE: usize = 1024;
+
+extern "C" {
+    /// The class info of every type, which the compiler emits into the program.
+    #[link_name = "keid.classinfo"]
+    static CLASS_INFO: [KeidAbiClassInfo; 0];
+    #[link_name = "keid.classinfo_count"]
+    static CLASS_INFO_COUNT: u32;
+    fn keid_free(mem: *mut c_void);
+}
+
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
 pub struct KeidAbiClassInfo {
@@ -23,6 +52,10 @@ pub struct KeidAbiClassInfo {
     interface_impls: *const KeidAbiInterfaceImpl,
     class_name: *const c_char,
     class_bitflags: u32,
+    superclass: *const KeidAbiClassInfo,
+    reference_fields_count: u32,
+    reference_fields: *const cycles::KeidAbiReferenceField,
+    instance_size: u64,
 }
 
 #[repr(C)]
@@ -39,16 +72,30 @@ pub enum ScopeType {
     Unscope = 1,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub struct DebugObject {
     pub object_id: u64,
+    /// The number of objects that have been allocated at the same address, including this one.
+    pub generation: u64,
     pub ref_count: i64,
+    /// The size of the instance in bytes, as reported by its class info.
+    pub size: u64,
+    /// The stack at `rtdbg_register_object`, which is symbolized if the object is leaked, misused, or for the heap
+    /// profile.
+    pub allocation_site: Vec<usize>,
 }
 
+/// An object that has been deallocated. It's remembered until another object is allocated at the same address, so any
+/// later use of it is reported with the stacks at which it was allocated and deallocated.
 #[derive(Debug, Clone)]
 pub struct DeadObject {
     pub object_id: u64,
+    pub generation: u64,
     pub class_name: String,
+    pub allocation_site: Vec<usize>,
+    pub deallocation_site: Vec<usize>,
+    /// Whether the memory of the object is in quarantine, i.e. it hasn't been returned to the allocator yet.
+    pub is_quarantined: bool,
 }
 
 #[derive(Debug)]
@@ -56,58 +103,184 @@ pub struct RuntimeDebugState {
     pub object_nonce: u64,
     pub objects: HashMap<*mut KeidAbiClassData, DebugObject>,
     pub dead_objects: HashMap<*mut KeidAbiClassData, DeadObject>,
+    /// Deallocated objects whose memory is freed once `quarantine_size` more objects have been deallocated, oldest first.
+    pub quarantine: VecDeque<*mut KeidAbiClassData>,
+    pub quarantine_size: usize,
+    /// Whether the stacks at which objects are allocated and freed are captured. Walking the stack on every allocation
+    /// is slow, so they're only captured if `KEID_RTDBG_SITES` is set, or if the heap is profiled per allocation site.
+    pub capture_sites: bool,
+    pub heap: profiler::HeapProfile,
+}
+
+lazy_static! {
+    // the debug state is shared by all threads, so every access to it is serialized
+    static ref RTDBG_LOCK: Mutex<()> = Mutex::new(());
 }
 
 #[no_mangle]
 pub extern "C" fn rtdbg_fail() -> ! {
-    println!("  [librtdbg] Exiting due to fatal runtime error");
-    unsafe {
-        keid_core_runtime_print_stack_frames();
-    }
+    report::report(Level::Error, "exit", "Exiting due to fatal runtime error", &[("backtrace", Value::Frames(&backtrace::capture()))]);
     std::process::exit(1)
 }
 
+/// Reports a fatal error and exits.
+fn fatal(event: &str, message: &str, fields: &[(&str, Value)]) -> ! {
+    report::report(Level::Error, event, &format!("Fatal error: {}", message), fields);
+    rtdbg_fail()
+}
+
 #[no_mangle]
 pub extern "C" fn rtdbg_initialize() -> *mut RuntimeDebugState {
     Box::into_raw(Box::new(RuntimeDebugState {
         object_nonce: 1,
         objects: HashMap::new(),
         dead_objects: HashMap::new(),
+        quarantine: VecDeque::new(),
+        quarantine_size: std::env::var("KEID_RTDBG_QUARANTINE")
+            .ok()
+            .and_then(|size| size.parse().ok())
+            .unwrap_or(DEFAULT_QUARANTINE_SIZE),
+        capture_sites: std::env::var_os("KEID_RTDBG_SITES").is_some() || profiler::is_enabled(),
+        heap: profiler::HeapProfile::default(),
     }))
 }
 
 #[no_mangle]
 pub extern "C" fn rtdbg_finish(rtdbg: *mut RuntimeDebugState) {
-    println!("\n  [librtdbg] Keid program execution finished.");
+    let _guard = RTDBG_LOCK.lock().unwrap();
+    report::report(Level::Info, "finish", "Keid program execution finished.", &[]);
     let rtdbg = unsafe { Box::from_raw(rtdbg) };
-    for (key, object) in &rtdbg.objects {
+
+    let mut leaks: Vec<(&*mut KeidAbiClassData, &DebugObject)> = rtdbg.objects.iter().collect();
+    leaks.sort_by_key(|(_, object)| object.object_id);
+    for (key, object) in leaks {
         let (_, class_name) = read_object_instance(&rtdbg, *key);
-        println!(
-            "  [librtdbg] Memory leak detected: object #{} (type {}) (at {:p}) with reference count of {}",
-            object.object_id, class_name, key, object.ref_count
+        let weak_count = unsafe { weak::weak_count(*key) };
+        let allocation_site = if report::is_enabled(Level::Warn) { backtrace::symbolize(&object.allocation_site) } else { Vec::new() };
+        let mut message = format!(
+            "Memory leak detected: object #{} (type {}) (at {:p}) with reference count of {} and weak reference count of {}",
+            object.object_id, class_name, key, object.ref_count, weak_count
+        );
+        if !allocation_site.is_empty() {
+            message.push_str(", allocated at:");
+        }
+        report::report(
+            Level::Warn,
+            "leak",
+            &message,
+            &[
+                ("object_id", Value::Int(object.object_id as i64)),
+                ("class", Value::Str(&class_name)),
+                ("ref_count", Value::Int(object.ref_count)),
+                ("weak_count", Value::Int(weak_count as i64)),
+                ("allocation_site", Value::Frames(&allocation_site)),
+            ],
+        );
+    }
+
+    let total = rtdbg.heap.total;
+    report::report(
+        Level::Info,
+        "summary",
+        &format!(
+            "Summary: {} objects ({} bytes) allocated, {} deallocated, {} leaked ({} bytes); at most {} objects ({} bytes) were live",
+            total.allocated,
+            total.allocated_bytes,
+            total.deallocated,
+            total.live(),
+            total.live_bytes,
+            total.peak_live,
+            total.peak_live_bytes
+        ),
+        &heap_stats_fields(&total),
+    );
+    for (class_name, stats) in rtdbg.heap.sorted_classes() {
+        let mut fields = vec![("class", Value::Str(class_name))];
+        fields.extend(heap_stats_fields(stats));
+        report::report(
+            Level::Info,
+            "class_summary",
+            &format!(
+                "  {}: {} allocated ({} bytes), {} deallocated, {} leaked ({} bytes), at most {} live ({} bytes)",
+                class_name,
+                stats.allocated,
+                stats.allocated_bytes,
+                stats.deallocated,
+                stats.live(),
+                stats.live_bytes,
+                stats.peak_live,
+                stats.peak_live_bytes
+            ),
+            &fields,
+        );
+    }
+    rtdbg.heap.write();
+}
+
+fn heap_stats_fields(stats: &profiler::HeapStats) -> Vec<(&'static str, Value<'static>)> {
+    vec![
+        ("allocated", Value::Int(stats.allocated as i64)),
+        ("allocated_bytes", Value::Int(stats.allocated_bytes as i64)),
+        ("deallocated", Value::Int(stats.deallocated as i64)),
+        ("leaked", Value::Int(stats.live() as i64)),
+        ("leaked_bytes", Value::Int(stats.live_bytes as i64)),
+        ("peak_live", Value::Int(stats.peak_live as i64)),
+        ("peak_live_bytes", Value::Int(stats.peak_live_bytes as i64)),
+    ]
+}
+
+unsafe fn read_class_info(data: *mut KeidAbiClassData) -> KeidAbiClassInfo {
+    let class_info = (*data).class_info;
+    if class_info.is_null() {
+        fatal("null_class_info", &format!("object instance at {:p} class info cannot be null", data), &[]);
+    }
+    // a pointer that isn't one of the emitted class infos comes from uninitialized or overwritten memory
+    let class_infos = std::ptr::addr_of!(CLASS_INFO) as *const KeidAbiClassInfo;
+    let index = (class_info as usize).wrapping_sub(class_infos as usize);
+    let size = std::mem::size_of::<KeidAbiClassInfo>();
+    if !index.is_multiple_of(size) || index / size >= CLASS_INFO_COUNT as usize {
+        fatal(
+            "invalid_class_info",
+            &format!(
+                "object instance at {:p} has class info pointer {:p}, which isn't the class info of any type; the object is \
+                 uninitialized or its memory was overwritten",
+                data, class_info
+            ),
+            &[],
         );
     }
+    *class_info
+}
+
+/// Reports an attempt to `action` an object that has already been deallocated, and exits.
+fn fatal_dead_object(event: &str, action: &str, data: *mut KeidAbiClassData, dead_object: &DeadObject) -> ! {
+    let allocation_site = backtrace::symbolize(&dead_object.allocation_site);
+    let deallocation_site = backtrace::symbolize(&dead_object.deallocation_site);
+    fatal(
+        event,
+        &format!(
+            "attempted {} of deallocated object #{} (at {:p}) (type {}, generation {})",
+            action, dead_object.object_id, data, dead_object.class_name, dead_object.generation
+        ),
+        &[
+            ("object_id", Value::Int(dead_object.object_id as i64)),
+            ("class", Value::Str(&dead_object.class_name)),
+            ("generation", Value::Int(dead_object.generation as i64)),
+            ("allocation_site", Value::Frames(&allocation_site)),
+            ("deallocation_site", Value::Frames(&deallocation_site)),
+        ],
+    )
 }
 
 fn read_object_instance(rtdbg: &RuntimeDebugState, data: *mut KeidAbiClassData) -> (bool, String) {
     if data.is_null() {
-        println!("  [librtdbg] Fatal error: object instance at {:p} cannot be null", data);
-        rtdbg_fail();
+        fatal("null_object", "object instance cannot be null", &[]);
     }
     if let Some(dead_object) = rtdbg.dead_objects.get(&(data as *mut _)) {
-        println!(
-            "  [librtdbg] Fatal error: attempted reference of deallocated object #{} (at {:p}) (type {})",
-            dead_object.object_id, data, dead_object.class_name
-        );
-        rtdbg_fail();
+        fatal_dead_object("use_after_free", "reference", data, dead_object);
     }
     let (is_struct, class_name) = unsafe {
-        let class_info = (*data).class_info;
-        if class_info.is_null() {
-            println!("  [librtdbg] Fatal error: object instance at {:p} class info cannot be null", data);
-            rtdbg_fail();
-        }
-        let class_info = *class_info;
+        let class_info = read_class_info(data);
         ((class_info.class_bitflags & TYPE_STRUCT) == TYPE_STRUCT, class_info.class_name)
     };
     let class_name = unsafe { CStr::from_ptr(class_name) };
@@ -115,72 +288,171 @@ fn read_object_instance(rtdbg: &RuntimeDebugState, data: *mut KeidAbiClassData)
     (is_struct, class_name)
 }
 
+/// Starts tracking a newly allocated object, with a reference count of 0.
+///
+/// # Safety
+///
+/// `rtdbg` must be null or the state returned by `rtdbg_initialize`, and `data` must be null or point to the class data of
+/// an object whose class info has been initialized.
 #[no_mangle]
-pub extern "C" fn rtdbg_register_object(rtdbg: *mut RuntimeDebugState, data: *mut KeidAbiClassData) {
+pub unsafe extern "C" fn rtdbg_register_object(rtdbg: *mut RuntimeDebugState, data: *mut KeidAbiClassData) {
     if rtdbg.is_null() {
-        println!("  [librtdbg] Fatal error: rtdbg instance cannot be null");
-        rtdbg_fail();
+        fatal("null_rtdbg", &format!("attempted registering object at {:p} before librtdbg was initialized", data), &[]);
     }
-    let mut rtdbg = unsafe { Box::from_raw(rtdbg) };
+    let _guard = RTDBG_LOCK.lock().unwrap();
+    let rtdbg = &mut *rtdbg;
 
-    if rtdbg.dead_objects.contains_key(&(data as *mut _)) {
-        rtdbg.dead_objects.remove(&(data as *mut _));
+    // the allocator only hands out memory that is in quarantine if it was freed behind librtdbg's back
+    let generation = match rtdbg.dead_objects.remove(&(data as *mut _)) {
+        Some(dead_object) if dead_object.is_quarantined => {
+            fatal_dead_object("quarantine_reuse", "reallocation of the quarantined memory", data, &dead_object)
+        }
+        Some(dead_object) => dead_object.generation + 1,
+        None => 1,
+    };
+    if let Some(live_object) = rtdbg.objects.get(&(data as *mut _)) {
+        let allocation_site = backtrace::symbolize(&live_object.allocation_site);
+        fatal(
+            "live_object_reuse",
+            &format!("attempted registering a new object at {:p}, which holds live object #{}", data, live_object.object_id),
+            &[("object_id", Value::Int(live_object.object_id as i64)), ("allocation_site", Value::Frames(&allocation_site))],
+        );
     }
 
-    let (is_struct, class_name) = read_object_instance(&rtdbg, data);
+    let (is_struct, class_name) = read_object_instance(rtdbg, data);
     if is_struct {
-        println!("  [librtdbg] Ignoring registry of stack-allocated struct (at {:p}) with type {}", data, class_name);
+        report::report(
+            Level::Trace,
+            "ignore_struct",
+            &format!("Ignoring registry of stack-allocated struct (at {:p}) with type {}", data, class_name),
+            &[("class", Value::Str(&class_name))],
+        );
         return;
     }
-    println!("  [librtdbg] Registered object #{} (at {:p}) with type {}", rtdbg.object_nonce, data, class_name);
+    report::report(
+        Level::Trace,
+        "register",
+        &format!("Registered object #{} (at {:p}, generation {}) with type {}", rtdbg.object_nonce, data, generation, class_name),
+        &[
+            ("object_id", Value::Int(rtdbg.object_nonce as i64)),
+            ("class", Value::Str(&class_name)),
+            ("generation", Value::Int(generation as i64)),
+        ],
+    );
+    let size = read_class_info(data).instance_size;
+    // leaks and misuses of the object are reported with the stack at which it was allocated
+    let allocation_site = if rtdbg.capture_sites { backtrace::capture_addresses() } else { Vec::new() };
+    rtdbg.heap.allocate(&class_name, size, &allocation_site);
     rtdbg.objects.insert(
         data,
         DebugObject {
             object_id: rtdbg.object_nonce,
+            generation,
             ref_count: 0,
+            size,
+            allocation_site,
         },
     );
     rtdbg.object_nonce += 1;
-    Box::into_raw(rtdbg);
 }
 
+/// Changes the reference count of an object for `keid.scope` and `keid.unscope`, and returns the previous count. The
+/// real count is changed while the debug state is locked, so that a concurrent scope change of the object on another
+/// thread can't be seen as a mismatch between the real and the tracked count.
+///
+/// # Safety
+///
+/// `rtdbg` must be null or the state returned by `rtdbg_initialize`, and `data` must be null or point to the class data of
+/// an object whose memory hasn't been freed. Uses of deallocated objects are only detected while they're in quarantine.
 #[no_mangle]
-pub extern "C" fn rtdbg_change_scope(
+pub unsafe extern "C" fn rtdbg_change_scope(
     rtdbg: *mut RuntimeDebugState,
     data: *mut KeidAbiClassData,
     scope_type: ScopeType,
-) {
+) -> i64 {
     if rtdbg.is_null() {
-        println!("  [librtdbg] Fatal error: rtdbg instance cannot be null");
-        rtdbg_fail();
+        fatal("null_rtdbg", &format!("attempted changing scope of object at {:p} before librtdbg was initialized", data), &[]);
+    }
+    let _guard = RTDBG_LOCK.lock().unwrap();
+    let rtdbg = &mut *rtdbg;
+    if let Some(dead_object) = rtdbg.dead_objects.get(&(data as *mut _)) {
+        match scope_type {
+            ScopeType::Scope => fatal_dead_object("use_after_free", "scope", data, dead_object),
+            ScopeType::Unscope => fatal_dead_object("double_free", "unscope", data, dead_object),
+        }
     }
-    let mut rtdbg = unsafe { Box::from_raw(rtdbg) };
-    let (is_struct, class_name) = read_object_instance(&rtdbg, data);
+    let (is_struct, class_name) = read_object_instance(rtdbg, data);
     if is_struct {
-        println!(
-            "  [librtdbg] Ignoring scope change of stack-allocated struct (at {:p}) with type {}",
-            data, class_name
+        // unscoping a struct would free memory that was never allocated on the heap
+        if let ScopeType::Unscope = scope_type {
+            fatal(
+                "unscope_struct",
+                &format!(
+                    "attempted unscope of stack-allocated struct (at {:p}) with type {}, which isn't reference counted",
+                    data, class_name
+                ),
+                &[("class", Value::Str(&class_name))],
+            );
+        }
+        report::report(
+            Level::Trace,
+            "ignore_struct",
+            &format!("Ignoring scope change of stack-allocated struct (at {:p}) with type {}", data, class_name),
+            &[("class", Value::Str(&class_name))],
         );
-        return;
+        return 0;
     }
     let debug_obj = match rtdbg.objects.get(&(data as *mut _)) {
         Some(debug_obj) => debug_obj,
-        None => {
-            println!(
-                "  [librtdbg] Fatal runtime error: attempted changing scope of unregistered object of type {} at {:p}",
+        None => fatal(
+            "unregistered_object",
+            &format!(
+                "attempted changing scope of unregistered object of type {} at {:p}; it was allocated before librtdbg was \
+                 initialized, or without `keid.register_object`",
                 class_name, data as *mut _
-            );
-            rtdbg_fail();
-        }
+            ),
+            &[("class", Value::Str(&class_name))],
+        ),
     };
-    let real_ref_count = unsafe { (*data).ref_count };
-    if real_ref_count != debug_obj.ref_count {
-        println!("  [librtdbg] Fatal runtime error: librtdbg tracked reference count = {}, but the real memory value = {} for object #{} (type {})", debug_obj.ref_count, real_ref_count, debug_obj.object_id, class_name);
-        rtdbg_fail();
-    }
-    let new_ref_count = match scope_type {
-        ScopeType::Scope => debug_obj.ref_count + 1,
-        ScopeType::Unscope => debug_obj.ref_count - 1,
+    let real_ref_count = AtomicI64::from_ptr(std::ptr::addr_of_mut!((*data).ref_count));
+    if real_ref_count.load(Ordering::Acquire) != debug_obj.ref_count {
+        fatal(
+            "ref_count_mismatch",
+            &format!(
+                "librtdbg tracked reference count = {}, but the real memory value = {} for object #{} (type {})",
+                debug_obj.ref_count,
+                real_ref_count.load(Ordering::Acquire),
+                debug_obj.object_id,
+                class_name
+            ),
+            &[
+                ("object_id", Value::Int(debug_obj.object_id as i64)),
+                ("class", Value::Str(&class_name)),
+                ("tracked_ref_count", Value::Int(debug_obj.ref_count)),
+                ("ref_count", Value::Int(real_ref_count.load(Ordering::Acquire))),
+            ],
+        );
+    }
+    if matches!(scope_type, ScopeType::Unscope) && debug_obj.ref_count < 1 {
+        let allocation_site = backtrace::symbolize(&debug_obj.allocation_site);
+        fatal(
+            "ref_count_underflow",
+            &format!(
+                "attempted unscope of object #{} (type {}) with a reference count of {}",
+                debug_obj.object_id, class_name, debug_obj.ref_count
+            ),
+            &[
+                ("object_id", Value::Int(debug_obj.object_id as i64)),
+                ("class", Value::Str(&class_name)),
+                ("ref_count", Value::Int(debug_obj.ref_count)),
+                ("allocation_site", Value::Frames(&allocation_site)),
+            ],
+        );
+    }
+    let (new_ref_count, previous_ref_count) = match scope_type {
+        ScopeType::Scope => (debug_obj.ref_count + 1, real_ref_count.fetch_add(1, Ordering::Relaxed)),
+        // the ordering makes the writes of other threads to the object visible to its destructor
+        ScopeType::Unscope => (debug_obj.ref_count - 1, real_ref_count.fetch_sub(1, Ordering::AcqRel)),
     };
     // println!(
     //     "  [librtdbg] Object #{} (type {}) ref count {} from {} to {}",
@@ -195,24 +467,246 @@ pub extern "C" fn rtdbg_change_scope(
     // );
 
     if new_ref_count == 0 {
-        println!(
-            "  [librtdbg] Deregistering object #{} (type {}) as its ref count is now 0",
-            debug_obj.object_id, class_name
+        report::report(
+            Level::Trace,
+            "deregister",
+            &format!(
+                "Deregistering object #{} (type {}) as its ref count is now 0 (weak reference count = {})",
+                debug_obj.object_id,
+                class_name,
+                weak::weak_count(data)
+            ),
+            &[("object_id", Value::Int(debug_obj.object_id as i64)), ("class", Value::Str(&class_name))],
         );
+        let debug_obj = rtdbg.objects.remove(&(data as *mut _)).unwrap();
+        rtdbg.heap.deallocate(&class_name, debug_obj.size, &debug_obj.allocation_site);
+        let deallocation_site = if rtdbg.capture_sites { backtrace::capture_addresses() } else { Vec::new() };
         rtdbg.dead_objects.insert(
             data as *mut _,
             DeadObject {
                 object_id: debug_obj.object_id,
+                generation: debug_obj.generation,
                 class_name,
+                allocation_site: debug_obj.allocation_site,
+                deallocation_site,
+                is_quarantined: false,
             },
         );
-        rtdbg.objects.remove(&(data as *mut _));
     } else {
         rtdbg.objects.get_mut(&(data as *mut _)).unwrap().ref_count = new_ref_count;
     }
-    Box::into_raw(rtdbg);
+    previous_ref_count
 }
 
-extern "C" {
-    fn keid_core_runtime_print_stack_frames();
+/// Frees the memory of a deallocated object. The memory is held in quarantine until `quarantine_size` more objects have
+/// been freed, so that uses through dangling references are reported as uses of the deallocated object, instead of
+/// silently reaching a new object at the same address.
+///
+/// # Safety
+///
+/// `rtdbg` must be null or the state returned by `rtdbg_initialize`, and `data` must be an object that was allocated with
+/// `keid_malloc` and that's no longer used, as its memory is eventually passed to `keid_free`.
+#[no_mangle]
+pub unsafe extern "C" fn rtdbg_free_object(rtdbg: *mut RuntimeDebugState, data: *mut KeidAbiClassData) {
+    if rtdbg.is_null() {
+        fatal("null_rtdbg", &format!("attempted freeing object at {:p} before librtdbg was initialized", data), &[]);
+    }
+    let _guard = RTDBG_LOCK.lock().unwrap();
+    let rtdbg = &mut *rtdbg;
+
+    match rtdbg.dead_objects.get_mut(&(data as *mut _)) {
+        Some(dead_object) if dead_object.is_quarantined => fatal_dead_object("double_free", "free", data, dead_object),
+        Some(dead_object) => dead_object.is_quarantined = true,
+        None => match rtdbg.objects.get(&(data as *mut _)) {
+            Some(debug_obj) => fatal(
+                "free_live_object",
+                &format!(
+                    "attempted freeing live object #{} (at {:p}) with reference count of {}",
+                    debug_obj.object_id, data, debug_obj.ref_count
+                ),
+                &[("object_id", Value::Int(debug_obj.object_id as i64)), ("ref_count", Value::Int(debug_obj.ref_count))],
+            ),
+            None => fatal("free_unregistered_object", &format!("attempted freeing unregistered object at {:p}", data), &[]),
+        },
+    }
+
+    rtdbg.quarantine.push_back(data);
+    while rtdbg.quarantine.len() > rtdbg.quarantine_size {
+        let oldest = rtdbg.quarantine.pop_front().unwrap();
+        // the dead object is still remembered, so uses of it are reported until the memory is reused
+        if let Some(dead_object) = rtdbg.dead_objects.get_mut(&oldest) {
+            dead_object.is_quarantined = false;
+        }
+        keid_free(oldest as *mut c_void);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{process::Command, ptr::null, sync::Mutex};
+
+    #[repr(transparent)]
+    struct ClassInfos([KeidAbiClassInfo; 1]);
+
+    // the class info is never mutated, so it can be shared between the test threads
+    unsafe impl Sync for ClassInfos {}
+
+    #[export_name = "keid.classinfo"]
+    static TEST_CLASS_INFO: ClassInfos = ClassInfos([KeidAbiClassInfo {
+        destructor: null(),
+        virtual_methods: null(),
+        interface_impls_count: 0,
+        interface_impls: null(),
+        class_name: c"Test".as_ptr(),
+        class_bitflags: 0,
+        superclass: null(),
+        reference_fields_count: 0,
+        reference_fields: null(),
+        instance_size: std::mem::size_of::<KeidAbiClassData>() as u64,
+    }]);
+
+    #[export_name = "keid.classinfo_count"]
+    static TEST_CLASS_INFO_COUNT: u32 = 1;
+
+    static FREED: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+    // the memory is leaked instead of freed, so no object can be allocated at the address of another one
+    #[no_mangle]
+    extern "C" fn keid_free(mem: *mut c_void) {
+        FREED.lock().unwrap().push(mem as usize);
+    }
+
+    #[export_name = "keid.scope"]
+    extern "C" fn keid_scope(_object: *mut KeidAbiClassData) {}
+
+    #[export_name = "keid.free_garbage"]
+    extern "C" fn keid_free_garbage(_object: *mut KeidAbiClassData) {}
+
+    fn new_object() -> *mut KeidAbiClassData {
+        Box::into_raw(Box::new(KeidAbiClassData {
+            class_info: TEST_CLASS_INFO.0.as_ptr(),
+            ref_count: 0,
+            weak_ref: std::ptr::null_mut(),
+        }))
+    }
+
+    /// Allocates, scopes and unscopes an object, which leaves it deallocated but not yet freed.
+    fn deallocated_object(rtdbg: *mut RuntimeDebugState) -> *mut KeidAbiClassData {
+        let object = new_object();
+        unsafe {
+            rtdbg_register_object(rtdbg, object);
+            rtdbg_change_scope(rtdbg, object, ScopeType::Scope);
+            rtdbg_change_scope(rtdbg, object, ScopeType::Unscope);
+        }
+        object
+    }
+
+    /// Fatal errors exit the process, so the test `name` reruns itself in a child process and checks that it reported
+    /// `event` before exiting. Returns whether the caller is the child process, which should trigger the error.
+    pub(crate) fn is_fatal_in_child(name: &str, event: &str) -> bool {
+        if std::env::var_os("RTDBG_TEST_CHILD").is_some() {
+            return true;
+        }
+        let output = Command::new(std::env::current_exe().unwrap())
+            .args([name, "--exact", "--nocapture"])
+            .env("RTDBG_TEST_CHILD", "1")
+            .env("KEID_RTDBG_FORMAT", "json")
+            .env_remove("KEID_RTDBG_OUTPUT")
+            .env_remove("KEID_RTDBG_LOG")
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(output.status.code(), Some(1), "{}", stderr);
+        assert!(stderr.contains(&format!("\"event\":\"{}\"", event)), "{}", stderr);
+        false
+    }
+
+    #[test]
+    fn quarantine_frees_oldest_objects() {
+        let rtdbg = rtdbg_initialize();
+        unsafe { (*rtdbg).quarantine_size = 1 };
+        let first = deallocated_object(rtdbg);
+        unsafe { rtdbg_free_object(rtdbg, first) };
+        assert!(!FREED.lock().unwrap().contains(&(first as usize)));
+
+        let second = deallocated_object(rtdbg);
+        unsafe { rtdbg_free_object(rtdbg, second) };
+        let freed = FREED.lock().unwrap();
+        assert!(freed.contains(&(first as usize)));
+        assert!(!freed.contains(&(second as usize)));
+        // the freed object is still remembered, so a later use of it is reported
+        assert!(!unsafe { &*rtdbg }.dead_objects[&first].is_quarantined);
+    }
+
+    #[test]
+    fn concurrent_scope_changes_are_tracked() {
+        // the pointers are sent to the threads as addresses, as raw pointers aren't `Send`
+        let rtdbg = rtdbg_initialize() as usize;
+        let object = new_object() as usize;
+        unsafe {
+            rtdbg_register_object(rtdbg as *mut _, object as *mut _);
+            rtdbg_change_scope(rtdbg as *mut _, object as *mut _, ScopeType::Scope);
+        }
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                std::thread::spawn(move || {
+                    for _ in 0..1000 {
+                        unsafe {
+                            rtdbg_change_scope(rtdbg as *mut _, object as *mut _, ScopeType::Scope);
+                            rtdbg_change_scope(rtdbg as *mut _, object as *mut _, ScopeType::Unscope);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let (rtdbg, object) = (rtdbg as *mut RuntimeDebugState, object as *mut KeidAbiClassData);
+        assert_eq!(unsafe { (*object).ref_count }, 1);
+        assert_eq!(unsafe { &*rtdbg }.objects[&object].ref_count, 1);
+        assert_eq!(unsafe { rtdbg_change_scope(rtdbg, object, ScopeType::Unscope) }, 1);
+    }
+
+    #[test]
+    fn double_free_is_fatal() {
+        if !is_fatal_in_child("tests::double_free_is_fatal", "double_free") {
+            return;
+        }
+        let rtdbg = rtdbg_initialize();
+        let object = deallocated_object(rtdbg);
+        unsafe {
+            rtdbg_free_object(rtdbg, object);
+            rtdbg_free_object(rtdbg, object);
+        }
+    }
+
+    #[test]
+    fn quarantine_reuse_is_fatal() {
+        if !is_fatal_in_child("tests::quarantine_reuse_is_fatal", "quarantine_reuse") {
+            return;
+        }
+        let rtdbg = rtdbg_initialize();
+        let object = deallocated_object(rtdbg);
+        unsafe {
+            rtdbg_free_object(rtdbg, object);
+            rtdbg_register_object(rtdbg, object);
+        }
+    }
+
+    #[test]
+    fn ref_count_underflow_is_fatal() {
+        if !is_fatal_in_child("tests::ref_count_underflow_is_fatal", "ref_count_underflow") {
+            return;
+        }
+        let rtdbg = rtdbg_initialize();
+        let object = new_object();
+        unsafe {
+            rtdbg_register_object(rtdbg, object);
+            rtdbg_change_scope(rtdbg, object, ScopeType::Unscope);
+        }
+    }
 }
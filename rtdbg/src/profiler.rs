@@ -0,0 +1,355 @@
+//! Heap profile of the objects that librtdbg tracks.
+//!
+//! Allocations are always counted per class, for the summary at `rtdbg_finish`. If `KEID_RTDBG_PROFILE` is set to a
+//! path, they are also counted per allocation site, and the profile is written to that path when the program finishes:
+//! as a text report if the path ends in `.txt`, and as an uncompressed pprof profile otherwise, which can be read with
+//! e.g. `go tool pprof -sample_index=alloc_space <path>`.
+
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+
+use crate::backtrace::{self, Frame};
+use crate::report::{self, Level, Value};
+
+lazy_static! {
+    static ref PROFILE_PATH: Option<String> = std::env::var("KEID_RTDBG_PROFILE").ok().filter(|path| !path.is_empty());
+}
+
+/// Whether allocations are profiled per allocation site, which requires capturing the stack of every allocation.
+pub fn is_enabled() -> bool {
+    PROFILE_PATH.is_some()
+}
+
+/// Allocation statistics of a class, an allocation site, or the whole heap. The peaks are the most objects and the most
+/// bytes that were live at any time, which need not have been at the same time.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeapStats {
+    pub allocated: u64,
+    pub allocated_bytes: u64,
+    pub deallocated: u64,
+    pub live_bytes: u64,
+    pub peak_live: u64,
+    pub peak_live_bytes: u64,
+}
+
+impl HeapStats {
+    pub fn live(&self) -> u64 {
+        self.allocated - self.deallocated
+    }
+
+    fn allocate(&mut self, size: u64) {
+        self.allocated += 1;
+        self.allocated_bytes += size;
+        self.live_bytes += size;
+        self.peak_live = self.peak_live.max(self.live());
+        self.peak_live_bytes = self.peak_live_bytes.max(self.live_bytes);
+    }
+
+    fn deallocate(&mut self, size: u64) {
+        self.deallocated += 1;
+        self.live_bytes -= size;
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct HeapProfile {
+    pub total: HeapStats,
+    pub classes: HashMap<String, HeapStats>,
+    /// Keyed by the stack returned by [`backtrace::capture_addresses`] and the class; only recorded if profiling is
+    /// enabled.
+    pub sites: HashMap<(Vec<usize>, String), HeapStats>,
+}
+
+impl HeapProfile {
+    pub fn allocate(&mut self, class_name: &str, size: u64, site: &[usize]) {
+        self.total.allocate(size);
+        self.classes.entry(class_name.to_owned()).or_default().allocate(size);
+        if is_enabled() {
+            self.sites.entry((site.to_vec(), class_name.to_owned())).or_default().allocate(size);
+        }
+    }
+
+    pub fn deallocate(&mut self, class_name: &str, size: u64, site: &[usize]) {
+        self.total.deallocate(size);
+        self.classes.entry(class_name.to_owned()).or_default().deallocate(size);
+        if is_enabled() {
+            self.sites.entry((site.to_vec(), class_name.to_owned())).or_default().deallocate(size);
+        }
+    }
+
+    /// Returns the classes sorted by name.
+    pub fn sorted_classes(&self) -> Vec<(&String, &HeapStats)> {
+        let mut classes: Vec<(&String, &HeapStats)> = self.classes.iter().collect();
+        classes.sort_by_key(|(class_name, _)| *class_name);
+        classes
+    }
+
+    /// Writes the profile to the path in `KEID_RTDBG_PROFILE`, if it's set.
+    pub fn write(&self) {
+        let path = match PROFILE_PATH.as_ref() {
+            Some(path) => path,
+            None => return,
+        };
+        let profile = if path.ends_with(".txt") { self.format_text().into_bytes() } else { self.encode_pprof() };
+        match std::fs::write(path, profile) {
+            Ok(()) => report::report(
+                Level::Info,
+                "profile",
+                &format!("Wrote heap profile to {}", path),
+                &[("path", Value::Str(path))],
+            ),
+            Err(e) => report::report(
+                Level::Error,
+                "profile_error",
+                &format!("Cannot write heap profile to {}: {}", path, e),
+                &[("path", Value::Str(path))],
+            ),
+        }
+    }
+
+    fn format_text(&self) -> String {
+        fn row(text: &mut String, stats: &HeapStats, name: &str) {
+            writeln!(
+                text,
+                "{:>10} {:>12} {:>10} {:>12} {:>10} {:>12}  {}",
+                stats.allocated,
+                stats.allocated_bytes,
+                stats.live(),
+                stats.live_bytes,
+                stats.peak_live,
+                stats.peak_live_bytes,
+                name
+            )
+            .unwrap();
+        }
+        let header = format!(
+            "{:>10} {:>12} {:>10} {:>12} {:>10} {:>12}  ",
+            "allocs", "alloc bytes", "live", "live bytes", "peak", "peak bytes"
+        );
+
+        let mut text = String::new();
+        writeln!(text, "Classes, by peak live bytes:").unwrap();
+        writeln!(text, "{}class", header).unwrap();
+        let mut classes = self.sorted_classes();
+        classes.sort_by(|(_, a), (_, b)| b.peak_live_bytes.cmp(&a.peak_live_bytes).then(b.allocated.cmp(&a.allocated)));
+        for (class_name, stats) in classes {
+            row(&mut text, stats, class_name);
+        }
+        row(&mut text, &self.total, "(total)");
+
+        writeln!(text, "\nAllocation sites, by peak live bytes:").unwrap();
+        writeln!(text, "{}class", header).unwrap();
+        let mut sites: Vec<(&(Vec<usize>, String), &HeapStats)> = self.sites.iter().collect();
+        sites.sort_by(|((_, a_class), a), ((_, b_class), b)| {
+            b.peak_live_bytes.cmp(&a.peak_live_bytes).then(b.allocated.cmp(&a.allocated)).then(a_class.cmp(b_class))
+        });
+        for ((site, class_name), stats) in sites {
+            row(&mut text, stats, class_name);
+            for frame in backtrace::symbolize(site) {
+                writeln!(text, "    at {}", frame).unwrap();
+            }
+        }
+        text
+    }
+
+    /// Encodes the profile as a `perftools.profiles.Profile` message, with one sample per allocation site and class.
+    fn encode_pprof(&self) -> Vec<u8> {
+        let mut pprof = PprofBuilder::default();
+        // the index of the empty string must be 0
+        pprof.string("");
+        let sample_types = [
+            ("alloc_objects", "count"),
+            ("alloc_space", "bytes"),
+            ("inuse_objects", "count"),
+            ("inuse_space", "bytes"),
+            ("peak_inuse_objects", "count"),
+            ("peak_inuse_space", "bytes"),
+        ];
+        for (name, unit) in sample_types {
+            let (name, unit) = (pprof.string(name), pprof.string(unit));
+            pprof.profile.message(1, |value_type| {
+                value_type.int(1, name);
+                value_type.int(2, unit);
+            });
+        }
+
+        let class_key = pprof.string("class");
+        let mut sites: Vec<(&(Vec<usize>, String), &HeapStats)> = self.sites.iter().collect();
+        sites.sort_by(|((a_site, a_class), _), ((b_site, b_class), _)| a_site.cmp(b_site).then(a_class.cmp(b_class)));
+        for ((site, class_name), stats) in sites {
+            let location_ids: Vec<u64> = site.iter().filter_map(|address| pprof.location(*address)).collect();
+            let class_name = pprof.string(class_name);
+            let values = [
+                stats.allocated,
+                stats.allocated_bytes,
+                stats.live(),
+                stats.live_bytes,
+                stats.peak_live,
+                stats.peak_live_bytes,
+            ];
+            pprof.profile.message(2, |sample| {
+                sample.packed(1, &location_ids);
+                sample.packed(2, &values);
+                sample.message(3, |label| {
+                    label.int(1, class_key);
+                    label.int(2, class_name);
+                });
+            });
+        }
+
+        let PprofBuilder {
+            mut profile,
+            strings,
+            locations,
+            functions,
+            ..
+        } = pprof;
+        for location in locations {
+            profile.message(4, |message| {
+                message.int(1, location.id as i64);
+                message.int(3, location.address as i64);
+                message.message(4, |line| {
+                    line.int(1, location.function_id as i64);
+                    line.int(2, location.line as i64);
+                });
+            });
+        }
+        for function in functions {
+            profile.message(5, |message| {
+                message.int(1, function.id as i64);
+                message.int(2, function.name);
+                message.int(3, function.name);
+                message.int(4, function.file);
+            });
+        }
+        for string in strings {
+            profile.bytes(6, string.as_bytes());
+        }
+        let default_sample_type = sample_types.iter().position(|(name, _)| *name == "alloc_space").unwrap();
+        profile.int(14, default_sample_type as i64 + 1);
+        profile.buf
+    }
+}
+
+struct PprofLocation {
+    id: u64,
+    address: usize,
+    function_id: u64,
+    line: u32,
+}
+
+struct PprofFunction {
+    id: u64,
+    name: i64,
+    file: i64,
+}
+
+/// Collects the string table, locations and functions that the samples of a pprof profile refer to.
+#[derive(Default)]
+struct PprofBuilder {
+    profile: ProtobufEncoder,
+    strings: Vec<String>,
+    string_indices: HashMap<String, i64>,
+    locations: Vec<PprofLocation>,
+    /// The IDs of the locations by address, or `None` for addresses outside of Keid code.
+    location_ids: HashMap<usize, Option<u64>>,
+    functions: Vec<PprofFunction>,
+    function_ids: HashMap<(String, String), u64>,
+}
+
+impl PprofBuilder {
+    fn string(&mut self, string: &str) -> i64 {
+        if let Some(index) = self.string_indices.get(string) {
+            return *index;
+        }
+        let index = self.strings.len() as i64;
+        self.strings.push(string.to_owned());
+        self.string_indices.insert(string.to_owned(), index);
+        index
+    }
+
+    fn location(&mut self, address: usize) -> Option<u64> {
+        if let Some(id) = self.location_ids.get(&address) {
+            return *id;
+        }
+        let id = backtrace::symbolize_frame(address).map(|frame| {
+            let function_id = self.function(&frame);
+            let id = self.locations.len() as u64 + 1;
+            self.locations.push(PprofLocation {
+                id,
+                address,
+                function_id,
+                line: frame.line,
+            });
+            id
+        });
+        self.location_ids.insert(address, id);
+        id
+    }
+
+    fn function(&mut self, frame: &Frame) -> u64 {
+        let key = (frame.function.clone(), frame.file.clone());
+        if let Some(id) = self.function_ids.get(&key) {
+            return *id;
+        }
+        let id = self.functions.len() as u64 + 1;
+        let (name, file) = (self.string(&frame.function), self.string(&frame.file));
+        self.functions.push(PprofFunction { id, name, file });
+        self.function_ids.insert(key, id);
+        id
+    }
+}
+
+const WIRE_VARINT: u64 = 0;
+const WIRE_LENGTH_DELIMITED: u64 = 2;
+
+/// A minimal encoder of protocol buffers; fields with default values are left out, like protobuf implementations do.
+#[derive(Default)]
+struct ProtobufEncoder {
+    buf: Vec<u8>,
+}
+
+impl ProtobufEncoder {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.buf.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        self.buf.push(value as u8);
+    }
+
+    fn key(&mut self, field: u64, wire_type: u64) {
+        self.varint((field << 3) | wire_type);
+    }
+
+    fn int(&mut self, field: u64, value: i64) {
+        if value != 0 {
+            self.key(field, WIRE_VARINT);
+            self.varint(value as u64);
+        }
+    }
+
+    fn bytes(&mut self, field: u64, bytes: &[u8]) {
+        self.key(field, WIRE_LENGTH_DELIMITED);
+        self.varint(bytes.len() as u64);
+        self.buf.extend_from_slice(bytes);
+    }
+
+    fn packed(&mut self, field: u64, values: &[u64]) {
+        if values.is_empty() {
+            return;
+        }
+        let mut packed = ProtobufEncoder::default();
+        for value in values {
+            packed.varint(*value);
+        }
+        self.bytes(field, &packed.buf);
+    }
+
+    fn message(&mut self, field: u64, encode: impl FnOnce(&mut ProtobufEncoder)) {
+        let mut message = ProtobufEncoder::default();
+        encode(&mut message);
+        self.bytes(field, &message.buf);
+    }
+}
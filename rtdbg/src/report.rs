@@ -0,0 +1,165 @@
+//! Reports of librtdbg, which are written to stderr so they don't interleave with the output of the program. They are
+//! configured with environment variables:
+//!
+//! - `KEID_RTDBG_LOG`: the least severe level that is reported; `error`, `warn`, `info` (the default) or `trace`.
+//! - `KEID_RTDBG_OUTPUT`: the path of a file that the reports are written to instead.
+//! - `KEID_RTDBG_FORMAT`: `text` (the default) or `json`, which writes every report as a JSON object on its own line.
+//! - `KEID_RTDBG_SITES`: if set, leaks and misuses of objects are reported with the stacks at which the objects were
+//!   allocated and freed, which are captured on every allocation and deallocation.
+
+use lazy_static::lazy_static;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{LineWriter, Write};
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Trace,
+}
+
+impl Level {
+    fn parse(name: &str) -> Option<Level> {
+        match name.to_ascii_lowercase().as_str() {
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "trace" => Some(Level::Trace),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Trace => "trace",
+        }
+    }
+}
+
+/// A value of a field of a report.
+pub enum Value<'a> {
+    Str(&'a str),
+    Int(i64),
+    /// Frames of a stack trace, innermost first; in text reports, they are written below the message.
+    Frames(&'a [String]),
+}
+
+struct Config {
+    level: Level,
+    is_json: bool,
+    output: Mutex<Box<dyn Write + Send>>,
+}
+
+impl Config {
+    fn from_env() -> Config {
+        let level = std::env::var("KEID_RTDBG_LOG").ok().and_then(|level| Level::parse(&level)).unwrap_or(Level::Info);
+        let is_json = std::env::var("KEID_RTDBG_FORMAT").map(|format| format.eq_ignore_ascii_case("json")).unwrap_or(false);
+        let output: Box<dyn Write + Send> = match std::env::var("KEID_RTDBG_OUTPUT") {
+            Ok(path) => match File::create(&path) {
+                // reports are written line by line, so none are lost if the program exits after a fatal error
+                Ok(file) => Box::new(LineWriter::new(file)),
+                Err(e) => {
+                    eprintln!("  [librtdbg] Cannot write reports to {}: {}", path, e);
+                    Box::new(std::io::stderr())
+                }
+            },
+            Err(_) => Box::new(std::io::stderr()),
+        };
+
+        Config {
+            level,
+            is_json,
+            output: Mutex::new(output),
+        }
+    }
+}
+
+lazy_static! {
+    static ref CONFIG: Config = Config::from_env();
+}
+
+/// Whether reports of `level` are written; used to skip work for reports that would be filtered out.
+pub fn is_enabled(level: Level) -> bool {
+    level <= CONFIG.level
+}
+
+fn write_json_str(dst: &mut String, str: &str) {
+    dst.push('"');
+    for c in str.chars() {
+        match c {
+            '"' => dst.push_str("\\\""),
+            '\\' => dst.push_str("\\\\"),
+            '\n' => dst.push_str("\\n"),
+            '\r' => dst.push_str("\\r"),
+            '\t' => dst.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(dst, "\\u{:04x}", c as u32).unwrap(),
+            c => dst.push(c),
+        }
+    }
+    dst.push('"');
+}
+
+fn format_json(level: Level, event: &str, message: &str, fields: &[(&str, Value)]) -> String {
+    let mut json = String::from("{\"level\":");
+    write_json_str(&mut json, level.name());
+    json.push_str(",\"event\":");
+    write_json_str(&mut json, event);
+    json.push_str(",\"message\":");
+    write_json_str(&mut json, message);
+    for (key, value) in fields {
+        json.push(',');
+        write_json_str(&mut json, key);
+        json.push(':');
+        match value {
+            Value::Str(str) => write_json_str(&mut json, str),
+            Value::Int(int) => write!(&mut json, "{}", int).unwrap(),
+            Value::Frames(frames) => {
+                json.push('[');
+                for (i, frame) in frames.iter().enumerate() {
+                    if i > 0 {
+                        json.push(',');
+                    }
+                    write_json_str(&mut json, frame);
+                }
+                json.push(']');
+            }
+        }
+    }
+    json.push_str("}\n");
+    json
+}
+
+fn format_text(message: &str, fields: &[(&str, Value)]) -> String {
+    let mut text = format!("  [librtdbg] {}\n", message);
+    // a lone stack trace is explained by the message, but several need telling apart
+    let stack_trace_count = fields.iter().filter(|(_, value)| matches!(value, Value::Frames(frames) if !frames.is_empty())).count();
+    for (key, value) in fields {
+        if let Value::Frames(frames) = value {
+            if stack_trace_count > 1 && !frames.is_empty() {
+                writeln!(&mut text, "  {}:", key.replace('_', " ")).unwrap();
+            }
+            for frame in frames.iter() {
+                writeln!(&mut text, "    at {}", frame).unwrap();
+            }
+        }
+    }
+    text
+}
+
+/// Writes a report about `event`. The message is all that text reports show, apart from stack traces; the fields make
+/// the details of the event available to tools that read JSON reports.
+pub fn report(level: Level, event: &str, message: &str, fields: &[(&str, Value)]) {
+    if !is_enabled(level) {
+        return;
+    }
+    let report = if CONFIG.is_json { format_json(level, event, message, fields) } else { format_text(message, fields) };
+    let mut output = CONFIG.output.lock().unwrap_or_else(|e| e.into_inner());
+    // there's nowhere left to report a failure to write a report
+    let _ = output.write_all(report.as_bytes());
+}
@@ -0,0 +1,316 @@
+//! Zero-cost exception handling for thrown Keid errors.
+//!
+//! `throw` wraps the error in a [`KeidException`] and raises it with `_Unwind_RaiseException` from libunwind. Calls that
+//! can throw are compiled to `invoke` instructions; their landing pads either unscope the locals of the frame and resume
+//! unwinding (cleanups), or catch the exception (the landing pads of `try` blocks). [`keid_personality`] is the
+//! personality routine of every Keid function, which finds those landing pads in the LSDA emitted by LLVM.
+
+use std::ffi::{c_int, c_void};
+
+/// Identifies exceptions raised by Keid; exceptions of other languages are never caught, but do run cleanups.
+const KEID_EXCEPTION_CLASS: u64 = u64::from_be_bytes(*b"KEIDKEID");
+
+const URC_FATAL_PHASE1_ERROR: c_int = 3;
+const URC_HANDLER_FOUND: c_int = 6;
+const URC_INSTALL_CONTEXT: c_int = 7;
+const URC_CONTINUE_UNWIND: c_int = 8;
+
+const UA_SEARCH_PHASE: c_int = 1;
+const UA_CLEANUP_PHASE: c_int = 2;
+const UA_HANDLER_FRAME: c_int = 4;
+
+const DW_EH_PE_OMIT: u8 = 0xff;
+const DW_EH_PE_ABSPTR: u8 = 0x00;
+const DW_EH_PE_ULEB128: u8 = 0x01;
+const DW_EH_PE_UDATA2: u8 = 0x02;
+const DW_EH_PE_UDATA4: u8 = 0x03;
+const DW_EH_PE_UDATA8: u8 = 0x04;
+const DW_EH_PE_SLEB128: u8 = 0x09;
+const DW_EH_PE_SDATA2: u8 = 0x0a;
+const DW_EH_PE_SDATA4: u8 = 0x0b;
+const DW_EH_PE_SDATA8: u8 = 0x0c;
+const DW_EH_PE_PCREL: u8 = 0x10;
+const DW_EH_PE_INDIRECT: u8 = 0x80;
+
+/// The landing pad receives the exception pointer in the first data register and the selector in the second one.
+const EXCEPTION_REGISTER: c_int = 0;
+const SELECTOR_REGISTER: c_int = 1;
+
+#[repr(C)]
+pub struct UnwindContext {
+    _private: [u8; 0],
+}
+
+#[repr(C, align(16))]
+pub struct UnwindException {
+    exception_class: u64,
+    exception_cleanup: Option<extern "C" fn(c_int, *mut UnwindException)>,
+    private: [usize; 2],
+}
+
+/// A thrown error. The unwinder header must remain the first field, so a pointer to the exception is also a pointer to
+/// the header.
+#[repr(C)]
+pub struct KeidException {
+    header: UnwindException,
+    error: *mut c_void,
+}
+
+extern "C" {
+    fn _Unwind_GetLanguageSpecificData(context: *mut UnwindContext) -> *const u8;
+    fn _Unwind_GetRegionStart(context: *mut UnwindContext) -> usize;
+    fn _Unwind_GetIPInfo(context: *mut UnwindContext, ip_before_insn: *mut c_int) -> usize;
+    fn _Unwind_SetGR(context: *mut UnwindContext, index: c_int, value: usize);
+    fn _Unwind_SetIP(context: *mut UnwindContext, value: usize);
+}
+
+/// Invoked by the unwinder if a foreign runtime catches and discards a Keid exception.
+extern "C" fn cleanup_exception(_reason: c_int, exception: *mut UnwindException) {
+    unsafe {
+        drop(Box::from_raw(exception as *mut KeidException));
+    }
+}
+
+/// Creates the exception that is raised to throw `error`.
+#[no_mangle]
+pub extern "C" fn keid_exception_new(error: *mut c_void) -> *mut KeidException {
+    Box::into_raw(Box::new(KeidException {
+        header: UnwindException {
+            exception_class: KEID_EXCEPTION_CLASS,
+            exception_cleanup: Some(cleanup_exception),
+            private: [0; 2],
+        },
+        error,
+    }))
+}
+
+/// Frees a caught exception and returns the error that was thrown.
+///
+/// # Safety
+///
+/// `exception` must be an exception created by `keid_exception_new` that has been caught and not taken yet.
+#[no_mangle]
+pub unsafe extern "C" fn keid_exception_take_error(exception: *mut KeidException) -> *mut c_void {
+    let exception = Box::from_raw(exception);
+    exception.error
+}
+
+unsafe fn read_uleb128(ptr: &mut *const u8) -> u64 {
+    let mut result = 0;
+    let mut shift = 0;
+    loop {
+        let byte = **ptr;
+        *ptr = ptr.add(1);
+        result |= ((byte & 0x7f) as u64) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return result;
+        }
+    }
+}
+
+unsafe fn read_sleb128(ptr: &mut *const u8) -> i64 {
+    let mut result = 0;
+    let mut shift = 0;
+    loop {
+        let byte = **ptr;
+        *ptr = ptr.add(1);
+        result |= ((byte & 0x7f) as i64) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            if shift < 64 && byte & 0x40 != 0 {
+                result |= -1 << shift;
+            }
+            return result;
+        }
+    }
+}
+
+unsafe fn read_value<T: Copy>(ptr: &mut *const u8) -> T {
+    let value = std::ptr::read_unaligned(*ptr as *const T);
+    *ptr = ptr.add(std::mem::size_of::<T>());
+    value
+}
+
+/// Reads a value encoded with one of the `DW_EH_PE_*` pointer encodings.
+unsafe fn read_encoded(ptr: &mut *const u8, encoding: u8) -> usize {
+    if encoding == DW_EH_PE_OMIT {
+        return 0;
+    }
+
+    let start = *ptr;
+    let mut value = match encoding & 0x0f {
+        DW_EH_PE_ABSPTR => read_value::<usize>(ptr),
+        DW_EH_PE_ULEB128 => read_uleb128(ptr) as usize,
+        DW_EH_PE_UDATA2 => read_value::<u16>(ptr) as usize,
+        DW_EH_PE_UDATA4 => read_value::<u32>(ptr) as usize,
+        DW_EH_PE_UDATA8 => read_value::<u64>(ptr) as usize,
+        DW_EH_PE_SLEB128 => read_sleb128(ptr) as usize,
+        DW_EH_PE_SDATA2 => read_value::<i16>(ptr) as usize,
+        DW_EH_PE_SDATA4 => read_value::<i32>(ptr) as usize,
+        DW_EH_PE_SDATA8 => read_value::<i64>(ptr) as usize,
+        format => panic!("keid_personality: unsupported pointer encoding {:#x}", format),
+    };
+    if value != 0 {
+        if encoding & 0x70 == DW_EH_PE_PCREL {
+            value = value.wrapping_add(start as usize);
+        }
+        if encoding & DW_EH_PE_INDIRECT != 0 {
+            value = *(value as *const usize);
+        }
+    }
+    value
+}
+
+/// A landing pad of the call site that is being unwound.
+struct LandingPad {
+    address: usize,
+    /// Whether the landing pad catches exceptions.
+    is_catch: bool,
+    /// Whether the landing pad performs cleanup, after which it resumes unwinding the exceptions that it doesn't catch.
+    is_cleanup: bool,
+}
+
+/// Looks up the landing pad of the call site at `ip` in the call site table of the LSDA.
+unsafe fn find_landing_pad(lsda: *const u8, function_start: usize, ip: usize) -> Option<LandingPad> {
+    let mut ptr = lsda;
+
+    let landing_pad_start_encoding = read_value::<u8>(&mut ptr);
+    let landing_pad_start = if landing_pad_start_encoding == DW_EH_PE_OMIT {
+        function_start
+    } else {
+        read_encoded(&mut ptr, landing_pad_start_encoding)
+    };
+
+    let type_table_encoding = read_value::<u8>(&mut ptr);
+    if type_table_encoding != DW_EH_PE_OMIT {
+        read_uleb128(&mut ptr);
+    }
+
+    let call_site_encoding = read_value::<u8>(&mut ptr);
+    let call_site_table_length = read_uleb128(&mut ptr) as usize;
+    let call_site_table_end = ptr.add(call_site_table_length);
+
+    while ptr < call_site_table_end {
+        let start = read_encoded(&mut ptr, call_site_encoding);
+        let length = read_encoded(&mut ptr, call_site_encoding);
+        let landing_pad = read_encoded(&mut ptr, call_site_encoding);
+        let action = read_uleb128(&mut ptr);
+
+        // the call sites are sorted by their start address
+        if ip < function_start + start {
+            break;
+        }
+        if ip < function_start + start + length {
+            if landing_pad == 0 {
+                return None;
+            }
+            // an action of 0 means that the landing pad only performs cleanup, otherwise it's the offset of its first
+            // action record plus 1
+            let (is_catch, is_cleanup) = if action == 0 {
+                (false, true)
+            } else {
+                read_actions(call_site_table_end.add(action as usize - 1))
+            };
+            return Some(LandingPad {
+                address: landing_pad_start + landing_pad,
+                is_catch,
+                is_cleanup,
+            });
+        }
+    }
+
+    None
+}
+
+/// Reads the chain of action records starting at `ptr`, and returns whether they contain a catch clause and a cleanup clause.
+unsafe fn read_actions(mut ptr: *const u8) -> (bool, bool) {
+    let mut is_catch = false;
+    let mut is_cleanup = false;
+    loop {
+        // a type filter of 0 is a cleanup clause, positive ones are catch clauses and negative ones are exception specifications
+        let type_filter = read_sleb128(&mut ptr);
+        is_catch |= type_filter > 0;
+        is_cleanup |= type_filter == 0;
+
+        // the displacement to the next action record is relative to the start of the displacement itself
+        let displacement_start = ptr;
+        let displacement = read_sleb128(&mut ptr);
+        if displacement == 0 {
+            return (is_catch, is_cleanup);
+        }
+        ptr = displacement_start.offset(displacement as isize);
+    }
+}
+
+/// The personality routine of all Keid functions.
+///
+/// # Safety
+///
+/// This must only be called by the unwinder, with the exception being raised and the context of the frame it unwinds.
+#[no_mangle]
+pub unsafe extern "C" fn keid_personality(
+    version: c_int,
+    actions: c_int,
+    exception_class: u64,
+    exception: *mut UnwindException,
+    context: *mut UnwindContext,
+) -> c_int {
+    if version != 1 {
+        return URC_FATAL_PHASE1_ERROR;
+    }
+
+    let lsda = _Unwind_GetLanguageSpecificData(context);
+    if lsda.is_null() {
+        return URC_CONTINUE_UNWIND;
+    }
+
+    let mut ip_before_insn = 0;
+    let mut ip = _Unwind_GetIPInfo(context, &mut ip_before_insn);
+    if ip_before_insn == 0 {
+        // the IP points to the instruction after the call
+        ip -= 1;
+    }
+
+    let landing_pad = match find_landing_pad(lsda, _Unwind_GetRegionStart(context), ip) {
+        Some(landing_pad) => landing_pad,
+        None => return URC_CONTINUE_UNWIND,
+    };
+    // only Keid errors can be caught, but foreign exceptions still release the locals of the frames they unwind
+    let can_catch = landing_pad.is_catch && exception_class == KEID_EXCEPTION_CLASS;
+
+    if actions & UA_SEARCH_PHASE != 0 {
+        return if can_catch { URC_HANDLER_FOUND } else { URC_CONTINUE_UNWIND };
+    }
+
+    if actions & UA_CLEANUP_PHASE != 0 {
+        // the exception is only caught in the frame that was found by the search phase, in every other frame the landing
+        // pad is entered with a selector of 0 to release the locals of the frame before it resumes unwinding
+        let is_handler = can_catch && actions & UA_HANDLER_FRAME != 0;
+        if !is_handler && !landing_pad.is_cleanup {
+            return URC_CONTINUE_UNWIND;
+        }
+        _Unwind_SetGR(context, EXCEPTION_REGISTER, exception as usize);
+        _Unwind_SetGR(context, SELECTOR_REGISTER, is_handler as usize);
+        _Unwind_SetIP(context, landing_pad.address);
+        return URC_INSTALL_CONTEXT;
+    }
+
+    URC_CONTINUE_UNWIND
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn actions_of_catching_landing_pads_are_read() {
+        // a catch clause that continues to a cleanup clause, as emitted for `landingpad cleanup catch ptr null`
+        let actions: [u8; 4] = [0, 0, 1, 0x7d];
+        unsafe {
+            assert_eq!(read_actions(actions.as_ptr()), (false, true));
+            assert_eq!(read_actions(actions.as_ptr().add(2)), (true, true));
+            assert_eq!(read_actions([1u8, 0].as_ptr()), (true, false));
+        }
+    }
+}
@@ -0,0 +1,116 @@
+//! Weak references to reference counted objects.
+//!
+//! All weak references to an object share a single heap-allocated [`KeidWeakRef`], which is stored in the class data of
+//! the object. The live object holds a weak reference of its own to the shared reference, so it can't be freed while
+//! new weak references are created from the object. The destructor of the object clears the target of the shared
+//! reference and releases the reference of the object, so weak references outliving their target can no longer be
+//! upgraded. The shared reference is freed once the last weak reference is released.
+
+use std::{
+    ptr::null_mut,
+    sync::atomic::{AtomicPtr, AtomicU64, Ordering},
+};
+
+use crate::{KeidAbiClassData, TYPE_STRUCT};
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct KeidWeakRef {
+    /// The referenced object, or null if it has been destroyed.
+    /// This must remain the first field; `core::mem::Weak<T>` loads the target through the reference pointer.
+    target: AtomicPtr<KeidAbiClassData>,
+    /// The amount of weak references, including the one held by the live target.
+    weak_count: AtomicU64,
+}
+
+/// Returns the shared weak reference slot in the class data of an object.
+///
+/// # Safety
+///
+/// `object` must point to the class data of a live object.
+unsafe fn weak_ref_slot<'a>(object: *mut KeidAbiClassData) -> &'a AtomicPtr<KeidWeakRef> {
+    AtomicPtr::from_ptr(std::ptr::addr_of_mut!((*object).weak_ref))
+}
+
+/// Returns the amount of weak references to a live object.
+///
+/// # Safety
+///
+/// `object` must point to the class data of a live object.
+pub unsafe fn weak_count(object: *mut KeidAbiClassData) -> u64 {
+    let weak_ref = weak_ref_slot(object).load(Ordering::Acquire);
+    if weak_ref.is_null() {
+        0
+    } else {
+        // the reference held by the object itself isn't counted
+        (*weak_ref).weak_count.load(Ordering::Relaxed) - 1
+    }
+}
+
+/// Creates a new weak reference to an object.
+/// Structs aren't reference counted, so weak references to them are created without a target.
+///
+/// # Safety
+///
+/// `object` must be null or point to the class data of a live object.
+#[no_mangle]
+pub unsafe extern "C" fn keid_weak_new(object: *mut KeidAbiClassData) -> *mut KeidWeakRef {
+    if object.is_null() || (*object).class_info.is_null() || ((*(*object).class_info).class_bitflags & TYPE_STRUCT) == TYPE_STRUCT {
+        return Box::into_raw(Box::new(KeidWeakRef {
+            target: AtomicPtr::new(null_mut()),
+            weak_count: AtomicU64::new(1),
+        }));
+    }
+
+    let slot = weak_ref_slot(object);
+    let mut weak_ref = slot.load(Ordering::Acquire);
+    if weak_ref.is_null() {
+        let new_weak_ref = Box::into_raw(Box::new(KeidWeakRef {
+            target: AtomicPtr::new(object),
+            weak_count: AtomicU64::new(2),
+        }));
+        match slot.compare_exchange(null_mut(), new_weak_ref, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => return new_weak_ref,
+            Err(installed) => {
+                // another thread installed a shared reference first
+                drop(Box::from_raw(new_weak_ref));
+                weak_ref = installed;
+            }
+        }
+    }
+    // the object keeps the shared reference alive, so it can't be freed concurrently
+    (*weak_ref).weak_count.fetch_add(1, Ordering::Relaxed);
+    weak_ref
+}
+
+/// Releases a weak reference created by `keid_weak_new`, or the reference held by an object.
+///
+/// # Safety
+///
+/// `weak_ref` must be null or a weak reference that hasn't been released yet.
+#[no_mangle]
+pub unsafe extern "C" fn keid_weak_release(weak_ref: *mut KeidWeakRef) {
+    if weak_ref.is_null() {
+        return;
+    }
+    if (*weak_ref).weak_count.fetch_sub(1, Ordering::Release) != 1 {
+        return;
+    }
+    std::sync::atomic::fence(Ordering::Acquire);
+    drop(Box::from_raw(weak_ref));
+}
+
+/// Invoked by the destructor of an object to detach it from its weak references.
+///
+/// # Safety
+///
+/// `object` must point to the class data of an object whose destructor is running.
+#[no_mangle]
+pub unsafe extern "C" fn keid_weak_clear(object: *mut KeidAbiClassData) {
+    let weak_ref = weak_ref_slot(object).swap(null_mut(), Ordering::AcqRel);
+    if weak_ref.is_null() {
+        return;
+    }
+    (*weak_ref).target.store(null_mut(), Ordering::Release);
+    keid_weak_release(weak_ref);
+}
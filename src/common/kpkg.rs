@@ -2,7 +2,7 @@ use bincode::*;
 
 use crate::tree::{
     ast::{FunctionModifier, FunctionType, Varargs},
-    AccessorNodeType, ClassType, FunctionNode, GenericDefNode, KeidModuleNode,
+    AccessorNodeType, ClassType, FunctionNode, GenericDefNode, KeidModuleNode, Visibility,
 };
 
 use super::{types::ComplexType, GenericIdentifier};
@@ -32,6 +32,7 @@ impl KeidPackageData {
                     ClassType::Enum => continue,
                 },
                 name: class.base_name.clone(),
+                is_public: class.visibility == Visibility::Public,
                 generic_defs: class.generic_defs.clone(),
                 fields: Vec::with_capacity(class.fields.len()),
                 methods: Vec::with_capacity(class.functions.len()),
@@ -41,6 +42,7 @@ impl KeidPackageData {
                 ty_decl.fields.push(FieldDecl {
                     name: field.name.clone(),
                     ty: field.ty.clone(),
+                    is_public: field.visibility == Visibility::Public,
                 });
             }
             for function_id in &class.functions {
@@ -50,7 +52,7 @@ impl KeidPackageData {
             }
             for accessor in &class.accessors {
                 ty_decl.accessors.push(AccessorDecl {
-                    modifiers: Vec::new(),
+                    modifiers: module.functions[accessor.function_id].modifiers.clone(),
                     kind: match accessor.accessor_type {
                         AccessorNodeType::Getter => AccessorKind::Getter,
                         AccessorNodeType::Setter(_) => AccessorKind::Setter,
@@ -82,6 +84,7 @@ pub enum TypeDeclKind {
 pub struct TypeDecl {
     pub kind: TypeDeclKind,
     pub name: String,
+    pub is_public: bool,
     pub generic_defs: Vec<GenericDefNode>,
     pub fields: Vec<FieldDecl>,
     pub methods: Vec<FunctionDecl>,
@@ -92,6 +95,7 @@ pub struct TypeDecl {
 pub struct FieldDecl {
     pub name: String,
     pub ty: ComplexType,
+    pub is_public: bool,
 }
 
 #[derive(Debug, Encode, Decode)]
@@ -7,7 +7,7 @@ pub use type_provider::*;
 use self::types::{ComplexType, IntoOpaqueType};
 
 use crate::compiler::llvm::{Insn, OpaqueValue};
-use crate::func::compilers::CallCompiler;
+use crate::func::compilers::{CallCompiler, InterfaceInvocation};
 use crate::func::utils::FunctionCompilerUtils;
 use crate::func::FunctionCompiler;
 use crate::parser;
@@ -23,6 +23,8 @@ pub trait TypeResolver = Fn(&str) -> Result<ComplexType>;
 pub struct CompilerError {
     pub message: String,
     pub loc: TokenLocation,
+    /// Additional context, such as the macro expansions that produced the code at `loc`.
+    pub notes: Vec<String>,
     pub backtrace: Backtrace,
 }
 
@@ -31,6 +33,7 @@ impl Clone for CompilerError {
         Self {
             message: self.message.clone(),
             loc: self.loc.clone(),
+            notes: self.notes.clone(),
             backtrace: Backtrace::disabled(),
         }
     }
@@ -45,9 +48,14 @@ impl Display for CompilerError {
 
 impl CompilerError {
     pub fn as_pest_error(&self, source: &str) -> pest::error::Error<Rule> {
+        let mut message = self.message.clone();
+        for note in &self.notes {
+            message.push_str("\nnote: ");
+            message.push_str(note);
+        }
         pest::error::Error::new_from_span(
             pest::error::ErrorVariant::<parser::Rule>::CustomError {
-                message: self.message.clone(),
+                message,
             },
             pest::Span::new(source, self.loc.start, self.loc.end).unwrap_or_else(|| panic!("message: {}", self.message)),
         )
@@ -78,6 +86,8 @@ pub trait ValueContainer {
     fn load(&self, fc: &mut FunctionCompiler) -> Result<OpaqueValue>;
     /// stores `val` into `self`
     fn store(&self, op: Operator, fc: &mut FunctionCompiler, val: TypedValue) -> Result<()>;
+    /// releases the value currently held by `self`, prior to it being overwritten by [`ValueContainer::store`]
+    fn unscope_current(&self, fc: &mut FunctionCompiler) -> Result<()>;
 }
 
 #[derive(Debug)]
@@ -97,32 +107,129 @@ impl ValueContainer for TypedValueContainer {
         Ok(())
     }
 
+    fn unscope_current(&self, fc: &mut FunctionCompiler) -> Result<()> {
+        let val = self.load(fc)?;
+        fc.try_unscope(&TypedValue::new(self.0.ty.clone(), val))?;
+        Ok(())
+    }
+
     fn get_type(&self) -> ComplexType {
         self.0.ty.clone()
     }
 }
 
+/// A function backing a `get` or `set` accessor.
+#[derive(Debug, Clone)]
+pub enum AccessorFunction {
+    /// An accessor whose implementation is known at compile time.
+    Static(ResolvedFunctionNode),
+    /// An accessor declared by an interface, which is looked up in the vtable of the instance.
+    Interface {
+        func: ResolvedFunctionNode,
+        interface_id: usize,
+        method_id: usize,
+    },
+}
+
+impl AccessorFunction {
+    pub fn get_function(&self) -> &ResolvedFunctionNode {
+        match self {
+            AccessorFunction::Static(func) | AccessorFunction::Interface { func, .. } => func,
+        }
+    }
+}
+
 #[derive(Debug)]
-pub struct AccessorValueContainer(ResolvedFunctionNode, OpaqueValue);
+pub struct AccessorValueContainer {
+    name: String,
+    getter: Option<AccessorFunction>,
+    setter: Option<AccessorFunction>,
+    instance: TypedValue,
+}
 
 impl AccessorValueContainer {
-    pub fn new(func: ResolvedFunctionNode, val: OpaqueValue) -> AccessorValueContainer {
-        AccessorValueContainer(func, val)
+    pub fn new(
+        name: String,
+        getter: Option<AccessorFunction>,
+        setter: Option<AccessorFunction>,
+        instance: TypedValue,
+    ) -> AccessorValueContainer {
+        AccessorValueContainer {
+            name,
+            getter,
+            setter,
+            instance,
+        }
+    }
+
+    fn call(&self, fc: &mut FunctionCompiler, accessor: &AccessorFunction, args: &[TypedValue]) -> Result<OpaqueValue> {
+        let func = accessor.get_function();
+        let func_ref = match accessor {
+            AccessorFunction::Static(func) => fc.get_function_ref(func)?,
+            AccessorFunction::Interface {
+                interface_id,
+                method_id,
+                ..
+            } => fc.get_interface_method_ptr(&InterfaceInvocation::Instance(self.instance.clone()), *interface_id, *method_id)?,
+        };
+        let mut call_args = vec![TypedValue::new(func.params[0].clone(), self.instance.val)];
+        call_args.extend_from_slice(args);
+        fc.call_function(func_ref, func, &call_args)
     }
 }
 
 impl ValueContainer for AccessorValueContainer {
     fn load(&self, fc: &mut FunctionCompiler) -> Result<OpaqueValue> {
-        let func_value = fc.get_function_ref(&self.0)?;
-        fc.call_function(func_value, &self.0, &[TypedValue::new(self.0.params[0].clone(), self.1)])
+        match &self.getter {
+            Some(getter) => self.call(fc, getter, &[]),
+            None => Err(crate::compiler_error!(
+                fc,
+                "Property `{}` of type `{}` has no `get` accessor",
+                self.name,
+                self.instance.ty.to_string()
+            )),
+        }
+    }
+
+    fn store(&self, op: Operator, fc: &mut FunctionCompiler, val: TypedValue) -> Result<()> {
+        let setter = match &self.setter {
+            Some(setter) => setter,
+            None => return Err(crate::compiler_error!(
+                fc,
+                "Property `{}` of type `{}` has no `set` accessor",
+                self.name,
+                self.instance.ty.to_string()
+            )),
+        };
+        fc.assert_callable(setter.get_function())?;
+        let value_type = self.get_type();
+        let val = match op {
+            Operator::Equals => val,
+            _ => {
+                // compound assignments call the getter, apply the operator to a temporary copy, and pass the result to the setter
+                let current = TypedValue::new(value_type.clone(), self.load(fc)?);
+                let temp = TypedValue::new(value_type.clone(), fc.emit(Insn::Alloca(value_type.as_llvm_type(fc.cpl))));
+                fc.copy(&current, &temp)?;
+                fc.store(op, val, &temp)?;
+                TypedValue::new(value_type.clone(), TypedValueContainer(temp).load(fc)?)
+            }
+        };
+        fc.assert_assignable_to(&val.ty, &value_type)?;
+        self.call(fc, setter, &[val])?;
+        Ok(())
     }
 
-    fn store(&self, _: Operator, _: &mut FunctionCompiler, _: TypedValue) -> Result<()> {
-        todo!()
+    fn unscope_current(&self, _: &mut FunctionCompiler) -> Result<()> {
+        // the setter is responsible for releasing the value it replaces
+        Ok(())
     }
 
     fn get_type(&self) -> ComplexType {
-        self.0.return_type.clone()
+        match (&self.getter, &self.setter) {
+            (Some(getter), _) => getter.get_function().return_type.clone(),
+            (None, Some(setter)) => setter.get_function().params[1].clone(),
+            (None, None) => unreachable!("accessors must have a getter or a setter"),
+        }
     }
 }
 
@@ -134,6 +241,7 @@ macro_rules! compiler_error {
         $crate::common::CompilerError {
             message,
             loc,
+            notes: Vec::new(),
             backtrace: std::backtrace::Backtrace::force_capture(),
         }
     }};
@@ -147,6 +255,7 @@ macro_rules! compiler_error_loc {
         $crate::common::CompilerError {
             message,
             loc,
+            notes: Vec::new(),
             backtrace: std::backtrace::Backtrace::force_capture(),
         }
     }};
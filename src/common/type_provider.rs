@@ -155,9 +155,195 @@ impl TypeProvider {
             self.roots.push(node);
         }
 
+        if errors.is_empty() {
+            errors.extend(self.validate_class_hierarchies());
+        }
+
+        errors
+    }
+
+    /// Checks the inheritance declared by every class in the resolved modules.
+    /// Only the source nodes are inspected, so this is safe to run before any class has been implemented.
+    fn validate_class_hierarchies(&self) -> Vec<(CompilerError, usize)> {
+        let mut errors = Vec::new();
+        for root in &self.roots {
+            for class in &root.classes {
+                if let Err(e) = self.validate_class_hierarchy(class) {
+                    errors.push((e, class.module_id));
+                }
+            }
+        }
         errors
     }
 
+    fn get_class_node_by_name(&self, name: &str) -> Option<&ClassNode> {
+        for classes in self.roots.iter().map(|root| &root.classes) {
+            for class in classes {
+                if class.base_name == name {
+                    return Some(class);
+                }
+            }
+        }
+        None
+    }
+
+    fn validate_class_hierarchy(&self, class: &ClassNode) -> Result<()> {
+        // each superclass of the class, paired with its generic arguments in terms of the generics of `class`
+        let mut chain: Vec<(&ClassNode, Vec<ComplexType>)> = Vec::new();
+        let mut current = class.superclass.clone();
+        while let Some(superclass_ident) = current {
+            let superclass = match self.get_class_node_by_name(&superclass_ident.name) {
+                Some(superclass) => superclass,
+                None => {
+                    return Err(compiler_error_loc!(
+                        &class.loc,
+                        "Unknown superclass `{}` of `{}`",
+                        superclass_ident.name,
+                        class.base_name
+                    ))
+                }
+            };
+            if superclass.class_type != ClassType::Class {
+                return Err(compiler_error_loc!(
+                    &class.loc,
+                    "Class `{}` cannot extend `{}`, which is not a class",
+                    class.base_name,
+                    superclass.base_name
+                ));
+            }
+            if superclass.base_name == class.base_name || chain.iter().any(|(existing, _)| existing.base_name == superclass.base_name) {
+                return Err(compiler_error_loc!(&class.loc, "Cyclic inheritance: `{}` is a superclass of itself", superclass.base_name));
+            }
+            if superclass.generic_defs.len() != superclass_ident.generic_args.len() {
+                return Err(compiler_error_loc!(
+                    &class.loc,
+                    "Superclass `{}` expects {} generic argument(s), but {} were given",
+                    superclass.base_name,
+                    superclass.generic_defs.len(),
+                    superclass_ident.generic_args.len()
+                ));
+            }
+
+            let generic_args = match chain.last() {
+                Some((subclass, subclass_args)) => superclass_ident
+                    .generic_args
+                    .iter()
+                    .map(|arg| extract_type(self, arg.clone(), &subclass.generic_defs, subclass_args))
+                    .collect::<anyhow::Result<Vec<_>>>()
+                    .unwrap_or_default(),
+                None => superclass_ident.generic_args.clone(),
+            };
+            current = superclass.superclass.clone();
+            chain.push((superclass, generic_args));
+        }
+
+        for field in &class.fields {
+            if let Some((superclass, _)) = chain.iter().find(|(superclass, _)| superclass.fields.iter().any(|f| f.name == field.name)) {
+                return Err(compiler_error_loc!(
+                    &class.loc,
+                    "Field `{}` of `{}` is already declared by superclass `{}`",
+                    field.name,
+                    class.base_name,
+                    superclass.base_name
+                ));
+            }
+        }
+
+        for function_id in &class.functions {
+            let func = self.get_function_node(class.module_id, *function_id).unwrap();
+            let is_virtual = func.modifiers.contains(&FunctionModifier::Virtual);
+            let is_override = func.modifiers.contains(&FunctionModifier::Override);
+            let method_name = utils::get_type_leaf(&func.base_name);
+
+            if is_virtual || is_override {
+                if class.class_type != ClassType::Class {
+                    return Err(compiler_error_loc!(&func.loc, "Only methods of classes can be `virtual` or `override`"));
+                }
+                if func.function_type == FunctionType::Static {
+                    return Err(compiler_error_loc!(&func.loc, "Static method `{}` cannot be `virtual` or `override`", func.base_name));
+                }
+                if func.generic_defs.len() != class.generic_defs.len() {
+                    return Err(compiler_error_loc!(&func.loc, "Generic method `{}` cannot be `virtual` or `override`", func.base_name));
+                }
+                if is_virtual && is_override {
+                    return Err(compiler_error_loc!(&func.loc, "Method `{}` cannot be both `virtual` and `override`", func.base_name));
+                }
+            }
+
+            if func.function_type == FunctionType::Static {
+                continue;
+            }
+
+            // the closest method of the same name declared by a superclass
+            let inherited = chain.iter().find_map(|(superclass, generic_args)| {
+                superclass
+                    .functions
+                    .iter()
+                    .map(|id| self.get_function_node(superclass.module_id, *id).unwrap())
+                    .find(|method| method.function_type == FunctionType::Instance && utils::get_type_leaf(&method.base_name) == method_name)
+                    .map(|method| (method, generic_args))
+            });
+
+            match inherited {
+                Some((inherited, generic_args)) => {
+                    let inherited_is_virtual = inherited.modifiers.contains(&FunctionModifier::Virtual)
+                        || inherited.modifiers.contains(&FunctionModifier::Override);
+                    if is_override && !inherited_is_virtual {
+                        return Err(compiler_error_loc!(
+                            &func.loc,
+                            "Method `{}` cannot override `{}`, which is not `virtual`",
+                            func.base_name,
+                            inherited.base_name
+                        ));
+                    }
+                    if !is_override && inherited_is_virtual {
+                        return Err(compiler_error_loc!(
+                            &func.loc,
+                            "Method `{}` hides the virtual method `{}` (help: declare it as `override`)",
+                            func.base_name,
+                            inherited.base_name
+                        ));
+                    }
+                    if is_override {
+                        // the first parameter is `this`, which is expected to differ
+                        let params_match = func.params.len() == inherited.params.len()
+                            && func.params.iter().zip(&inherited.params).skip(1).all(|(param, inherited_param)| {
+                                match extract_type(self, inherited_param.ty.clone(), &inherited.generic_defs, generic_args) {
+                                    Ok(ty) => ty == param.ty,
+                                    Err(_) => true,
+                                }
+                            });
+                        let return_type_matches =
+                            match extract_type(self, inherited.return_type.clone(), &inherited.generic_defs, generic_args) {
+                                Ok(ty) => ty == func.return_type,
+                                Err(_) => true,
+                            };
+                        if !params_match || !return_type_matches {
+                            return Err(compiler_error_loc!(
+                                &func.loc,
+                                "Method `{}` does not match the signature of the method it overrides, `{}`",
+                                func.base_name,
+                                inherited.base_name
+                            ));
+                        }
+                    }
+                }
+                None if is_override => {
+                    return Err(compiler_error_loc!(
+                        &func.loc,
+                        "Method `{}` is declared as `override`, but no superclass of `{}` declares a virtual method `{}`",
+                        func.base_name,
+                        class.base_name,
+                        method_name
+                    ));
+                }
+                None => (),
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn get_impl_source_interface(&self, interface_impl: &InterfaceImplNode) -> &ClassNode {
         for root in &self.roots {
             for interface in &root.classes {
@@ -242,6 +428,14 @@ impl TypeProvider {
                     });
                 }
             }
+            for field in &root.fields {
+                if utils::get_type_namespace(&field.name) == namespace {
+                    members.push(NamespaceMember {
+                        name: utils::get_type_leaf(&field.name).to_owned(),
+                        member_type: NamespaceMemberType::Member,
+                    });
+                }
+            }
         }
         members
     }
@@ -310,11 +504,11 @@ impl TypeProvider {
         None
     }
 
-    pub fn get_static_field_by_name(&self, field_name: &str) -> Option<ComplexType> {
+    pub fn get_static_field_by_name(&self, field_name: &str) -> Option<&FieldNode> {
         for fields in self.roots.iter().map(|root| &root.fields) {
             for field in fields {
                 if field.name == field_name {
-                    return Some(field.ty.clone());
+                    return Some(field);
                 }
             }
         }
@@ -423,22 +617,22 @@ impl TypeProvider {
         match (child, parent) {
             (ComplexType::Basic(BasicType::Unknown), _) | (_, ComplexType::Basic(BasicType::Unknown)) => true,
             (ComplexType::Basic(BasicType::Object(child_ident)), ComplexType::Basic(BasicType::Object(parent_ident))) => {
-                let mut child_ident = child_ident;
+                let mut child_ident = child_ident.clone();
                 loop {
-                    if child_ident == parent_ident {
+                    if &child_ident == parent_ident {
                         return true;
                     }
 
-                    let child_class = match self.get_class_by_name(child_ident) {
+                    let child_class = match self.get_class_by_name(&child_ident) {
                         Some(class) => class,
-                        None => match self.get_enum_by_name(child_ident) {
+                        None => match self.get_enum_by_name(&child_ident) {
                             Some(_) => return false,
                             None => {
                                 panic!("class does not exist: {:?} (parent = {:?})", child_ident, parent.to_string())
                             }
                         },
                     };
-                    let resolved_interface_impls = self.get_resolved_interface_impls(child_ident);
+                    let resolved_interface_impls = self.get_resolved_interface_impls(&child_ident);
                     for resolved_interface_impl in resolved_interface_impls {
                         let interface_impl = self.get_source_interface_impl(&resolved_interface_impl);
                         let interface = self.get_impl_source_interface(interface_impl);
@@ -453,8 +647,7 @@ impl TypeProvider {
                         }
                     }
 
-                    let source_class = self.get_source_class(&child_class);
-                    match &source_class.superclass {
+                    match child_class.superclass {
                         Some(superclass) => {
                             child_ident = superclass;
                         }
@@ -478,6 +671,67 @@ impl TypeProvider {
         None
     }
 
+    /// Returns the interface implementations of the given type and of each of its superclasses.
+    /// If an interface is implemented more than once along the hierarchy, the implementation closest to the given type is used.
+    pub fn get_inherited_interface_impls(&self, type_root: &GenericIdentifier) -> Vec<ResolvedInterfaceImplNode> {
+        let mut impls: Vec<ResolvedInterfaceImplNode> = Vec::new();
+        let mut current = Some(type_root.clone());
+        while let Some(ident) = current {
+            for resolved_interface_impl in self.get_resolved_interface_impls(&ident) {
+                if !impls.iter().any(|existing| {
+                    existing.interface_id == resolved_interface_impl.interface_id
+                        && existing.interface_generic_impls == resolved_interface_impl.interface_generic_impls
+                }) {
+                    impls.push(resolved_interface_impl);
+                }
+            }
+            current = match self.get_class_by_name(&ident) {
+                Some(class_impl) if class_impl.class_type == ClassType::Class => class_impl.superclass,
+                _ => None,
+            };
+        }
+        impls
+    }
+
+    /// Returns the virtual method table of the given class.
+    /// The table of a class starts with the table of its superclass, where each `override` method replaces the method it overrides,
+    /// followed by the `virtual` methods declared by the class itself.
+    pub fn get_virtual_methods(&self, class_impl: &ResolvedClassNode) -> Vec<ResolvedFunctionNode> {
+        let mut methods = match &class_impl.superclass {
+            Some(superclass) if class_impl.class_type == ClassType::Class => match self.get_class_by_name(superclass) {
+                Some(superclass_impl) => self.get_virtual_methods(&superclass_impl),
+                None => Vec::new(),
+            },
+            _ => Vec::new(),
+        };
+
+        let source_class = self.get_source_class(class_impl);
+        for function_id in &source_class.functions {
+            let func = self.get_function_node(source_class.module_id, *function_id).unwrap();
+            let is_override = func.modifiers.contains(&FunctionModifier::Override);
+            if !is_override && !func.modifiers.contains(&FunctionModifier::Virtual) {
+                continue;
+            }
+
+            let func_impl = func.create_impl(self, &class_impl.generic_impls).unwrap();
+            let method_name = utils::get_type_leaf(&func.base_name);
+            match methods.iter().position(|method| utils::get_type_leaf(&self.get_source_function(method).base_name) == method_name) {
+                Some(slot) if is_override => methods[slot] = func_impl,
+                _ => methods.push(func_impl),
+            }
+        }
+        methods
+    }
+
+    /// Returns the slot of the given virtual method in the virtual method table of its declaring class,
+    /// which is the same slot in the table of every subclass.
+    pub fn get_virtual_method_slot(&self, class_impl: &ResolvedClassNode, func: &FunctionNode) -> Option<usize> {
+        let method_name = utils::get_type_leaf(&func.base_name);
+        self.get_virtual_methods(class_impl)
+            .iter()
+            .position(|method| utils::get_type_leaf(&self.get_source_function(method).base_name) == method_name)
+    }
+
     pub fn get_resolved_interface_impls(&self, type_root: &GenericIdentifier) -> Vec<ResolvedInterfaceImplNode> {
         let mut impls = Vec::new();
         for interface_impls in self.roots.iter().map(|root| &root.interface_impls) {
@@ -95,6 +95,23 @@ impl BasicType {
             _ => false,
         }
     }
+
+    pub fn is_integer(&self) -> bool {
+        matches!(
+            self,
+            BasicType::Char
+                | BasicType::UInt8
+                | BasicType::UInt16
+                | BasicType::UInt32
+                | BasicType::UInt64
+                | BasicType::Int8
+                | BasicType::Int16
+                | BasicType::Int32
+                | BasicType::Int64
+                | BasicType::USize
+                | BasicType::ISize
+        )
+    }
 }
 
 impl IntoOpaqueType for BasicType {
@@ -0,0 +1,251 @@
+//! Source-level code coverage.
+//!
+//! If [`Compiler::coverage`](super::Compiler::coverage) is set, every function increments a counter when it's entered
+//! and every statement increments one before it's executed. The counters are keyed by the location of their token, so
+//! the instantiations of a generic function share theirs. When the program exits, librtdbg appends the counters to the
+//! file in `KEID_COVERAGE_OUTPUT` (`keid.coverage` by default), and [`generate_lcov`] turns one or more of those dumps
+//! into an lcov report of the `.keid` lines.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+};
+
+use anyhow::{anyhow, Result};
+
+use super::llvm::{Context, Module};
+use crate::tree::ast::TokenLocation;
+
+/// The first line of every dump written by librtdbg.
+const DUMP_HEADER: &str = "# keid coverage v1";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CoveragePointKind {
+    /// The entry of the function with the given name.
+    Function(String),
+    Statement,
+}
+
+#[derive(Debug, Clone)]
+pub struct CoveragePoint {
+    pub file: String,
+    pub line: usize,
+    pub col: usize,
+    pub kind: CoveragePointKind,
+}
+
+#[derive(Default)]
+pub struct CoverageStorage {
+    pub points: Vec<CoveragePoint>,
+    /// The index of the point of each token, by its file and location.
+    point_ids: HashMap<(String, usize, usize), usize>,
+}
+
+impl CoverageStorage {
+    /// Returns the index of the counter of the token at `loc` in `file`, adding a point for it if there isn't one yet.
+    pub fn get_or_add_point(&mut self, file: &str, loc: &TokenLocation, line: usize, col: usize, kind: CoveragePointKind) -> usize {
+        let key = (file.to_owned(), loc.start, loc.end);
+        if let Some(id) = self.point_ids.get(&key) {
+            return *id;
+        }
+        let id = self.points.len();
+        self.points.push(CoveragePoint {
+            file: file.to_owned(),
+            line,
+            col,
+            kind,
+        });
+        self.point_ids.insert(key, id);
+        id
+    }
+
+    /// Defines the counters and the table of points that librtdbg dumps at exit:
+    ///   - `keid.coverage_counters`: a zero-initialized `i64` for every point
+    ///   - `keid.coverage_points`: `{ ptr file, ptr function name (null for statements), i32 line, i32 column }` for every point
+    ///   - `keid.coverage_count`: the number of points, as an `i64`
+    pub fn create_coverage_storage(&self, context: &mut Context, module: &mut Module) {
+        let counters_type = context.get_array_type(context.get_i64_type(), self.points.len());
+        let global_counters = module.create_global(context, "keid.coverage_counters", counters_type);
+        module.initialize_global(global_counters, context.const_null(counters_type));
+
+        let string_type = context.get_pointer_type(context.get_i8_type());
+        let point_type = context.get_struct_type(
+            "KeidCoveragePoint",
+            &[string_type, string_type, context.get_i32_type(), context.get_i32_type()],
+        );
+
+        // every file and function name is only stored once
+        let mut strings = HashMap::new();
+        let mut get_string = |context: &mut Context, module: &mut Module, value: &str| {
+            if let Some(global) = strings.get(value) {
+                return *global;
+            }
+            let global = module.create_global(
+                context,
+                &format!("keid.coverage_string.{}", strings.len()),
+                context.get_array_type(context.get_i8_type(), value.len() + 1),
+            );
+            module.initialize_global(global, context.const_string(value));
+            module.set_global_constant(global);
+            strings.insert(value.to_owned(), global);
+            global
+        };
+
+        let mut point_structs = Vec::with_capacity(self.points.len());
+        for point in &self.points {
+            let file = get_string(context, module, &point.file);
+            let function_name = match &point.kind {
+                CoveragePointKind::Function(name) => get_string(context, module, name),
+                CoveragePointKind::Statement => context.const_null_ptr(context.get_i8_type()),
+            };
+            point_structs.push(context.create_const_struct(
+                point_type,
+                &mut [
+                    file,
+                    function_name,
+                    context.const_int(context.get_i32_type(), point.line as u64),
+                    context.const_int(context.get_i32_type(), point.col as u64),
+                ],
+            ));
+        }
+
+        let global_points =
+            module.create_global(context, "keid.coverage_points", context.get_array_type(point_type, point_structs.len()));
+        module.initialize_global(global_points, context.create_const_array(point_type, &point_structs));
+        module.set_global_constant(global_points);
+
+        let global_count = module.create_global(context, "keid.coverage_count", context.get_i64_type());
+        module.initialize_global(global_count, context.const_int(context.get_i64_type(), self.points.len() as u64));
+        module.set_global_constant(global_count);
+    }
+}
+
+#[derive(Default)]
+struct FileCoverage {
+    /// The line and the hit count of every function, by name.
+    functions: BTreeMap<String, (usize, u64)>,
+    /// The hit count of every line with a statement, which is the highest count of the statements on the line.
+    lines: BTreeMap<usize, u64>,
+}
+
+/// Generates an lcov tracefile from the contents of one or more coverage dumps. The counts of a point in several dumps,
+/// e.g. of several runs of the same program, are summed.
+pub fn generate_lcov(dump: &str) -> Result<String> {
+    let mut points: BTreeMap<(String, usize, usize, Option<String>), u64> = BTreeMap::new();
+    for (i, line) in dump.lines().enumerate() {
+        if line.is_empty() || line == DUMP_HEADER {
+            continue;
+        }
+        let invalid_line = || anyhow!("invalid coverage dump: line {}: {}", i + 1, line);
+        let fields: Vec<&str> = line.split('\t').collect();
+        let (function_name, fields) = match fields.as_slice() {
+            ["F", file, line_no, col, count, name] => (Some(name.to_string()), (file, line_no, col, count)),
+            ["S", file, line_no, col, count] => (None, (file, line_no, col, count)),
+            _ => return Err(invalid_line()),
+        };
+        let (file, line_no, col, count) = fields;
+        let line_no = line_no.parse().map_err(|_| invalid_line())?;
+        let col = col.parse().map_err(|_| invalid_line())?;
+        let count: u64 = count.parse().map_err(|_| invalid_line())?;
+        *points.entry((file.to_string(), line_no, col, function_name)).or_default() += count;
+    }
+
+    let mut files: BTreeMap<String, FileCoverage> = BTreeMap::new();
+    for ((file, line_no, _, function_name), count) in points {
+        let file = files.entry(file).or_default();
+        match function_name {
+            Some(name) => {
+                let function = file.functions.entry(name).or_insert((line_no, 0));
+                function.1 += count;
+            }
+            None => {
+                let line = file.lines.entry(line_no).or_default();
+                *line = (*line).max(count);
+            }
+        }
+    }
+
+    let mut lcov = String::new();
+    for (path, file) in files {
+        writeln!(lcov, "SF:{}", path).unwrap();
+        for (name, (line_no, _)) in &file.functions {
+            writeln!(lcov, "FN:{},{}", line_no, name).unwrap();
+        }
+        for (name, (_, count)) in &file.functions {
+            writeln!(lcov, "FNDA:{},{}", count, name).unwrap();
+        }
+        writeln!(lcov, "FNF:{}", file.functions.len()).unwrap();
+        writeln!(lcov, "FNH:{}", file.functions.values().filter(|(_, count)| *count > 0).count()).unwrap();
+        for (line_no, count) in &file.lines {
+            writeln!(lcov, "DA:{},{}", line_no, count).unwrap();
+        }
+        writeln!(lcov, "LF:{}", file.lines.len()).unwrap();
+        writeln!(lcov, "LH:{}", file.lines.values().filter(|count| **count > 0).count()).unwrap();
+        writeln!(lcov, "end_of_record").unwrap();
+    }
+    Ok(lcov)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_lcov() {
+        // two runs of the same program, of which only the first one called `helper` and neither called `unused`
+        let dump = "# keid coverage v1\n\
+            F\tmain.keid\t1\t1\t1\tmain()\n\
+            S\tmain.keid\t2\t5\t1\n\
+            S\tmain.keid\t2\t12\t3\n\
+            F\tutil.keid\t4\t1\t1\thelper()\n\
+            S\tutil.keid\t5\t5\t1\n\
+            S\tutil.keid\t6\t5\t0\n\
+            F\tunused.keid\t1\t1\t0\tunused()\n\
+            S\tunused.keid\t2\t5\t0\n\
+            # keid coverage v1\n\
+            F\tmain.keid\t1\t1\t1\tmain()\n\
+            S\tmain.keid\t2\t5\t1\n\
+            S\tmain.keid\t2\t12\t3\n\
+            F\tutil.keid\t4\t1\t0\thelper()\n\
+            S\tutil.keid\t5\t5\t0\n\
+            S\tutil.keid\t6\t5\t0\n";
+        let lcov = generate_lcov(dump).unwrap();
+        assert_eq!(
+            lcov.lines().collect::<Vec<_>>(),
+            [
+                "SF:main.keid",
+                "FN:1,main()",
+                "FNDA:2,main()",
+                "FNF:1",
+                "FNH:1",
+                // the counts of the statements on a line are summed over the runs, but not with each other
+                "DA:2,6",
+                "LF:1",
+                "LH:1",
+                "end_of_record",
+                "SF:unused.keid",
+                "FN:1,unused()",
+                "FNDA:0,unused()",
+                "FNF:1",
+                "FNH:0",
+                "DA:2,0",
+                "LF:1",
+                "LH:0",
+                "end_of_record",
+                "SF:util.keid",
+                "FN:4,helper()",
+                "FNDA:1,helper()",
+                "FNF:1",
+                "FNH:1",
+                "DA:5,1",
+                "DA:6,0",
+                "LF:2",
+                "LH:1",
+                "end_of_record",
+            ]
+        );
+
+        let error = generate_lcov("# keid coverage v1\nS\tmain.keid\t2\t5\n").unwrap_err();
+        assert_eq!(error.to_string(), "invalid coverage dump: line 2: S\tmain.keid\t2\t5");
+    }
+}
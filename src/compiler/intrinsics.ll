@@ -1,7 +1,7 @@
 source_filename = "intrinsics.ll"
 
-; heap data for a class: ptr (classinfo pointer), i64 (ref count)
-%KeidAbiClassData = type { ptr, i64 }
+; heap data for a class: ptr (classinfo pointer), i64 (ref count), ptr (weak reference, or null if there are no weak references)
+%KeidAbiClassData = type { ptr, i64, ptr }
 
 ; pointer to either a stack-allocated struct or a heap-allocated class: ptr (classinfo pointer)
 %KeidAbiStructData = type { ptr }
@@ -16,7 +16,16 @@ source_filename = "intrinsics.ll"
 ;      TYPE_CLASS  = 0x00
 ;      TYPE_STRUCT = 0x01
 ; 6  ptr to %KeidAbiClassInfo (superclass pointer, or null if this represents the core::object::Object type)
-%KeidAbiClassInfo = type { ptr, ptr, i32, ptr, ptr, i32, ptr }
+; 7  i32 (length of reference fields)
+; 8  ptr to [%KeidAbiReferenceField] (n-length reference fields, or null if there are none)
+; 9  i64 (size of the heap data of an instance, or 0 for structs)
+%KeidAbiClassInfo = type { ptr, ptr, i32, ptr, ptr, i32, ptr, i32, ptr, i64 }
+
+; metadata of a class field that holds a reference to another reference counted object:
+;  i32 (byte offset of the field in the class data)
+;  i32 (field flags)
+;    FIELD_NULLABLE = 0x01 (the field is a nullable type, i.e. { ptr, i8 })
+%KeidAbiReferenceField = type { i32, i32 }
 
 ; interface implementation metadata:
 ;  i32 (interface unique ID)
@@ -33,15 +42,25 @@ source_filename = "intrinsics.ll"
 @find_interface_method_null_error = private unnamed_addr constant [85 x i8] c"NullValueError: attempted retrieval of interface method pointer from a null pointer\0A\00", align 1
 @illegal_ref_count_error = private unnamed_addr constant [43 x i8] c"InternalError: illegal reference count %i\0A\00", align 1
 @no_such_method_error = private unnamed_addr constant [104 x i8] c"NoSuchMethodError: attempted to invoke an invalid interface method (interface id = %i, method id = %i)\0A\00", align 1
+@find_virtual_method_null_error = private unnamed_addr constant [83 x i8] c"NullValueError: attempted retrieval of virtual method pointer from a null pointer\0A\00", align 1
+@no_such_virtual_method_error = private unnamed_addr constant [83 x i8] c"NoSuchMethodError: attempted to invoke an invalid virtual method (method id = %i)\0A\00", align 1
 @index_out_of_bounds_error = private unnamed_addr constant [84 x i8] c"IndexOutOfBoundsError: attempted to access index %i of a collection with length %i\0A\00", align 1
-@error_already_thrown_error = private unnamed_addr constant [100 x i8] c "ErrorAlreadyThrownError: attempted to throw error when current thread already is handling an error\0A\00", align 1
+@unwind_error = private unnamed_addr constant [62 x i8] c"UnwindError: failed to raise a thrown error (reason code %i)\0A\00", align 1
+@thread_spawn_error = private unnamed_addr constant [55 x i8] c"ThreadError: failed to spawn a thread (error code %i)\0A\00", align 1
+@thread_join_error = private unnamed_addr constant [54 x i8] c"ThreadError: failed to join a thread (error code %i)\0A\00", align 1
+@thread_detach_error = private unnamed_addr constant [56 x i8] c"ThreadError: failed to detach a thread (error code %i)\0A\00", align 1
 @allocator = global ptr null, align 8
 @deallocator = global ptr null, align 8
-@current_error = thread_local global ptr null, align 8
 @rtdbg_instance = global ptr null, align 8
 
 @"core::runtime::hasInit" = external global i1
 @keid.classinfo = external global [0 x %KeidAbiClassInfo]
+$IF(COVERAGE, ```
+; defined in the metadata module, see `CoverageStorage::create_coverage_storage`
+@keid.coverage_points = external global [0 x { ptr, ptr, i32, i32 }]
+@keid.coverage_counters = external global [0 x i64]
+@keid.coverage_count = external global i64
+```)
 
 ; This is an implementation of strlen() in LLVM IR that works in no-std environments.
 define i64 @keid_cstrlen(ptr %str) {
@@ -162,6 +181,36 @@ block.main:
   ret ptr %res 
 }
 
+; Returns the pointer to the implementation of a virtual class method for the runtime class of an object.
+;  %object - the object that the virtual method is being invoked on
+;  %method_id - the slot of the method within the virtual method table of the class that declares it
+define ptr @keid.find_virtual_method(ptr %object, i32 %method_id) {
+block.check_null:
+  %is_null = icmp eq ptr %object, null
+  br i1 %is_null, label %block.break_null, label %block.main
+block.break_null:
+  call i32 @printf(ptr @find_virtual_method_null_error)
+  call void @"core::runtime::printStackFrames()"()
+  call void @exit(i32 1)
+  unreachable
+block.main:
+  %class_info_ptr = getelementptr inbounds %KeidAbiClassData, ptr %object, i32 0, i32 0
+  %class_info = load ptr, ptr %class_info_ptr, align 8 ; load the class info pointer from the class data
+  %vtable_ptr = getelementptr inbounds %KeidAbiClassInfo, ptr %class_info, i32 0, i32 1
+  %vtable = load ptr, ptr %vtable_ptr, align 8
+  %vtable_is_null = icmp eq ptr %vtable, null
+  br i1 %vtable_is_null, label %block.fail, label %block.found
+block.found:
+  %method_ptr_ptr = getelementptr inbounds ptr, ptr %vtable, i32 %method_id
+  %method_ptr = load ptr, ptr %method_ptr_ptr, align 8
+  ret ptr %method_ptr
+block.fail: ; the class has no virtual methods -- fatal unrecoverable error
+  call i32 @printf(ptr @no_such_virtual_method_error, i32 %method_id)
+  call void @"core::runtime::printStackFrames()"()
+  call void @exit(i32 1)
+  unreachable
+}
+
 ; Asserts that the parameter is not zero.
 ; If the parameter is zero, then an error is thrown and the program exits early.
 ; In the future, once error handling is fully implemented,
@@ -220,29 +269,31 @@ $IF(RTDBG, ```
 ;   %object - pointer to the reference counted object
 define void @keid.scope(ptr %object) {
 block.check_null:
-;   ; null check, i.e. `if %object == NULL`
-;   %object_is_null = icmp eq ptr %object, null
-;   br i1 %object_is_null, label %block.is_null, label %block.is_not_null
-
-; block.is_null:
-;   ret void ; exit early if %object is a null pointer
-
-; block.is_not_null:
-;   %is_struct = call i1 @keid.is_struct(ptr %object)
-;   br i1 %is_struct, label %block.is_struct, label %block.is_not_struct
-
-; block.is_struct:
-;   ret void ; exit early if %object is a struct type (i.e. has no ref count)
-
-; block.is_not_struct:
-; $IF(RTDBG, ```
-;   %rtdbg = load ptr, ptr @rtdbg_instance, align 4
-;   call void @rtdbg_change_scope(ptr %rtdbg, ptr %object, i8 0)
-; ```)
-;   %ref_count_ptr = getelementptr inbounds %KeidAbiClassData, ptr %object, i32 0, i32 1
-;   %ref_count = load i64, ptr %ref_count_ptr, align 4 ; load the ref count from %ref_count_ptr
-;   %new_count = add i64 %ref_count, 1                 ; add one to the ref count
-;   store i64 %new_count, ptr %ref_count_ptr, align 4  ; store the (ref count + 1) into %ref_count_ptr
+  ; null check, i.e. `if %object == NULL`
+  %object_is_null = icmp eq ptr %object, null
+  br i1 %object_is_null, label %block.is_null, label %block.is_not_null
+
+block.is_null:
+  ret void ; exit early if %object is a null pointer
+
+block.is_not_null:
+  %is_struct = call i1 @keid.is_struct(ptr %object)
+  br i1 %is_struct, label %block.is_struct, label %block.is_not_struct
+
+block.is_struct:
+  ret void ; exit early if %object is a struct type (i.e. has no ref count)
+
+block.is_not_struct:
+$IF(RTDBG, ```
+  ; librtdbg changes the ref count while it holds its lock, so the count it tracks can't race with other threads
+  %rtdbg = load ptr, ptr @rtdbg_instance, align 4
+  call i64 @rtdbg_change_scope(ptr %rtdbg, ptr %object, i8 0)
+```)
+$IFNOT(RTDBG, ```
+  %ref_count_ptr = getelementptr inbounds %KeidAbiClassData, ptr %object, i32 0, i32 1
+  ; objects can be shared between threads, so the ref count is changed atomically
+  atomicrmw add ptr %ref_count_ptr, i64 1 monotonic ; add one to the ref count
+```)
   ret void
 }
 
@@ -251,97 +302,76 @@ block.check_null:
 ;   %object - pointer to the reference counted object
 define void @keid.unscope(ptr %object) {
 block.check_null:
-;   ; null check, i.e. `if %object == NULL`
-;   %object_is_null = icmp eq ptr %object, null
-;   br i1 %object_is_null, label %block.is_null, label %block.is_not_invalid_null
-
-; block.is_null:                          
-;   ret void ; exit early if %object is a null pointer
-
-; block.is_struct:
-;   ret void ; exit early if %object is a struct type (i.e. has no ref count)
-
-; block.is_not_invalid_null:
-;   %is_struct = call i1 @keid.is_struct(ptr %object)
-;   br i1 %is_struct, label %block.is_struct, label %block.is_not_struct
-
-; block.is_not_struct:
-; $IF(RTDBG, ```
-;   %rtdbg = load ptr, ptr @rtdbg_instance, align 4
-;   call void @rtdbg_change_scope(ptr %rtdbg, ptr %object, i8 1)
-; ```)
-;   %ref_count_ptr = getelementptr inbounds %KeidAbiClassData, ptr %object, i32 0, i32 1
-;   %ref_count = load i64, ptr %ref_count_ptr, align 4 ; load the ref count from %object
-;   %ref_count_is_invalid = icmp slt i64 %ref_count, 1
-;   br i1 %ref_count_is_invalid, label %block.is_invalid, label %block.is_not_null
-
-; block.is_invalid:
-;   call i32 @printf(ptr @illegal_ref_count_error, i64 %ref_count)
-;   call void @"core::runtime::printStackFrames()"()
-;   call void @exit(i32 1)
-;   unreachable
-
-; block.is_not_null:
-;   %ref_count_is_null = icmp eq i64 %ref_count, 0
-;   br i1 %ref_count_is_null, label %block.is_null, label %block.test_should_free
-
-; block.test_should_free:
-;   %should_free = icmp eq i64 %ref_count, 1     ; check if should free; i.e. `if ref count == 1`
-;   br i1 %should_free, label %block.should_free, label %block.should_not_free
-
-; block.should_free:                     
-;   ; if ref count == 1 call the object's destructor and then free it
-;   %classinfo_ptr_offset = getelementptr inbounds %KeidAbiClassData, ptr %object, i32 0, i32 0
-;   %classinfo_ptr = load ptr, ptr %classinfo_ptr_offset, align 4 ; load the classinfo pointer from the class data
-
-;   %destructor_ptr_offset = getelementptr inbounds %KeidAbiClassInfo, ptr %classinfo_ptr, i32 0, i32 0
-;   %destructor_ptr = load ptr, ptr %destructor_ptr_offset, align 4 ; dereference the classinfo pointer to get the destructor pointer
-
-;   call void %destructor_ptr(ptr %object) ; invoke the object's destructor method
-
-;   %raw_pointer = bitcast ptr %object to ptr
-;   tail call void @keid_free(ptr %raw_pointer) ; free the object's heap memory
-;   ret void
-
-; block.should_not_free:
-;   ; if ref count != 1 (i.e. is > 1):
-;   %new_count = sub i64 %ref_count, 1          ; decrease the ref count by 1
-;   store i64 %new_count, ptr %ref_count_ptr, align 4 ; store the (ref count - 1) into %ref_count_ptr
-  ret void
-}
+  ; null check, i.e. `if %object == NULL`
+  %object_is_null = icmp eq ptr %object, null
+  br i1 %object_is_null, label %block.is_null, label %block.is_not_null
 
-define void @keid.throw_error(ptr %error) {
-block.check_error:
-  %has_error = call i1 @keid.check_unhandled_error()
-  br i1 %has_error, label %block.has_error, label %block.set_error
-block.has_error:
-  call i32 @printf(ptr @error_already_thrown_error)
+block.is_null:
+  ret void ; exit early if %object is a null pointer
+
+block.is_not_null:
+  %is_struct = call i1 @keid.is_struct(ptr %object)
+  br i1 %is_struct, label %block.is_struct, label %block.is_not_struct
+
+block.is_struct:
+  ret void ; exit early if %object is a struct type (i.e. has no ref count)
+
+block.is_not_struct:
+$IF(RTDBG, ```
+  ; librtdbg changes the ref count while it holds its lock, so the count it tracks can't race with other threads
+  %rtdbg = load ptr, ptr @rtdbg_instance, align 4
+  %ref_count = call i64 @rtdbg_change_scope(ptr %rtdbg, ptr %object, i8 1) ; returns the previous ref count
+```)
+$IFNOT(RTDBG, ```
+  %ref_count_ptr = getelementptr inbounds %KeidAbiClassData, ptr %object, i32 0, i32 1
+  ; objects can be shared between threads, so the ref count is changed atomically
+  ; the ordering makes the writes of other threads to the object visible to the destructor
+  %ref_count = atomicrmw sub ptr %ref_count_ptr, i64 1 acq_rel ; decrease the ref count by 1 and load the previous ref count
+```)
+  %ref_count_is_invalid = icmp slt i64 %ref_count, 1
+  br i1 %ref_count_is_invalid, label %block.is_invalid, label %block.test_should_free
+
+block.is_invalid:
+  call i32 @printf(ptr @illegal_ref_count_error, i64 %ref_count)
   call void @"core::runtime::printStackFrames()"()
   call void @exit(i32 1)
   unreachable
-block.set_error:
-  store ptr %error, ptr @current_error
+
+block.test_should_free:
+  %should_free = icmp eq i64 %ref_count, 1 ; check if should free; i.e. `if ref count == 1`
+  br i1 %should_free, label %block.should_free, label %block.should_not_free
+
+block.should_free:
+  ; if ref count == 1 call the object's destructor and then free it
+  %classinfo_ptr_offset = getelementptr inbounds %KeidAbiClassData, ptr %object, i32 0, i32 0
+  %classinfo_ptr = load ptr, ptr %classinfo_ptr_offset, align 4 ; load the classinfo pointer from the class data
+
+  %destructor_ptr_offset = getelementptr inbounds %KeidAbiClassInfo, ptr %classinfo_ptr, i32 0, i32 0
+  %destructor_ptr = load ptr, ptr %destructor_ptr_offset, align 4 ; dereference the classinfo pointer to get the destructor pointer
+
+  call void @keid_gc_release(ptr %object) ; the object can no longer be the root of a garbage cycle
+  call void %destructor_ptr(ptr %object) ; invoke the object's destructor method
+  tail call void @keid.free_object(ptr %object) ; free the object's heap memory
   ret void
-}
 
-; Immediately invoked at the start of all catch statements.
-define void @keid.clear_unhandled_error() {
-block.main:
-  store ptr null, ptr @current_error
+block.should_not_free:
+  ; if ref count != 1 (i.e. is > 1):
+  call void @keid_gc_possible_root(ptr %object) ; the remaining references might all come from a garbage cycle
   ret void
 }
 
-define i1 @keid.check_unhandled_error() {
+; Throws %error by unwinding the stack until a landing pad of a `try` block catches it.
+; The landing pads of the unwound functions unscope their locals along the way.
+define void @keid.throw_error(ptr %error) {
 block.main:
-  %current_error = load ptr, ptr @current_error, align 4
-  %current_error_is_not_null = icmp ne ptr %current_error, null
-  ret i1 %current_error_is_not_null
-}
+  %exception = call ptr @keid_exception_new(ptr %error)
+  %reason = call i32 @_Unwind_RaiseException(ptr %exception)
 
-define ptr @keid.get_unhandled_error() {
-block.main:
-  %current_error = load ptr, ptr @current_error, align 4
-  ret ptr %current_error
+  ; _Unwind_RaiseException only returns if the error could not be thrown
+  call i32 @printf(ptr @unwind_error, i32 %reason)
+  call void @"core::runtime::printStackFrames()"()
+  call void @exit(i32 1)
+  unreachable
 }
 
 ; Only works for testing for object assignability via direct single inheritance.
@@ -369,28 +399,25 @@ block.return_true:
   ret i1 true
 }
 
-define void @_keid_start() {
+define void @_keid_start() personality ptr @keid_personality {
 block.main:
 $IF(RTDBG, ```
   %rtdbg = call ptr @rtdbg_initialize()
   store ptr %rtdbg, ptr @rtdbg_instance
 ```)
 
-  call void @"keid.init()"()
- 
+  invoke void @"keid.init()"() to label %block.initialized unwind label %block.unhandled_error
+block.initialized:
   ; now that initialization has finished, notify the runtime
   store i1 1, ptr @"core::runtime::hasInit", align 1
 
   ; invoke the user-defined main function
-  call void @"keid.main()"()
-
-  ; check if there is an unhandled error from the main function
-  %unhandled_error = call ptr @keid.get_unhandled_error()
-  %check_unhandled_error = icmp ne ptr %unhandled_error, null
-  br i1 %check_unhandled_error, label %block.print_error, label %block.exit_gracefully
-block.print_error:
-  ; clear the error so that Error::print can execute
-  call void @keid.clear_unhandled_error()
+  invoke void @"keid.main()"() to label %block.exit_gracefully unwind label %block.unhandled_error
+block.unhandled_error:
+  ; errors that weren't caught by any `try` block end up here
+  %landing_pad = landingpad { ptr, i32 } catch ptr null
+  %exception = extractvalue { ptr, i32 } %landing_pad, 0
+  %unhandled_error = call ptr @keid_exception_take_error(ptr %exception)
   call void @"core::error::Error::print(core::error::Error)"(ptr %unhandled_error)
   call void @keid_exit(i32 1)
   unreachable
@@ -405,22 +432,140 @@ $IF(RTDBG, ```
   %rtdbg = load ptr, ptr @rtdbg_instance, align 4
   call void @rtdbg_finish(ptr %rtdbg)
 ```)
+$IF(COVERAGE, ```
+  %coverage_count = load i64, ptr @keid.coverage_count, align 8
+  call void @keid_coverage_dump(ptr @keid.coverage_points, ptr @keid.coverage_counters, i64 %coverage_count)
+```)
+$IF(PROFILE, ```
+  call void @keid_core_runtime_write_function_profile()
+```)
 
   call void @exit(i32 %code)
   unreachable
 }
 
+; Entry point of every thread started by `std::thread.spawn`.
+;   %thread - pointer to the std::thread::Thread object of the new thread
+define ptr @keid.thread_start(ptr %thread) personality ptr @keid_personality {
+block.main:
+  invoke void @"std::thread::runThread(std::thread::Thread)"(ptr %thread) to label %block.finished unwind label %block.unhandled_error
+block.finished:
+  ; release the reference taken by keid_thread_spawn
+  call void @keid.unscope(ptr %thread)
+  ret ptr null
+block.unhandled_error:
+  ; runThread stores the errors of the thread, so this is only reached if storing the error failed
+  %landing_pad = landingpad { ptr, i32 } catch ptr null
+  %exception = extractvalue { ptr, i32 } %landing_pad, 0
+  %unhandled_error = call ptr @keid_exception_take_error(ptr %exception)
+  call void @"core::error::Error::print(core::error::Error)"(ptr %unhandled_error)
+  call void @keid_exit(i32 1)
+  unreachable
+}
+
+; Starts a new thread and returns its pthread handle.
+;   %thread - pointer to the std::thread::Thread object of the new thread
+define i64 @keid_thread_spawn(ptr %thread) {
+block.main:
+  %handle = alloca i64, align 8
+
+  ; the new thread keeps its Thread object alive until it has finished
+  call void @keid.scope(ptr %thread)
+  %result = call i32 @pthread_create(ptr %handle, ptr null, ptr @keid.thread_start, ptr %thread)
+  %failed = icmp ne i32 %result, 0
+  br i1 %failed, label %block.failed, label %block.spawned
+block.failed:
+  call i32 @printf(ptr @thread_spawn_error, i32 %result)
+  call void @"core::runtime::printStackFrames()"()
+  call void @exit(i32 1)
+  unreachable
+block.spawned:
+  %handle_value = load i64, ptr %handle, align 8
+  ret i64 %handle_value
+}
+
+; Blocks until the thread with the pthread handle %handle has finished.
+define void @keid_thread_join(i64 %handle) {
+block.main:
+  %result = call i32 @pthread_join(i64 %handle, ptr null)
+  %failed = icmp ne i32 %result, 0
+  br i1 %failed, label %block.failed, label %block.joined
+block.failed:
+  call i32 @printf(ptr @thread_join_error, i32 %result)
+  call void @"core::runtime::printStackFrames()"()
+  call void @exit(i32 1)
+  unreachable
+block.joined:
+  ret void
+}
+
+; Releases the resources of the thread with the pthread handle %handle once it has finished, without waiting for it.
+define void @keid_thread_detach(i64 %handle) {
+block.main:
+  %result = call i32 @pthread_detach(i64 %handle)
+  %failed = icmp ne i32 %result, 0
+  br i1 %failed, label %block.failed, label %block.detached
+block.failed:
+  call i32 @printf(ptr @thread_detach_error, i32 %result)
+  call void @"core::runtime::printStackFrames()"()
+  call void @exit(i32 1)
+  unreachable
+block.detached:
+  ret void
+}
+
 ; wrappers of libc fuctions
 define ptr @keid_malloc(i64 %bytes) {
 block.main:
   %malloc = load ptr, ptr @allocator, align 4
+$IF(RTDBG, ```
+  ; librtdbg records the size of every allocation, and the padding lets it attribute accesses just past the end to it
+  %padded_bytes = call i64 @rtdbg_padded_allocation_size(i64 %bytes)
+  %res = call ptr %malloc(i64 %padded_bytes)
+  call void @rtdbg_record_allocation(ptr %res, i64 %bytes)
+```)
+$IFNOT(RTDBG, ```
   %res = call ptr %malloc(i64 %bytes)
+```)
   ret ptr %res
 }
 
+; Frees the heap memory of an object whose destructor has run, which is also how the cycle collector frees garbage.
+define void @keid.free_object(ptr %object) {
+block.main:
+$IF(RTDBG, ```
+  ; librtdbg holds the memory in quarantine for a while, so uses after free are reported instead of reaching new objects
+  %rtdbg_instance = load ptr, ptr @rtdbg_instance, align 4
+  call void @rtdbg_free_object(ptr %rtdbg_instance, ptr %object)
+```)
+$IFNOT(RTDBG, ```
+  tail call void @keid_free(ptr %object)
+```)
+  ret void
+}
+
+; Frees an object of a garbage cycle by releasing the reference that the cycle collector took with keid.scope to keep it
+; alive while the destructors of the cycle run. The destructor of the object has already run, so it isn't run again.
+define void @keid.free_garbage(ptr %object) {
+block.main:
+$IF(RTDBG, ```
+  %rtdbg = load ptr, ptr @rtdbg_instance, align 4
+  call i64 @rtdbg_change_scope(ptr %rtdbg, ptr %object, i8 1)
+```)
+$IFNOT(RTDBG, ```
+  %ref_count_ptr = getelementptr inbounds %KeidAbiClassData, ptr %object, i32 0, i32 1
+  atomicrmw sub ptr %ref_count_ptr, i64 1 acq_rel
+```)
+  tail call void @keid.free_object(ptr %object)
+  ret void
+}
+
 define void @keid_free(ptr %mem) {
 block.main:
   %free = load ptr, ptr @deallocator, align 4
+$IF(RTDBG, ```
+  call void @rtdbg_record_deallocation(ptr %mem)
+```)
   call ptr %free(ptr %mem)
   ret void
 }
@@ -439,16 +584,41 @@ declare void @exit(i32)
 declare i32 @printf(ptr, ...)
 declare void @free(ptr)
 declare ptr @malloc(i32)
+declare i32 @pthread_create(ptr, ptr, ptr, ptr)
+declare i32 @pthread_join(i64, ptr)
+declare i32 @pthread_detach(i64)
+
+; libunwind functions
+declare i32 @_Unwind_RaiseException(ptr)
 
 $IF(RTDBG, ```
 ; librtdbg functions
-declare void @rtdbg_change_scope(ptr, ptr, i8)
+declare i64 @rtdbg_change_scope(ptr, ptr, i8)
 declare ptr @rtdbg_initialize()
 declare void @rtdbg_finish(ptr)
 declare void @rtdbg_register_object(ptr, ptr)
+declare void @rtdbg_free_object(ptr, ptr)
+declare i64 @rtdbg_padded_allocation_size(i64)
+declare void @rtdbg_record_allocation(ptr, i64)
+declare void @rtdbg_record_deallocation(ptr)
 declare void @rtdbg_clear_reference_count(ptr, ptr)
 ```)
 declare void @rtdbg_dump_basic_type(ptr)
+$IF(COVERAGE, ```
+declare void @keid_coverage_dump(ptr, ptr, i64)
+```)
+$IF(PROFILE, ```
+declare void @keid_core_runtime_write_function_profile()
+```)
+
+; exception handling functions
+declare i32 @keid_personality(i32, i32, i64, ptr, ptr)
+declare ptr @keid_exception_new(ptr)
+declare ptr @keid_exception_take_error(ptr)
+
+; cycle collector functions
+declare void @keid_gc_possible_root(ptr)
+declare void @keid_gc_release(ptr)
 
 ; keid core library functions
 declare void @"keid.init()"()
@@ -458,3 +628,4 @@ declare void @"core::mem::Pointer::to<char>(usize)"(i64, ptr)
 declare ptr @"core::string::String::fromUtf8Slice([char])"(ptr)
 declare void @"core::runtime::printStackFrames()"()
 declare void @"core::error::Error::print(core::error::Error)"(ptr)
+declare void @"std::thread::runThread(std::thread::Thread)"(ptr)
@@ -21,6 +21,7 @@ use llvm_sys::linker::LLVMLinkModules2;
 use llvm_sys::prelude::*;
 use llvm_sys::target::*;
 use llvm_sys::target_machine::*;
+use llvm_sys::LLVMModuleFlagBehavior;
 
 use super::get_eval_only;
 use super::Insn;
@@ -191,6 +192,9 @@ impl Context {
                 std::ptr::null(),
                 0,
             );
+            // without these flags, LLVM discards the debug info of the module
+            self.add_module_flag(mdl, "Debug Info Version", LLVMDebugMetadataVersion());
+            self.add_module_flag(mdl, "Dwarf Version", 4);
 
             Module {
                 is_clone: false,
@@ -205,6 +209,13 @@ impl Context {
         }
     }
 
+    fn add_module_flag(&self, mdl: LLVMModuleRef, key: &str, value: u32) {
+        unsafe {
+            let value = LLVMValueAsMetadata(LLVMConstInt(LLVMInt32TypeInContext(self.ctx), value as _, 0));
+            LLVMAddModuleFlag(mdl, LLVMModuleFlagBehavior::LLVMModuleFlagBehaviorWarning, key.as_ptr() as *const _, key.len(), value);
+        }
+    }
+
     pub fn create_const_array(&self, element_type: OpaqueType, values: &[OpaqueValue]) -> OpaqueValue {
         unsafe {
             let mut inner_values: Vec<LLVMValueRef> = values.iter().map(|par| par.0).collect();
@@ -432,9 +443,11 @@ impl Context {
 
                 let ref_count_type = self.get_isize_type();
                 let info_type = self.get_pointer_type(self.get_abi_class_info_type());
-                let mut field_types = Vec::with_capacity(class_impl.fields.len());
+                let weak_ref_type = self.get_pointer_type(self.get_i8_type());
+                let mut field_types = Vec::with_capacity(class_impl.fields.len() + 3);
                 field_types.push(info_type);
                 field_types.push(ref_count_type);
+                field_types.push(weak_ref_type);
                 for field in &class_impl.fields {
                     field_types.push(field.ty.as_llvm_type(cpl));
                 }
@@ -471,12 +484,26 @@ impl Context {
         self.get_struct_type(
             "KeidAbiClassInfo",
             &[
-                self.get_pointer_type(self.get_i8_type()),                 // destructor
-                self.get_pointer_type(self.get_i8_type()),                 // vtable
-                self.get_i32_type(),                                       // interfaces length
-                self.get_pointer_type(self.get_abi_interface_impl_type()), // interfaces
-                self.get_pointer_type(self.get_i8_type()),                 // class name
-                self.get_i32_type(),                                       // class bitflags
+                self.get_pointer_type(self.get_i8_type()),                  // destructor
+                self.get_pointer_type(self.get_i8_type()),                  // vtable
+                self.get_i32_type(),                                        // interfaces length
+                self.get_pointer_type(self.get_abi_interface_impl_type()),  // interfaces
+                self.get_pointer_type(self.get_i8_type()),                  // class name
+                self.get_i32_type(),                                        // class bitflags
+                self.get_pointer_type(self.get_i8_type()),                  // superclass classinfo
+                self.get_i32_type(),                                        // reference fields length
+                self.get_pointer_type(self.get_abi_reference_field_type()), // reference fields
+                self.get_i64_type(),                                        // instance size
+            ],
+        )
+    }
+
+    pub fn get_abi_reference_field_type(&self) -> OpaqueType {
+        self.get_struct_type(
+            "KeidAbiReferenceField",
+            &[
+                self.get_i32_type(), // byte offset of the field in the class data
+                self.get_i32_type(), // field bitflags
             ],
         )
     }
@@ -492,6 +519,16 @@ impl Context {
         )
     }
 
+    pub fn get_abi_landing_pad_type(&self) -> OpaqueType {
+        self.get_struct_type(
+            "KeidAbiLandingPad",
+            &[
+                self.get_pointer_type(self.get_i8_type()), // exception
+                self.get_i32_type(),                       // selector
+            ],
+        )
+    }
+
     pub fn get_abi_nullable_type(&self, item_type: OpaqueType, item_type_name: &str) -> OpaqueType {
         self.get_struct_type(&format!("?{}", item_type_name), &[item_type, self.get_i8_type()])
     }
@@ -666,7 +703,15 @@ impl Clone for Module {
 }
 
 impl Module {
+    /// Completes the debug info of the module; called before the module is linked or emitted.
+    fn finalize_debug_info(&self) {
+        if !self.debug.is_null() {
+            unsafe { LLVMDIBuilderFinalize(self.debug) }
+        }
+    }
+
     pub fn link_into(self, dest: &Module) {
+        self.finalize_debug_info();
         unsafe {
             if LLVMLinkModules2(dest.mdl, self.mdl) != 0 {
                 eprintln!("LLVMLinkModules2 failed");
@@ -691,40 +736,46 @@ impl Module {
         }
     }
 
-    pub fn add_function(&self, name: &str, func_type: OpaqueFunctionType, _line_no: u32) -> Function {
+    /// Adds a function to the module. Functions declared at a line (`line_no` > 0) get debug info, so the line tables
+    /// that symbolize native backtraces cover them.
+    pub fn add_function(&self, name: &str, func_type: OpaqueFunctionType, line_no: u32) -> Function {
         unsafe {
             let name_cstr = CString::new(name).expect("invalid name");
-
-            // let debug_function_type = llvm_sys::debuginfo::LLVMDIBuilderCreateSubroutineType(
-            //     self.debug,
-            //     self.debug_file,
-            //     parameter_types.as_mut_ptr(),
-            //     parameter_types.len() as _,
-            //     0,
-            // );
-            // let debug_function = llvm_sys::debuginfo::LLVMDIBuilderCreateFunction(
-            //     self.debug,
-            //     self.debug_compile_unit,
-            //     name_cstr.as_ptr(),
-            //     name.len(),
-            //     std::ptr::null(),
-            //     0,
-            //     self.debug_file,
-            //     line_no,
-            //     debug_function_type,
-            //     IsLocalToUnit,
-            //     IsDefinition,
-            //     ScopeLine,
-            //     Flags,
-            //     IsOptimized,
-            // );
+            let func = LLVMAddFunction(self.mdl, name_cstr.as_ptr() as *const _, func_type.0);
+
+            let subprogram = if line_no > 0 && !self.debug.is_null() {
+                let debug_function_type =
+                    LLVMDIBuilderCreateSubroutineType(self.debug, self.debug_file, std::ptr::null_mut(), 0, LLVMDIFlagZero);
+                let subprogram = LLVMDIBuilderCreateFunction(
+                    self.debug,
+                    self.debug_file,
+                    name_cstr.as_ptr(),
+                    name.len(),
+                    name_cstr.as_ptr(),
+                    name.len(),
+                    self.debug_file,
+                    line_no,
+                    debug_function_type,
+                    i32::from(false),
+                    i32::from(true),
+                    line_no,
+                    LLVMDIFlagZero,
+                    i32::from(!self.target.is_debug),
+                );
+                LLVMSetSubprogram(func, subprogram);
+                subprogram
+            } else {
+                std::ptr::null_mut()
+            };
 
             Function {
                 ctx: self.ctx,
                 mdl: self.mdl,
-                func: LLVMAddFunction(self.mdl, name_cstr.as_ptr() as *const _, func_type.0),
+                func,
                 debug: self.debug,
                 debug_file: self.debug_file,
+                subprogram,
+                line_no,
                 target: self.target.clone(),
             }
         }
@@ -750,6 +801,12 @@ impl Module {
         }
     }
 
+    pub fn set_global_constant(&self, global: OpaqueValue) {
+        unsafe {
+            LLVMSetGlobalConstant(global.0, i32::from(true));
+        }
+    }
+
     pub fn get_or_extern_global(&mut self, global_var: &GlobalVariable) -> OpaqueValue {
         if let Some(val) = self.global_variables.get(&global_var.name) {
             *val
@@ -774,6 +831,7 @@ impl Module {
     }
 
     pub fn to_llvm_ir(&self) -> String {
+        self.finalize_debug_info();
         unsafe {
             let as_str = LLVMPrintModuleToString(self.mdl);
             let cstr = CStr::from_ptr(as_str);
@@ -784,6 +842,7 @@ impl Module {
     }
 
     pub fn to_object_code(&self, module_name: &str, target: &LLVMTargetData) -> Result<LLVMArray> {
+        self.finalize_debug_info();
         unsafe {
             let mut error_message: *mut _ = std::ptr::null_mut();
             if LLVMVerifyModule(self.mdl, LLVMVerifierFailureAction::LLVMReturnStatusAction, &mut error_message) != 0 {
@@ -827,19 +886,31 @@ pub struct Function {
     func: LLVMValueRef,
     debug: LLVMDIBuilderRef,
     debug_file: LLVMMetadataRef,
+    /// The debug info of the function, or null if it has none.
+    subprogram: LLVMMetadataRef,
+    line_no: u32,
     target: LLVMTargetData,
 }
 
 impl Function {
     pub fn create_builder(&self) -> InsnBuilder {
         unsafe {
+            let bdl = LLVMCreateBuilderInContext(self.ctx);
+            if !self.subprogram.is_null() {
+                // every call in a function with debug info needs a location, even before the first statement
+                LLVMSetCurrentDebugLocation2(
+                    bdl,
+                    LLVMDIBuilderCreateDebugLocation(self.ctx, self.line_no, 0, self.subprogram, std::ptr::null_mut()),
+                );
+            }
             InsnBuilder {
                 ctx: self.ctx,
-                bdl: LLVMCreateBuilderInContext(self.ctx),
+                bdl,
                 func: self.func,
                 block_offset: 0,
                 debug: self.debug,
                 debug_file: self.debug_file,
+                subprogram: self.subprogram,
                 target: self.target.clone(),
             }
         }
@@ -864,6 +935,11 @@ impl Function {
         }
     }
 
+    /// Sets the personality function that is used to unwind through the landing pads of this function.
+    pub fn set_personality(&self, personality: OpaqueFunctionValue) {
+        unsafe { LLVMSetPersonalityFn(self.func, personality.0) }
+    }
+
     pub fn as_val(&self) -> OpaqueFunctionValue {
         OpaqueFunctionValue(self.func)
     }
@@ -896,6 +972,7 @@ pub struct InsnBuilder {
     block_offset: usize,
     debug: LLVMDIBuilderRef,
     debug_file: LLVMMetadataRef,
+    subprogram: LLVMMetadataRef,
     target: LLVMTargetData,
 }
 
@@ -964,11 +1041,16 @@ impl InsnBuilder {
         unsafe { OpaqueType(LLVMGetAllocatedType(var.0)) }
     }
 
-    pub fn emit(&self, insn: Insn, _line: u32, _col: u32) -> OpaqueValue {
+    pub fn emit(&self, insn: Insn, line: u32, col: u32) -> OpaqueValue {
         if get_eval_only() {
             return OpaqueValue(std::ptr::null_mut());
         }
         unsafe {
+            // instructions without a line of their own keep the location of the previous instruction
+            if line > 0 && !self.subprogram.is_null() {
+                LLVMSetCurrentDebugLocation2(self.bdl, LLVMDIBuilderCreateDebugLocation(self.ctx, line, col, self.subprogram, std::ptr::null_mut()));
+            }
+
             let insn_name = CString::new("").expect("invalid insn name");
             let insn_name = insn_name.as_ptr();
             let ret = match insn {
@@ -993,6 +1075,24 @@ impl InsnBuilder {
                     let args = args.as_mut_slice();
                     LLVMBuildCall2(self.bdl, ty.0, func.0, args.as_mut_ptr(), args.len() as u32, insn_name)
                 }
+                Insn::Invoke(func, ty, args, normal, unwind) => {
+                    let mut args: Vec<LLVMValueRef> = args.iter().map(|arg| arg.0).collect();
+                    let args = args.as_mut_slice();
+                    LLVMBuildInvoke2(self.bdl, ty.0, func.0, args.as_mut_ptr(), args.len() as u32, normal.0, unwind.0, insn_name)
+                }
+                Insn::LandingPad(ty, is_catch) => {
+                    // the personality function is set on the function instead of the landing pad
+                    let landing_pad = LLVMBuildLandingPad(self.bdl, ty.0, std::ptr::null_mut(), u32::from(is_catch), insn_name);
+                    // exceptions that aren't caught (such as foreign exceptions) still enter the landing pad to clean up
+                    LLVMSetCleanup(landing_pad, 1);
+                    if is_catch {
+                        // a null type info catches every exception
+                        LLVMAddClause(landing_pad, LLVMConstNull(LLVMPointerType(LLVMInt8TypeInContext(self.ctx), 0)));
+                    }
+                    landing_pad
+                }
+                Insn::ExtractValue(aggregate, idx) => LLVMBuildExtractValue(self.bdl, aggregate.0, idx, insn_name),
+                Insn::Resume(value) => LLVMBuildResume(self.bdl, value.0),
                 Insn::GetElementPtr(struct_ref, value_type, value_idx) => {
                     LLVMBuildStructGEP2(self.bdl, value_type.0, struct_ref.0, value_idx, insn_name)
                 }
@@ -1045,9 +1145,6 @@ impl InsnBuilder {
                 }
             };
 
-            // let loc = llvm_sys::debuginfo::LLVMDIBuilderCreateDebugLocation(self.ctx, line, col, self.debug_file, std::ptr::null_mut());
-            // llvm_sys::debuginfo::LLVMInstructionSetDebugLoc(ret, loc);
-
             OpaqueValue(ret)
         }
     }
@@ -1179,6 +1276,16 @@ impl LLVMTargetData {
             unsafe { LLVMSizeOfTypeInBits(self.data, ty.0) / 8 }
         }
     }
+
+    /// Returns the byte offset of the element at `index` within the struct type `ty`.
+    pub fn get_element_offset(&self, ty: OpaqueType, index: u32) -> u64 {
+        if self.is_llvm_ir() {
+            let target = LLVMTargetData::new("x86_64-unknown-linux-gnu", false, true).unwrap();
+            target.get_element_offset(ty, index)
+        } else {
+            unsafe { LLVMOffsetOfElement(self.data, ty.0, index) }
+        }
+    }
 }
 
 impl Drop for LLVMTargetData {
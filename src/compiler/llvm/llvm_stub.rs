@@ -187,10 +187,18 @@ impl Context {
         OpaqueType {}
     }
 
+    pub fn get_abi_reference_field_type(&self) -> OpaqueType {
+        OpaqueType {}
+    }
+
     pub fn get_abi_interface_impl_type(&self) -> OpaqueType {
         OpaqueType {}
     }
 
+    pub fn get_abi_landing_pad_type(&self) -> OpaqueType {
+        OpaqueType {}
+    }
+
     pub fn get_abi_nullable_type(&self, _: OpaqueType, _: &str) -> OpaqueType {
         OpaqueType {}
     }
@@ -268,6 +276,8 @@ impl Module {
 
     pub fn initialize_global(&self, _: OpaqueValue, _: OpaqueValue) {}
 
+    pub fn set_global_constant(&self, _: OpaqueValue) {}
+
     pub fn get_or_extern_global(&mut self, global_var: &GlobalVariable) -> OpaqueValue {
         if let Some(val) = self.global_variables.get(&global_var.name) {
             *val
@@ -308,6 +318,8 @@ impl Function {
         OpaqueValue {}
     }
 
+    pub fn set_personality(&self, _: OpaqueFunctionValue) {}
+
     pub fn as_val(&self) -> OpaqueFunctionValue {
         OpaqueFunctionValue {}
     }
@@ -376,4 +388,8 @@ impl LLVMTargetData {
     pub fn get_type_size(&self, _: OpaqueType) -> u64 {
         8
     }
+
+    pub fn get_element_offset(&self, _: OpaqueType, index: u32) -> u64 {
+        index as u64 * 8
+    }
 }
@@ -24,6 +24,10 @@ pub enum Insn {
     Store(OpaqueValue, OpaqueValue),                                 // Store src par0 -> dest par1
     Load(OpaqueValue, OpaqueType),                                   // pointer, element type of pointer
     Call(OpaqueFunctionValue, OpaqueFunctionType, Vec<OpaqueValue>), // func ref, func type, args, return type
+    Invoke(OpaqueFunctionValue, OpaqueFunctionType, Vec<OpaqueValue>, OpaqueBasicBlock, OpaqueBasicBlock), // func ref, func type, args, normal, unwind
+    LandingPad(OpaqueType, bool),                                    // landing pad type, also catches all exceptions (always cleans up)
+    ExtractValue(OpaqueValue, u32),                                  // aggregate value, element index
+    Resume(OpaqueValue),                                             // resume unwinding of the landing pad value
     GetElementPtr(OpaqueValue, OpaqueType, u32),                     // struct ref, struct type, value index
     GetElementPtrDynamic(OpaqueValue, OpaqueType, OpaqueValue),      // struct ref, struct type, dynamic value index
     CondBr(OpaqueValue, OpaqueBasicBlock, OpaqueBasicBlock),         // test, then, else
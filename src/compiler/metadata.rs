@@ -1,10 +1,10 @@
-use std::fmt::Write;
+use std::{collections::HashMap, fmt::Write};
 
 use super::llvm::{Context, Linkage, Module, OpaqueValue};
 use crate::{
     common::{GenericIdentifier, TypeProvider},
     func::utils,
-    tree::{ClassType, GenericNode, ResolvedClassNode},
+    tree::{ast::Varargs, ClassType, GenericNode, ResolvedClassNode},
 };
 
 pub struct VirtualMethodInfo {
@@ -12,10 +12,19 @@ pub struct VirtualMethodInfo {
     pub func_ptr: OpaqueValue,
 }
 
+/// A field of a class that holds a reference to another reference-counted object.
+pub struct ReferenceFieldInfo {
+    /// The byte offset of the field within the class data.
+    pub offset: u32,
+    pub is_nullable: bool,
+}
+
 pub struct ClassInfo {
     pub class_impl: ResolvedClassNode,
     pub destructor_ptr: OpaqueValue,
     pub virtual_methods: Vec<VirtualMethodInfo>,
+    pub reference_fields: Vec<ReferenceFieldInfo>,
+    pub instance_size: u64,
 }
 
 pub struct ClassInfoStorage {
@@ -24,6 +33,7 @@ pub struct ClassInfoStorage {
 }
 
 const TYPE_STRUCT: i32 = 0x01;
+const FIELD_NULLABLE: i32 = 0x01;
 
 impl ClassInfoStorage {
     pub fn new(context: &mut Context) -> ClassInfoStorage {
@@ -40,9 +50,22 @@ impl ClassInfoStorage {
             return;
         }
 
+        self.register_superclasses(context, type_provider);
+
         let info_array_type = context.get_abi_class_info_type();
         let vtable_item_type = context.get_pointer_type(context.get_void_type());
 
+        // the class-level virtual methods of each class, in the order of their slots
+        let virtual_methods: Vec<_> = self
+            .classes
+            .iter()
+            .map(|class_info| match class_info.class_impl.class_type {
+                ClassType::Class => type_provider.get_virtual_methods(&class_info.class_impl),
+                _ => Vec::new(),
+            })
+            .collect();
+        let mut externed_functions = HashMap::new();
+
         let mut class_info_structs = Vec::with_capacity(self.classes.len());
         let mut interface_impl_structs = Vec::new();
         let mut vtable_pointers = Vec::new();
@@ -55,7 +78,7 @@ impl ClassInfoStorage {
             let class_info = &self.classes[i];
             let base_name = type_provider.get_source_class(&class_info.class_impl).base_name.clone();
             let resolved_interface_impls = type_provider
-                .get_resolved_interface_impls(&GenericIdentifier::from_name_with_args(&base_name, &class_info.class_impl.generic_impls));
+                .get_inherited_interface_impls(&GenericIdentifier::from_name_with_args(&base_name, &class_info.class_impl.generic_impls));
 
             for resolved_interface_impl in resolved_interface_impls {
                 let interface_impl = type_provider.get_source_interface_impl(&resolved_interface_impl);
@@ -92,6 +115,7 @@ impl ClassInfoStorage {
                 ));
                 vtable_offset += interface_impl.functions.len();
             }
+            vtable_offset += virtual_methods[i].len();
         }
 
         let global_interface_impl = self.module.create_global(
@@ -102,13 +126,26 @@ impl ClassInfoStorage {
         let global_interface_impl_array = context.create_const_array(context.get_abi_interface_impl_type(), &interface_impl_structs);
         self.module.initialize_global(global_interface_impl, global_interface_impl_array);
 
+        let array_type = context.get_array_type(context.get_abi_class_info_type(), self.classes.len());
+        let global_class_info = self.module.create_global(context, "keid.classinfo", array_type);
+
+        // the reference fields of every class, which the cycle collector uses to traverse the object graph
+        let reference_field_type = context.get_abi_reference_field_type();
+        let reference_field_count = self.classes.iter().map(|class_info| class_info.reference_fields.len()).sum();
+        let global_reference_fields = self.module.create_global(
+            context,
+            "keid.reference_fields",
+            context.get_array_type(reference_field_type, reference_field_count),
+        );
+        let mut reference_field_structs = Vec::with_capacity(reference_field_count);
+
         let mut interface_impl_offset = 0;
         for i in 0..self.classes.len() {
             let class_info = &self.classes[i];
             let base_name = type_provider.get_source_class(&class_info.class_impl).base_name.clone();
 
             let resolved_interface_impls = type_provider
-                .get_resolved_interface_impls(&GenericIdentifier::from_name_with_args(&base_name, &class_info.class_impl.generic_impls));
+                .get_inherited_interface_impls(&GenericIdentifier::from_name_with_args(&base_name, &class_info.class_impl.generic_impls));
             for resolved_interface_impl in &resolved_interface_impls {
                 let interface_impl = type_provider.get_source_interface_impl(resolved_interface_impl);
 
@@ -117,7 +154,8 @@ impl ClassInfoStorage {
 
                 let mut functions: Vec<usize> = Vec::new();
                 functions.extend(&source_interface.functions);
-                functions.extend(interface_impl.accessors.iter().map(|acc| acc.function_id));
+                // accessors are placed after the methods, in the order the interface declares them
+                functions.extend(source_interface.accessors.iter().map(|acc| acc.function_id));
                 functions.dedup();
 
                 for source_function_id in functions {
@@ -140,16 +178,8 @@ impl ClassInfoStorage {
                         }
 
                         let node_impl = node.create_impl(type_provider, &resolved_interface_impl.generic_impls).unwrap();
-                        let externed_function = self
-                            .module
-                            .add_function(
-                                &node_impl.external_name,
-                                context.get_function_type(&[], node.varargs, context.get_void_type()),
-                                0,
-                            )
-                            .as_val()
-                            .to_value();
-                        context.set_linkage(externed_function, Linkage::LLVMExternalLinkage);
+                        let externed_function =
+                            self.extern_function(context, &mut externed_functions, &node_impl.external_name, node.varargs);
                         vtable_pointers.push(externed_function);
                     }
                 }
@@ -163,13 +193,34 @@ impl ClassInfoStorage {
             };
             interface_impl_offset += resolved_interface_impls.len();
 
+            // the class-level virtual methods are placed directly after the interface methods of the class
+            let vtable_len = vtable_pointers.len();
+            let mut virtual_method_infos = Vec::with_capacity(virtual_methods[i].len());
+            for (method_id, method) in virtual_methods[i].iter().enumerate() {
+                let func_ptr = self.extern_function(context, &mut externed_functions, &method.external_name, method.varargs);
+                vtable_pointers.push(func_ptr);
+                virtual_method_infos.push(VirtualMethodInfo {
+                    method_id,
+                    func_ptr,
+                });
+            }
+
             // if there are no virtual methods, the vtable pointer is null
-            // let vtable_pointer = if vtable_pointers.len() == vtable_len {
-            //     context.const_null_ptr(vtable_item_type)
-            // } else {
-            //     context.const_get_element_ptr_dynamic(vtable_item_type, vtable, vtable_len)
-            // };
-            let vtable_pointer = context.const_null_ptr(vtable_item_type); // there are no class-level virtual methods currently
+            let vtable_pointer = if vtable_pointers.len() == vtable_len {
+                context.const_null_ptr(vtable_item_type)
+            } else {
+                context.const_get_element_ptr_dynamic(vtable_item_type, vtable, vtable_len)
+            };
+
+            // if the class has no superclass, the superclass pointer is null
+            let superclass_pointer = match &class_info.class_impl.superclass {
+                Some(superclass) if class_info.class_impl.class_type == ClassType::Class => {
+                    let superclass_impl = type_provider.get_class_by_name(superclass).unwrap();
+                    let superclass_index = self.classes.iter().position(|cls| cls.class_impl == superclass_impl).unwrap();
+                    context.const_get_element_ptr_dynamic(info_array_type, global_class_info, superclass_index)
+                }
+                _ => context.const_null_ptr(info_array_type),
+            };
 
             let class_name_str = class_info.class_impl.full_name.clone();
             let class_name_array = context.const_string(&class_name_str);
@@ -186,23 +237,50 @@ impl ClassInfoStorage {
             }
             let class_bitflags = context.const_int(context.get_i32_type(), class_bitflags as _);
 
+            // if the class has no reference fields, the reference fields pointer is null
+            let reference_fields_ptr = if class_info.reference_fields.is_empty() {
+                context.const_null_ptr(reference_field_type)
+            } else {
+                context.const_get_element_ptr_dynamic(reference_field_type, global_reference_fields, reference_field_structs.len())
+            };
+            for field in &class_info.reference_fields {
+                let field_bitflags = if field.is_nullable { FIELD_NULLABLE } else { 0 };
+                reference_field_structs.push(context.create_const_struct(
+                    reference_field_type,
+                    &mut [
+                        context.const_int(context.get_i32_type(), field.offset as _),   // field offset
+                        context.const_int(context.get_i32_type(), field_bitflags as _), // field bitflags
+                    ],
+                ));
+            }
+
             class_info_structs.push(context.create_const_struct(
                 info_array_type,
                 &mut [
-                    class_info.destructor_ptr,                                                        // destructor
-                    vtable_pointer,                                                                   // vtable
-                    context.const_int(context.get_i32_type(), resolved_interface_impls.len() as u64), // interfaces length
-                    interfaces_ptr,                                                                   // interfaces
-                    class_name_global,                                                                // class name
+                    class_info.destructor_ptr,                                                           // destructor
+                    vtable_pointer,                                                                      // vtable
+                    context.const_int(context.get_i32_type(), resolved_interface_impls.len() as u64),    // interfaces length
+                    interfaces_ptr,                                                                      // interfaces
+                    class_name_global,                                                                   // class name
                     class_bitflags,
+                    superclass_pointer,                                                                  // superclass
+                    context.const_int(context.get_i32_type(), class_info.reference_fields.len() as u64), // reference fields length
+                    reference_fields_ptr,                                                                // reference fields
+                    context.const_int(context.get_i64_type(), class_info.instance_size),                 // instance size
                 ],
             ));
+            self.classes[i].virtual_methods = virtual_method_infos;
         }
 
-        let array_type = context.get_array_type(context.get_abi_class_info_type(), class_info_structs.len());
-        let global_class_info = self.module.create_global(context, "keid.classinfo", array_type);
         let global_class_info_array = context.create_const_array(info_array_type, &class_info_structs);
         self.module.initialize_global(global_class_info, global_class_info_array);
+        // lets librtdbg tell class info pointers apart from uninitialized memory
+        let global_class_info_count = self.module.create_global(context, "keid.classinfo_count", context.get_i32_type());
+        self.module.initialize_global(global_class_info_count, context.const_int(context.get_i32_type(), self.classes.len() as u64));
+        self.module.set_global_constant(global_class_info_count);
+
+        let global_reference_fields_array = context.create_const_array(reference_field_type, &reference_field_structs);
+        self.module.initialize_global(global_reference_fields, global_reference_fields_array);
 
         let vtable_value = context.create_const_array(vtable_item_type, &vtable_pointers);
         let replacement_vtable =
@@ -211,6 +289,38 @@ impl ClassInfoStorage {
         self.module.initialize_global(replacement_vtable, vtable_value);
     }
 
+    /// Ensures that every superclass of a class with class info also has class info of its own.
+    pub fn register_superclasses(&mut self, context: &Context, type_provider: &TypeProvider) {
+        let mut i = 0;
+        while i < self.classes.len() {
+            let class_impl = &self.classes[i].class_impl;
+            if class_impl.class_type == ClassType::Class && let Some(superclass) = class_impl.superclass.clone() {
+                let superclass_impl = type_provider.get_class_by_name(&superclass).unwrap();
+                self.get_abi_class_info_offset(context, &superclass_impl);
+            }
+            i += 1;
+        }
+    }
+
+    fn extern_function(
+        &self,
+        context: &Context,
+        externed_functions: &mut HashMap<String, OpaqueValue>,
+        external_name: &str,
+        varargs: Varargs,
+    ) -> OpaqueValue {
+        // adding the same function twice would cause LLVM to rename the second declaration
+        *externed_functions.entry(external_name.to_owned()).or_insert_with(|| {
+            let externed_function = self
+                .module
+                .add_function(external_name, context.get_function_type(&[], varargs, context.get_void_type()), 0)
+                .as_val()
+                .to_value();
+            context.set_linkage(externed_function, Linkage::LLVMExternalLinkage);
+            externed_function
+        })
+    }
+
     pub fn get_abi_class_info_offset(&mut self, context: &Context, class: &ResolvedClassNode) -> usize {
         self.classes.iter().position(|cls| &cls.class_impl == class).unwrap_or_else(|| {
             let new_index = self.classes.len();
@@ -218,6 +328,8 @@ impl ClassInfoStorage {
                 class_impl: class.clone(),
                 destructor_ptr: context.const_null_ptr(context.get_void_type()),
                 virtual_methods: Vec::new(),
+                reference_fields: Vec::new(),
+                instance_size: 0,
             });
 
             new_index
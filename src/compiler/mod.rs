@@ -7,10 +7,13 @@ use llvm::*;
 mod metadata;
 pub use metadata::*;
 
+mod coverage;
+pub use coverage::*;
+
 use crate::{
     common::{kpkg::KeidPackageData, types::*, *},
     func::*,
-    parser::preprocessor::{self, PreprocessorContext},
+    parser::preprocessor::{self, PreprocessorContext, SourceMap},
     tree::{self, ast::*, *},
 };
 
@@ -20,16 +23,27 @@ pub struct CompilationUnit {
     pub path_name: String,
     pub mdl: Module,
     pub externed_functions: Vec<ExternedFunction>,
+    pub source_map: SourceMap,
 }
 
 impl CompilationUnit {
-    pub fn new(module_id: usize, mdl: Module, path_name: String) -> CompilationUnit {
+    pub fn new(module_id: usize, mdl: Module, path_name: String, source_map: SourceMap) -> CompilationUnit {
         CompilationUnit {
             module_id,
             mdl,
             path_name,
             externed_functions: Vec::new(),
+            source_map,
+        }
+    }
+
+    /// Adds a note for every macro expansion that produced the code at the location of `error`.
+    fn annotate(&self, error: &CompilerError) -> CompilerError {
+        let mut error = error.clone();
+        for name in self.source_map.get_expansions(&error.loc) {
+            error.notes.push(format!("in expansion of macro `{}`", name));
         }
+        error
     }
 }
 
@@ -67,7 +81,7 @@ impl SignatureCompiler {
         // add the init module as the very first module
         {
             let init_mdl = context.create_module("keid/keid_init", "keid_init");
-            units.push(CompilationUnit::new(0, init_mdl, "keid_init".to_owned()));
+            units.push(CompilationUnit::new(0, init_mdl, "keid_init".to_owned(), SourceMap::default()));
 
             type_provider.roots.push(KeidModuleNode {
                 classes: Vec::new(),
@@ -80,6 +94,7 @@ impl SignatureCompiler {
                     base_name: "keid.init".to_owned(),
                     external_name: "keid.init".to_owned(),
                     namespace_name: "".to_owned(),
+                    visibility: Visibility::Public,
                     body: None,
                     modifiers: vec![FunctionModifier::Internal],
                     function_type: FunctionType::Static,
@@ -89,6 +104,10 @@ impl SignatureCompiler {
                     params: Vec::new(),
                     return_type: BasicType::Void.to_complex(),
                     varargs: Varargs::None,
+                    loc: TokenLocation {
+                        start: 0,
+                        end: 0,
+                    },
                 }],
                 namespace: "keid::init".to_owned(),
             });
@@ -99,7 +118,7 @@ impl SignatureCompiler {
             type_provider.include_file(file.clone(), module_id);
 
             let mdl = context.create_module(&file.source_path, &utils::path_to_module_name(root, &file.source_path));
-            units.push(CompilationUnit::new(module_id, mdl, file.source_path.clone()));
+            units.push(CompilationUnit::new(module_id, mdl, file.source_path.clone(), file.source_map.clone()));
             module_id += 1;
         }
 
@@ -139,7 +158,22 @@ pub struct Compiler {
     pub type_provider: TypeProvider,
     pub class_info: ClassInfoStorage,
     pub units: Vec<CompilationUnit>,
+    /// Whether functions push and pop their frames for `core::runtime.getStackFrames()`. Backtraces of errors are
+    /// captured from the native stack instead, so this is off by default.
+    pub track_stack_frames: bool,
+    /// Whether the program is built for librtdbg, which tracks every object, and checks every slice element access,
+    /// `Pointer` dereference and `core::mem.copy` against the sizes of the allocations that it records.
+    pub use_rtdbg: bool,
+    /// Whether functions and statements count how often they're executed, see [`generate_lcov`].
+    pub coverage: bool,
+    pub coverage_storage: CoverageStorage,
+    /// Whether every function reports its entry and exit to the function profiler of librtdbg, which counts the calls and
+    /// the time spent in each function and writes them as folded stacks when the program exits. Implies that stack frames
+    /// are tracked, for every function rather than only the ones that `track_stack_frames` tracks. Programs run with
+    /// [`Compiler::interpret`] can't be profiled.
+    pub profile_functions: bool,
     errors: Vec<(CompilerError, usize)>,
+    warnings: Vec<(CompilerError, usize)>,
     function_queue: Vec<QueuedFunction>,
 }
 
@@ -150,14 +184,20 @@ impl Compiler {
             class_info,
             context,
             units: Vec::new(),
+            track_stack_frames: false,
+            use_rtdbg: false,
+            coverage: false,
+            coverage_storage: CoverageStorage::default(),
+            profile_functions: false,
             errors: Vec::new(),
+            warnings: Vec::new(),
             type_provider: TypeProvider::new(),
             function_queue: Vec::new(),
         }
     }
 
-    pub fn add_function(&self, mdl: &Module, external_name: &str, func: &ResolvedFunctionNode) -> Function {
-        let function = mdl.add_function(external_name, func.as_llvm_type(self), 0);
+    pub fn add_function(&self, mdl: &Module, external_name: &str, func: &ResolvedFunctionNode, line_no: u32) -> Function {
+        let function = mdl.add_function(external_name, func.as_llvm_type(self), line_no);
         for i in 0..func.params.len() {
             if func.params[i].is_struct(&self.type_provider)
                 && (!func.params[i].to_string().starts_with("core::mem::Pointer")
@@ -172,6 +212,15 @@ impl Compiler {
         function
     }
 
+    /// Returns the line at which a function with a body is declared, or 0 for functions without a body or location.
+    fn get_declaration_line(&self, unit: &CompilationUnit, func_impl: &ResolvedFunctionNode) -> u32 {
+        let source = self.type_provider.get_source_function(func_impl);
+        if source.body.is_none() || source.loc.end == 0 {
+            return 0;
+        }
+        unit.source_map.get_line_col(source.loc.start).map_or(0, |(line, _)| line as u32)
+    }
+
     pub fn queue_function_compilation(&mut self, func_impl: ResolvedFunctionNode) -> OpaqueFunctionValue {
         if let Some(func) = self.type_provider.get_compiled_function(&func_impl.external_name) {
             return func;
@@ -179,7 +228,8 @@ impl Compiler {
 
         let unit = self.units.iter().enumerate().find(|(_, unit)| unit.module_id == func_impl.module_id).unwrap();
 
-        let llvm_func = self.add_function(&unit.1.mdl, &func_impl.external_name, &func_impl);
+        let line_no = self.get_declaration_line(unit.1, &func_impl);
+        let llvm_func = self.add_function(&unit.1.mdl, &func_impl.external_name, &func_impl, line_no);
         let llvm_func_val = llvm_func.as_val();
         self.type_provider.add_compiled_function(&func_impl.external_name, llvm_func_val);
 
@@ -218,13 +268,22 @@ impl Compiler {
                     let mut func_cpl = FunctionCompiler::new(self, unit, &queued_function.func_impl, import_map, queued_function.llvm_func);
                     func_cpl.compile();
 
-                    let (errors, consumed_unit) = func_cpl.consume();
+                    let (errors, warnings, consumed_unit) = func_cpl.consume();
                     if !errors.is_empty() {
                         has_error = true;
                         for error in errors {
                             self.errors.push((error, unit_id));
                         }
                     }
+                    for warning in warnings {
+                        // generic functions are compiled once per implementation, so the same warning can be reported many times
+                        let is_duplicate = self.warnings.iter().any(|(other, other_unit_id)| {
+                            *other_unit_id == unit_id && other.loc == warning.loc && other.message == warning.message
+                        });
+                        if !is_duplicate {
+                            self.warnings.push((warning, unit_id));
+                        }
+                    }
 
                     consumed_unit
                 };
@@ -236,12 +295,21 @@ impl Compiler {
     pub fn get_errors(&self) -> Vec<(String, CompilerError)> {
         let mut errors = Vec::with_capacity(self.errors.len());
         for (error, unit_id) in &self.errors {
-            let path_name = &self.units[*unit_id].path_name;
-            errors.push((path_name.clone(), error.clone()));
+            let unit = &self.units[*unit_id];
+            errors.push((unit.path_name.clone(), unit.annotate(error)));
         }
         errors
     }
 
+    pub fn get_warnings(&self) -> Vec<(String, CompilerError)> {
+        let mut warnings = Vec::with_capacity(self.warnings.len());
+        for (warning, unit_id) in &self.warnings {
+            let unit = &self.units[*unit_id];
+            warnings.push((unit.path_name.clone(), unit.annotate(warning)));
+        }
+        warnings
+    }
+
     pub fn compile(&mut self, resources: SignatureCompilation, target: &LLVMTargetData) -> bool {
         self.units = resources.units;
         self.type_provider = resources.type_provider;
@@ -252,6 +320,12 @@ impl Compiler {
                 name: "keid.classinfo".to_string(),
                 ty: array_type,
             });
+            if self.coverage {
+                unit.mdl.extern_global(&GlobalVariable {
+                    name: "keid.coverage_counters".to_string(),
+                    ty: self.context.get_array_type(self.context.get_i64_type(), 0),
+                });
+            }
         }
 
         if !resources.errors.is_empty() {
@@ -274,6 +348,13 @@ impl Compiler {
                 )
                 .unwrap(),
         );
+        // `keid.thread_start` runs the task of every thread through `runThread`, which isn't called from any program
+        if let Some(run_thread) = self.type_provider.get_function_by_name(
+            &GenericIdentifier::from_name("std::thread::runThread"),
+            &[BasicType::Object(GenericIdentifier::from_name("std::thread::Thread")).to_complex()],
+        ) {
+            self.queue_function_compilation(run_thread);
+        }
         if self.compile_loop() {
             return true;
         }
@@ -352,6 +433,21 @@ impl Compiler {
                         }
                     }
 
+                    // virtual methods are only called indirectly through the vtable of a class, so they need to be queued too
+                    let virtual_methods: Vec<ResolvedFunctionNode> = self
+                        .type_provider
+                        .get_all_resolved_classes()
+                        .iter()
+                        .filter(|class_impl| class_impl.class_type == ClassType::Class)
+                        .flat_map(|class_impl| self.type_provider.get_virtual_methods(class_impl))
+                        .collect();
+                    for func in virtual_methods {
+                        if self.type_provider.get_compiled_function(&func.external_name).is_none() {
+                            self.queue_function_compilation(func);
+                            queued += 1;
+                        }
+                    }
+
                     if queued == 0 {
                         break;
                     }
@@ -363,6 +459,8 @@ impl Compiler {
 
                 // Compiler Phase 3
                 // queue class destructors for compilation
+                // the destructor of a class calls the destructor of its superclass, so superclasses need class info as well
+                self.class_info.register_superclasses(&self.context, &self.type_provider);
                 let class_names: Vec<_> = self
                     .class_info
                     .classes
@@ -393,16 +491,26 @@ impl Compiler {
             }
         }
 
+        for i in 0..self.class_info.classes.len() {
+            let reference_fields = self.get_reference_fields(&self.class_info.classes[i].class_impl);
+            self.class_info.classes[i].reference_fields = reference_fields;
+            let instance_size = self.get_instance_size(&self.class_info.classes[i].class_impl);
+            self.class_info.classes[i].instance_size = instance_size;
+        }
         self.class_info.create_class_info_storage(&mut self.context, &self.type_provider);
 
         if target.is_opaque_pointers {
-            let processed_intrinsics = preprocessor::preprocess(
-                include_str!("./intrinsics.ll"),
-                &PreprocessorContext {
-                    use_rtdbg: false,
-                },
-            )
-            .unwrap();
+            let mut preprocessor_ctx = PreprocessorContext::for_target(&self.target, target.is_debug);
+            if self.use_rtdbg {
+                preprocessor_ctx.define_flag("RTDBG");
+            }
+            if self.coverage {
+                preprocessor_ctx.define_flag("COVERAGE");
+            }
+            if self.profile_functions {
+                preprocessor_ctx.define_flag("PROFILE");
+            }
+            let processed_intrinsics = preprocessor::preprocess(include_str!("./intrinsics.ll"), &preprocessor_ctx).unwrap();
 
             let string_class = self.type_provider.get_class_by_name(&GenericIdentifier::from_name("core::string::String")).unwrap();
             let string_classinfo_offset = self.class_info.get_abi_class_info_offset(&self.context, &string_class).to_string();
@@ -414,6 +522,7 @@ impl Compiler {
                 path_name: "keid_intrinsics".to_string(),
                 mdl: intrinsics_module,
                 externed_functions: Vec::new(),
+                source_map: SourceMap::default(),
             });
         }
 
@@ -423,19 +532,79 @@ impl Compiler {
             path_name: "keid_core_reflect_abi".to_string(),
             mdl: reflect_abi_module,
             externed_functions: Vec::new(),
+            source_map: SourceMap::default(),
+        });
+
+        let sync_module = self.context.parse_llvm_ir(include_str!("../../assets/impl/linux/std/sync.ll"), "sync.ll");
+        self.units.push(CompilationUnit {
+            module_id: self.units.len(),
+            path_name: "keid_std_sync_impl".to_string(),
+            mdl: sync_module,
+            externed_functions: Vec::new(),
+            source_map: SourceMap::default(),
         });
 
+        if self.coverage {
+            self.coverage_storage.create_coverage_storage(&mut self.context, &mut self.class_info.module);
+        }
         self.units.push(CompilationUnit {
             module_id: self.units.len(),
             path_name: "keid_metadata".to_string(),
             mdl: self.class_info.module.clone(),
             externed_functions: Vec::new(),
+            source_map: SourceMap::default(),
         });
 
         false
     }
 
+    /// Returns the size of the heap data of a class, or 0 for types that aren't allocated on the heap.
+    fn get_instance_size(&self, class_impl: &ResolvedClassNode) -> u64 {
+        if class_impl.class_type != ClassType::Class {
+            return 0;
+        }
+        self.context.target.get_type_size(self.context.get_abi_class_data_type(self, class_impl))
+    }
+
+    /// Returns the fields of a class that hold references to other reference-counted objects,
+    /// which are the same fields that the destructor of the class releases.
+    fn get_reference_fields(&self, class_impl: &ResolvedClassNode) -> Vec<ReferenceFieldInfo> {
+        if class_impl.class_type != ClassType::Class {
+            return Vec::new();
+        }
+
+        let data_type = self.context.get_abi_class_data_type(self, class_impl);
+        let mut reference_fields = Vec::new();
+        for (i, field) in class_impl.fields.iter().enumerate() {
+            let (ident, is_nullable) = match &field.ty {
+                ComplexType::Basic(BasicType::Object(ident)) => (ident, false),
+                ComplexType::Nullable(box ComplexType::Basic(BasicType::Object(ident))) => (ident, true),
+                _ => continue,
+            };
+            // structs and enums aren't reference counted
+            match self.type_provider.get_class_by_name(ident) {
+                Some(class) if class.class_type != ClassType::Struct => (),
+                _ => continue,
+            }
+
+            // offset of 3 for (classinfo ptr) + (ref count) + (weak ref ptr)
+            let offset = self.context.target.get_element_offset(data_type, 3 + i as u32);
+            reference_fields.push(ReferenceFieldInfo {
+                offset: offset as u32,
+                is_nullable,
+            });
+        }
+
+        reference_fields
+    }
+
     pub fn interpret(mut self) {
+        // the profiler of librtdbg isn't mapped into the execution engine, so the profile could never be written
+        if self.profile_functions {
+            eprintln!("error: function profiling (--profile) isn't supported by the interpreter; compile the program instead");
+            std::process::exit(1);
+        }
+
         let base_module = self.units.remove(0).mdl;
         while !self.units.is_empty() {
             let unit = self.units.remove(0);
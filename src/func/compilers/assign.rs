@@ -21,6 +21,13 @@ pub trait AssignmentCompiler {
     ) -> Result<()>;
 
     fn compile_let(&mut self, lt: &Let) -> Result<()>;
+
+    fn compile_global_field_assign(
+        &mut self,
+        field: &FieldNode,
+        op: Operator,
+        rhs: &Token<Expr>,
+    ) -> Result<()>;
 }
 
 impl<'a> AssignmentCompiler for FunctionCompiler<'a> {
@@ -66,14 +73,19 @@ impl<'a> AssignmentCompiler for FunctionCompiler<'a> {
             )); // this Load loads the address stored in the Pointer<T> struct
             let addr_ptr = self.emit(Insn::IntToPtr(
                 addr_int,
-                element.to_reference().as_llvm_type(self.cpl),
+                element.clone().to_reference().as_llvm_type(self.cpl),
             ));
+            let size = self.compile_sizeof_expr(&element)?;
+            self.check_memory_access(addr_ptr, size.val)?;
             self.emit(Insn::Store(rhs.val, addr_ptr));
 
             return Ok(());
         }
         match &lhs.token {
             Expr::Member(member_expr) => {
+                if member_expr.members.iter().any(|member| member.ty == MemberType::NullSafeClass) {
+                    return Err(compiler_error!(self, "The `?.` operator cannot be used on the left side of an assignment"));
+                }
                 let previous_members = &member_expr.members[0..member_expr.members.len() - 1];
                 let previous_result = if !previous_members.is_empty()
                     && member_expr.prefix.is_some()
@@ -97,10 +109,23 @@ impl<'a> AssignmentCompiler for FunctionCompiler<'a> {
                     // no previous members
                     if namespace.0.len() == 1 && let Ok(local_ident) = self.resolve_ident(&namespace.0[0]) {
                         // if x is a local variable then just use that
-                        // self.load_local_var(local_ident)?
-                        self.load_local_var(&local_ident)?
+                        let local = self.load_local_var(&local_ident)?;
+                        self.load_narrowed(Some(&local_ident.name), local)?
                     } else {
-                        todo!("static field assignment: {:#?}", member_expr);
+                        // if x.y names a namespace-level field, then assign to it directly
+                        if let Expr::Ident(field_name) = &member_expr.members[0].value.token {
+                            let mut qualified_name = namespace.0.clone();
+                            qualified_name.push(field_name.clone());
+                            self.loc(&field_name.loc);
+                            if let Some(field) = self.resolve_global_field(&Qualifier(qualified_name))? {
+                                return self.compile_global_field_assign(&field, op, rhs);
+                            }
+                        }
+
+                        // otherwise, x is a namespace-level field holding the object that y is a member of
+                        let field_ref = self.resolve_static_field_reference(namespace)?;
+                        let container = TypedValueContainer(field_ref);
+                        TypedValue::new(container.get_type(), container.load(self)?)
                     }
                 } else {
                     // x = y
@@ -134,11 +159,7 @@ impl<'a> AssignmentCompiler for FunctionCompiler<'a> {
                                     &class_impl,
                                     field_name,
                                 )?;
-                                let val = class_member.load(self)?;
-                                self.try_unscope(&TypedValue {
-                                    ty: class_member.get_type(),
-                                    val,
-                                })?;
+                                class_member.unscope_current(self)?;
 
                                 let compiled_rhs =
                                     self.compile_expr(rhs, Some(&class_member.get_type()))?;
@@ -176,6 +197,22 @@ impl<'a> AssignmentCompiler for FunctionCompiler<'a> {
                 }
             }
             Expr::Ident(ident) => {
+                match self.resolve_ident(ident) {
+                    Ok(local_var) if local_var.is_const => {
+                        return Err(compiler_error!(
+                            self,
+                            "Cannot assign to constant `{}`",
+                            ident.token.0
+                        ));
+                    }
+                    Ok(_) => (),
+                    Err(_) => {
+                        if let Some(field) = self.resolve_global_field(&Qualifier(vec![ident.clone()]))? {
+                            return self.compile_global_field_assign(&field, op, rhs);
+                        }
+                    }
+                }
+
                 match self.resolve_static_field_reference(&Qualifier(vec![ident.clone()])) {
                     Ok(static_field) => {
                         self.try_unscope(&static_field)?;
@@ -200,6 +237,31 @@ impl<'a> AssignmentCompiler for FunctionCompiler<'a> {
         Ok(())
     }
 
+    /// Assigns `rhs` to a namespace-level field.
+    /// Fields declared with `const` can only be assigned by their initializer.
+    fn compile_global_field_assign(
+        &mut self,
+        field: &FieldNode,
+        op: Operator,
+        rhs: &Token<Expr>,
+    ) -> Result<()> {
+        if field.is_const {
+            return Err(compiler_error!(
+                self,
+                "Cannot assign to constant field `{}`",
+                field.name
+            ));
+        }
+
+        let global = self.get_global_field_ptr(field);
+        let val = TypedValueContainer(global.clone()).load(self)?;
+        self.try_unscope(&TypedValue::new(global.ty.clone(), val))?;
+
+        let compiled_rhs = self.compile_expr(rhs, Some(&global.ty))?;
+        self.loc(&rhs.loc);
+        self.store(op, compiled_rhs, &global)
+    }
+
     fn compile_let(&mut self, lt: &Let) -> Result<()> {
         if self.resolve_ident(&lt.name).is_ok() {
             return Err(compiler_error!(
@@ -212,12 +274,37 @@ impl<'a> AssignmentCompiler for FunctionCompiler<'a> {
         self.state.get_current_block_mut().locals.push(LocalVar {
             name: lt.name.token.0.clone(),
             value: self.cpl.context.const_unknown(),
+            is_const: lt.is_const,
         });
 
+        if lt.is_const && lt.initial_value.is_none() {
+            return Err(compiler_error!(
+                self,
+                "Constant `{}` must be initialized",
+                lt.name.token.0
+            ));
+        }
+
         let (initial_ref, var_type) = match (&lt.var_type, &lt.initial_value) {
             (Some(var_type), Some(initial_value)) => {
                 self.loc(&var_type.loc);
                 let var_type = self.resolve_type(&var_type.complex)?;
+                self.assert_type_accessible(&var_type)?;
+                // like global constants, local constants must fit in their declared type
+                if lt.is_const && let ComplexType::Basic(basic_type) = &var_type {
+                    let constant = StaticExpr::evaluate(initial_value, &mut |path| {
+                        if path.0.len() == 1 && self.resolve_ident(&path.0[0]).is_ok() {
+                            return Ok(None);
+                        }
+                        Ok(self.resolve_global_field(path)?.and_then(|field| field.constant_value))
+                    })?;
+                    if let Some(value @ StaticExpr::Integer(val)) = &constant
+                        && !value.fits_in(basic_type)
+                    {
+                        self.loc(&initial_value.loc);
+                        return Err(compiler_error!(self, "Constant `{}` does not fit in `{}`", val, var_type.to_string()));
+                    }
+                }
                 let initial_ref = self.compile_expr(initial_value, Some(&var_type))?;
 
                 self.loc(&initial_value.loc);
@@ -240,7 +327,10 @@ impl<'a> AssignmentCompiler for FunctionCompiler<'a> {
             (None, None) => unreachable!(),
         };
 
-        self.try_scope(&TypedValue::new(var_type.clone(), initial_ref.val))?;
+        // a new object starts out with the reference of the expression that created it, which the local takes over
+        if !matches!(lt.initial_value.as_ref().map(|value| &value.token), Some(Expr::New(_))) {
+            self.try_scope(&TypedValue::new(var_type.clone(), initial_ref.val))?;
+        }
 
         let var_ref = self.emit(Insn::Alloca(var_type.as_llvm_type(self.cpl)));
         let typed_local_var = TypedValue::new(var_type, var_ref);
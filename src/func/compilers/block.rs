@@ -1,7 +1,7 @@
 use crate::{
     common::types::BasicType,
     common::Result,
-    compiler::llvm::{BuilderBlock, Insn},
+    compiler::llvm::Insn,
     compiler_error,
     func::*,
 };
@@ -17,19 +17,43 @@ pub trait BlockCompiler {
     fn compile_block_statement(&mut self, block: &[Token<Statement>], block_type: BlockType) -> bool;
     fn compile_fixed_block(&mut self, fixed: &FixedBlock) -> Result<bool>;
     fn compile_throw(&mut self, expr: &Token<Expr>) -> Result<()>;
+    fn compile_rethrow(&mut self) -> Result<()>;
+    fn throw_error(&mut self, error: TypedValue) -> Result<()>;
+    fn enter_dead_block(&mut self);
     fn compile_try_catch(&mut self, try_catch: &TryCatch) -> Result<()>;
+    fn take_caught_error(&mut self, exception: OpaqueValue, stack_depth: OpaqueValue) -> Result<OpaqueValue>;
+    fn is_instance_of(&mut self, object: OpaqueValue, ty: &ComplexType) -> Result<OpaqueValue>;
+    fn compile_finally_block(&mut self, finally_block: &Option<Vec<Token<Statement>>>, stack_len: usize) -> bool;
+    fn compile_finally_and_rethrow(
+        &mut self,
+        finally_block: &Option<Vec<Token<Statement>>>,
+        stack_len: usize,
+        caught_error: OpaqueValue,
+    ) -> Result<()>;
+    /// Compiles the finally blocks of every try statement that a `return` exits, from the innermost outwards.
+    /// Returns true if one of them returns itself.
+    fn compile_enclosing_finally_blocks(&mut self, returned: &Option<TypedValue>) -> Result<bool>;
 }
 
 impl<'a> BlockCompiler for FunctionCompiler<'a> {
     /// Returns true if the block ends with a return statement, otherwise false.
     fn compile_block(&mut self, block: &[Token<Statement>]) -> bool {
         let mut returns = false;
+        // unlike a return, the block is still on the stack after a throw
+        let mut throws = false;
         for tkn in block {
             self.loc(&tkn.loc);
             if returns && tkn.token != Statement::Unreachable {
                 self.state.errors.push(compiler_error!(self, "Illegal statement: after a return statement in the same block"));
                 return true;
             }
+            if throws && tkn.token != Statement::Unreachable {
+                self.state.errors.push(compiler_error!(self, "Illegal statement: after a throw statement in the same block"));
+                return false;
+            }
+            if tkn.token != Statement::Unreachable {
+                self.count_coverage(&tkn.loc, CoveragePointKind::Statement);
+            }
             let result = match &tkn.token {
                 Statement::Unreachable => {
                     self.emit(Insn::Unreachable);
@@ -38,45 +62,27 @@ impl<'a> BlockCompiler for FunctionCompiler<'a> {
                     Ok(())
                 }
                 Statement::Return(return_val) => {
-                    // popping the block will generate keid.unscope() calls
-                    // thus, `compile_return` needs to be compiled after it (the return instruction must be the last)
-                    // `compile_return` expects that there be an active block though
-                    // to fix this, we first clone the top-level block's locals
-                    let cloned_locals = self.state.get_current_block().locals.clone();
-
-                    // then we generate all the keid.unscope() calls
-                    let block_vars: Vec<Vec<LocalVar>> = self.state.block_stack.iter().map(|block| block.locals.clone()).collect();
-                    for vars in block_vars {
-                        for var in vars {
-                            match self.try_unscope(&var.value) {
-                                Ok(_) => (),
-                                Err(e) => {
-                                    self.state.errors.push(e);
-                                    return true;
-                                }
-                            }
-                        }
-                    }
-
-                    // pop the cloned block without keid.scope() by popping the stack directly
-                    let block_type = self.state.block_stack.pop().unwrap().block_type;
-
-                    // create a new block and put all the locals in it
-                    self.state.block_stack.push(ScopeBlock {
-                        locals: cloned_locals,
-                        llvm_block: BuilderBlock::null(),
-                        block_type,
-                    });
-
-                    // compile the return statement
+                    // `compile_return` unscopes the locals of every block itself, once the returned value is evaluated,
+                    // so the block is popped without generating keid.unscope() calls
                     let res = self.compile_return(return_val);
                     returns = true;
-                    // pop the cloned block without keid.scope() by popping the stack directly
                     self.state.block_stack.pop();
                     res
                 }
-                Statement::Let(lt) => self.compile_let(lt),
-                Statement::Assign(assign) => self.compile_assign(&assign.lhs, assign.op, &assign.rhs, assign.deref),
+                Statement::Let(lt) => {
+                    let result = self.compile_let(lt);
+                    // the new local shadows any narrowed path of the same name
+                    self.state.invalidate_narrowing(&lt.name.token.0);
+                    result
+                }
+                Statement::Assign(assign) => {
+                    let result = self.compile_assign(&assign.lhs, assign.op, &assign.rhs, assign.deref);
+                    // the newly assigned value might be null
+                    if let Some(path) = utils::get_narrowing_path(&assign.lhs.token) {
+                        self.state.invalidate_narrowing(&path);
+                    }
+                    result
+                }
                 Statement::IfChain(if_chain) => self.compile_if_chain(if_chain),
                 Statement::Expr(expr) => self.compile_expr(expr, None).map(|_| ()),
                 Statement::ForLoop(for_loop) => self.compile_for_loop(for_loop),
@@ -98,9 +104,12 @@ impl<'a> BlockCompiler for FunctionCompiler<'a> {
                     Err(e) => Err(e),
                 },
                 Statement::Throw(throw) => {
-                    let res = self.compile_throw(throw);
-                    returns = true;
-                    res
+                    throws = true;
+                    self.compile_throw(throw)
+                }
+                Statement::Rethrow => {
+                    throws = true;
+                    self.compile_rethrow()
                 }
                 Statement::TryCatch(try_catch) => self.compile_try_catch(try_catch),
                 x => unimplemented!("{:#?}", x),
@@ -137,6 +146,7 @@ impl<'a> BlockCompiler for FunctionCompiler<'a> {
             locals: Vec::new(),
             llvm_block: new_block,
             block_type,
+            narrowed: Vec::new(),
         });
         let result = self.compile_block(block);
         if !result {
@@ -159,7 +169,9 @@ impl<'a> BlockCompiler for FunctionCompiler<'a> {
             self.loc(&conditional.test.loc);
             self.assert_assignable_to(&compiled_test.ty, &BasicType::Bool.to_complex())?;
 
-            let if_block = self.state.new_block(&mut self.builder);
+            // the body can use nullable values checked by the condition as non-null
+            let mut if_block = self.state.new_block(&mut self.builder);
+            utils::collect_non_null_paths(&conditional.test.token, &mut if_block.narrowed);
             blocks.push((compiled_test, if_block, &conditional.body));
         }
 
@@ -215,6 +227,9 @@ impl<'a> BlockCompiler for FunctionCompiler<'a> {
         self.builder.append_block(&loop_block.llvm_block);
         self.builder.use_block(&loop_block.llvm_block);
 
+        let narrowed = &mut self.state.get_current_block_mut().narrowed;
+        utils::collect_non_null_paths(&while_loop.condition.token, narrowed);
+
         self.compile_block(&while_loop.block);
         self.pop_block()?; // pop `loop_block` and all of its variables
 
@@ -231,15 +246,49 @@ impl<'a> BlockCompiler for FunctionCompiler<'a> {
         let error = self.compile_expr(expr, Some(&error_type))?;
         self.assert_assignable_to(&error.ty, &error_type)?;
 
+        self.throw_error(error)?;
+        self.enter_dead_block();
+
+        Ok(())
+    }
+
+    fn compile_rethrow(&mut self) -> Result<()> {
+        let caught_error = self.state.block_stack.iter().rev().find_map(|block| match &block.block_type {
+            BlockType::Catch(catch_block) => Some(catch_block.error),
+            _ => None,
+        });
+        let caught_error = match caught_error {
+            Some(caught_error) => caught_error,
+            None => return Err(compiler_error!(self, "Illegal statement: `throw` without an error outside of a catch clause")),
+        };
+
+        let error_type = BasicType::Object(GenericIdentifier::from_name("core::error::Error")).to_complex();
+        self.throw_error(TypedValue::new(error_type, caught_error))?;
+        self.enter_dead_block();
+
+        Ok(())
+    }
+
+    fn throw_error(&mut self, error: TypedValue) -> Result<()> {
+        let error_type = BasicType::Object(GenericIdentifier::from_name("core::error::Error")).to_complex();
         let throw_error_callable =
             ResolvedFunctionNode::externed("keid.throw_error", &[error_type], Varargs::None, BasicType::Void.to_complex());
         let throw_error_callable_ref = self.get_function_ref(&throw_error_callable)?;
-        self.call_function(throw_error_callable_ref, &throw_error_callable, &[error])?;
-        self.handle_unhandled_error(false)?;
+        let error = self.implicit_cast(error, &throw_error_callable.params[0])?;
+        self.emit_throwing_call(throw_error_callable_ref, throw_error_callable.as_llvm_type(self.cpl), vec![error.val])?;
+        self.emit(Insn::Unreachable);
 
         Ok(())
     }
 
+    fn enter_dead_block(&mut self) {
+        // anything after the throw is unreachable, but the enclosing blocks still need a block to emit their cleanup into
+        let rotated_parent = self.state.new_rotated_parent(&mut self.builder);
+        self.state.block_stack.pop();
+        self.builder.append_block(&rotated_parent.llvm_block);
+        self.state.push_block(&self.builder, rotated_parent);
+    }
+
     fn compile_for_loop(&mut self, for_loop: &ForLoop) -> Result<()> {
         let mut source_iterator_ptr = self.compile_expr(&for_loop.iterator, None)?;
 
@@ -345,6 +394,7 @@ impl<'a> BlockCompiler for FunctionCompiler<'a> {
                 ty: element_type,
                 val: value_ptr,
             },
+            is_const: false,
         });
 
         // compile the `loop_block`
@@ -391,10 +441,60 @@ impl<'a> BlockCompiler for FunctionCompiler<'a> {
     }
 
     fn compile_try_catch(&mut self, try_catch: &TryCatch) -> Result<()> {
+        let error_type = BasicType::Object(GenericIdentifier::from_name("core::error::Error")).to_complex();
+
+        let mut clause_types = Vec::new();
+        let mut catches_all = false;
+        for clause in &try_catch.catch_clauses {
+            if catches_all {
+                self.warn(compiler_error!(self, "Unreachable catch clause: every error is caught by an earlier clause"));
+            }
+            let clause_type = match &clause.error_type {
+                Some(ty) => {
+                    self.loc(&ty.loc);
+                    let resolved = self.resolve_type(&ty.complex)?;
+                    let is_class = match &resolved {
+                        ComplexType::Basic(BasicType::Object(ident)) => self
+                            .cpl
+                            .type_provider
+                            .get_class_by_name(ident)
+                            .map(|class_impl| class_impl.class_type == ClassType::Class)
+                            .unwrap_or(false),
+                        _ => false,
+                    };
+                    if !is_class || !self.cpl.type_provider.is_assignable_to(&resolved, &error_type) {
+                        return Err(compiler_error!(
+                            self,
+                            "Cannot catch `{}`: the type of a catch clause must be a class that extends `core::error::Error`",
+                            resolved.to_string()
+                        ));
+                    }
+                    catches_all |= resolved == error_type;
+                    Some(resolved)
+                }
+                None => {
+                    catches_all = true;
+                    None
+                }
+            };
+            clause_types.push(clause_type);
+        }
+
+        // the landing pads within the try block store the caught exception before entering the dispatch block
+        let exception = self.emit(Insn::Alloca(self.cpl.context.get_pointer_type(self.cpl.context.get_i8_type())));
+
+        // the functions unwound by a thrown error don't pop their stack frames
+        let get_stack_depth_callable =
+            ResolvedFunctionNode::externed("keid_core_runtime_get_stack_depth", &[], Varargs::None, BasicType::USize.to_complex());
+        let get_stack_depth_ref = self.get_function_ref(&get_stack_depth_callable)?;
+        let stack_depth = self.call_function(get_stack_depth_ref, &get_stack_depth_callable, &[])?;
+
         let rotated_parent = self.state.new_rotated_parent(&mut self.builder);
+        let stack_len = self.state.block_stack.len();
 
         let try_block = self.builder.create_block();
-        let catch_block = self.builder.create_block();
+        let dispatch_block = self.builder.create_block();
+        let finally_rethrow_block = self.builder.create_block();
 
         self.emit(Insn::Br(try_block.as_val()));
 
@@ -404,53 +504,104 @@ impl<'a> BlockCompiler for FunctionCompiler<'a> {
                 llvm_block: try_block,
                 locals: Vec::new(),
                 block_type: BlockType::Try(TryBlock {
-                    catch_block: catch_block.clone(),
+                    catch_block: dispatch_block.clone(),
+                    exception,
+                    stack_depth,
+                    finally_block: try_catch.finally_block.clone(),
                 }),
+                narrowed: Vec::new(),
             };
 
             self.builder.append_block(&try_scope_block.llvm_block);
             self.state.push_block(&self.builder, try_scope_block);
 
             // compile the contents of the try block
-            self.compile_block(&try_catch.try_block);
-            self.pop_block()?; // pop the try block
-
-            self.emit(Insn::Br(rotated_parent.llvm_block.as_val())); // jump to after the catch block
+            if !self.compile_block(&try_catch.try_block) {
+                self.pop_block()?; // pop the try block
+                if !self.compile_finally_block(&try_catch.finally_block, stack_len) {
+                    self.emit(Insn::Br(rotated_parent.llvm_block.as_val())); // jump to after the try statement
+                }
+            }
         }
-        // catch block
+        // dispatch block, which enters the first catch clause that matches the runtime class of the caught error
         {
-            let catch_scope_block = ScopeBlock {
-                llvm_block: catch_block,
+            // errors thrown by the catch clauses also run the finally block before they propagate
+            let block_type = match &try_catch.finally_block {
+                Some(_) => BlockType::Try(TryBlock {
+                    catch_block: finally_rethrow_block.clone(),
+                    exception,
+                    stack_depth,
+                    finally_block: try_catch.finally_block.clone(),
+                }),
+                None => BlockType::Generic,
+            };
+            let dispatch_scope_block = ScopeBlock {
+                llvm_block: dispatch_block,
                 locals: Vec::new(),
-                block_type: BlockType::Generic,
+                block_type,
+                narrowed: Vec::new(),
             };
-            self.builder.append_block(&catch_scope_block.llvm_block);
-            self.state.push_block(&self.builder, catch_scope_block);
-
-            let error_type = BasicType::Object(GenericIdentifier::from_name("core::error::Error")).to_complex();
-            let get_unhandled_error_callable =
-                ResolvedFunctionNode::externed("keid.get_unhandled_error", &[], Varargs::None, error_type.clone());
-            let get_unhandled_error_ref = self.get_function_ref(&get_unhandled_error_callable)?;
-            let unhandled_error = self.call_function(get_unhandled_error_ref, &get_unhandled_error_callable, &[])?;
-
-            let clear_unhandled_error_callable =
-                ResolvedFunctionNode::externed("keid.clear_unhandled_error", &[], Varargs::None, BasicType::Void.to_complex());
-            let clear_unhandled_error_ref = self.get_function_ref(&clear_unhandled_error_callable)?;
-            self.call_function(clear_unhandled_error_ref, &clear_unhandled_error_callable, &[])?;
-
-            self.state.get_current_block_mut().locals.push(LocalVar {
-                name: try_catch.error_var.as_ref().unwrap().token.0.clone(),
-                value: TypedValue {
-                    ty: error_type,
-                    val: unhandled_error,
-                },
-            });
+            self.builder.append_block(&dispatch_scope_block.llvm_block);
+            self.state.push_block(&self.builder, dispatch_scope_block);
+
+            let caught_error = self.take_caught_error(exception, stack_depth)?;
+
+            for (clause, clause_type) in try_catch.catch_clauses.iter().zip(clause_types) {
+                let clause_block = self.builder.create_block();
+                let next_clause_block = self.builder.create_block();
+
+                match &clause_type {
+                    Some(ty) => {
+                        let is_assignable = self.is_instance_of(caught_error, ty)?;
+                        self.emit(Insn::CondBr(is_assignable, clause_block.as_val(), next_clause_block.as_val()));
+                    }
+                    None => {
+                        self.emit(Insn::Br(clause_block.as_val()));
+                    }
+                }
 
-            // compile the contents of the catch block
-            self.compile_block(&try_catch.catch_block);
-            self.pop_block()?; // pop the catch block
+                let mut clause_scope_block = ScopeBlock {
+                    llvm_block: clause_block,
+                    locals: Vec::new(),
+                    block_type: BlockType::Catch(CatchBlock { error: caught_error }),
+                    narrowed: Vec::new(),
+                };
+                if let Some(error_var) = &clause.error_var {
+                    clause_scope_block.locals.push(LocalVar {
+                        name: error_var.token.0.clone(),
+                        value: TypedValue {
+                            ty: clause_type.unwrap_or_else(|| error_type.clone()),
+                            val: caught_error,
+                        },
+                        is_const: false,
+                    });
+                }
+                self.builder.append_block(&clause_scope_block.llvm_block);
+                self.state.push_block(&self.builder, clause_scope_block);
+
+                // compile the contents of the catch clause
+                if !self.compile_block(&clause.block) {
+                    self.pop_block()?; // pop the catch clause
+                    if !self.compile_finally_block(&try_catch.finally_block, stack_len) {
+                        self.emit(Insn::Br(rotated_parent.llvm_block.as_val())); // jump to after the try statement
+                    }
+                }
 
-            self.emit(Insn::Br(rotated_parent.llvm_block.as_val())); // jump to after the catch block
+                self.builder.append_block(&next_clause_block);
+                self.builder.use_block(&next_clause_block);
+            }
+
+            // none of the catch clauses matched the error
+            self.compile_finally_and_rethrow(&try_catch.finally_block, stack_len, caught_error)?;
+            self.pop_block()?; // pop the dispatch block
+        }
+        // finally block of the errors thrown by the catch clauses
+        if try_catch.finally_block.is_some() {
+            self.builder.append_block(&finally_rethrow_block);
+            self.builder.use_block(&finally_rethrow_block);
+
+            let caught_error = self.take_caught_error(exception, stack_depth)?;
+            self.compile_finally_and_rethrow(&try_catch.finally_block, stack_len, caught_error)?;
         }
 
         self.state.block_stack.pop(); // pop the parent block
@@ -459,4 +610,115 @@ impl<'a> BlockCompiler for FunctionCompiler<'a> {
 
         Ok(())
     }
+
+    fn take_caught_error(&mut self, exception: OpaqueValue, stack_depth: OpaqueValue) -> Result<OpaqueValue> {
+        let error_type = BasicType::Object(GenericIdentifier::from_name("core::error::Error")).to_complex();
+        let exception = self.emit(Insn::Load(exception, self.cpl.context.get_pointer_type(self.cpl.context.get_i8_type())));
+        let take_error_callable = ResolvedFunctionNode::externed(
+            "keid_exception_take_error",
+            &[BasicType::Void.to_complex().to_reference()],
+            Varargs::None,
+            error_type,
+        );
+        let take_error_ref = self.get_function_ref(&take_error_callable)?;
+        let caught_error = self.call_function(
+            take_error_ref,
+            &take_error_callable,
+            &[TypedValue::new(BasicType::Void.to_complex().to_reference(), exception)],
+        )?;
+
+        let truncate_stack_frames_callable = ResolvedFunctionNode::externed(
+            "keid_core_runtime_truncate_stack_frames",
+            &[BasicType::USize.to_complex()],
+            Varargs::None,
+            BasicType::Void.to_complex(),
+        );
+        let truncate_stack_frames_ref = self.get_function_ref(&truncate_stack_frames_callable)?;
+        self.call_function(
+            truncate_stack_frames_ref,
+            &truncate_stack_frames_callable,
+            &[TypedValue::new(BasicType::USize.to_complex(), stack_depth)],
+        )?;
+
+        Ok(caught_error)
+    }
+
+    fn is_instance_of(&mut self, object: OpaqueValue, ty: &ComplexType) -> Result<OpaqueValue> {
+        let class_impl = self.cpl.type_provider.get_class_by_name(&GenericIdentifier::from_complex_type(ty)).unwrap();
+        let class_info = self.cpl.class_info.get_abi_class_info_ptr(&self.cpl.context, &self.unit.mdl, &class_impl);
+
+        let is_assignable_to_impl = ResolvedFunctionNode::externed(
+            "keid.is_assignable_to",
+            &[BasicType::Void.to_complex().to_reference(), BasicType::Void.to_complex().to_reference()],
+            Varargs::None,
+            BasicType::Bool.to_complex(),
+        );
+        let is_assignable_to_ref = self.get_function_ref(&is_assignable_to_impl)?;
+
+        Ok(self.emit(Insn::Call(is_assignable_to_ref, is_assignable_to_impl.as_llvm_type(self.cpl), vec![object, class_info])))
+    }
+
+    /// Compiles `finally_block` outside of the blocks at and above `stack_len`, i.e. outside of the try statement.
+    /// Returns true if the finally block returns.
+    fn compile_finally_block(&mut self, finally_block: &Option<Vec<Token<Statement>>>, stack_len: usize) -> bool {
+        let finally_block = match finally_block {
+            Some(finally_block) => finally_block,
+            None => return false,
+        };
+
+        let exited_blocks = self.state.block_stack.split_off(stack_len);
+        let returns = self.compile_block_statement(finally_block, BlockType::Generic);
+        self.state.block_stack.extend(exited_blocks);
+
+        returns
+    }
+
+    fn compile_finally_and_rethrow(
+        &mut self,
+        finally_block: &Option<Vec<Token<Statement>>>,
+        stack_len: usize,
+        caught_error: OpaqueValue,
+    ) -> Result<()> {
+        if self.compile_finally_block(finally_block, stack_len) {
+            return Ok(());
+        }
+
+        let error_type = BasicType::Object(GenericIdentifier::from_name("core::error::Error")).to_complex();
+        let exited_blocks = self.state.block_stack.split_off(stack_len);
+        let result = self.throw_error(TypedValue::new(error_type, caught_error));
+        self.state.block_stack.extend(exited_blocks);
+
+        result
+    }
+
+    fn compile_enclosing_finally_blocks(&mut self, returned: &Option<TypedValue>) -> Result<bool> {
+        for i in (0..self.state.block_stack.len()).rev() {
+            let finally_block = match &self.state.block_stack[i].block_type {
+                BlockType::Try(try_block) => try_block.finally_block.clone(),
+                _ => None,
+            };
+            if finally_block.is_none() {
+                continue;
+            }
+
+            // the locals are unscoped once all finally blocks have run, unless one of them returns itself:
+            // then its `return` releases the returned value and the locals that are out of scope in the finally block
+            let mut pending_unscopes: Vec<TypedValue> = returned.iter().cloned().collect();
+            for block_idx in i..self.state.block_stack.len() {
+                for local_idx in 0..self.state.block_stack[block_idx].locals.len() {
+                    if self.is_unwound_local(block_idx, local_idx) {
+                        pending_unscopes.push(self.state.block_stack[block_idx].locals[local_idx].value.clone());
+                    }
+                }
+            }
+            let outer_unscopes = std::mem::replace(&mut self.state.pending_unscopes, pending_unscopes);
+            let returns = self.compile_finally_block(&finally_block, i);
+            self.state.pending_unscopes = outer_unscopes;
+            if returns {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
 }
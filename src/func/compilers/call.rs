@@ -2,7 +2,10 @@ use crate::{
     common::{types::*, *},
     compiler::llvm::{self, *},
     compiler_error,
-    func::{utils::FunctionCompilerUtils, FunctionCompiler},
+    func::{
+        utils::{FunctionCompilerUtils, ScopeChange},
+        FunctionCompiler,
+    },
     tree::ast::*,
     tree::*,
 };
@@ -33,6 +36,12 @@ pub trait CallCompiler {
 
     fn compile_instance_func_call(&mut self, fc: &FuncCall, instance: &TypedValue) -> Result<TypedValue>;
 
+    /// Compiles a call to a method of the superclass of `this`, e.g. `super.toString()`.
+    /// The method is always called statically, even if it is virtual.
+    fn compile_super_func_call(&mut self, fc: &FuncCall) -> Result<TypedValue>;
+
+    fn get_virtual_method_ptr(&mut self, instance: &TypedValue, slot: usize) -> Result<OpaqueFunctionValue>;
+
     fn get_interface_method_ptr(
         &mut self,
         invocation: &InterfaceInvocation,
@@ -49,6 +58,9 @@ pub trait CallCompiler {
 
     fn call_function(&mut self, func_ref: OpaqueFunctionValue, callable: &ResolvedFunctionNode, args: &[TypedValue])
         -> Result<OpaqueValue>;
+
+    /// Checks the source and destination regions of a call to `core::mem.copy` when compiling for librtdbg.
+    fn check_memory_copy(&mut self, callable: &ResolvedFunctionNode, args: &[TypedValue]) -> Result<()>;
 }
 
 impl<'a> CallCompiler for FunctionCompiler<'a> {
@@ -216,6 +228,11 @@ impl<'a> CallCompiler for FunctionCompiler<'a> {
 
     fn compile_static_func_call(&mut self, sfc: &StaticFuncCall) -> Result<TypedValue> {
         let (callable, args) = self.resolve_static_function(sfc, &sfc.call.args)?;
+        self.loc(&sfc.call.name.loc);
+        self.assert_callable(&callable)?;
+        if self.cpl.use_rtdbg && self.cpl.type_provider.get_source_function(&callable).base_name == "core::mem::copy" {
+            self.check_memory_copy(&callable, &args)?;
+        }
         let func_ref = self.get_function_ref(&callable)?;
         self.compile_func_call(func_ref, &callable, &args)
     }
@@ -223,7 +240,22 @@ impl<'a> CallCompiler for FunctionCompiler<'a> {
     fn compile_instance_func_call(&mut self, fc: &FuncCall, instance: &TypedValue) -> Result<TypedValue> {
         match self.resolve_instance_function(fc, instance, &fc.args) {
             Ok((callable, args)) => {
-                let func_ref = self.get_function_ref(&callable)?;
+                self.loc(&fc.name.loc);
+                self.assert_callable(&callable)?;
+                let virtual_slot = {
+                    let source = self.cpl.type_provider.get_source_function(&callable);
+                    if source.modifiers.contains(&FunctionModifier::Virtual) || source.modifiers.contains(&FunctionModifier::Override) {
+                        let declaring_class =
+                            self.cpl.type_provider.get_class_by_name(&GenericIdentifier::from_complex_type(&callable.params[0])).unwrap();
+                        self.cpl.type_provider.get_virtual_method_slot(&declaring_class, source)
+                    } else {
+                        None
+                    }
+                };
+                let func_ref = match virtual_slot {
+                    Some(slot) => self.get_virtual_method_ptr(&args[0], slot)?,
+                    None => self.get_function_ref(&callable)?,
+                };
                 Ok(self.compile_func_call(func_ref, &callable, &args)?)
             }
             Err(e) => {
@@ -240,7 +272,7 @@ impl<'a> CallCompiler for FunctionCompiler<'a> {
                             }
                         }
                         _ => {
-                            let resolved_interface_impls = self.cpl.type_provider.get_resolved_interface_impls(&ident);
+                            let resolved_interface_impls = self.cpl.type_provider.get_inherited_interface_impls(&ident);
                             let mut candidates = Vec::new();
                             for resolved_interface_impl in &resolved_interface_impls {
                                 let interface_impl = self.cpl.type_provider.get_source_interface_impl(resolved_interface_impl);
@@ -304,6 +336,51 @@ impl<'a> CallCompiler for FunctionCompiler<'a> {
         }
     }
 
+    fn compile_super_func_call(&mut self, fc: &FuncCall) -> Result<TypedValue> {
+        let this = self.resolve_ident(&Identifier::from_string("this"));
+        self.loc(&fc.name.loc);
+        let this = match this {
+            Ok(this) => self.load_local_var(&this)?,
+            Err(_) => return Err(compiler_error!(self, "`super` can only be used in instance methods")),
+        };
+        let superclass = match self.cpl.type_provider.get_class_by_name(&GenericIdentifier::from_complex_type(&this.ty)) {
+            Some(class_impl) if class_impl.class_type == ClassType::Class => class_impl.superclass,
+            _ => None,
+        };
+        let superclass = match superclass {
+            Some(superclass) => superclass,
+            None => return Err(compiler_error!(self, "Type `{}` has no superclass", this.ty.to_string())),
+        };
+
+        let instance = TypedValue::new(BasicType::Object(superclass).to_complex(), this.val);
+        let (callable, args) = self.resolve_instance_function(fc, &instance, &fc.args)?;
+        self.loc(&fc.name.loc);
+        self.assert_callable(&callable)?;
+        let func_ref = self.get_function_ref(&callable)?;
+        self.compile_func_call(func_ref, &callable, &args)
+    }
+
+    fn get_virtual_method_ptr(&mut self, instance: &TypedValue, slot: usize) -> Result<OpaqueFunctionValue> {
+        let find_virtual_method_impl = ResolvedFunctionNode::externed(
+            "keid.find_virtual_method",
+            &[
+                BasicType::Void.to_complex().to_reference(), // pointer
+                BasicType::Int32.to_complex(),
+            ],
+            Varargs::None,
+            BasicType::Void.to_complex().to_reference(), // pointer
+        );
+        let find_virtual_method_ref = self.get_function_ref(&find_virtual_method_impl)?;
+
+        let slot_const = self.cpl.context.const_int(self.cpl.context.get_i32_type(), slot as _);
+        let method_ptr = self.emit(Insn::Call(
+            find_virtual_method_ref,
+            find_virtual_method_impl.as_llvm_type(self.cpl),
+            vec![instance.val, slot_const],
+        ));
+        Ok(method_ptr.to_function())
+    }
+
     fn get_interface_method_ptr(
         &mut self,
         instance: &InterfaceInvocation,
@@ -349,7 +426,9 @@ impl<'a> CallCompiler for FunctionCompiler<'a> {
     fn compile_new_call(&mut self, nc: &NewCall) -> Result<TypedValue> {
         self.loc(&nc.ty.loc);
 
-        let class_instance = self.instantiate_object(self.resolve_type(&nc.ty.complex).unwrap())?;
+        let class_type = self.resolve_type(&nc.ty.complex)?;
+        self.assert_type_accessible(&class_type)?;
+        let class_instance = self.instantiate_object(class_type)?;
         match &class_instance.ty {
             ComplexType::Basic(BasicType::Object(ident)) => {
                 let class_impl = self.cpl.type_provider.get_class_by_name(ident).unwrap();
@@ -424,6 +503,11 @@ impl<'a> CallCompiler for FunctionCompiler<'a> {
                 let ref_count_ptr = self.emit(Insn::GetElementPtr(class_pointer, class_abi, 1));
                 self.emit(Insn::Store(const_one, ref_count_ptr));
 
+                // no weak references exist yet
+                let const_null = self.cpl.context.const_null_ptr(self.cpl.context.get_i8_type());
+                let weak_ref_ptr = self.emit(Insn::GetElementPtr(class_pointer, class_abi, 2));
+                self.emit(Insn::Store(const_null, weak_ref_ptr));
+
                 let class_abi_ptr_type = self.cpl.context.get_pointer_type(class_abi);
                 let class_pointer = self.emit(Insn::PointerCast(class_pointer, class_abi_ptr_type));
                 class_instance.val = class_pointer;
@@ -509,6 +593,19 @@ impl<'a> CallCompiler for FunctionCompiler<'a> {
                 .collect::<Result<Vec<_>>>()?,
         };
 
+        // only the compiler knows whether a generic value is reference counted, so these are compiled to its reference counting
+        if callable.module_id != usize::MAX {
+            let scope_change = match self.cpl.type_provider.get_source_function(callable).base_name.as_str() {
+                "core::mem::retain" => Some(ScopeChange::Inside),
+                "core::mem::release" => Some(ScopeChange::Outside),
+                _ => None,
+            };
+            if let Some(scope_change) = scope_change {
+                self.try_change_scope(&args[0], scope_change)?;
+                return Ok(self.cpl.context.const_unknown().val);
+            }
+        }
+
         let is_extern = callable.module_id == usize::MAX || {
             let mods = self.cpl.type_provider.get_source_function(callable).modifiers.clone();
             mods.contains(&FunctionModifier::Extern)
@@ -567,17 +664,14 @@ impl<'a> CallCompiler for FunctionCompiler<'a> {
             .unzip();
 
         let func_type = self.cpl.context.get_function_type(&arg_types, callable.varargs, return_type.as_llvm_type(self.cpl));
-        let call = self.emit(Insn::Call(func_ref, func_type, arg_vals));
+        // external functions can't throw errors
+        let call = if is_extern {
+            self.emit(Insn::Call(func_ref, func_type, arg_vals))
+        } else {
+            self.emit_throwing_call(func_ref, func_type, arg_vals)?
+        };
         self.add_call_site_attributes(call, callable);
 
-        // prevent recursively checking errors, or error-checking external functions
-        if callable.module_id != usize::MAX
-            && callable.external_name != "keid.check_unhandled_error"
-            && callable.external_name != "keid.get_unhandled_error"
-        {
-            self.handle_unhandled_error(true)?;
-        }
-
         let result = if is_struct {
             return_memory.unwrap()
         } else if coerce_pointer {
@@ -592,4 +686,17 @@ impl<'a> CallCompiler for FunctionCompiler<'a> {
 
         Ok(result)
     }
+
+    fn check_memory_copy(&mut self, callable: &ResolvedFunctionNode, args: &[TypedValue]) -> Result<()> {
+        // copy<T>(src: Pointer<T>, dst: Pointer<T>, count: usize) copies `sizeof(T) * count` bytes
+        let element_size = self.compile_sizeof_expr(&callable.generic_impls[0])?;
+        let size = self.emit(Insn::IMul(element_size.val, args[2].val));
+        for pointer in &args[..2] {
+            let addr_ptr = self.emit(Insn::GetElementPtr(pointer.val, pointer.ty.as_llvm_type(self.cpl), 1)); // the `address` field
+            let addr_int = self.emit(Insn::Load(addr_ptr, BasicType::USize.as_llvm_type(self.cpl)));
+            let address = self.emit(Insn::IntToPtr(addr_int, BasicType::Void.to_complex().to_reference().as_llvm_type(self.cpl)));
+            self.check_memory_access(address, size)?;
+        }
+        Ok(())
+    }
 }
@@ -12,6 +12,103 @@ use crate::{
     func::{utils::FunctionCompilerUtils, *},
 };
 
+/// The values of a matched type that are handled by some set of match branches.
+struct MatchCoverage {
+    /// Whether every value is handled, such as by a `_` pattern.
+    all: bool,
+    null: bool,
+    variant_names: Vec<String>,
+    /// Whether every value of each variant of the matched enum is handled.
+    variants: Vec<bool>,
+    constants: Vec<StaticExpr>,
+}
+
+impl MatchCoverage {
+    fn new(enum_impl: Option<&ResolvedEnumNode>) -> MatchCoverage {
+        MatchCoverage {
+            all: false,
+            null: false,
+            variant_names: enum_impl
+                .map(|enum_impl| enum_impl.elements.iter().map(|element| element.name.clone()).collect())
+                .unwrap_or_default(),
+            variants: vec![false; enum_impl.map_or(0, |enum_impl| enum_impl.elements.len())],
+            constants: Vec::new(),
+        }
+    }
+
+    fn is_exhaustive(&self, is_nullable: bool) -> bool {
+        self.all || (!self.variants.is_empty() && self.variants.iter().all(|covered| *covered) && (self.null || !is_nullable))
+    }
+
+    fn merge(&mut self, other: MatchCoverage) {
+        self.all |= other.all;
+        self.null |= other.null;
+        for (covered, other_covered) in self.variants.iter_mut().zip(other.variants) {
+            *covered |= other_covered;
+        }
+        self.constants.extend(other.constants);
+    }
+
+    /// Records the values handled by `pattern`.
+    /// The variants (or `None` for `null`) that the pattern refers to are added to `mentioned`,
+    /// even if it only handles some of their values.
+    fn add_pattern(&mut self, pattern: &Token<MatchPattern>, mentioned: &mut Vec<(Option<usize>, TokenLocation)>) {
+        match &pattern.token {
+            MatchPattern::Catchall => self.all = true,
+            MatchPattern::Binding {
+                pattern,
+                ..
+            } => self.add_pattern(pattern, mentioned),
+            MatchPattern::Alternatives(alternatives) => {
+                for alternative in alternatives {
+                    self.add_pattern(alternative, mentioned);
+                }
+            }
+            MatchPattern::Expr(Token {
+                token: Expr::Null,
+                ..
+            }) => {
+                self.null = true;
+                mentioned.push((None, pattern.loc.clone()));
+            }
+            MatchPattern::Expr(expr) => {
+                if let Ok(Some(constant)) = StaticExpr::evaluate(expr, &mut |_| Ok(None)) {
+                    self.constants.push(constant);
+                }
+            }
+            MatchPattern::Enum {
+                member,
+                fields,
+            } => {
+                // unknown variants are reported when the pattern is compiled
+                let variant_id = self.variant_names.iter().position(|name| *name == member.token.0);
+                let is_irrefutable =
+                    fields.iter().flatten().all(|field| field.pattern.as_ref().map_or(true, MatchCoverage::is_irrefutable));
+                if let Some(variant_id) = variant_id {
+                    self.variants[variant_id] |= is_irrefutable;
+                    mentioned.push((Some(variant_id), pattern.loc.clone()));
+                }
+            }
+            MatchPattern::Range {
+                ..
+            } => (),
+        }
+    }
+
+    /// Whether the pattern matches any value.
+    fn is_irrefutable(pattern: &Token<MatchPattern>) -> bool {
+        match &pattern.token {
+            MatchPattern::Catchall => true,
+            MatchPattern::Binding {
+                pattern,
+                ..
+            } => MatchCoverage::is_irrefutable(pattern),
+            MatchPattern::Alternatives(alternatives) => alternatives.iter().any(MatchCoverage::is_irrefutable),
+            _ => false,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct ResolvedEnumMember {
     pub enum_impl: ResolvedEnumNode,
@@ -28,6 +125,9 @@ pub trait ExprCompiler {
     fn compile_string_literal_expr(&mut self, str: &str) -> Result<TypedValue>;
     fn compile_sizeof_expr(&mut self, ty: &ComplexType) -> Result<TypedValue>;
     fn resolve_static_field_reference(&mut self, sfr: &Qualifier) -> Result<TypedValue>;
+    fn resolve_global_field(&mut self, sfr: &Qualifier) -> Result<Option<FieldNode>>;
+    fn get_global_field_ptr(&mut self, field: &FieldNode) -> TypedValue;
+    fn load_global_field(&mut self, field: &FieldNode) -> Result<TypedValue>;
     fn resolve_enum_variant(&mut self, declaring_type: &GenericIdentifier, member: &str) -> Result<ResolvedEnumMember>;
     fn compile_new_enum_member(
         &mut self,
@@ -36,7 +136,21 @@ pub trait ExprCompiler {
         data: Option<Vec<NewCallField>>,
     ) -> Result<TypedValue>;
     fn compile_match_expr(&mut self, mtch: &MatchExpr) -> Result<TypedValue>;
+    fn infer_match_branch_type(&mut self, branch: &MatchExprBranch, value: &TypedValue) -> Result<Option<(ComplexType, TokenLocation)>>;
+    fn compile_match_pattern(
+        &mut self,
+        pattern: &Token<MatchPattern>,
+        value: &TypedValue,
+        fail_block: &BuilderBlock,
+        bindings: &mut Vec<LocalVar>,
+    ) -> Result<TypedValue>;
+    fn bind_match_local(&mut self, bindings: &mut Vec<LocalVar>, name: &Token<Identifier>, value: TypedValue) -> Result<()>;
+    fn continue_if(&mut self, condition: OpaqueValue, fail_block: &BuilderBlock);
+    fn check_match_branches(&mut self, value_ty: &ComplexType, mtch: &MatchExpr) -> Result<()>;
     fn load_local_var(&mut self, var: &LocalVar) -> Result<TypedValue>;
+    fn compile_member_chain(&mut self, current: TypedValue, path: Option<String>, members: &[Member]) -> Result<TypedValue>;
+    fn compile_null_safe_member_chain(&mut self, current: TypedValue, path: Option<String>, members: &[Member]) -> Result<TypedValue>;
+    fn load_narrowed(&mut self, path: Option<&str>, value: TypedValue) -> Result<TypedValue>;
 }
 
 impl<'a> ExprCompiler for FunctionCompiler<'a> {
@@ -48,9 +162,16 @@ impl<'a> ExprCompiler for FunctionCompiler<'a> {
             Expr::SignedIntLit(val) => self.compile_integer_literal_expr(*val, type_hint)?,
             Expr::StringLit(str) => self.compile_string_literal_expr(str)?,
             Expr::Ident(ident) => {
+                if self.resolve_ident(ident).is_err()
+                    && let Some(field) = self.resolve_global_field(&Qualifier(vec![ident.clone()]))?
+                {
+                    let value = self.load_global_field(&field)?;
+                    return self.load_narrowed(Some(&ident.token.0), value);
+                }
                 let field_ref = self.resolve_static_field_reference(&Qualifier(vec![ident.clone()]))?;
                 let container = TypedValueContainer(field_ref);
-                TypedValue::new(container.get_type(), container.load(self)?)
+                let value = TypedValue::new(container.get_type(), container.load(self)?);
+                self.load_narrowed(Some(&ident.token.0), value)?
             }
             Expr::Reference(expr) => {
                 let compiled_expr = self.compile_expr(expr, None)?;
@@ -67,9 +188,11 @@ impl<'a> ExprCompiler for FunctionCompiler<'a> {
             Expr::Member(member_expr) => {
                 let mut members = member_expr.members.clone();
 
-                let (mut current, skip) = if let Some(prefix) = &member_expr.prefix {
+                let (current, skip, path) = if let Some(prefix) = &member_expr.prefix {
                     let (current, skip) = if prefix.0.len() == 1 && let Ok(local_ident) = self.resolve_ident(&prefix.0[0]) {
                         (self.load_local_var(&local_ident)?, 0)
+                    } else if prefix.0.len() == 1 && prefix.0[0].token.0 == "super" && let Expr::FuncCall(call) = &members[0].value.token {
+                        (self.compile_super_func_call(call)?, 1)
                     } else {
                         let result = 'result: {
                             let imported_names =
@@ -95,6 +218,12 @@ impl<'a> ExprCompiler for FunctionCompiler<'a> {
                                     break 'result None;
                                 }
                                 Expr::Ident(ident) => {
+                                    let mut qualified_name = prefix.0.clone();
+                                    qualified_name.push(ident.clone());
+                                    if let Some(field) = self.resolve_global_field(&Qualifier(qualified_name))? {
+                                        break 'result Some(self.load_global_field(&field)?);
+                                    }
+
                                     for imported_name in imported_names {
                                         let mut parts = Vec::new();
                                         for part in imported_name.split("::") {
@@ -118,135 +247,29 @@ impl<'a> ExprCompiler for FunctionCompiler<'a> {
                         };
                         match result {
                             Some(result) => (result, 1),
-                            None => {
-                                let identifier = self.resolve_static_field_reference(prefix)?;
-                                (identifier, 0)
-                            }
+                            None => match self.resolve_global_field(prefix)? {
+                                Some(field) => (self.load_global_field(&field)?, 0),
+                                None => (self.resolve_static_field_reference(prefix)?, 0),
+                            },
                         }
                     };
-                    if skip == 0 {
+                    let (current, path) = if skip == 0 {
                         members[0].ty = MemberType::Class;
-                    }
-                    (current, skip)
+                        let path = prefix.to_string();
+                        (self.load_narrowed(Some(&path), current)?, Some(path))
+                    } else {
+                        (current, None)
+                    };
+                    (current, skip, path)
                 } else {
-                    (self.compile_expr(&members[0].value, None)?, 1)
+                    let path = match &members[0].value.token {
+                        Expr::Ident(ident) => Some(ident.token.0.clone()),
+                        _ => None,
+                    };
+                    (self.compile_expr(&members[0].value, None)?, 1, path)
                 };
 
-                for next_member in members.iter().skip(skip) {
-                    self.loc(&next_member.value.loc);
-
-                    current = self.unbox_object(current)?;
-
-                    match (next_member.ty, current.ty.clone()) {
-                        (MemberType::Class, ComplexType::Array(element_type)) => match &next_member.value.token {
-                            Expr::Ident(field_name) => {
-                                if field_name.token.0 == "length" {
-                                    let length_ptr = self.emit(Insn::GetElementPtr(
-                                        current.val,
-                                        self.cpl.context.get_abi_slice_type(element_type.as_llvm_type(self.cpl), &element_type.to_string()),
-                                        1,
-                                    ));
-
-                                    current = TypedValue {
-                                        ty: BasicType::USize.to_complex(),
-                                        val: self.emit(Insn::Load(length_ptr, BasicType::USize.as_llvm_type(self.cpl))),
-                                    };
-                                } else {
-                                    return Err(compiler_error!(
-                                        self,
-                                        "No such field `{}` in type `{}`",
-                                        field_name.token.0,
-                                        current.ty.to_string()
-                                    ));
-                                }
-                            }
-                            x => unimplemented!("{:#?}", x),
-                        },
-                        (MemberType::Array, ComplexType::Array(_)) => {
-                            let index = self.compile_expr(&next_member.value, Some(&BasicType::USize.to_complex()))?;
-                            self.assert_assignable_to(&index.ty, &BasicType::USize.to_complex())?;
-                            current = self.load_array_element(&current, &index)?;
-                        }
-                        (MemberType::Class, ComplexType::Basic(_)) => {
-                            let instance = self.autobox_primitive(current.clone())?;
-                            let ident = match &instance.ty {
-                                ComplexType::Basic(BasicType::Object(ident)) => ident,
-                                ty => return Err(compiler_error!(self, "The `.` operator is forbidden on type `{}`", ty.to_string())),
-                            };
-
-                            let class_impl = match self.cpl.type_provider.get_class_by_name(ident) {
-                                Some(class_impl) => match class_impl.class_type {
-                                    ClassType::Interface => {
-                                        match &next_member.value.token {
-                                            Expr::FuncCall(fc) => {
-                                                current = self.compile_instance_func_call(fc, &instance)?;
-                                            }
-                                            Expr::Ident(field) => {
-                                                return Err(compiler_error!(
-                                                    self,
-                                                    "Could not resolve accessor `{}::{}`",
-                                                    ident.to_string(),
-                                                    field.token.0
-                                                ))
-                                            }
-                                            x => unreachable!("{:?}", x),
-                                        }
-
-                                        continue;
-                                    }
-                                    _ => class_impl,
-                                },
-                                None => match self.cpl.type_provider.get_enum_by_name(ident) {
-                                    Some(_) => match &next_member.value.token {
-                                        Expr::FuncCall(fc) => {
-                                            current = self.compile_instance_func_call(fc, &instance)?;
-                                            continue;
-                                        }
-                                        x => unreachable!("{:?}", x),
-                                    },
-                                    None => return Err(compiler_error!(self, "[ER4] Could not resolve type `{}`", ident.to_string())),
-                                },
-                            };
-                            match &next_member.value.token {
-                                Expr::Ident(field_name) => {
-                                    let member = self.resolve_class_member_ptr(&instance, &class_impl, field_name)?;
-                                    let val = member.load(self)?;
-                                    current = TypedValue {
-                                        val,
-                                        ty: member.get_type(),
-                                    };
-                                }
-                                Expr::Unary(unary) => match &unary.value.token {
-                                    Expr::Ident(field_name) => {
-                                        let member = self.resolve_class_member_ptr(&instance, &class_impl, field_name)?;
-                                        let expr = member.load(self)?;
-                                        current = self.compile_unary_expr(
-                                            unary.op,
-                                            &TypedValue {
-                                                val: expr,
-                                                ty: member.get_type(),
-                                            },
-                                        )?;
-                                    }
-                                    x => unreachable!("{:?}", x),
-                                },
-                                Expr::FuncCall(fc) => {
-                                    current = self.compile_instance_func_call(fc, &instance)?;
-                                }
-                                x => unreachable!("{:?}", x),
-                            }
-                        }
-                        (MemberType::Class, _) => {
-                            return Err(compiler_error!(self, "[ER8] The `.` operator is forbidden on type `{}`", current.ty.to_string()))
-                        }
-                        (MemberType::Array, _) => {
-                            return Err(compiler_error!(self, "[ER9] The `[]` operator is forbidden on type `{}`", current.ty.to_string()))
-                        }
-                        (x, y) => unreachable!("x = {:?}, y = {:?}", x, y),
-                    }
-                }
-
-                current
+                self.compile_member_chain(current, path, &members[skip..])?
             }
             Expr::Range(range) => {
                 let obj =
@@ -256,9 +279,9 @@ impl<'a> ExprCompiler for FunctionCompiler<'a> {
                 let start = self.compile_expr(&range.start, Some(&BasicType::Int32.to_complex()))?;
                 let end = self.compile_expr(&range.end, Some(&BasicType::Int32.to_complex()))?;
 
-                let start_ptr = self.resolve_class_member_ptr(&obj, &class_impl, &Identifier::from_string("start"))?;
-                let end_ptr = self.resolve_class_member_ptr(&obj, &class_impl, &Identifier::from_string("end"))?;
-                let current_ptr = self.resolve_class_member_ptr(&obj, &class_impl, &Identifier::from_string("current"))?;
+                let start_ptr = self.resolve_class_member_ptr_unchecked(&obj, &class_impl, &Identifier::from_string("start"))?;
+                let end_ptr = self.resolve_class_member_ptr_unchecked(&obj, &class_impl, &Identifier::from_string("end"))?;
+                let current_ptr = self.resolve_class_member_ptr_unchecked(&obj, &class_impl, &Identifier::from_string("current"))?;
 
                 start_ptr.store(Operator::Equals, self, start.clone())?;
                 end_ptr.store(Operator::Equals, self, end)?;
@@ -342,49 +365,17 @@ impl<'a> ExprCompiler for FunctionCompiler<'a> {
 
     fn compile_match_expr(&mut self, mtch: &MatchExpr) -> Result<TypedValue> {
         let value = self.compile_expr(&mtch.value, None)?;
+        self.check_match_branches(&value.ty, mtch)?;
 
         let mut match_result_types = Vec::new();
         for branch in &mtch.branches {
             llvm::set_eval_only(true);
+            let result_type = self.infer_match_branch_type(branch, &value);
+            llvm::set_eval_only(false);
 
-            let mut temp_block = self.state.new_block(&mut self.builder);
-            match &branch.arg {
-                MatchExprBranchArg::EnumWithData {
-                    member,
-                    ..
-                } => {
-                    let resolved = self.resolve_enum_variant(&GenericIdentifier::from_complex_type(&value.ty), &member.token.0)?;
-                    match resolved.data_type {
-                        Some(_) => {
-                            for field in resolved.data_type.unwrap() {
-                                temp_block.locals.push(LocalVar {
-                                    name: field.name,
-                                    value: TypedValue::new(field.ty, self.cpl.context.const_null_ptr(self.cpl.context.get_void_type())),
-                                });
-                            }
-                        }
-                        None => return Err(compiler_error_loc!(&member.loc, "Enum variant `{}` takes no associated data", member.token.0)),
-                    }
-                }
-                _ => (),
-            }
-
-            self.state.block_stack.push(temp_block);
-            match &branch.statement.token {
-                Statement::Expr(expr) => {
-                    let result = self.compile_expr(expr, None)?;
-                    match_result_types.push((result.ty, expr.loc.clone()));
-                }
-                _ => {
-                    let returns = self.compile_block_statement(&[branch.statement.clone()], BlockType::Generic);
-                    if !returns {
-                        match_result_types.push((BasicType::Void.to_complex(), branch.statement.loc.clone()));
-                    }
-                }
+            if let Some(result_type) = result_type? {
+                match_result_types.push(result_type);
             }
-            self.state.block_stack.pop();
-
-            llvm::set_eval_only(false);
         }
 
         for (ty, loc) in &match_result_types {
@@ -411,62 +402,30 @@ impl<'a> ExprCompiler for FunctionCompiler<'a> {
                 self.state.new_rotated_parent(&mut self.builder)
             };
 
-            match &branch.arg {
-                MatchExprBranchArg::Catchall(loc) => {
-                    if i != mtch.branches.len() - 1 {
-                        return Err(compiler_error_loc!(loc, "Catchall branch must be the last branch in the match"));
-                    }
-
-                    self.emit(Insn::Br(statement_block.llvm_block.as_val()));
-                }
-                MatchExprBranchArg::EnumWithData {
-                    member,
-                    ..
-                }
-                | MatchExprBranchArg::Enum(member) => {
-                    let resolved = self.resolve_enum_variant(&GenericIdentifier::from_complex_type(&value.ty), &member.token.0)?;
-                    let test_variant_id_ptr = self.emit(Insn::GetElementPtr(value.val, value.ty.as_llvm_type(self.cpl), 0));
-                    let test_variant_id = self.emit(Insn::Load(test_variant_id_ptr, self.cpl.context.get_i32_type()));
-                    let real_variant_id_const = self.cpl.context.const_int(self.cpl.context.get_i32_type(), resolved.variant_id as _);
-                    let are_equal = self.emit(Insn::ICmp(LLVMIntPredicate::LLVMIntEQ, test_variant_id, real_variant_id_const));
-
-                    self.emit(Insn::CondBr(are_equal, statement_block.llvm_block.as_val(), rotated_parent.llvm_block.as_val()));
+            let mut bindings = Vec::new();
+            self.compile_match_pattern(&branch.pattern, &value, &rotated_parent.llvm_block, &mut bindings)?;
+
+            match &branch.guard {
+                Some(guard) => {
+                    // the guard can refer to the values bound by the pattern
+                    let mut guard_block = self.state.new_block(&mut self.builder);
+                    guard_block.locals = bindings.clone();
+                    self.state.block_stack.push(guard_block);
+                    let condition = self.compile_expr(guard, Some(&BasicType::Bool.to_complex()));
+                    self.state.block_stack.pop();
+
+                    let condition = condition?;
+                    self.assert_assignable_to(&condition.ty, &BasicType::Bool.to_complex())?;
+                    self.emit(Insn::CondBr(condition.val, statement_block.llvm_block.as_val(), rotated_parent.llvm_block.as_val()));
                 }
-                MatchExprBranchArg::Expr(expr) => {
-                    let to_match = self.compile_expr(expr, Some(&value.ty))?;
-                    self.assert_assignable_to(&to_match.ty, &value.ty)?;
-
-                    let are_equal = self.compile_logic_expr(value.clone(), Operator::Equals, to_match)?;
-                    self.assert_assignable_to(&are_equal.ty, &BasicType::Bool.to_complex())?;
-
-                    self.emit(Insn::CondBr(are_equal.val, statement_block.llvm_block.as_val(), rotated_parent.llvm_block.as_val()));
+                None => {
+                    self.emit(Insn::Br(statement_block.llvm_block.as_val()));
                 }
             }
 
             self.builder.append_block(&statement_block.llvm_block);
             self.state.push_block(&mut self.builder, statement_block);
-
-            match &branch.arg {
-                MatchExprBranchArg::EnumWithData {
-                    member,
-                    ..
-                } => {
-                    let resolved = self.resolve_enum_variant(&GenericIdentifier::from_complex_type(&value.ty), &member.token.0)?;
-
-                    let specific_variant_type =
-                        self.cpl.context.get_abi_enum_type_specific_element(self.cpl, &resolved.enum_impl, resolved.variant_id);
-                    let enum_ptr = self.emit(Insn::BitCast(value.val, self.cpl.context.get_pointer_type(specific_variant_type)));
-                    for (i, field) in resolved.data_type.unwrap().into_iter().enumerate() {
-                        let field_ptr = self.emit(Insn::GetElementPtr(enum_ptr, specific_variant_type, (i + 1) as u32));
-                        // let field_val = TypedValueContainer(TypedValue::new(field.ty.clone(), field_ptr)).load(self)?;
-                        self.state.get_current_block_mut().locals.push(LocalVar {
-                            name: field.name,
-                            value: TypedValue::new(field.ty, field_ptr),
-                        });
-                    }
-                }
-                _ => (),
-            }
+            self.state.get_current_block_mut().locals.extend(bindings);
 
             match &branch.statement.token {
                 Statement::Expr(expr) => {
@@ -489,9 +448,6 @@ impl<'a> ExprCompiler for FunctionCompiler<'a> {
             self.builder.use_block(&rotated_parent.llvm_block);
         }
 
-        // self.builder.use_block(&final_rotated_parent.llvm_block);
-        // final_rotated_parent.llvm_block.append();
-
         let loaded_result = match prealloc_result.ty {
             ComplexType::Basic(BasicType::Void) => prealloc_result.val,
             _ => TypedValueContainer(prealloc_result.clone()).load(self)?,
@@ -499,6 +455,300 @@ impl<'a> ExprCompiler for FunctionCompiler<'a> {
         Ok(TypedValue::new(prealloc_result.ty, loaded_result))
     }
 
+    /// Compiles a branch of a match expression without emitting any code, to find the type of the value it results in.
+    /// Returns `None` if the branch always returns from the function.
+    fn infer_match_branch_type(&mut self, branch: &MatchExprBranch, value: &TypedValue) -> Result<Option<(ComplexType, TokenLocation)>> {
+        let fail_block = self.builder.create_block();
+        let mut temp_block = self.state.new_block(&mut self.builder);
+        self.compile_match_pattern(&branch.pattern, value, &fail_block, &mut temp_block.locals)?;
+
+        self.state.block_stack.push(temp_block);
+        let result = (|| {
+            if let Some(guard) = &branch.guard {
+                self.compile_expr(guard, Some(&BasicType::Bool.to_complex()))?;
+            }
+            Ok(match &branch.statement.token {
+                Statement::Expr(expr) => Some((self.compile_expr(expr, None)?.ty, expr.loc.clone())),
+                _ => match self.compile_block_statement(&[branch.statement.clone()], BlockType::Generic) {
+                    true => None,
+                    false => Some((BasicType::Void.to_complex(), branch.statement.loc.clone())),
+                },
+            })
+        })();
+        self.state.block_stack.pop();
+
+        result
+    }
+
+    /// Emits code that tests whether `value` matches `pattern`, jumping to `fail_block` if it doesn't.
+    /// Afterwards, the builder is positioned in a block that is only reached if the value matched,
+    /// and the locals bound by the pattern have been appended to `bindings`.
+    ///
+    /// The matched value is returned, unwrapped if the pattern only matches non-null values.
+    fn compile_match_pattern(
+        &mut self,
+        pattern: &Token<MatchPattern>,
+        value: &TypedValue,
+        fail_block: &BuilderBlock,
+        bindings: &mut Vec<LocalVar>,
+    ) -> Result<TypedValue> {
+        self.loc(&pattern.loc);
+        match &pattern.token {
+            MatchPattern::Catchall => Ok(value.clone()),
+            MatchPattern::Binding {
+                name,
+                pattern,
+            } => {
+                let matched = self.compile_match_pattern(pattern, value, fail_block, bindings)?;
+                let binding = self.emit(Insn::Alloca(matched.ty.as_llvm_type(self.cpl)));
+                let binding = TypedValue::new(matched.ty.clone(), binding);
+                self.copy(&matched, &binding)?;
+                self.bind_match_local(bindings, name, binding)?;
+                Ok(matched)
+            }
+            MatchPattern::Alternatives(alternatives) => {
+                let matched_block = self.builder.create_block();
+                for (i, alternative) in alternatives.iter().enumerate() {
+                    let next_block = if i == alternatives.len() - 1 {
+                        None
+                    } else {
+                        Some(self.builder.create_block())
+                    };
+
+                    let mut alternative_bindings = Vec::new();
+                    self.compile_match_pattern(alternative, value, next_block.as_ref().unwrap_or(fail_block), &mut alternative_bindings)?;
+                    if let Some(binding) = alternative_bindings.first() {
+                        return Err(compiler_error_loc!(&alternative.loc, "Cannot bind `{}` inside of alternative patterns", binding.name));
+                    }
+                    self.emit(Insn::Br(matched_block.as_val()));
+
+                    if let Some(next_block) = next_block {
+                        self.builder.append_block(&next_block);
+                        self.builder.use_block(&next_block);
+                    }
+                }
+
+                self.builder.append_block(&matched_block);
+                self.builder.use_block(&matched_block);
+                Ok(value.clone())
+            }
+            MatchPattern::Expr(Token {
+                token: Expr::Null,
+                ..
+            }) => {
+                if !matches!(value.ty, ComplexType::Nullable(_)) {
+                    return Err(compiler_error!(self, "Cannot match `null` against non-nullable type `{}`", value.ty.to_string()));
+                }
+                let nullability = self.load_nullability(value);
+                let const_zero = self.cpl.context.const_int(self.cpl.context.get_i8_type(), 0);
+                let is_null = self.emit(Insn::ICmp(LLVMIntPredicate::LLVMIntEQ, nullability, const_zero));
+                self.continue_if(is_null, fail_block);
+                Ok(value.clone())
+            }
+            _ if matches!(value.ty, ComplexType::Nullable(_)) => {
+                // any other pattern only matches values that are present
+                let nullability = self.load_nullability(value);
+                let const_zero = self.cpl.context.const_int(self.cpl.context.get_i8_type(), 0);
+                let is_present = self.emit(Insn::ICmp(LLVMIntPredicate::LLVMIntNE, nullability, const_zero));
+                self.continue_if(is_present, fail_block);
+
+                let inner = self.load_nullable_inner(value)?;
+                self.compile_match_pattern(pattern, &inner, fail_block, bindings)
+            }
+            MatchPattern::Enum {
+                member,
+                fields,
+            } => {
+                let enum_ident = match &value.ty {
+                    ComplexType::Basic(BasicType::Object(ident)) if ident.name == "core::object::Box" => {
+                        // a box is matched by its contents
+                        let element = self.unbox_object(value.clone())?;
+                        return self.compile_match_pattern(pattern, &element, fail_block, bindings);
+                    }
+                    ComplexType::Basic(BasicType::Object(ident)) if self.cpl.type_provider.get_enum_by_name(ident).is_some() => {
+                        ident.clone()
+                    }
+                    _ => {
+                        if fields.is_some() {
+                            return Err(compiler_error!(self, "Cannot destructure non-enum type `{}`", value.ty.to_string()));
+                        }
+                        // a bare identifier, such as a constant, is compared by equality
+                        let expr = Token {
+                            token: MatchPattern::Expr(Token {
+                                token: Expr::Ident(member.clone()),
+                                loc: member.loc.clone(),
+                            }),
+                            loc: pattern.loc.clone(),
+                        };
+                        return self.compile_match_pattern(&expr, value, fail_block, bindings);
+                    }
+                };
+
+                let resolved = self.resolve_enum_variant(&enum_ident, &member.token.0)?;
+                let test_variant_id_ptr = self.emit(Insn::GetElementPtr(value.val, value.ty.as_llvm_type(self.cpl), 0));
+                let test_variant_id = self.emit(Insn::Load(test_variant_id_ptr, self.cpl.context.get_i32_type()));
+                let discriminant = resolved.enum_impl.elements[resolved.variant_id].discriminant;
+                let real_variant_id_const = self.cpl.context.const_int(self.cpl.context.get_i32_type(), discriminant as _);
+                let are_equal = self.emit(Insn::ICmp(LLVMIntPredicate::LLVMIntEQ, test_variant_id, real_variant_id_const));
+                self.continue_if(are_equal, fail_block);
+
+                let (fields, data_type) = match (fields, resolved.data_type) {
+                    (None, _) => return Ok(value.clone()),
+                    (Some(_), None) => {
+                        return Err(compiler_error_loc!(&member.loc, "Enum variant `{}` takes no associated data", member.token.0))
+                    }
+                    (Some(fields), Some(data_type)) => (fields, data_type),
+                };
+
+                let specific_variant_type =
+                    self.cpl.context.get_abi_enum_type_specific_element(self.cpl, &resolved.enum_impl, resolved.variant_id);
+                let enum_ptr = self.emit(Insn::BitCast(value.val, self.cpl.context.get_pointer_type(specific_variant_type)));
+                for field in fields {
+                    let data_field = data_type.iter().enumerate().find(|(_, data_field)| data_field.name == field.name.token.0);
+                    let (field_offset, data_field) = match data_field {
+                        Some(data_field) => data_field,
+                        None => {
+                            return Err(compiler_error_loc!(
+                                &field.name.loc,
+                                "Enum variant `{}` associated data has no field `{}`",
+                                member.token.0,
+                                field.name.token.0
+                            ))
+                        }
+                    };
+                    // offset 1 to skip the variant ID
+                    let field_ptr = self.emit(Insn::GetElementPtr(enum_ptr, specific_variant_type, (field_offset + 1) as u32));
+                    let field_value = TypedValue::new(data_field.ty.clone(), field_ptr);
+                    match &field.pattern {
+                        Some(field_pattern) => {
+                            let loaded = TypedValue::new(data_field.ty.clone(), TypedValueContainer(field_value).load(self)?);
+                            self.compile_match_pattern(field_pattern, &loaded, fail_block, bindings)?;
+                        }
+                        None => self.bind_match_local(bindings, &field.name, field_value)?,
+                    }
+                }
+                Ok(value.clone())
+            }
+            MatchPattern::Range {
+                start,
+                end,
+            } => {
+                if !matches!(&value.ty, ComplexType::Basic(basic) if basic.is_integer()) {
+                    return Err(compiler_error!(self, "Range patterns can only match integers, not `{}`", value.ty.to_string()));
+                }
+
+                let start = self.compile_expr(start, Some(&value.ty))?;
+                self.assert_assignable_to(&start.ty, &value.ty)?;
+                let end = self.compile_expr(end, Some(&value.ty))?;
+                self.assert_assignable_to(&end.ty, &value.ty)?;
+
+                let is_above_start = self.compile_logic_expr(value.clone(), Operator::GreaterThanOrEquals, start)?;
+                self.continue_if(is_above_start.val, fail_block);
+                let is_below_end = self.compile_logic_expr(value.clone(), Operator::LessThan, end)?;
+                self.continue_if(is_below_end.val, fail_block);
+                Ok(value.clone())
+            }
+            MatchPattern::Expr(expr) => {
+                let to_match = self.compile_expr(expr, Some(&value.ty))?;
+                self.assert_assignable_to(&to_match.ty, &value.ty)?;
+
+                let are_equal = self.compile_logic_expr(value.clone(), Operator::Equals, to_match)?;
+                self.assert_assignable_to(&are_equal.ty, &BasicType::Bool.to_complex())?;
+                self.continue_if(are_equal.val, fail_block);
+                Ok(value.clone())
+            }
+        }
+    }
+
+    /// Adds a local bound by a match pattern, where `value` is a pointer to the bound value.
+    fn bind_match_local(&mut self, bindings: &mut Vec<LocalVar>, name: &Token<Identifier>, value: TypedValue) -> Result<()> {
+        if bindings.iter().any(|binding| binding.name == name.token.0) {
+            return Err(compiler_error_loc!(&name.loc, "Identifier `{}` is bound more than once in the same pattern", name.token.0));
+        }
+        bindings.push(LocalVar {
+            name: name.token.0.clone(),
+            value,
+            is_const: false,
+        });
+        Ok(())
+    }
+
+    /// Continues in a new block if `condition` is true, otherwise jumps to `fail_block`.
+    fn continue_if(&mut self, condition: OpaqueValue, fail_block: &BuilderBlock) {
+        let next_block = self.builder.create_block();
+        self.emit(Insn::CondBr(condition, next_block.as_val(), fail_block.as_val()));
+        self.builder.append_block(&next_block);
+        self.builder.use_block(&next_block);
+    }
+
+    /// Checks that a match over an enum handles every variant, and warns about branches that can never be taken.
+    fn check_match_branches(&mut self, value_ty: &ComplexType, mtch: &MatchExpr) -> Result<()> {
+        let is_nullable = matches!(value_ty, ComplexType::Nullable(_));
+        let enum_impl = match value_ty {
+            ComplexType::Basic(BasicType::Object(ident)) | ComplexType::Nullable(box ComplexType::Basic(BasicType::Object(ident))) => {
+                self.cpl.type_provider.get_enum_by_name(ident)
+            }
+            _ => None,
+        };
+
+        let mut coverage = MatchCoverage::new(enum_impl.as_ref());
+        for branch in &mtch.branches {
+            if coverage.is_exhaustive(is_nullable) {
+                self.warn(compiler_error_loc!(
+                    &branch.pattern.loc,
+                    "Unreachable match branch: all possible values are handled by earlier branches"
+                ));
+                continue;
+            }
+
+            let mut branch_coverage = MatchCoverage::new(enum_impl.as_ref());
+            let mut mentioned = Vec::new();
+            branch_coverage.add_pattern(&branch.pattern, &mut mentioned);
+            for (variant, loc) in mentioned {
+                let is_duplicate = match variant {
+                    Some(variant_id) => coverage.variants[variant_id],
+                    None => coverage.null,
+                };
+                if is_duplicate {
+                    let name = variant.map_or("null", |variant_id| coverage.variant_names[variant_id].as_str());
+                    return Err(compiler_error_loc!(&loc, "Duplicate match branch for `{}`", name));
+                }
+            }
+            let is_repeated_constant = |constant: &StaticExpr| coverage.constants.contains(constant);
+            if !branch_coverage.constants.is_empty() && branch_coverage.constants.iter().all(is_repeated_constant) {
+                self.warn(compiler_error_loc!(&branch.pattern.loc, "Unreachable match branch: the value is handled by an earlier branch"));
+            }
+
+            // a guarded branch might not be taken even if its pattern matches
+            if branch.guard.is_none() {
+                coverage.merge(branch_coverage);
+            }
+        }
+
+        if let Some(enum_impl) = &enum_impl
+            && !coverage.is_exhaustive(is_nullable)
+        {
+            let mut missing: Vec<String> = enum_impl
+                .elements
+                .iter()
+                .zip(&coverage.variants)
+                .filter(|(_, covered)| !**covered)
+                .map(|(element, _)| format!("`{}`", element.name))
+                .collect();
+            if is_nullable && !coverage.null {
+                missing.push("`null`".to_owned());
+            }
+            return Err(compiler_error_loc!(
+                &mtch.value.loc,
+                "Non-exhaustive match over `{}`: {} not handled",
+                value_ty.to_string(),
+                missing.join(", ")
+            ));
+        }
+
+        Ok(())
+    }
+
     /// Returns a pointer to the local identifier, static field, or enum resolved by this function.
     fn resolve_static_field_reference(&mut self, sfr: &Qualifier) -> Result<TypedValue> {
         self.loc(&sfr.get_location());
@@ -510,43 +760,84 @@ impl<'a> ExprCompiler for FunctionCompiler<'a> {
             }
         }
 
-        let field_name = sfr.to_string();
-        let (field_name, field_type) = match self.cpl.type_provider.get_static_field_by_name(&field_name) {
-            Some(field) => (field_name, field),
-            None => {
-                let namespaced_name = format!("{}::{}", self.get_source_function().namespace_name, field_name);
-                match self.cpl.type_provider.get_static_field_by_name(&namespaced_name) {
-                    Some(field) => (namespaced_name, field),
-                    None => {
-                        if sfr.0.len() > 1 {
-                            let declaring_type = Qualifier(sfr.0[0..sfr.0.len() - 1].to_vec());
-                            let enum_name = GenericIdentifier::from_name(&declaring_type.to_string());
-                            let member = sfr.0[sfr.0.len() - 1].token.0.clone();
-                            match self.cpl.type_provider.get_enum_by_name(&enum_name) {
-                                Some(_) => return self.compile_new_enum_member(&enum_name, &member, None),
-                                None => {
-                                    let namespaced_name =
-                                        format!("{}::{}", self.get_source_function().namespace_name, declaring_type.to_string());
-                                    let enum_name = GenericIdentifier::from_name(&namespaced_name);
-                                    match self.cpl.type_provider.get_enum_by_name(&enum_name) {
-                                        Some(_) => return self.compile_new_enum_member(&enum_name, &member, None),
-                                        None => (),
-                                    }
-                                }
-                            };
-                        }
+        if let Some(field) = self.resolve_global_field(sfr)? {
+            return Ok(self.get_global_field_ptr(&field));
+        }
 
-                        return Err(compiler_error!(self, "No such enum member, static field, or local identifier `{}`", field_name));
+        if sfr.0.len() > 1 {
+            let declaring_type = Qualifier(sfr.0[0..sfr.0.len() - 1].to_vec());
+            let enum_name = GenericIdentifier::from_name(&declaring_type.to_string());
+            let member = sfr.0[sfr.0.len() - 1].token.0.clone();
+            match self.cpl.type_provider.get_enum_by_name(&enum_name) {
+                Some(_) => {
+                    self.assert_type_accessible(&BasicType::Object(enum_name.clone()).to_complex())?;
+                    return self.compile_new_enum_member(&enum_name, &member, None);
+                }
+                None => {
+                    let namespaced_name = format!("{}::{}", self.get_source_function().namespace_name, declaring_type.to_string());
+                    let enum_name = GenericIdentifier::from_name(&namespaced_name);
+                    match self.cpl.type_provider.get_enum_by_name(&enum_name) {
+                        Some(_) => return self.compile_new_enum_member(&enum_name, &member, None),
+                        None => (),
                     }
                 }
-            }
+            };
+        }
+
+        Err(compiler_error!(self, "No such enum member, static field, or local identifier `{}`", sfr.to_string()))
+    }
+
+    /// Finds the namespace-level field denoted by `sfr`, or `None` if there is no such field.
+    /// The namespace of the field is resolved using the imports of the current file, falling back to the namespace of the current function.
+    fn resolve_global_field(&mut self, sfr: &Qualifier) -> Result<Option<FieldNode>> {
+        let field_name = &sfr.0[sfr.0.len() - 1].token.0;
+        let mut candidates = if sfr.0.len() > 1 {
+            let namespace = Qualifier(sfr.0[0..sfr.0.len() - 1].to_vec()).to_string();
+            utils::lookup_import_map(&self.import_map, &namespace).into_iter().map(|ns| format!("{}::{}", ns, field_name)).collect()
+        } else {
+            vec![field_name.clone()]
         };
+        candidates.push(format!("{}::{}", self.get_source_function().namespace_name, sfr.to_string()));
 
+        for candidate in candidates {
+            if let Some(field) = self.cpl.type_provider.get_static_field_by_name(&candidate) {
+                let field = field.clone();
+                self.assert_accessible(&field.visibility, "Field", &field.name)?;
+                return Ok(Some(field));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Returns a pointer to the global variable backing the given namespace-level field.
+    fn get_global_field_ptr(&mut self, field: &FieldNode) -> TypedValue {
         let global = self.unit.mdl.get_or_extern_global(&GlobalVariable {
-            name: field_name,
-            ty: field_type.as_llvm_type(self.cpl),
+            name: field.name.clone(),
+            ty: field.ty.as_llvm_type(self.cpl),
         });
-        Ok(TypedValue::new(field_type, global))
+        TypedValue::new(field.ty.clone(), global)
+    }
+
+    /// Loads the value of a namespace-level field.
+    /// Constants that were evaluated at compile time are emitted inline instead of being read from their global.
+    fn load_global_field(&mut self, field: &FieldNode) -> Result<TypedValue> {
+        match (&field.constant_value, &field.ty) {
+            (Some(StaticExpr::Integer(val)), ComplexType::Basic(basic)) if basic.is_integer() => {
+                return Ok(TypedValue::new(field.ty.clone(), self.cpl.context.const_int(basic.as_llvm_type(self.cpl), *val as u64)));
+            }
+            (Some(StaticExpr::Bool(val)), ComplexType::Basic(BasicType::Bool)) => {
+                let bool_val = self.cpl.context.const_int(BasicType::Bool.as_llvm_type(self.cpl), u64::from(*val));
+                return Ok(TypedValue::new(field.ty.clone(), bool_val));
+            }
+            (Some(StaticExpr::String(val)), ComplexType::Basic(BasicType::Object(obj))) if obj.name == "core::string::String" => {
+                return self.compile_string_literal_expr(val);
+            }
+            _ => (),
+        }
+
+        let container = TypedValueContainer(self.get_global_field_ptr(field));
+        Ok(TypedValue::new(container.get_type(), container.load(self)?))
     }
 
     fn resolve_enum_variant(&mut self, declaring_type: &GenericIdentifier, member: &str) -> Result<ResolvedEnumMember> {
@@ -588,7 +879,8 @@ impl<'a> ExprCompiler for FunctionCompiler<'a> {
         let any_variant_type = self.cpl.context.get_abi_enum_type_any_element(self.cpl, &resolved.enum_impl);
         let enum_ptr = self.emit(Insn::Alloca(any_variant_type));
 
-        let variant_id_const = self.cpl.context.const_int(self.cpl.context.get_i32_type(), resolved.variant_id as _);
+        let discriminant = resolved.enum_impl.elements[resolved.variant_id].discriminant;
+        let variant_id_const = self.cpl.context.const_int(self.cpl.context.get_i32_type(), discriminant as _);
         let variant_id_ptr = self.emit(Insn::GetElementPtr(enum_ptr, any_variant_type, 0));
 
         self.emit(Insn::Store(variant_id_const, variant_id_ptr));
@@ -740,6 +1032,8 @@ impl<'a> ExprCompiler for FunctionCompiler<'a> {
                     let addr_ptr = self.emit(Insn::GetElementPtr(val.val, val.ty.as_llvm_type(self.cpl), 1)); // pointer to the `address` field in the Pointer<T> struct
                     let addr_int = self.emit(Insn::Load(addr_ptr, BasicType::USize.as_llvm_type(self.cpl))); // this Load loads the address stored in the Pointer<T> struct
                     let addr_ptr = self.emit(Insn::IntToPtr(addr_int, ty.clone().to_reference().as_llvm_type(self.cpl)));
+                    let size = self.compile_sizeof_expr(&ty)?;
+                    self.check_memory_access(addr_ptr, size.val)?;
                     let val = self.emit(Insn::Load(addr_ptr, ty.as_llvm_type(self.cpl))); // this Load derefences the pointer
 
                     TypedValue {
@@ -756,6 +1050,7 @@ impl<'a> ExprCompiler for FunctionCompiler<'a> {
 
     fn compile_integer_literal_expr(&mut self, val: i64, type_hint: Option<&ComplexType>) -> Result<TypedValue> {
         let ty = match type_hint {
+            Some(ComplexType::Nullable(inner)) => return self.compile_integer_literal_expr(val, Some(inner)),
             Some(ComplexType::Basic(basic)) => match basic {
                 BasicType::Bool
                 | BasicType::Void
@@ -844,4 +1139,198 @@ impl<'a> ExprCompiler for FunctionCompiler<'a> {
             val,
         })
     }
+
+    /// Compiles the accesses in `members` one after another, starting with `current`.
+    /// `path` is the narrowing path of `current`, see [`utils::get_narrowing_path`].
+    fn compile_member_chain(&mut self, mut current: TypedValue, mut path: Option<String>, members: &[Member]) -> Result<TypedValue> {
+        for (i, next_member) in members.iter().enumerate() {
+            self.loc(&next_member.value.loc);
+
+            current = self.unbox_object(current)?;
+
+            let member_ty = match (next_member.ty, &current.ty) {
+                (MemberType::NullSafeClass, ComplexType::Nullable(_)) => {
+                    return self.compile_null_safe_member_chain(current, path, &members[i..]);
+                }
+                (MemberType::NullSafeClass, ty) => {
+                    let warning = compiler_error!(
+                        self,
+                        "The `?.` operator is used on non-nullable type `{}`; help: use the `.` operator instead",
+                        ty.to_string()
+                    );
+                    self.warn(warning);
+                    MemberType::Class
+                }
+                (ty, _) => ty,
+            };
+            path = match (path, member_ty, &next_member.value.token) {
+                (Some(path), MemberType::Class, Expr::Ident(field_name)) => Some(format!("{}.{}", path, field_name.token.0)),
+                _ => None,
+            };
+
+            match (member_ty, current.ty.clone()) {
+                (MemberType::Class, ComplexType::Array(element_type)) => match &next_member.value.token {
+                    Expr::Ident(field_name) => {
+                        if field_name.token.0 == "length" {
+                            let length_ptr = self.emit(Insn::GetElementPtr(
+                                current.val,
+                                self.cpl.context.get_abi_slice_type(element_type.as_llvm_type(self.cpl), &element_type.to_string()),
+                                1,
+                            ));
+
+                            current = TypedValue {
+                                ty: BasicType::USize.to_complex(),
+                                val: self.emit(Insn::Load(length_ptr, BasicType::USize.as_llvm_type(self.cpl))),
+                            };
+                        } else {
+                            return Err(compiler_error!(
+                                self,
+                                "No such field `{}` in type `{}`",
+                                field_name.token.0,
+                                current.ty.to_string()
+                            ));
+                        }
+                    }
+                    x => unimplemented!("{:#?}", x),
+                },
+                (MemberType::Array, ComplexType::Array(_)) => {
+                    let index = self.compile_expr(&next_member.value, Some(&BasicType::USize.to_complex()))?;
+                    self.assert_assignable_to(&index.ty, &BasicType::USize.to_complex())?;
+                    current = self.load_array_element(&current, &index)?;
+                }
+                (MemberType::Class, ComplexType::Basic(_)) => {
+                    let instance = self.autobox_primitive(current.clone())?;
+                    let ident = match &instance.ty {
+                        ComplexType::Basic(BasicType::Object(ident)) => ident,
+                        ty => return Err(compiler_error!(self, "The `.` operator is forbidden on type `{}`", ty.to_string())),
+                    };
+
+                    let class_impl = match self.cpl.type_provider.get_class_by_name(ident) {
+                        Some(class_impl) => match &next_member.value.token {
+                            Expr::FuncCall(fc) if class_impl.class_type == ClassType::Interface => {
+                                current = self.compile_instance_func_call(fc, &instance)?;
+                                continue;
+                            }
+                            _ => class_impl,
+                        },
+                        None => match self.cpl.type_provider.get_enum_by_name(ident) {
+                            Some(_) => match &next_member.value.token {
+                                Expr::FuncCall(fc) => {
+                                    current = self.compile_instance_func_call(fc, &instance)?;
+                                    continue;
+                                }
+                                x => unreachable!("{:?}", x),
+                            },
+                            None => return Err(compiler_error!(self, "[ER4] Could not resolve type `{}`", ident.to_string())),
+                        },
+                    };
+                    match &next_member.value.token {
+                        Expr::Ident(field_name) => {
+                            let member = self.resolve_class_member_ptr(&instance, &class_impl, field_name)?;
+                            let val = member.load(self)?;
+                            current = TypedValue {
+                                val,
+                                ty: member.get_type(),
+                            };
+                        }
+                        Expr::Unary(unary) => match &unary.value.token {
+                            Expr::Ident(field_name) => {
+                                let member = self.resolve_class_member_ptr(&instance, &class_impl, field_name)?;
+                                let expr = member.load(self)?;
+                                current = self.compile_unary_expr(
+                                    unary.op,
+                                    &TypedValue {
+                                        val: expr,
+                                        ty: member.get_type(),
+                                    },
+                                )?;
+                            }
+                            x => unreachable!("{:?}", x),
+                        },
+                        Expr::FuncCall(fc) => {
+                            current = self.compile_instance_func_call(fc, &instance)?;
+                        }
+                        x => unreachable!("{:?}", x),
+                    }
+                }
+                (MemberType::Class, _) => {
+                    return Err(compiler_error!(self, "[ER8] The `.` operator is forbidden on type `{}`", current.ty.to_string()))
+                }
+                (MemberType::Array, _) => {
+                    return Err(compiler_error!(self, "[ER9] The `[]` operator is forbidden on type `{}`", current.ty.to_string()))
+                }
+                (x, y) => unreachable!("x = {:?}, y = {:?}", x, y),
+            }
+
+            current = self.load_narrowed(path.as_deref(), current)?;
+        }
+
+        Ok(current)
+    }
+
+    /// Compiles a member chain whose first access uses `?.` on the nullable value `current`.
+    /// The rest of the chain is only evaluated if `current` is present; otherwise the whole chain evaluates to `null`.
+    fn compile_null_safe_member_chain(&mut self, current: TypedValue, path: Option<String>, members: &[Member]) -> Result<TypedValue> {
+        let mut members = members.to_vec();
+        members[0].ty = MemberType::Class;
+
+        llvm::set_eval_only(true);
+        let inferred = self.load_nullable_inner(&current).and_then(|inner| self.compile_member_chain(inner, path.clone(), &members));
+        llvm::set_eval_only(false);
+
+        // the chain is null if any part of it is null, so an already nullable result is not wrapped again
+        let result_type = match inferred?.ty {
+            ty @ ComplexType::Nullable(_) => ty,
+            ty if ty == BasicType::Void.to_complex() => ty,
+            ty => ComplexType::Nullable(Box::new(ty)),
+        };
+        let result = if result_type == BasicType::Void.to_complex() {
+            TypedValue::new(result_type, self.cpl.context.const_null_ptr(self.cpl.context.get_void_type()))
+        } else {
+            let allocated = self.emit(Insn::Alloca(result_type.as_llvm_type(self.cpl)));
+            TypedValue::new(result_type, allocated)
+        };
+        let has_result = result.ty != BasicType::Void.to_complex();
+
+        let present_block = self.builder.create_block();
+        let null_block = self.builder.create_block();
+        let end_block = self.builder.create_block();
+
+        let nullability = self.load_nullability(&current);
+        let const_zero = self.cpl.context.const_int(self.cpl.context.get_i8_type(), 0);
+        let is_present = self.emit(Insn::ICmp(LLVMIntPredicate::LLVMIntNE, nullability, const_zero));
+        self.emit(Insn::CondBr(is_present, present_block.as_val(), null_block.as_val()));
+
+        self.builder.append_block(&present_block);
+        self.builder.use_block(&present_block);
+        let inner = self.load_nullable_inner(&current)?;
+        let value = self.compile_member_chain(inner, path, &members)?;
+        if has_result {
+            self.copy(&value, &result)?;
+        }
+        self.emit(Insn::Br(end_block.as_val()));
+
+        self.builder.append_block(&null_block);
+        self.builder.use_block(&null_block);
+        if has_result {
+            let null = TypedValue::new(BasicType::Null.to_complex(), self.cpl.context.const_null_ptr(self.cpl.context.get_void_type()));
+            self.copy(&null, &result)?;
+        }
+        self.emit(Insn::Br(end_block.as_val()));
+
+        self.builder.append_block(&end_block);
+        self.builder.use_block(&end_block);
+
+        Ok(result)
+    }
+
+    /// Unwraps a nullable value loaded from `path` if an enclosing `if` or `while` condition has established that it isn't null.
+    fn load_narrowed(&mut self, path: Option<&str>, value: TypedValue) -> Result<TypedValue> {
+        match (path, &value.ty) {
+            (Some(path), ComplexType::Nullable(_)) if self.state.is_narrowed(path) => {
+                self.compile_unary_expr(Operator::NonNullAssertion, &value)
+            }
+            _ => Ok(value),
+        }
+    }
 }
@@ -9,6 +9,10 @@ use crate::{
 pub trait LogicCompiler {
     fn compile_logic_expr(&mut self, lhs: TypedValue, op: Operator, rhs: TypedValue) -> Result<TypedValue>;
     fn compile_cast(&mut self, from: TypedValue, to: ComplexType) -> Result<TypedValue>;
+    /// Compares two values where at least one is nullable.
+    /// Two null values are equal and a null value is ordered before any present value;
+    /// if both values are present, their inner values are compared instead.
+    fn compile_nullable_logic_expr(&mut self, lhs: TypedValue, op: Operator, rhs: TypedValue) -> Result<TypedValue>;
 }
 
 impl<'a> LogicCompiler for FunctionCompiler<'a> {
@@ -85,6 +89,8 @@ impl<'a> LogicCompiler for FunctionCompiler<'a> {
                     return Ok(TypedValue::new(BasicType::Bool.to_complex(), inverted_bool));
                 }
             }
+            (ComplexType::Nullable(_), ComplexType::Basic(BasicType::Null)) | (ComplexType::Basic(BasicType::Null), ComplexType::Nullable(_)) => (),
+            (ComplexType::Nullable(_), _) | (_, ComplexType::Nullable(_)) => return self.compile_nullable_logic_expr(lhs, op, rhs),
             (ComplexType::Basic(BasicType::Object(lhs_ident)), ComplexType::Basic(BasicType::Object(rhs_ident))) => {
                 let (operator_interface_name, func_name) = match op {
                     Operator::Add => ("core::ops::Add", "add"),
@@ -149,19 +155,16 @@ impl<'a> LogicCompiler for FunctionCompiler<'a> {
 
         let (val, ty) = match &op {
             Operator::Equals | Operator::NotEquals => {
-                let (lhs_val, rhs_val) = match ((lhs.val, &lhs.ty), (rhs.val, &rhs.ty)) {
-                    ((_, ComplexType::Nullable(_)), (_, ComplexType::Nullable(_))) => {
-                        todo!("compare nullables")
+                let (lhs_val, rhs_val) = match (&lhs.ty, &rhs.ty) {
+                    (ComplexType::Nullable(_), ComplexType::Basic(BasicType::Null)) => {
+                        let nullability = self.load_nullability(&lhs);
+                        (nullability, self.cpl.context.const_int(self.cpl.context.get_i8_type(), 0))
                     }
-                    ((nullable, ComplexType::Nullable(inner)), (_, ComplexType::Basic(BasicType::Null)))
-                    | ((_, ComplexType::Basic(BasicType::Null)), (nullable, ComplexType::Nullable(inner))) => {
-                        let nullability_ptr =
-                            self.emit(Insn::GetElementPtr(nullable, ComplexType::Nullable(inner.clone()).as_llvm_type(self.cpl), 1));
-                        let nullability = self.emit(Insn::Load(nullability_ptr, self.cpl.context.get_i8_type()));
-                        let const_zero = self.cpl.context.const_int(self.cpl.context.get_i8_type(), 0);
-                        (nullability, const_zero)
+                    (ComplexType::Basic(BasicType::Null), ComplexType::Nullable(_)) => {
+                        let nullability = self.load_nullability(&rhs);
+                        (nullability, self.cpl.context.const_int(self.cpl.context.get_i8_type(), 0))
                     }
-                    ((lhs_val, _), (rhs_val, _)) => (lhs_val, rhs_val),
+                    _ => (lhs.val, rhs.val),
                 };
 
                 let predicate = match &op {
@@ -191,4 +194,72 @@ impl<'a> LogicCompiler for FunctionCompiler<'a> {
             val,
         })
     }
+
+    fn compile_nullable_logic_expr(&mut self, lhs: TypedValue, op: Operator, rhs: TypedValue) -> Result<TypedValue> {
+        let predicate = match op {
+            Operator::Equals => IntPredicate::LLVMIntEQ,
+            Operator::NotEquals => IntPredicate::LLVMIntNE,
+            Operator::LessThan => IntPredicate::LLVMIntULT,
+            Operator::GreaterThan => IntPredicate::LLVMIntUGT,
+            Operator::LessThanOrEquals => IntPredicate::LLVMIntULE,
+            Operator::GreaterThanOrEquals => IntPredicate::LLVMIntUGE,
+            _ => {
+                return Err(compiler_error!(
+                    self,
+                    "Cannot apply operator `{:?}` to values of type `{}` and `{}`",
+                    op,
+                    lhs.ty.to_string(),
+                    rhs.ty.to_string()
+                ))
+            }
+        };
+
+        // a non-nullable operand is always present
+        let const_present = self.cpl.context.const_int(self.cpl.context.get_i8_type(), 1);
+        let lhs_present = match &lhs.ty {
+            ComplexType::Nullable(_) => self.load_nullability(&lhs),
+            _ => const_present,
+        };
+        let rhs_present = match &rhs.ty {
+            ComplexType::Nullable(_) => self.load_nullability(&rhs),
+            _ => const_present,
+        };
+
+        // unless both operands are present, the result is the comparison of their nullability flags
+        let result_ptr = self.emit(Insn::Alloca(BasicType::Bool.as_llvm_type(self.cpl)));
+        let nullability_result = self.emit(Insn::ICmp(predicate, lhs_present, rhs_present));
+        self.emit(Insn::Store(nullability_result, result_ptr));
+
+        let compare_block = self.builder.create_block();
+        let rotated_parent_block = self.builder.create_block();
+
+        let present_count = self.emit(Insn::IAdd(lhs_present, rhs_present));
+        let const_two = self.cpl.context.const_int(self.cpl.context.get_i8_type(), 2);
+        let both_present = self.emit(Insn::ICmp(IntPredicate::LLVMIntEQ, present_count, const_two));
+        self.emit(Insn::CondBr(both_present, compare_block.as_val(), rotated_parent_block.as_val()));
+
+        {
+            self.builder.append_block(&compare_block);
+            self.builder.use_block(&compare_block);
+
+            let lhs_inner = match &lhs.ty {
+                ComplexType::Nullable(_) => self.load_nullable_inner(&lhs)?,
+                _ => lhs,
+            };
+            let rhs_inner = match &rhs.ty {
+                ComplexType::Nullable(_) => self.load_nullable_inner(&rhs)?,
+                _ => rhs,
+            };
+            let inner_result = self.compile_logic_expr(lhs_inner, op, rhs_inner)?;
+            self.emit(Insn::Store(inner_result.val, result_ptr));
+
+            self.emit(Insn::Br(rotated_parent_block.as_val()));
+        }
+
+        self.builder.append_block(&rotated_parent_block);
+        self.builder.use_block(&rotated_parent_block);
+
+        let result = self.emit(Insn::Load(result_ptr, BasicType::Bool.as_llvm_type(self.cpl)));
+        Ok(TypedValue::new(BasicType::Bool.to_complex(), result))
+    }
 }
@@ -5,7 +5,7 @@ use crate::{
     tree::ast::{Expr, Token},
 };
 
-use super::ExprCompiler;
+use super::{BlockCompiler, ExprCompiler};
 
 pub trait ReturnCompiler {
     fn compile_return(&mut self, return_val: &Option<Token<Expr>>) -> Result<()>;
@@ -14,7 +14,7 @@ pub trait ReturnCompiler {
 impl<'a> ReturnCompiler for FunctionCompiler<'a> {
     fn compile_return(&mut self, return_val: &Option<Token<Expr>>) -> Result<()> {
         let return_type = self.resolve_type(&self.func.return_type)?;
-        match return_val {
+        let returned = match return_val {
             Some(expr) => {
                 let compiled_expr = self.compile_expr(expr, Some(&return_type))?;
                 self.assert_assignable_to(&compiled_expr.ty, &return_type)?;
@@ -23,20 +23,36 @@ impl<'a> ReturnCompiler for FunctionCompiler<'a> {
                 if return_type.is_struct(&self.cpl.type_provider) {
                     let preallocated_return = self.llvm_func.get_param(self.func.params.len() as u32);
                     self.copy(&casted, &TypedValue::new(return_type, preallocated_return))?;
-
-                    self.pop_stack_frame()?;
-                    self.emit(Insn::RetVoid);
+                    None
                 } else {
-                    self.pop_stack_frame()?;
-                    self.emit(Insn::Ret(casted.val));
+                    // the caller gets a reference of its own, so returning a local doesn't free it when it's unscoped;
+                    // a new object already starts out with that reference
+                    if !matches!(expr.token, Expr::New(_)) {
+                        self.try_scope(&casted)?;
+                    }
+                    Some(casted)
                 }
             }
             None => {
                 self.assert_assignable_to(&BasicType::Void.to_complex(), &return_type)?;
-                self.pop_stack_frame()?;
-                self.emit(Insn::RetVoid);
+                None
             }
         };
+
+        // a `return` within a finally block replaces the `return` of the try statement
+        for pending in self.state.pending_unscopes.clone() {
+            self.try_unscope(&pending)?;
+        }
+        // the finally blocks run after the returned value is evaluated, and before the locals they can access are unscoped
+        if self.compile_enclosing_finally_blocks(&returned)? {
+            return Ok(());
+        }
+        self.unscope_all_locals()?;
+        self.pop_stack_frame()?;
+        match returned {
+            Some(returned) => self.emit(Insn::Ret(returned.val)),
+            None => self.emit(Insn::RetVoid),
+        };
         Ok(())
     }
 }
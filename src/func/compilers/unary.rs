@@ -1,7 +1,7 @@
 use crate::{
     common::{
         types::{BasicType, ComplexType, IntoOpaqueType},
-        Result, TypedValue, TypedValueContainer, ValueContainer,
+        Result, TypedValue,
     },
     compiler::llvm::Insn,
     compiler_error,
@@ -35,7 +35,7 @@ impl<'a> UnaryCompiler for FunctionCompiler<'a> {
                 }
             }
             Operator::NonNullAssertion => match &expr.ty {
-                ComplexType::Nullable(inner) => {
+                ComplexType::Nullable(_) => {
                     let assert_impl = ResolvedFunctionNode::externed(
                         "keid.assert_non_null",
                         &[BasicType::Int8.to_complex()],
@@ -44,17 +44,10 @@ impl<'a> UnaryCompiler for FunctionCompiler<'a> {
                     );
                     let assert_func = self.get_function_ref(&assert_impl)?;
 
-                    let nullability_ptr = self.emit(Insn::GetElementPtr(expr.val, expr.ty.as_llvm_type(self.cpl), 1));
-                    let nullablility = self.emit(Insn::Load(nullability_ptr, self.cpl.context.get_i8_type()));
+                    let nullablility = self.load_nullability(&expr);
                     self.emit(Insn::Call(assert_func, assert_impl.as_llvm_type(self.cpl), vec![nullablility]));
 
-                    let value_ptr = self.emit(Insn::GetElementPtr(expr.val, expr.ty.as_llvm_type(self.cpl), 0));
-                    let value = TypedValueContainer(TypedValue::new(*inner.clone(), value_ptr)).load(self)?;
-
-                    TypedValue {
-                        ty: *inner.clone(),
-                        val: value,
-                    }
+                    self.load_nullable_inner(&expr)?
                 }
                 _ => {
                     return Err(compiler_error!(
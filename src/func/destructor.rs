@@ -28,13 +28,29 @@ impl<'a> DestructorCompiler for FunctionCompiler<'a> {
         };
 
         let this = self.state.block_stack[0].locals[0].value.clone();
-        let this_type = match this.ty.get_root_type() {
+        let class_impl = match this.ty.get_root_type() {
             BasicType::Object(ident) => match self.cpl.type_provider.get_class_by_name(&ident) {
-                Some(class_impl) => self.cpl.context.get_abi_class_data_type(self.cpl, &class_impl),
+                Some(class_impl) => class_impl,
                 None => panic!("unresolved type: {}", ident.to_string()),
             },
             _ => unreachable!(),
         };
+        let this_type = self.cpl.context.get_abi_class_data_type(self.cpl, &class_impl);
+
+        // the fields declared by the class come after the fields inherited from its superclasses
+        let field_offset = 3 + (class_impl.fields.len() - fields.len()) as u32; // offset of 3 for (classinfo ptr) + (ref count) + (weak ref ptr)
+
+        // weak references must not be upgraded once the object is being destroyed
+        if class_impl.class_type == ClassType::Class {
+            let clear_impl = ResolvedFunctionNode::externed(
+                "keid_weak_clear",
+                &[BasicType::Void.to_complex().to_reference()],
+                Varargs::None,
+                BasicType::Void.to_complex(),
+            );
+            let clear_func = self.get_function_ref(&clear_impl)?;
+            self.emit(Insn::Call(clear_func, clear_impl.as_llvm_type(self.cpl), vec![this.val]));
+        }
 
         if let Some(body) = destructor {
             self.compile_block(&body);
@@ -43,7 +59,7 @@ impl<'a> DestructorCompiler for FunctionCompiler<'a> {
         for i in 0..fields.len() {
             let field_type = &fields[i];
             if matches!(field_type, ComplexType::Basic(BasicType::Object { .. })) {
-                let field_ptr = self.emit(Insn::GetElementPtr(this.val, this_type, 2 + i as u32)); // offset of 2 for (ref count) + (classinfo ptr)
+                let field_ptr = self.emit(Insn::GetElementPtr(this.val, this_type, field_offset + i as u32));
                 let field = TypedValueContainer(TypedValue::new(field_type.clone(), field_ptr)).load(self)?;
 
                 self.try_unscope(&TypedValue::new(field_type.clone(), field))?;
@@ -53,11 +69,30 @@ impl<'a> DestructorCompiler for FunctionCompiler<'a> {
                     ..
                 }))
             ) {
-                let field_ptr = self.emit(Insn::GetElementPtr(this.val, this_type, 2 + i as u32)); // offset of 2 for (ref count) + (classinfo ptr)
+                let field_ptr = self.emit(Insn::GetElementPtr(this.val, this_type, field_offset + i as u32));
                 self.try_unscope(&TypedValue::new(field_type.clone(), field_ptr))?;
             }
         }
 
+        // the inherited fields are released by the destructor of the superclass
+        if let Some(superclass) = &class_impl.superclass
+            && superclass.name != "core::object::Object"
+        {
+            let superclass_type = BasicType::Object(superclass.clone()).to_complex();
+            let superclass_destructor = match self.cpl.type_provider.get_function_by_name(
+                &GenericIdentifier::from_name_with_args(&format!("{}::keid.destructor", superclass.name), &superclass.generic_args),
+                &[superclass_type.clone()],
+            ) {
+                Some(mut superclass_destructor) => {
+                    superclass_destructor.params[0] = superclass_type;
+                    superclass_destructor
+                }
+                None => panic!("missing destructor for superclass: {}", superclass.to_string()),
+            };
+            let superclass_destructor_ref = self.get_function_ref(&superclass_destructor)?;
+            self.emit(Insn::Call(superclass_destructor_ref, superclass_destructor.as_llvm_type(self.cpl), vec![this.val]));
+        }
+
         self.emit(Insn::RetVoid);
         self.state.block_stack.clear();
 
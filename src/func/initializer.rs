@@ -23,6 +23,20 @@ impl<'a> GlobalInitializerCompiler for FunctionCompiler<'a> {
                 let field_type = self.resolve_type(&field.ty)?;
                 let llvm_type = field_type.as_llvm_type(self.cpl);
 
+                // scalar constants are baked into a read-only global instead of being stored at runtime
+                let folded = match (&field.constant_value, &field_type) {
+                    (Some(StaticExpr::Integer(_)), ComplexType::Basic(basic)) if basic.is_integer() => Some(self.load_global_field(&field)?),
+                    (Some(StaticExpr::Bool(_)), ComplexType::Basic(BasicType::Bool)) => Some(self.load_global_field(&field)?),
+                    _ => None,
+                };
+                if let Some(folded) = folded {
+                    let mdl = &mut self.cpl.units[module_id].mdl;
+                    let global = mdl.create_global(&mut self.cpl.context, &field.name, llvm_type);
+                    mdl.initialize_global(global, folded.val);
+                    mdl.set_global_constant(global);
+                    continue;
+                }
+
                 {
                     let mdl = &mut self.cpl.units[module_id].mdl;
                     let global = mdl.create_global(&mut self.cpl.context, &field.name, llvm_type);
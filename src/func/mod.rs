@@ -9,6 +9,7 @@ use crate::{
 
 mod state;
 use anyhow::anyhow;
+use std::path::Path;
 pub use state::*;
 
 pub mod compilers;
@@ -28,6 +29,8 @@ pub mod utils;
 pub struct LocalVar {
     name: String,
     value: TypedValue,
+    /// Whether the variable was declared with `const`, and so cannot be reassigned.
+    is_const: bool,
 }
 
 pub struct FunctionCompiler<'a> {
@@ -61,8 +64,15 @@ impl<'a> FunctionCompiler<'a> {
         }
     }
 
-    pub fn consume(self) -> (Vec<CompilerError>, CompilationUnit) {
-        (self.state.errors, self.unit)
+    pub fn consume(self) -> (Vec<CompilerError>, Vec<CompilerError>, CompilationUnit) {
+        (self.state.errors, self.state.warnings, self.unit)
+    }
+
+    /// Reports a warning, unless the current code is only being compiled to infer its type (in which case it will be compiled again).
+    pub(crate) fn warn(&mut self, warning: CompilerError) {
+        if !llvm::get_eval_only() {
+            self.state.warnings.push(warning);
+        }
     }
 
     pub fn compile(&mut self) {
@@ -81,7 +91,8 @@ impl<'a> FunctionCompiler<'a> {
     }
 
     pub fn emit(&self, insn: Insn) -> OpaqueValue {
-        self.builder.emit(insn, 0, 0)
+        let (line, col) = self.unit.source_map.get_line_col(self.state.current_token.start).unwrap_or((0, 0));
+        self.builder.emit(insn, line as u32, col as u32)
     }
 
     fn initialize_local_vars(&mut self) -> Result<()> {
@@ -105,6 +116,7 @@ impl<'a> FunctionCompiler<'a> {
                 local_vars.push(LocalVar {
                     name: params[i].name.clone(),
                     value,
+                    is_const: false,
                 });
             }
             local_vars
@@ -137,7 +149,19 @@ impl<'a> FunctionCompiler<'a> {
     }
 
     fn push_stack_frame(&mut self) -> Result<()> {
-        if !Self::get_hidden_function_names().contains(&self.func.callable_name.as_str()) {
+        if self.cpl.profile_functions {
+            // the runtime is called directly, so that every function is profiled and no time is spent converting the names
+            let enter_frame = ResolvedFunctionNode::externed(
+                "keid_core_runtime_enter_profiled_frame",
+                &[BasicType::Char.to_complex().to_reference(), BasicType::Char.to_complex().to_reference()],
+                Varargs::None,
+                BasicType::Void.to_complex(),
+            );
+            let enter_frame_ref = self.get_function_ref(&enter_frame)?;
+            let frame = self.emit(Insn::GlobalString(self.get_stack_frame_name()));
+            let function_name = self.emit(Insn::GlobalString(self.func.external_name.clone()));
+            self.emit(Insn::Call(enter_frame_ref, enter_frame.as_llvm_type(self.cpl), vec![frame, function_name]));
+        } else if self.cpl.track_stack_frames && !Self::get_hidden_function_names().contains(&self.func.callable_name.as_str()) {
             let push_stack_frame_impl = self
                 .cpl
                 .type_provider
@@ -147,15 +171,41 @@ impl<'a> FunctionCompiler<'a> {
                 )
                 .unwrap();
             let push_stack_frame_ref = self.get_function_ref(&push_stack_frame_impl)?;
-            let function_name = self.compile_string_literal_expr(&self.func.external_name)?;
-            self.call_function(push_stack_frame_ref, &push_stack_frame_impl, &[function_name])?;
+            let frame = self.get_stack_frame_name();
+            let frame = self.compile_string_literal_expr(&frame)?;
+            self.call_function(push_stack_frame_ref, &push_stack_frame_impl, &[frame])?;
         }
 
         Ok(())
     }
 
+    /// Returns the name of the function followed by the file and line of its declaration, e.g. `main (main.keid:3)`.
+    fn get_stack_frame_name(&self) -> String {
+        let loc = &self.get_source_function().loc;
+        let file_name = Path::new(&self.unit.path_name).file_name().and_then(|name| name.to_str());
+        match (self.unit.source_map.get_line_col(loc.start), file_name) {
+            // functions generated by the compiler have no location
+            (Some((line, _)), Some(file_name)) if loc.end > 0 => format!("{} ({}:{})", self.func.external_name, file_name, line),
+            _ => self.func.external_name.clone(),
+        }
+    }
+
+    /// Returns the file, line and column of the token that is being compiled, e.g. `main.keid:3:5`.
+    fn get_source_location(&self) -> String {
+        let file_name = Path::new(&self.unit.path_name).file_name().and_then(|name| name.to_str()).unwrap_or(&self.unit.path_name);
+        match self.unit.source_map.get_line_col(self.state.current_token.start) {
+            Some((line, col)) => format!("{}:{}:{}", file_name, line, col),
+            None => file_name.to_owned(),
+        }
+    }
+
     fn pop_stack_frame(&mut self) -> Result<()> {
-        if !Self::get_hidden_function_names().contains(&self.func.callable_name.as_str()) {
+        if self.cpl.profile_functions {
+            let exit_frame =
+                ResolvedFunctionNode::externed("keid_core_runtime_exit_profiled_frame", &[], Varargs::None, BasicType::Void.to_complex());
+            let exit_frame_ref = self.get_function_ref(&exit_frame)?;
+            self.emit(Insn::Call(exit_frame_ref, exit_frame.as_llvm_type(self.cpl), Vec::new()));
+        } else if self.cpl.track_stack_frames && !Self::get_hidden_function_names().contains(&self.func.callable_name.as_str()) {
             let pop_stack_frame_impl =
                 self.cpl.type_provider.get_function_by_name(&GenericIdentifier::from_name("core::runtime::popStackFrame"), &[]).unwrap();
             let pop_stack_frame_ref = self.get_function_ref(&pop_stack_frame_impl)?;
@@ -184,9 +234,11 @@ impl<'a> FunctionCompiler<'a> {
         if let Some(body) = &source.body {
             // TODO: can this be done without clone()?
             let body = body.clone();
+            let (function_name, function_loc) = (source.base_name.clone(), source.loc.clone());
 
             self.initialize_body();
             self.push_stack_frame()?;
+            self.count_coverage(&function_loc, CoveragePointKind::Function(function_name));
 
             if body.len() == 1 {
                 match body[0].token.clone() {
@@ -227,7 +279,11 @@ impl<'a> FunctionCompiler<'a> {
 
     fn compile_implicit_return(&mut self) -> Result<()> {
         if let Some(body) = self.get_source_function().body.as_ref() {
-            if !body.last().map(|tkn| matches!(&tkn.token, Statement::Return(_))).unwrap_or(false) {
+            let ends_with_throw = body.last().map(|tkn| matches!(&tkn.token, Statement::Throw(_) | Statement::Rethrow)).unwrap_or(false);
+            if ends_with_throw && self.func.return_type != BasicType::Void.to_complex() {
+                // the block after the throw is never entered, but it still needs a terminator
+                self.emit(Insn::Unreachable);
+            } else if !body.last().map(|tkn| matches!(&tkn.token, Statement::Return(_))).unwrap_or(false) {
                 // if the last token is not a return, an implicit one gets added
                 if self.func.return_type == BasicType::Void.to_complex() {
                     self.pop_block()?;
@@ -253,6 +309,31 @@ impl<'a> FunctionCompiler<'a> {
         Ok(())
     }
 
+    /// Unscopes the locals of every block, which a `return` exits.
+    fn unscope_all_locals(&mut self) -> Result<()> {
+        for i in (0..self.state.block_stack.len()).rev() {
+            self.unscope_unwound_locals(i)?;
+        }
+        Ok(())
+    }
+
+    /// Unscopes the locals of the block at `block_idx` that go out of scope when it's exited, see `is_unwound_local`.
+    fn unscope_unwound_locals(&mut self, block_idx: usize) -> Result<()> {
+        let locals = self.state.block_stack[block_idx].locals.clone();
+        for j in 0..locals.len() {
+            if self.is_unwound_local(block_idx, j) {
+                self.try_unscope(&locals[j].value)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns true if the local at `local_idx` of the block at `block_idx` is unscoped when an error unwinds through it.
+    /// The parameters of internal functions (such as destructors) are never scoped, see `initialize_local_vars`.
+    fn is_unwound_local(&self, block_idx: usize, local_idx: usize) -> bool {
+        block_idx > 0 || local_idx >= self.func.params.len() || !self.get_source_function().modifiers.contains(&FunctionModifier::Internal)
+    }
+
     /// Returns a LocalVar as a pointer to the given identifier.
     fn resolve_ident(&mut self, ident: &Token<Identifier>) -> Result<LocalVar> {
         self.loc(&ident.loc);
@@ -351,14 +432,72 @@ impl<'a> FunctionCompiler<'a> {
         self.resolve_type_with_context(ty, &self.import_map)
     }
 
+    /// Returns an error if a declaration with the given visibility cannot be accessed from the function being compiled.
+    /// Compiler-generated functions, such as destructors and the global initializer, can access any declaration.
+    fn assert_accessible(&self, visibility: &Visibility, kind: &str, name: &str) -> Result<()> {
+        let source = self.get_source_function();
+        match visibility {
+            Visibility::Private(namespace)
+                if !source.modifiers.contains(&FunctionModifier::Internal) && !visibility.is_accessible_from(&source.namespace_name) =>
+            {
+                Err(compiler_error!(self, "{} `{}` is private to namespace `{}`", kind, name, namespace))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn assert_callable(&self, callable: &ResolvedFunctionNode) -> Result<()> {
+        let source = self.cpl.type_provider.get_source_function(callable);
+        self.assert_accessible(&source.visibility, "Function", &source.base_name)
+    }
+
+    /// Returns an error if the given type, or any of its generic arguments, refers to a class or enum that is private to another namespace.
+    fn assert_type_accessible(&self, ty: &ComplexType) -> Result<()> {
+        if let BasicType::Object(ident) = ty.get_root_type() {
+            if let Some(class_impl) = self.cpl.type_provider.get_class_by_name(&ident) {
+                let class = self.cpl.type_provider.get_source_class(&class_impl);
+                self.assert_accessible(&class.visibility, "Type", &class.base_name)?;
+            } else if let Some(enum_impl) = self.cpl.type_provider.get_enum_by_name(&ident) {
+                let enm = self.cpl.type_provider.get_source_enum(&enum_impl);
+                self.assert_accessible(&enm.visibility, "Type", &enm.base_name)?;
+            }
+            for arg in &ident.generic_args {
+                self.assert_type_accessible(arg)?;
+            }
+        }
+        Ok(())
+    }
+
     fn resolve_class_member_ptr(
         &mut self,
         class_instance: &TypedValue,
         type_root: &ResolvedClassNode,
         field_name: &Token<Identifier>,
     ) -> Result<Box<dyn ValueContainer>> {
+        let (member, visibility) = self.resolve_class_member(class_instance, type_root, field_name)?;
+        self.assert_accessible(&visibility, "Member", &format!("{}::{}", type_root.full_name, field_name.token.0))?;
+        Ok(member)
+    }
+
+    /// Resolves a member like [`Self::resolve_class_member_ptr`], but without checking its visibility.
+    /// This is used for members that are accessed by compiler-generated code rather than by the user.
+    fn resolve_class_member_ptr_unchecked(
+        &mut self,
+        class_instance: &TypedValue,
+        type_root: &ResolvedClassNode,
+        field_name: &Token<Identifier>,
+    ) -> Result<Box<dyn ValueContainer>> {
+        Ok(self.resolve_class_member(class_instance, type_root, field_name)?.0)
+    }
+
+    fn resolve_class_member(
+        &mut self,
+        class_instance: &TypedValue,
+        type_root: &ResolvedClassNode,
+        field_name: &Token<Identifier>,
+    ) -> Result<(Box<dyn ValueContainer>, Visibility)> {
         let field_offset = match type_root.class_type {
-            ClassType::Class => 2,                         // ref count + info ptr
+            ClassType::Class => 3,                         // info ptr + ref count + weak ref ptr
             ClassType::Struct | ClassType::Interface => 1, // info ptr
             ClassType::Enum => panic!("enums have no members"),
         };
@@ -369,46 +508,118 @@ impl<'a> FunctionCompiler<'a> {
             if field.name == field_name.token.0 {
                 let element_ptr =
                     self.emit(Insn::GetElementPtr(class_instance.val, type_root.as_llvm_type(self.cpl), field_offset + i as u32));
-                return Ok(Box::new(TypedValueContainer(TypedValue::new(field.ty.clone(), element_ptr))));
+                return Ok((Box::new(TypedValueContainer(TypedValue::new(field.ty.clone(), element_ptr))), field.visibility.clone()));
             }
         }
-        let getter_impl = 'getter_block: {
-            let class = &self.cpl.type_provider.get_source_class(type_root);
-
-            for accessor in &class.accessors {
-                if accessor.name == field_name.token.0 {
-                    let getter = self.cpl.type_provider.get_function_node(type_root.module_id, accessor.function_id).unwrap();
-                    break 'getter_block getter
-                        .create_impl(&self.cpl.type_provider, &type_root.generic_impls)
-                        .map_err(|e| compiler_error!(self, "{}", e))?;
-                }
+        let (getter, setter) = self.resolve_accessors(type_root, &field_name.token.0)?;
+        let visibility = match getter.as_ref().or(setter.as_ref()) {
+            Some(accessor) => self.cpl.type_provider.get_source_function(accessor.get_function()).visibility.clone(),
+            None => {
+                return Err(compiler_error!(
+                    self,
+                    "No such field or accessor `{}` in type `{}`",
+                    field_name.token.0,
+                    type_root.full_name,
+                ))
             }
+        };
+        Ok((
+            Box::new(AccessorValueContainer::new(field_name.token.0.clone(), getter, setter, class_instance.clone())),
+            visibility,
+        ))
+    }
 
-            let source_type = self.cpl.type_provider.get_source_class(type_root);
-            let resolved_interface_impls = self
+    /// Finds the `get` and `set` accessors named `name` on instances of `type_root`.
+    /// Accessors declared by an interface are dispatched dynamically, since the implementing type is only known at runtime.
+    fn resolve_accessors(
+        &mut self,
+        type_root: &ResolvedClassNode,
+        name: &str,
+    ) -> Result<(Option<AccessorFunction>, Option<AccessorFunction>)> {
+        let mut getter = None;
+        let mut setter = None;
+
+        if type_root.class_type == ClassType::Interface {
+            let interface = self.cpl.type_provider.get_source_class(type_root);
+            let interface_id = self
                 .cpl
                 .type_provider
-                .get_resolved_interface_impls(&GenericIdentifier::from_name_with_args(&source_type.base_name, &type_root.generic_impls));
-            for resolved_interface_impl in resolved_interface_impls {
-                let interface_impl = self.cpl.type_provider.get_source_interface_impl(&resolved_interface_impl);
-                for accessor in &interface_impl.accessors {
-                    if accessor.name == field_name.token.0 {
-                        let getter = self.cpl.type_provider.get_function_node(interface_impl.module_id, accessor.function_id).unwrap();
-                        break 'getter_block getter
-                            .create_impl(&self.cpl.type_provider, &resolved_interface_impl.generic_impls)
-                            .map_err(|e| compiler_error!(self, "{}", e))?;
+                .get_resolved_interface_id(&GenericIdentifier::from_name_with_args(&interface.base_name, &type_root.generic_impls));
+            for (i, accessor) in interface.accessors.iter().enumerate() {
+                if accessor.name == name {
+                    let func = self
+                        .cpl
+                        .type_provider
+                        .get_function_node(interface.module_id, accessor.function_id)
+                        .unwrap()
+                        .create_impl(&self.cpl.type_provider, &type_root.generic_impls)
+                        .map_err(|e| compiler_error!(self, "{}", e))?;
+                    // accessors are placed after the methods in the vtable of an interface impl
+                    let func = AccessorFunction::Interface {
+                        func,
+                        interface_id,
+                        method_id: interface.functions.len() + i,
+                    };
+                    match accessor.accessor_type {
+                        AccessorNodeType::Getter => getter = Some(func),
+                        AccessorNodeType::Setter(_) => setter = Some(func),
                     }
                 }
             }
-            return Err(compiler_error!(
-                self,
-                "No such field or `get` accessor `{}` in type `{}`",
-                field_name.token.0,
-                type_root.full_name,
-            ));
+            return Ok((getter, setter));
+        }
+
+        let mut add_accessor = |accessor: &AccessorNode, func: ResolvedFunctionNode| {
+            let slot = match accessor.accessor_type {
+                AccessorNodeType::Getter => &mut getter,
+                AccessorNodeType::Setter(_) => &mut setter,
+            };
+            // an accessor declared closer to the type takes precedence
+            if slot.is_none() {
+                *slot = Some(AccessorFunction::Static(func));
+            }
         };
 
-        Ok(Box::new(AccessorValueContainer::new(getter_impl, class_instance.val)))
+        // accessors declared by a superclass are inherited by its subclasses
+        let mut current_class = Some(type_root.clone());
+        while let Some(class_impl) = current_class {
+            let class = self.cpl.type_provider.get_source_class(&class_impl);
+            for accessor in class.accessors.iter().filter(|accessor| accessor.name == name) {
+                let func = self
+                    .cpl
+                    .type_provider
+                    .get_function_node(class_impl.module_id, accessor.function_id)
+                    .unwrap()
+                    .create_impl(&self.cpl.type_provider, &class_impl.generic_impls)
+                    .map_err(|e| compiler_error!(self, "{}", e))?;
+                add_accessor(accessor, func);
+            }
+            current_class = match &class_impl.superclass {
+                Some(superclass) if class_impl.class_type == ClassType::Class => self.cpl.type_provider.get_class_by_name(superclass),
+                _ => None,
+            };
+        }
+
+        let source_type = self.cpl.type_provider.get_source_class(type_root);
+        let resolved_interface_impls = self
+            .cpl
+            .type_provider
+            .get_inherited_interface_impls(&GenericIdentifier::from_name_with_args(&source_type.base_name, &type_root.generic_impls));
+        for resolved_interface_impl in resolved_interface_impls {
+            let interface_impl = self.cpl.type_provider.get_source_interface_impl(&resolved_interface_impl);
+            for accessor in interface_impl.accessors.iter().filter(|accessor| accessor.name == name) {
+                let func = self
+                    .cpl
+                    .type_provider
+                    .get_function_node(interface_impl.module_id, accessor.function_id)
+                    .unwrap()
+                    .create_impl(&self.cpl.type_provider, &resolved_interface_impl.generic_impls)
+                    .map_err(|e| compiler_error!(self, "{}", e))?;
+                add_accessor(accessor, func);
+            }
+        }
+
+        Ok((getter, setter))
     }
 
     pub fn is_unsafe(&self) -> bool {
@@ -464,8 +675,35 @@ impl<'a> FunctionCompiler<'a> {
                 match self.find_function(&func, args, Some(instance.clone()))? {
                     Some(func) => func,
                     None => {
-                        let resolved_interface_impls = self.cpl.type_provider.get_resolved_interface_impls(ident);
+                        // methods declared by a superclass are called with `this` upcast to the superclass
+                        let mut superclass = match self.cpl.type_provider.get_class_by_name(ident) {
+                            Some(class_impl) if class_impl.class_type == ClassType::Class => class_impl.superclass,
+                            _ => None,
+                        };
+                        while let Some(superclass_ident) = superclass {
+                            let inherited_func = GenericIdentifier::from_name_with_args(
+                                &format!("{}::{}", superclass_ident.name, fc.name.token.0),
+                                &generic_args,
+                            );
+                            let superclass_instance =
+                                TypedValue::new(BasicType::Object(superclass_ident.clone()).to_complex(), instance.val);
+                            if let Some(func) = self.find_function(&inherited_func, args, Some(superclass_instance))? {
+                                return Ok(func);
+                            }
+                            superclass =
+                                self.cpl.type_provider.get_class_by_name(&superclass_ident).and_then(|class_impl| class_impl.superclass);
+                        }
+
+                        let resolved_interface_impls = self.cpl.type_provider.get_inherited_interface_impls(ident);
                         for resolved_interface_impl in resolved_interface_impls {
+                            let impl_instance = TypedValue::new(
+                                BasicType::Object(GenericIdentifier::from_name_with_args(
+                                    &resolved_interface_impl.target_name,
+                                    &resolved_interface_impl.target_generic_impls,
+                                ))
+                                .to_complex(),
+                                instance.val,
+                            );
                             let (module_id, function_ids) = {
                                 let source_impl = self.cpl.type_provider.get_source_interface_impl(&resolved_interface_impl);
                                 (source_impl.module_id, source_impl.functions.clone())
@@ -480,7 +718,7 @@ impl<'a> FunctionCompiler<'a> {
                                             &resolved_interface_impl.generic_impls,
                                         ),
                                         args,
-                                        Some(instance.clone()),
+                                        Some(impl_instance.clone()),
                                     )? {
                                         Some(func) => return Ok(func),
                                         None => (),
@@ -542,7 +780,7 @@ impl<'a> FunctionCompiler<'a> {
         } else {
             // intrinsic
             if func.module_id == usize::MAX {
-                let intrinsic_function = self.cpl.add_function(&self.unit.mdl, &func.external_name, func).as_val();
+                let intrinsic_function = self.cpl.add_function(&self.unit.mdl, &func.external_name, func, 0).as_val();
                 self.unit.externed_functions.push(ExternedFunction {
                     external_name: func.external_name.clone(),
                     value_ref: intrinsic_function,
@@ -556,7 +794,7 @@ impl<'a> FunctionCompiler<'a> {
 
                 if func.module_id != self.unit.module_id {
                     let source_external_name = func.external_name.clone();
-                    compiled_function = Some(self.cpl.add_function(&self.unit.mdl, &source_external_name, func).as_val());
+                    compiled_function = Some(self.cpl.add_function(&self.unit.mdl, &source_external_name, func, 0).as_val());
                 }
 
                 let compiled_function = compiled_function.ok_or(anyhow!("Compiled function not found: {}", func.callable_name)).unwrap();
@@ -573,7 +811,7 @@ impl<'a> FunctionCompiler<'a> {
         self.try_change_scope(object, ScopeChange::Inside)
     }
 
-    fn try_unscope(&mut self, object: &TypedValue) -> Result<bool> {
+    pub(crate) fn try_unscope(&mut self, object: &TypedValue) -> Result<bool> {
         self.try_change_scope(object, ScopeChange::Outside)
     }
 }
@@ -1,7 +1,7 @@
 use crate::{
-    common::CompilerError,
-    compiler::llvm::{BuilderBlock, InsnBuilder, OpaqueFunctionValue},
-    tree::ast::TokenLocation,
+    common::{CompilerError, TypedValue},
+    compiler::llvm::{BuilderBlock, InsnBuilder, OpaqueFunctionValue, OpaqueValue},
+    tree::ast::{Statement, Token, TokenLocation},
 };
 
 use super::LocalVar;
@@ -15,6 +15,18 @@ pub struct ExternedFunction {
 #[derive(Debug, Clone, PartialEq)]
 pub struct TryBlock {
     pub catch_block: BuilderBlock,
+    /// Pointer to the caught exception, which is stored by the landing pads before they enter the catch block.
+    pub exception: OpaqueValue,
+    /// The amount of runtime stack frames when the try block was entered.
+    pub stack_depth: OpaqueValue,
+    /// Compiled before every jump out of the try statement, whether by `return` or by a propagated error.
+    pub finally_block: Option<Vec<Token<Statement>>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CatchBlock {
+    /// The caught error, which is rethrown by a bare `throw`.
+    pub error: OpaqueValue,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -22,6 +34,7 @@ pub enum BlockType {
     Generic,
     Unsafe,
     Try(TryBlock),
+    Catch(CatchBlock),
 }
 
 #[derive(Debug, Clone)]
@@ -29,24 +42,33 @@ pub struct ScopeBlock {
     pub locals: Vec<LocalVar>,
     pub llvm_block: BuilderBlock,
     pub block_type: BlockType,
+    /// Paths of nullable locals and fields (e.g. `x` or `this.cause`) that are known to be non-null within this block.
+    pub narrowed: Vec<String>,
 }
 
 #[derive(Debug)]
 pub struct FunctionCompilerState {
     pub errors: Vec<CompilerError>,
+    /// Diagnostics that don't prevent the function from compiling, such as unreachable code.
+    pub warnings: Vec<CompilerError>,
     pub current_token: TokenLocation,
     pub block_stack: Vec<ScopeBlock>,
+    /// The returned value and the exited locals of a `return` whose finally blocks are being compiled,
+    /// which are released by a `return` within one of the finally blocks instead.
+    pub pending_unscopes: Vec<TypedValue>,
 }
 
 impl FunctionCompilerState {
     pub fn new() -> FunctionCompilerState {
         FunctionCompilerState {
             errors: Vec::new(),
+            warnings: Vec::new(),
             current_token: TokenLocation {
                 start: 0,
                 end: 0,
             },
             block_stack: Vec::new(),
+            pending_unscopes: Vec::new(),
         }
     }
 
@@ -55,6 +77,7 @@ impl FunctionCompilerState {
             llvm_block: bdl.create_block(),
             locals: Vec::new(),
             block_type: BlockType::Generic,
+            narrowed: Vec::new(),
         }
     }
 
@@ -64,6 +87,7 @@ impl FunctionCompilerState {
             llvm_block: bdl.create_block(),
             locals: current_block.locals.clone(),
             block_type: current_block.block_type.clone(),
+            narrowed: current_block.narrowed.clone(),
         }
     }
 
@@ -79,4 +103,16 @@ impl FunctionCompilerState {
     pub fn get_current_block_mut(&mut self) -> &mut ScopeBlock {
         self.block_stack.last_mut().unwrap()
     }
+
+    pub fn is_narrowed(&self, path: &str) -> bool {
+        self.block_stack.iter().any(|block| block.narrowed.iter().any(|narrowed| narrowed == path))
+    }
+
+    /// Forgets that `path`, and every path that goes through it, is non-null, e.g. after it has been assigned to.
+    pub fn invalidate_narrowing(&mut self, path: &str) {
+        let nested_prefix = format!("{}.", path);
+        for block in &mut self.block_stack {
+            block.narrowed.retain(|narrowed| narrowed != path && !narrowed.starts_with(&nested_prefix));
+        }
+    }
 }
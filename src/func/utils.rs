@@ -63,11 +63,33 @@ pub trait FunctionCompilerUtils {
     fn upcast(&mut self, src: TypedValue, parent: &str, generic_args: Option<Vec<ComplexType>>) -> Result<Option<TypedValue>>;
 
     fn autobox_primitive(&mut self, primitive: TypedValue) -> Result<TypedValue>;
-    fn handle_unhandled_error(&mut self, check: bool) -> Result<()>;
-    fn return_null(&mut self);
+
+    /// Emits a call to a function that can throw an error.
+    /// If the error has to be handled by this function, either by a `try` block or by unscoping the locals that are in scope,
+    /// the call is emitted as an `invoke` that unwinds to a landing pad. Otherwise, a plain call is emitted at no extra cost.
+    fn emit_throwing_call(&mut self, func_ref: OpaqueFunctionValue, func_type: OpaqueFunctionType, args: Vec<OpaqueValue>) -> Result<OpaqueValue>;
+
+    /// Compiles the landing pad of an `invoke`, which unscopes the locals of the unwound blocks and then either enters the
+    /// catch block of the innermost `try` block, or resumes unwinding if the error isn't caught (e.g. foreign exceptions).
+    fn compile_landing_pad(&mut self) -> Result<()>;
+
+    /// Returns true if values of type `ty` are reference counted, and so need to be unscoped when they go out of scope.
+    fn is_reference_counted(&self, ty: &ComplexType) -> bool;
 
     fn unbox_object(&mut self, value: TypedValue) -> Result<TypedValue>;
     fn heap_allocate(&mut self, ty: OpaqueType, count: Option<OpaqueValue>) -> Result<OpaqueValue>;
+
+    /// When compiling for librtdbg, emits a check that the `size` bytes at `address` lie within the allocation that
+    /// `address` points into, which reports the current source location if they don't. Emits nothing otherwise.
+    fn check_memory_access(&mut self, address: OpaqueValue, size: OpaqueValue) -> Result<()>;
+    /// When compiling with coverage, emits an increment of the coverage counter of the token at `loc`. Emits nothing
+    /// otherwise, or if the token was generated by the compiler.
+    fn count_coverage(&mut self, loc: &TokenLocation, kind: CoveragePointKind);
+
+    /// Loads the `i8` flag of a nullable value, which is `1` if the value is present and `0` if it is null.
+    fn load_nullability(&mut self, nullable: &TypedValue) -> OpaqueValue;
+    /// Loads the inner value of a nullable value without checking whether it is null.
+    fn load_nullable_inner(&mut self, nullable: &TypedValue) -> Result<TypedValue>;
 }
 
 impl<'a> FunctionCompilerUtils for FunctionCompiler<'a> {
@@ -76,7 +98,7 @@ impl<'a> FunctionCompilerUtils for FunctionCompiler<'a> {
             ComplexType::Basic(BasicType::Object(ident)) => {
                 if ident.name == "core::object::Box" {
                     let type_root = self.cpl.type_provider.get_class_by_name(ident).unwrap();
-                    let element_ptr = self.resolve_class_member_ptr(
+                    let element_ptr = self.resolve_class_member_ptr_unchecked(
                         &value,
                         &type_root,
                         &Token {
@@ -181,7 +203,7 @@ impl<'a> FunctionCompilerUtils for FunctionCompiler<'a> {
                 if ident.name == "core::object::Box" {
                     let value = self.instantiate_object(dest.ty.clone())?;
                     let type_root = self.cpl.type_provider.get_class_by_name(ident).unwrap();
-                    let element_ptr = self.resolve_class_member_ptr(
+                    let element_ptr = self.resolve_class_member_ptr_unchecked(
                         &value,
                         &type_root,
                         &Token {
@@ -294,6 +316,9 @@ impl<'a> FunctionCompilerUtils for FunctionCompiler<'a> {
             let data = self.emit(Insn::Load(data_ptr, self.cpl.context.get_pointer_type(data_type)));
 
             let element_ptr = self.emit(Insn::GetElementPtrDynamic(data, data_type, offset_index));
+            // the slice bounds are checked above, but the slice itself may not match the array data it points to
+            let element_size = self.compile_sizeof_expr(&element_type)?;
+            self.check_memory_access(element_ptr, element_size.val)?;
 
             self.emit(Insn::Br(rotated_parent_block.as_val()));
 
@@ -454,13 +479,14 @@ impl<'a> FunctionCompilerUtils for FunctionCompiler<'a> {
                     val: new_nullable,
                 });
             }
-            (ComplexType::Basic(_), ComplexType::Nullable(_)) => {
+            (ComplexType::Basic(_), ComplexType::Nullable(inner)) => {
                 // coerce from a normal type to a nullable
 
                 let new_nullable = self.emit(Insn::Alloca(dest_type.as_llvm_type(self.cpl)));
 
+                // struct and enum values are copied field by field into the nullable
                 let value_ptr = self.emit(Insn::GetElementPtr(new_nullable, dest_type.as_llvm_type(self.cpl), 0));
-                self.emit(Insn::Store(src.val, value_ptr));
+                self.copy(&src, &TypedValue::new(*inner.clone(), value_ptr))?;
 
                 let nullability_ptr = self.emit(Insn::GetElementPtr(new_nullable, dest_type.as_llvm_type(self.cpl), 1));
                 let null_value = self.cpl.context.const_int(self.cpl.context.get_i8_type(), 1); // 1 indicates the value is non-null
@@ -471,6 +497,22 @@ impl<'a> FunctionCompilerUtils for FunctionCompiler<'a> {
                     val: new_nullable,
                 });
             }
+            (ComplexType::Basic(BasicType::Object(ident)), ComplexType::Basic(BasicType::Object(dest_ident))) if ident != *dest_ident => {
+                // an instance of a class shares its layout with its superclasses, so only the type needs to change
+                let mut superclass = match self.cpl.type_provider.get_class_by_name(&ident) {
+                    Some(class_impl) if class_impl.class_type == ClassType::Class => class_impl.superclass,
+                    _ => None,
+                };
+                while let Some(superclass_ident) = superclass {
+                    if &superclass_ident == dest_ident {
+                        return Ok(TypedValue {
+                            ty: dest_type.clone(),
+                            val: src.val,
+                        });
+                    }
+                    superclass = self.cpl.type_provider.get_class_by_name(&superclass_ident).and_then(|class_impl| class_impl.superclass);
+                }
+            }
             _ => (),
         }
         Ok(src)
@@ -506,7 +548,23 @@ impl<'a> FunctionCompilerUtils for FunctionCompiler<'a> {
             }
         }
 
-        let resolved_interface_impls = self.cpl.type_provider.get_resolved_interface_impls(base_type);
+        let mut superclass = match self.cpl.type_provider.get_class_by_name(base_type) {
+            Some(class_impl) if class_impl.class_type == ClassType::Class => class_impl.superclass,
+            _ => None,
+        };
+        while let Some(superclass_ident) = superclass {
+            if superclass_ident.name == parent
+                && generic_args.as_ref().map_or(true, |generic_args| &superclass_ident.generic_args == generic_args)
+            {
+                return Ok(Some(TypedValue {
+                    ty: BasicType::Object(superclass_ident).to_complex(),
+                    val: src.val,
+                }));
+            }
+            superclass = self.cpl.type_provider.get_class_by_name(&superclass_ident).and_then(|class_impl| class_impl.superclass);
+        }
+
+        let resolved_interface_impls = self.cpl.type_provider.get_inherited_interface_impls(base_type);
         for resolved_interface_impl in resolved_interface_impls {
             let interface_impl = self.cpl.type_provider.get_source_interface_impl(&resolved_interface_impl);
             if interface_impl.interface_name == parent {
@@ -566,53 +624,107 @@ impl<'a> FunctionCompilerUtils for FunctionCompiler<'a> {
         Ok(boxed)
     }
 
-    fn handle_unhandled_error(&mut self, check: bool) -> Result<()> {
+    fn emit_throwing_call(&mut self, func_ref: OpaqueFunctionValue, func_type: OpaqueFunctionType, args: Vec<OpaqueValue>) -> Result<OpaqueValue> {
         // some compiler-generated function calls are outside the block stack
-        // we don't check those compiler-generated calls for errors
-        if self.state.block_stack.is_empty() {
-            return Ok(());
-        }
-
-        let catch_block = self.state.block_stack.iter().rev().find_map(|block| match &block.block_type {
-            BlockType::Try(catch) => Some(catch.catch_block.as_val()),
-            _ => None,
+        // errors thrown by those calls are never handled by this function
+        let has_landing_pad = self.state.block_stack.iter().enumerate().any(|(i, block)| {
+            matches!(block.block_type, BlockType::Try(_))
+                || block.locals.iter().enumerate().any(|(j, var)| self.is_unwound_local(i, j) && self.is_reference_counted(&var.value.ty))
         });
+        if !has_landing_pad {
+            return Ok(self.emit(Insn::Call(func_ref, func_type, args)));
+        }
 
+        let landing_pad_block = self.builder.create_block();
         let rotated_parent = self.state.new_rotated_parent(&mut self.builder);
-        if check {
-            let check_unhandled_error_callable =
-                ResolvedFunctionNode::externed("keid.check_unhandled_error", &[], Varargs::None, BasicType::Bool.to_complex());
-            let check_unhandled_error_ref = self.get_function_ref(&check_unhandled_error_callable)?;
-            let check_unhandled_error_val = self.call_function(check_unhandled_error_ref, &check_unhandled_error_callable, &[])?;
 
-            let return_block = self.builder.create_block();
+        let call = self.emit(Insn::Invoke(func_ref, func_type, args, rotated_parent.llvm_block.as_val(), landing_pad_block.as_val()));
 
-            self.emit(Insn::CondBr(check_unhandled_error_val, return_block.as_val(), rotated_parent.llvm_block.as_val()));
-
-            self.builder.append_block(&return_block);
-            self.builder.use_block(&return_block);
-        }
-
-        if let Some(catch_block) = catch_block {
-            // jump to the catch block to handle the error
-            self.emit(Insn::Br(catch_block));
-        } else {
-            // the error is unhandled, terminate execution of this function immediately
-            self.return_null();
-        }
+        self.builder.append_block(&landing_pad_block);
+        self.builder.use_block(&landing_pad_block);
+        self.compile_landing_pad()?;
 
         self.state.block_stack.pop();
         self.builder.append_block(&rotated_parent.llvm_block);
         self.state.push_block(&self.builder, rotated_parent);
 
+        Ok(call)
+    }
+
+    fn compile_landing_pad(&mut self) -> Result<()> {
+        let personality_impl = ResolvedFunctionNode::externed(
+            "keid_personality",
+            &[
+                BasicType::Int32.to_complex(),
+                BasicType::Int32.to_complex(),
+                BasicType::UInt64.to_complex(),
+                BasicType::Void.to_complex().to_reference(),
+                BasicType::Void.to_complex().to_reference(),
+            ],
+            Varargs::None,
+            BasicType::Int32.to_complex(),
+        );
+        let personality = self.get_function_ref(&personality_impl)?;
+        self.llvm_func.set_personality(personality);
+
+        let try_block = self.state.block_stack.iter().rev().find_map(|block| match &block.block_type {
+            BlockType::Try(try_block) => Some(try_block.clone()),
+            _ => None,
+        });
+
+        let landing_pad = self.emit(Insn::LandingPad(self.cpl.context.get_abi_landing_pad_type(), try_block.is_some()));
+
+        // unscope the locals of every block that is exited by unwinding to the catch block, or out of this function
+        let mut unwound_blocks = 0;
+        for i in (0..self.state.block_stack.len()).rev() {
+            self.unscope_unwound_locals(i)?;
+            unwound_blocks += 1;
+            if matches!(self.state.block_stack[i].block_type, BlockType::Try(_)) {
+                break;
+            }
+        }
+
+        match try_block {
+            Some(try_block) => {
+                // the personality routine sets the selector to 0 if the exception isn't caught, e.g. for foreign exceptions
+                let selector = self.emit(Insn::ExtractValue(landing_pad, 1));
+                let const_zero = self.cpl.context.const_int(self.cpl.context.get_i32_type(), 0);
+                let is_cleanup = self.emit(Insn::ICmp(IntPredicate::LLVMIntEQ, selector, const_zero));
+
+                let catch_block = self.builder.create_block();
+                let cleanup_block = self.builder.create_block();
+                self.emit(Insn::CondBr(is_cleanup, cleanup_block.as_val(), catch_block.as_val()));
+
+                self.builder.append_block(&catch_block);
+                self.builder.use_block(&catch_block);
+                let exception = self.emit(Insn::ExtractValue(landing_pad, 0));
+                self.emit(Insn::Store(exception, try_block.exception));
+                self.emit(Insn::Br(try_block.catch_block.as_val()));
+
+                // the exception unwinds out of this function, so the locals outside of the try block are unscoped as well
+                self.builder.append_block(&cleanup_block);
+                self.builder.use_block(&cleanup_block);
+                for i in (0..self.state.block_stack.len() - unwound_blocks).rev() {
+                    self.unscope_unwound_locals(i)?;
+                }
+                self.emit(Insn::Resume(landing_pad));
+            }
+            None => {
+                self.emit(Insn::Resume(landing_pad));
+            }
+        }
+
         Ok(())
     }
 
-    fn return_null(&mut self) {
-        if self.func.return_type == BasicType::Void.to_complex() || self.func.return_type.is_struct(&self.cpl.type_provider) {
-            self.emit(Insn::RetVoid);
-        } else {
-            self.emit(Insn::Ret(self.cpl.context.const_null(self.func.return_type.as_llvm_type(self.cpl))));
+    fn is_reference_counted(&self, ty: &ComplexType) -> bool {
+        match ty {
+            ComplexType::Nullable(inner) => self.is_reference_counted(inner),
+            ComplexType::Basic(BasicType::Object(ident)) => match self.cpl.type_provider.get_class_by_name(ident) {
+                Some(class) => class.class_type != ClassType::Struct,
+                None => self.cpl.type_provider.get_enum_by_name(ident).is_none(),
+            },
+            _ => false,
         }
     }
 
@@ -636,6 +748,62 @@ impl<'a> FunctionCompilerUtils for FunctionCompiler<'a> {
         let memory = self.call_function(malloc_func_ref, &malloc_func, &[size_const])?;
         Ok(memory)
     }
+
+    fn check_memory_access(&mut self, address: OpaqueValue, size: OpaqueValue) -> Result<()> {
+        if !self.cpl.use_rtdbg {
+            return Ok(());
+        }
+
+        let check_access = ResolvedFunctionNode::externed(
+            "rtdbg_check_access",
+            &[BasicType::Void.to_complex().to_reference(), BasicType::USize.to_complex(), BasicType::Char.to_complex().to_reference()],
+            Varargs::None,
+            BasicType::Void.to_complex(),
+        );
+        let check_access_ref = self.get_function_ref(&check_access)?;
+        let location = self.get_source_location();
+        let location = self.emit(Insn::GlobalString(location));
+        self.emit(Insn::Call(check_access_ref, check_access.as_llvm_type(self.cpl), vec![address, size, location]));
+        Ok(())
+    }
+
+    fn count_coverage(&mut self, loc: &TokenLocation, kind: CoveragePointKind) {
+        if !self.cpl.coverage || loc.end == 0 {
+            return;
+        }
+        let (line, col) = match self.unit.source_map.get_line_col(loc.start) {
+            Some(line_col) => line_col,
+            None => return,
+        };
+
+        let id = self.cpl.coverage_storage.get_or_add_point(&self.unit.path_name, loc, line, col, kind);
+        let counter_type = self.cpl.context.get_i64_type();
+        let counters = self.unit.mdl.get_or_extern_global(&GlobalVariable {
+            name: "keid.coverage_counters".to_string(),
+            ty: self.cpl.context.get_array_type(counter_type, 0),
+        });
+        // like gcov, the increments aren't atomic, so concurrent hits may be counted once, but never not at all
+        let id = self.cpl.context.const_int(counter_type, id as u64);
+        let counter_ptr = self.emit(Insn::GetElementPtrDynamic(counters, counter_type, id));
+        let count = self.emit(Insn::Load(counter_ptr, counter_type));
+        let count = self.emit(Insn::IAdd(count, self.cpl.context.const_int(counter_type, 1)));
+        self.emit(Insn::Store(count, counter_ptr));
+    }
+
+    fn load_nullability(&mut self, nullable: &TypedValue) -> OpaqueValue {
+        let nullability_ptr = self.emit(Insn::GetElementPtr(nullable.val, nullable.ty.as_llvm_type(self.cpl), 1));
+        self.emit(Insn::Load(nullability_ptr, self.cpl.context.get_i8_type()))
+    }
+
+    fn load_nullable_inner(&mut self, nullable: &TypedValue) -> Result<TypedValue> {
+        let inner = match &nullable.ty {
+            ComplexType::Nullable(inner) => *inner.clone(),
+            ty => unreachable!("not a nullable type: {}", ty.to_string()),
+        };
+        let value_ptr = self.emit(Insn::GetElementPtr(nullable.val, nullable.ty.as_llvm_type(self.cpl), 0));
+        let value = TypedValueContainer(TypedValue::new(inner.clone(), value_ptr)).load(self)?;
+        Ok(TypedValue::new(inner, value))
+    }
 }
 
 pub fn get_import_map_with(imports: &[ImportNode], all_type_names: Vec<&String>) -> HashMap<String, String> {
@@ -776,3 +944,60 @@ pub fn get_type_namespace(ty: &str) -> &str {
 pub fn get_type_leaf(ty: &str) -> &str {
     &ty[ty.rfind("::").map(|i| i + 2).unwrap_or(0)..ty.len()]
 }
+
+/// Returns the path that null narrowing tracks for a local or a chain of fields (e.g. `x` or `this.cause`),
+/// or `None` if `expr` is anything else.
+pub fn get_narrowing_path(expr: &Expr) -> Option<String> {
+    match expr {
+        Expr::Ident(ident) => Some(ident.token.0.clone()),
+        Expr::Member(member_expr) => {
+            let mut parts = Vec::new();
+            if let Some(prefix) = &member_expr.prefix {
+                parts.push(prefix.to_string());
+            }
+            for member in &member_expr.members {
+                match (member.ty, &member.value.token) {
+                    (MemberType::Root | MemberType::Class | MemberType::NullSafeClass, Expr::Ident(ident)) => {
+                        parts.push(ident.token.0.clone())
+                    }
+                    _ => return None,
+                }
+            }
+            Some(parts.join("."))
+        }
+        _ => None,
+    }
+}
+
+/// Collects the paths that are known to be non-null whenever `condition` is true,
+/// e.g. both `x` and `y.z` for `x != null && y.z != null`, or both `x` and `x.y` for `x?.y != null`.
+pub fn collect_non_null_paths(condition: &Expr, paths: &mut Vec<String>) {
+    if let Expr::Logic(logic) = condition {
+        match (logic.op, &logic.lhs.token, &logic.rhs.token) {
+            (Operator::BooleanAnd, lhs, rhs) => {
+                collect_non_null_paths(lhs, paths);
+                collect_non_null_paths(rhs, paths);
+            }
+            (Operator::NotEquals, Expr::Null, value) | (Operator::NotEquals, value, Expr::Null) => {
+                // a null-safe chain can only be non-null if every value `?.` was used on is non-null too
+                if let Expr::Member(member_expr) = value {
+                    for (i, member) in member_expr.members.iter().enumerate() {
+                        if member.ty == MemberType::NullSafeClass {
+                            let receiver = Expr::Member(MemberExpr {
+                                prefix: member_expr.prefix.clone(),
+                                members: member_expr.members[..i].to_vec(),
+                            });
+                            if let Some(path) = get_narrowing_path(&receiver) {
+                                paths.push(path);
+                            }
+                        }
+                    }
+                }
+                if let Some(path) = get_narrowing_path(value) {
+                    paths.push(path);
+                }
+            }
+            _ => (),
+        }
+    }
+}
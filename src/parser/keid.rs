@@ -1,10 +1,11 @@
 use crate::{tree::ast::*, tree::*};
 use anyhow::{anyhow, Result};
 use lazy_static::lazy_static;
-use pest::{error::*, iterators::*, pratt_parser::*, Parser, Span};
+use pest::{error::*, iterators::*, pratt_parser::*, Parser, Position, Span};
+use std::cell::RefCell;
 use std::path::Path;
 
-use super::preprocessor::{self, PreprocessorContext};
+use super::preprocessor::{self, PreprocessorContext, PreprocessorError, SourceMap};
 
 #[derive(pest_derive::Parser)]
 #[grammar = "./parser/keid.pest"]
@@ -29,11 +30,17 @@ lazy_static! {
         .op(Op::postfix(Rule::op_null_assert));
 }
 
+thread_local! {
+    // the source map of the file that is being parsed, so that every location refers to the original source
+    static SOURCE_MAP: RefCell<SourceMap> = RefCell::new(SourceMap::default());
+}
+
 pub fn span_to_location(span: &Span) -> TokenLocation {
-    TokenLocation {
+    let loc = TokenLocation {
         start: span.start(),
         end: span.end(),
-    }
+    };
+    SOURCE_MAP.with(|source_map| source_map.borrow().translate(&loc))
 }
 
 fn tokenize<T: Clone + PartialEq>(span: &Span, comp: T) -> Token<T> {
@@ -100,6 +107,7 @@ fn parse_member(pairs: Pairs<Rule>) -> Result<MemberExpr> {
                 (MemberType::Root, root)
             }
             Rule::class_member => (MemberType::Class, parse_expr(pair.into_inner().next().unwrap())?),
+            Rule::null_safe_member => (MemberType::NullSafeClass, parse_expr(pair.into_inner().nth(1).unwrap())?),
             Rule::array_member => (MemberType::Array, parse_expr(pair.into_inner().next().unwrap())?),
             x => unimplemented!("{:?}", x),
         };
@@ -168,7 +176,7 @@ fn parse_unary_type_expr(mut pairs: Pairs<Rule>) -> QualifiedType {
 fn parse_postfix_expr(operand: Token<Expr>, operator: Pair<Rule>) -> Result<Token<Expr>> {
     Ok(Token {
         loc: TokenLocation {
-            start: operator.as_span().start(),
+            start: span_to_location(&operator.as_span()).start,
             end: operand.loc.end,
         },
         token: Expr::Unary(UnaryExpr {
@@ -188,7 +196,7 @@ fn parse_logic_expr(pairs: Pairs<Rule>) -> Result<Token<Expr>> {
             let operand = operand?;
             Ok(Token {
                 loc: TokenLocation {
-                    start: operator.as_span().start(),
+                    start: span_to_location(&operator.as_span()).start,
                     end: operand.loc.end,
                 },
                 token: Expr::Unary(UnaryExpr {
@@ -282,6 +290,59 @@ fn parse_expr(pair: Pair<Rule>) -> Result<Token<Expr>> {
     Ok(tokenize(&span, token))
 }
 
+fn parse_match_pattern(pair: Pair<Rule>) -> Result<Token<MatchPattern>> {
+    let span = pair.as_span();
+    let mut alternatives = pair.into_inner().map(parse_single_match_pattern).collect::<Result<Vec<_>>>()?;
+    if alternatives.len() == 1 {
+        return Ok(alternatives.remove(0));
+    }
+    Ok(tokenize(&span, MatchPattern::Alternatives(alternatives)))
+}
+
+fn parse_single_match_pattern(pair: Pair<Rule>) -> Result<Token<MatchPattern>> {
+    let span = pair.as_span();
+    let pattern = match pair.as_rule() {
+        Rule::inner_match_catchall => MatchPattern::Catchall,
+        Rule::match_binding => {
+            let mut inner = pair.into_inner();
+            let name = Identifier::from_ident(&inner.next().unwrap());
+            MatchPattern::Binding {
+                name,
+                pattern: Box::new(parse_single_match_pattern(inner.next().unwrap())?),
+            }
+        }
+        Rule::inner_match_enum => {
+            let mut inner = pair.into_inner();
+            let member = Identifier::from_ident(&inner.next().unwrap());
+            let mut fields = Vec::new();
+            for field in inner {
+                let mut field = field.into_inner();
+                fields.push(MatchPatternField {
+                    name: Identifier::from_ident(&field.next().unwrap()),
+                    pattern: field.next().map(parse_match_pattern).transpose()?,
+                });
+            }
+            MatchPattern::Enum {
+                member,
+                fields: Some(fields),
+            }
+        }
+        Rule::match_range => {
+            let mut inner = pair.into_inner();
+            MatchPattern::Range {
+                start: parse_expr(inner.next().unwrap())?,
+                end: parse_expr(inner.next().unwrap())?,
+            }
+        }
+        Rule::ident => MatchPattern::Enum {
+            member: Identifier::from_ident(&pair),
+            fields: None,
+        },
+        _ => MatchPattern::Expr(parse_expr(pair)?),
+    };
+    Ok(tokenize(&span, pattern))
+}
+
 fn parse_match_expr(mut pairs: Pairs<Rule>) -> Result<MatchExpr> {
     pairs.next(); // skip "match" keyword
 
@@ -289,25 +350,20 @@ fn parse_match_expr(mut pairs: Pairs<Rule>) -> Result<MatchExpr> {
     let mut branches = Vec::new();
     for next in pairs {
         let mut stmt = next.into_inner();
-        let arg = stmt.next().unwrap();
-        let arg = match arg.as_rule() {
-            Rule::inner_match_catchall => MatchExprBranchArg::Catchall(span_to_location(&arg.as_span())),
-            Rule::inner_match_enum => {
-                let mut inner_enum = arg.into_inner();
-                let member = Identifier::from_ident(&inner_enum.next().unwrap());
-                let data_type = parse_anonymous_struct_type_decl(inner_enum.next().unwrap().into_inner());
-                MatchExprBranchArg::EnumWithData {
-                    member,
-                    data_type,
-                }
+        let pattern = parse_match_pattern(stmt.next().unwrap())?;
+        let guard = match stmt.peek().unwrap().as_rule() {
+            Rule::match_guard => {
+                let mut guard = stmt.next().unwrap().into_inner();
+                guard.next(); // skip "if" keyword
+                Some(parse_expr(guard.next().unwrap())?)
             }
-            Rule::ident => MatchExprBranchArg::Enum(Identifier::from_ident(&arg)),
-            _ => MatchExprBranchArg::Expr(parse_expr(arg.clone())?),
+            _ => None,
         };
         let statement = parse_statement(stmt.next().unwrap())?;
 
         branches.push(MatchExprBranch {
-            arg,
+            pattern,
+            guard,
             statement,
         })
     }
@@ -351,6 +407,7 @@ fn parse_let(mut pairs: Pairs<Rule>) -> Result<Let> {
     };
 
     Ok(Let {
+        is_public: false,
         is_const,
         name,
         var_type,
@@ -358,7 +415,22 @@ fn parse_let(mut pairs: Pairs<Rule>) -> Result<Let> {
     })
 }
 
+fn parse_global_decl(mut pairs: Pairs<Rule>) -> Result<Let> {
+    let is_public = pairs.peek().unwrap().as_rule() == Rule::keyword_public;
+    if is_public {
+        pairs.next(); // skip the "public" keyword
+    }
+    Ok(Let {
+        is_public,
+        ..parse_let(pairs.next().unwrap().into_inner())?
+    })
+}
+
 fn parse_field_decl(mut pairs: Pairs<Rule>) -> Result<Let> {
+    let is_public = pairs.peek().unwrap().as_rule() == Rule::keyword_public;
+    if is_public {
+        pairs.next(); // skip the "public" keyword
+    }
     let name = Identifier::from_ident(&pairs.next().unwrap());
     let var_type = Some(QualifiedType::from_idents(pairs.next().unwrap()));
     let initial_value = match pairs.next().map(parse_expr) {
@@ -367,6 +439,7 @@ fn parse_field_decl(mut pairs: Pairs<Rule>) -> Result<Let> {
         None => None,
     };
     Ok(Let {
+        is_public,
         is_const: false,
         name,
         var_type,
@@ -545,17 +618,61 @@ fn parse_fixed_block(mut pairs: Pairs<Rule>) -> Result<FixedBlock> {
     })
 }
 
-fn parse_try_catch_statement(mut pairs: Pairs<Rule>) -> Result<TryCatch> {
+fn parse_catch_clause(mut pairs: Pairs<Rule>) -> Result<CatchClause> {
+    pairs.next(); // skip "catch" keyword
+    let mut error_var = None;
+    let mut error_type = None;
+    let mut next = pairs.next().unwrap();
+    if next.as_rule() == Rule::ident {
+        error_var = Some(Identifier::from_ident(&next));
+        next = pairs.next().unwrap();
+        if next.as_rule() == Rule::variable_type {
+            error_type = Some(QualifiedType::from_idents(next));
+            next = pairs.next().unwrap();
+        }
+    }
+    let block = parse_block(next)?;
+
+    Ok(CatchClause {
+        error_var,
+        error_type,
+        block,
+    })
+}
+
+fn parse_try_catch_statement(pair: Pair<Rule>) -> Result<TryCatch> {
+    let mut pairs = pair.clone().into_inner();
     pairs.next(); // skip "try" keyword
     let try_block = parse_block(pairs.next().unwrap())?;
-    pairs.next(); // skip "catch" keyword
-    let error_var = Identifier::from_ident(&pairs.next().unwrap());
-    let catch_block = parse_block(pairs.next().unwrap())?;
+
+    let mut catch_clauses = Vec::new();
+    let mut finally_block = None;
+    for clause in pairs {
+        match clause.as_rule() {
+            Rule::catch_clause => catch_clauses.push(parse_catch_clause(clause.into_inner())?),
+            Rule::finally_clause => {
+                let mut inner = clause.into_inner();
+                inner.next(); // skip "finally" keyword
+                finally_block = Some(parse_block(inner.next().unwrap())?);
+            }
+            x => unreachable!("{:?}", x),
+        }
+    }
+
+    if catch_clauses.is_empty() && finally_block.is_none() {
+        return Err(Error::<Rule>::new_from_span(
+            ErrorVariant::CustomError {
+                message: "Try statement must have at least one catch clause or a finally block".to_string(),
+            },
+            pair.as_span(),
+        )
+        .into());
+    }
 
     Ok(TryCatch {
         try_block,
-        error_var: Some(error_var),
-        catch_block,
+        catch_clauses,
+        finally_block,
     })
 }
 
@@ -576,7 +693,8 @@ fn parse_statement(pair: Pair<Rule>) -> Result<Token<Statement>> {
             Rule::indefinite_loop => Statement::IndefiniteLoop(parse_indefinite_loop(inner)?),
             Rule::unreachable_statement => Statement::Unreachable,
             Rule::throw_statement => Statement::Throw(parse_throw_statement(inner)?),
-            Rule::try_statement => Statement::TryCatch(parse_try_catch_statement(inner)?),
+            Rule::rethrow_statement => Statement::Rethrow,
+            Rule::try_statement => Statement::TryCatch(parse_try_catch_statement(pair.clone())?),
             _ => Statement::Expr(parse_expr(pair)?),
         },
     ))
@@ -821,6 +939,7 @@ fn parse_associated_type_decl(mut pairs: Pairs<Rule>) -> Result<AssociatedTypeDe
 }
 
 fn parse_interface_accessor_decl(mut pairs: Pairs<Rule>) -> Result<Vec<AccessorDecl>> {
+    let mut modifiers = Vec::new();
     let mut accessor_types = Vec::new();
     let name;
     loop {
@@ -832,16 +951,14 @@ fn parse_interface_accessor_decl(mut pairs: Pairs<Rule>) -> Result<Vec<AccessorD
             Rule::keyword_set => {
                 accessor_types.push(AccessorType::Setter(Token {
                     token: Identifier("_".to_owned()),
-                    loc: TokenLocation {
-                        start: pair.as_span().start(),
-                        end: pair.as_span().end(),
-                    },
+                    loc: span_to_location(&pair.as_span()),
                 }));
             }
             Rule::ident => {
                 name = Identifier::from_ident(&pair);
                 break;
             }
+            Rule::function_modifiers => modifiers = parse_modifiers(pair.into_inner()),
             x => unreachable!("{:?}", x),
         }
     }
@@ -850,7 +967,7 @@ fn parse_interface_accessor_decl(mut pairs: Pairs<Rule>) -> Result<Vec<AccessorD
     Ok(accessor_types
         .into_iter()
         .map(|accessor_type| AccessorDecl {
-            modifiers: Vec::new(),
+            modifiers: modifiers.clone(),
             accessor_type,
             name: name.clone(),
             value_type: value_type.clone(),
@@ -873,7 +990,15 @@ fn parse_accessor_decl(mut pairs: Pairs<Rule>) -> Result<AccessorDecl> {
             }
             Rule::keyword_set => {
                 name = Identifier::from_ident(&pairs.next().unwrap());
-                accessor_type = AccessorType::Setter(Identifier::from_ident(&pairs.next().unwrap()));
+                // the parameter name is optional and defaults to `value`
+                let param = match pairs.peek() {
+                    Some(param) if param.as_rule() == Rule::ident => Identifier::from_ident(&pairs.next().unwrap()),
+                    _ => Token {
+                        token: Identifier("value".to_owned()),
+                        loc: name.loc.clone(),
+                    },
+                };
+                accessor_type = AccessorType::Setter(param);
                 break;
             }
             Rule::function_modifiers => modifiers = parse_modifiers(pair.into_inner()),
@@ -953,9 +1078,11 @@ fn parse_anonymous_struct_type_decl(mut pairs: Pairs<Rule>) -> AnonymousStructTy
 }
 
 fn parse_enum_decl(mut pairs: Pairs<Rule>, namespace: Qualifier) -> Result<EnumDecl> {
+    let mut is_public = false;
     for pair in pairs.by_ref() {
         match pair.as_rule() {
             Rule::keyword_enum => break,
+            Rule::type_modifiers => is_public = true,
             _ => (),
         }
     }
@@ -970,15 +1097,24 @@ fn parse_enum_decl(mut pairs: Pairs<Rule>, namespace: Qualifier) -> Result<EnumD
     for enum_statement in enum_statements {
         let mut enum_statement = enum_statement.into_inner();
         let name = Identifier::from_ident(&enum_statement.next().unwrap());
-        let data = enum_statement.next().map(|anon| parse_anonymous_struct_type_decl(anon.into_inner()));
+        let mut data = None;
+        let mut discriminant = None;
+        for pair in enum_statement {
+            match pair.as_rule() {
+                Rule::anonymous_struct_type => data = Some(parse_anonymous_struct_type_decl(pair.into_inner())),
+                _ => discriminant = Some(parse_expr(pair)?),
+            }
+        }
 
         elements.push(EnumElementDecl {
             name,
             data,
+            discriminant,
         })
     }
 
     Ok(EnumDecl {
+        is_public,
         name,
         generics,
         elements,
@@ -986,9 +1122,11 @@ fn parse_enum_decl(mut pairs: Pairs<Rule>, namespace: Qualifier) -> Result<EnumD
 }
 
 fn parse_class_decl(mut pairs: Pairs<Rule>, namespace: Qualifier, ty: ClassType) -> Result<ClassDecl> {
+    let mut is_public = false;
     for pair in pairs.by_ref() {
         match pair.as_rule() {
             Rule::keyword_class | Rule::keyword_interface | Rule::keyword_struct => break,
+            Rule::type_modifiers => is_public = true,
             _ => (),
         }
     }
@@ -1029,6 +1167,7 @@ fn parse_class_decl(mut pairs: Pairs<Rule>, namespace: Qualifier, ty: ClassType)
     }
 
     Ok(ClassDecl {
+        is_public,
         ty,
         name,
         generics,
@@ -1062,6 +1201,7 @@ fn parse_program<T: AsRef<Path>>(file_path: T, pair: Pair<Rule>) -> Result<KeidF
         attributes: Vec::new(),
         enums: Vec::new(),
         namespace,
+        source_map: SourceMap::default(),
     };
 
     for pair in pairs {
@@ -1077,7 +1217,7 @@ fn parse_program<T: AsRef<Path>>(file_path: T, pair: Pair<Rule>) -> Result<KeidF
                 program.classes.push(parse_class_decl(pair.into_inner(), program.namespace.clone(), ClassType::Interface)?)
             }
             Rule::interface_impl => program.interface_impls.push(parse_interface_impl(pair.into_inner())?),
-            Rule::let_statement => program.fields.push(parse_let(pair.into_inner())?),
+            Rule::global_decl => program.fields.push(parse_global_decl(pair.into_inner())?),
             Rule::struct_decl => program.classes.push(parse_class_decl(pair.into_inner(), program.namespace.clone(), ClassType::Struct)?),
             Rule::attribute_decl => program.attributes.push(parse_attribute_decl(pair.into_inner(), program.namespace.clone())?),
             Rule::enum_decl => program.enums.push(parse_enum_decl(pair.into_inner(), program.namespace.clone())?),
@@ -1099,14 +1239,45 @@ pub fn parse_qualified_type(code: &str) -> Result<QualifiedType> {
     }
 }
 
-pub fn parse(file_name: &str, code: &str) -> Result<KeidFile> {
-    let code = preprocessor::preprocess(
-        code,
-        &PreprocessorContext {
-            use_rtdbg: false,
+/// Parses a file whose conditional compilation depends on the defines of `ctx`, which should be created with
+/// [`PreprocessorContext::for_target`] for the target that the file is compiled for.
+pub fn parse(file_name: &str, code: &str, ctx: &PreprocessorContext) -> Result<KeidFile> {
+    let (processed, source_map) = preprocessor::preprocess_with_source_map(code, ctx).map_err(|e| into_syntax_error(e, code))?;
+
+    SOURCE_MAP.with(|current| *current.borrow_mut() = source_map.clone());
+    let result = parse_preprocessed(file_name, &processed);
+    SOURCE_MAP.with(|current| *current.borrow_mut() = SourceMap::default());
+
+    match result {
+        Ok(mut program) => {
+            program.source_map = source_map;
+            Ok(program)
+        }
+        // syntax errors refer to the preprocessed code
+        Err(e) => match e.downcast::<Error<Rule>>() {
+            Ok(e) => Err(translate_syntax_error(e, code, &source_map).into()),
+            Err(e) => Err(e),
         },
-    )?;
-    let result = SyntaxParser::parse(Rule::program, &code);
+    }
+}
+
+/// Parses the files of a program, given as pairs of file names and code. The macros declared by every file are
+/// registered first, so that each file can import the macros of any other file.
+pub fn parse_all(files: &[(String, String)], ctx: &PreprocessorContext) -> Result<Vec<KeidFile>> {
+    let mut ctx = ctx.clone();
+    for (_, code) in files {
+        ctx.register_macros(code).map_err(|e| into_syntax_error(e, code))?;
+    }
+    files.iter().map(|(file_name, code)| parse(file_name, code, &ctx)).collect()
+}
+
+/// Preprocesses `code` without parsing it, to show what its macros expand to.
+pub fn expand(code: &str, ctx: &PreprocessorContext) -> Result<String> {
+    preprocessor::expand(code, ctx).map_err(|e| into_syntax_error(e, code))
+}
+
+fn parse_preprocessed(file_name: &str, code: &str) -> Result<KeidFile> {
+    let result = SyntaxParser::parse(Rule::program, code);
     match result {
         Ok(mut pairs) => match pairs.next() {
             Some(pair) => {
@@ -1118,3 +1289,45 @@ pub fn parse(file_name: &str, code: &str) -> Result<KeidFile> {
         Err(msg) => Err(msg.into()),
     }
 }
+
+fn translate_syntax_error(error: Error<Rule>, code: &str, source_map: &SourceMap) -> Error<Rule> {
+    let (start, end) = match error.location {
+        InputLocation::Pos(pos) => (pos, pos),
+        InputLocation::Span(span) => span,
+    };
+    let loc = source_map.translate(&TokenLocation {
+        start,
+        end,
+    });
+
+    Error::new_from_pos(with_expansion_notes(error.variant, &source_map.get_expansions(&loc)), Position::new(code, loc.start).unwrap())
+}
+
+/// Reports a [`PreprocessorError`] like a syntax error in the original source.
+fn into_syntax_error(error: anyhow::Error, code: &str) -> anyhow::Error {
+    match error.downcast::<PreprocessorError>() {
+        Ok(error) => {
+            let variant = with_expansion_notes(
+                ErrorVariant::CustomError {
+                    message: error.message,
+                },
+                &error.expansions,
+            );
+            Error::<Rule>::new_from_span(variant, Span::new(code, error.loc.start, error.loc.end).unwrap()).into()
+        }
+        Err(error) => error,
+    }
+}
+
+fn with_expansion_notes(variant: ErrorVariant<Rule>, expansions: &[String]) -> ErrorVariant<Rule> {
+    if expansions.is_empty() {
+        return variant;
+    }
+    let mut message = variant.message().into_owned();
+    for name in expansions {
+        message.push_str(&format!("\nnote: in expansion of macro `{}`", name));
+    }
+    ErrorVariant::CustomError {
+        message,
+    }
+}
@@ -1,15 +1,106 @@
 use anyhow::anyhow;
 use anyhow::Result;
+use pest::error::InputLocation;
 use pest::iterators::*;
 use pest::Parser;
+use std::collections::HashMap;
 use std::fmt::Write;
+use thiserror::Error;
 
+use crate::tree::ast::TokenLocation;
+
+/// The maximum number of preprocessing rounds, which bounds how deeply macro calls can be nested.
+const MAX_EXPANSION_DEPTH: usize = 8;
+
+/// The defines that are visible to conditional compilation (`$IF`, `$IFNOT`, `$ELSE` and `$DEFINED`), and the macros
+/// that can be imported with `macro import`.
+#[derive(Debug, Clone, Default)]
 pub struct PreprocessorContext {
-    pub use_rtdbg: bool,
+    /// Flags (such as `RTDBG`, `DEBUG` or feature names) map to an empty string, other defines (such as `OS`) to their value.
+    pub defines: HashMap<String, String>,
+    /// The macros declared by each namespace that was registered with [`PreprocessorContext::register_macros`].
+    pub macros: HashMap<String, Vec<MacroDecl>>,
+}
+
+impl PreprocessorContext {
+    pub fn new() -> PreprocessorContext {
+        PreprocessorContext::default()
+    }
+
+    /// Defines `OS` and `ARCH` from the components of the target triple, and either the `DEBUG` or the `RELEASE` flag.
+    pub fn for_target(target_triple: &str, is_debug: bool) -> PreprocessorContext {
+        let components: Vec<&str> = target_triple.split('-').collect();
+        let mut ctx = PreprocessorContext::new();
+        ctx.define("ARCH", components[0]);
+        if let Some(os) = components.get(2) {
+            ctx.define("OS", os);
+        }
+        ctx.define_flag(if is_debug { "DEBUG" } else { "RELEASE" });
+        ctx
+    }
+
+    pub fn define(&mut self, name: &str, value: &str) {
+        self.defines.insert(name.to_owned(), value.to_owned());
+    }
+
+    pub fn define_flag(&mut self, name: &str) {
+        self.define(name, "");
+    }
+
+    pub fn is_defined(&self, name: &str) -> bool {
+        self.defines.contains_key(name)
+    }
+
+    /// A define is set unless it's undefined, `false` or `0`.
+    pub fn is_set(&self, name: &str) -> bool {
+        match self.defines.get(name) {
+            Some(value) => value != "false" && value != "0",
+            None => false,
+        }
+    }
+
+    /// Makes the macros declared in `code` importable from its namespace. Every file has to be registered before any
+    /// file that imports its macros is preprocessed, which `parser::parse_all` takes care of.
+    pub fn register_macros(&mut self, code: &str) -> Result<()> {
+        let namespace = match code.lines().find_map(|line| line.trim().strip_prefix("namespace ")) {
+            Some(namespace) => namespace.trim().to_owned(),
+            None => return Ok(()),
+        };
+        let decls = parse_user_macro_decls(code)?;
+        if !decls.is_empty() {
+            self.macros.entry(namespace).or_default().extend(decls);
+        }
+        Ok(())
+    }
+}
+
+/// An error that occurred while preprocessing, located in the original source.
+#[derive(Error, Debug, Clone, PartialEq)]
+#[error("{message}")]
+pub struct PreprocessorError {
+    pub message: String,
+    pub loc: TokenLocation,
+    /// The names of the macros whose expansion contains `loc`, innermost first.
+    pub expansions: Vec<String>,
+}
+
+impl PreprocessorError {
+    fn new(message: String, span: &pest::Span) -> PreprocessorError {
+        PreprocessorError {
+            message,
+            loc: TokenLocation {
+                start: span.start(),
+                end: span.end(),
+            },
+            expansions: Vec::new(),
+        }
+    }
 }
 
-pub type IntrinsicMacroDecl = fn(args: &[String], ctx: &PreprocessorContext) -> String;
+/// `previous_condition` is the outcome of the `$IF` or `$IFNOT` call directly before the intrinsic macro call, if any.
+pub type IntrinsicMacroDecl = fn(args: &[String], ctx: &PreprocessorContext, previous_condition: &mut Option<bool>) -> Result<String>;
 
+#[derive(Debug, Clone)]
 pub enum MacroDeclMode {
     UserDefined {
         args: Vec<String>,
@@ -18,6 +109,7 @@ pub enum MacroDeclMode {
     Intrinsic(IntrinsicMacroDecl),
 }
 
+#[derive(Debug, Clone)]
 pub struct MacroDecl {
     pub mode: MacroDeclMode,
     pub name: String,
@@ -27,6 +119,158 @@ pub struct MacroDecl {
 pub struct MacroCall {
     pub name: String,
     pub args: Vec<String>,
+    /// The offset of every argument in the input.
+    pub arg_offsets: Vec<usize>,
+}
+
+/// Where a part of the output of a preprocessing round comes from.
+#[derive(Debug, Clone, PartialEq)]
+enum SegmentOrigin {
+    /// Text that was copied from the input, starting at the given offset.
+    Input(usize),
+    /// The argument of a conditional macro call (such as `$IF`) that was selected, starting at the given offset in the
+    /// input. Unlike other expansions, it's translated into the argument rather than the macro call.
+    Selected(usize),
+    /// The expansion of the macro called at `call_site` in the input.
+    Expansion {
+        name: String,
+        call_site: TokenLocation,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Segment {
+    start: usize,
+    end: usize,
+    origin: SegmentOrigin,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MacroExpansion {
+    pub name: String,
+    /// The location of the macro call in the original source.
+    pub call_site: TokenLocation,
+}
+
+/// Translates locations in the preprocessed code back into the original source.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SourceMap {
+    /// The segments of the output of every preprocessing round that changed the code.
+    rounds: Vec<Vec<Segment>>,
+    /// The offsets at which the lines of the original source start.
+    line_starts: Vec<usize>,
+    /// Every macro expansion, in the order in which they were expanded.
+    pub expansions: Vec<MacroExpansion>,
+}
+
+impl SourceMap {
+    fn new(code: &str) -> SourceMap {
+        let mut line_starts = vec![0];
+        line_starts.extend(code.match_indices('\n').map(|(i, _)| i + 1));
+
+        SourceMap {
+            rounds: Vec::new(),
+            line_starts,
+            expansions: Vec::new(),
+        }
+    }
+
+    /// Translates an offset in the output of a round into its input. If `is_last` is set, the offset is the last one of
+    /// a location and the exclusive end of the translated location is returned.
+    fn translate_offset(segments: &[Segment], offset: usize, is_last: bool) -> usize {
+        // the separators between segments are attributed to the segment before them
+        let idx = segments.partition_point(|segment| segment.start <= offset);
+        if idx == 0 {
+            return offset;
+        }
+        let segment = &segments[idx - 1];
+        match &segment.origin {
+            SegmentOrigin::Input(start) | SegmentOrigin::Selected(start) if is_last => {
+                start + offset.min(segment.end - 1) - segment.start + 1
+            }
+            SegmentOrigin::Input(start) | SegmentOrigin::Selected(start) => start + offset.min(segment.end) - segment.start,
+            SegmentOrigin::Expansion {
+                call_site,
+                ..
+            } if is_last => call_site.end,
+            SegmentOrigin::Expansion {
+                call_site,
+                ..
+            } => call_site.start,
+        }
+    }
+
+    fn translate_round(segments: &[Segment], loc: &TokenLocation) -> TokenLocation {
+        let start = Self::translate_offset(segments, loc.start, false);
+        let end = if loc.end > loc.start {
+            Self::translate_offset(segments, loc.end - 1, true).max(start)
+        } else {
+            start
+        };
+
+        TokenLocation {
+            start,
+            end,
+        }
+    }
+
+    /// Translates a location in the preprocessed code into the original source.
+    /// Locations within a macro expansion are translated to the location of the macro call.
+    pub fn translate(&self, loc: &TokenLocation) -> TokenLocation {
+        self.translate_rounds(self.rounds.len(), loc)
+    }
+
+    fn translate_rounds(&self, round_count: usize, loc: &TokenLocation) -> TokenLocation {
+        let mut loc = loc.clone();
+        for segments in self.rounds[..round_count].iter().rev() {
+            loc = Self::translate_round(segments, &loc);
+        }
+        loc
+    }
+
+    /// Returns the names of the macros whose expansion contains `loc` (a location in the original source), innermost first.
+    pub fn get_expansions(&self, loc: &TokenLocation) -> Vec<String> {
+        self.expansions
+            .iter()
+            .rev()
+            .filter(|expansion| expansion.call_site.start <= loc.start && loc.end <= expansion.call_site.end)
+            .map(|expansion| expansion.name.clone())
+            .collect()
+    }
+
+    /// Returns the 1-based line and column of an offset in the original source, unless the source is unknown.
+    pub fn get_line_col(&self, offset: usize) -> Option<(usize, usize)> {
+        let line = self.line_starts.partition_point(|start| *start <= offset);
+        if line == 0 {
+            return None;
+        }
+        Some((line, offset - self.line_starts[line - 1] + 1))
+    }
+
+    fn push_round(&mut self, segments: Vec<Segment>) {
+        let round_count = self.rounds.len();
+        for segment in &segments {
+            if let SegmentOrigin::Expansion {
+                name,
+                call_site,
+            } = &segment.origin
+            {
+                let call_site = self.translate_rounds(round_count, call_site);
+                self.expansions.push(MacroExpansion {
+                    name: name.clone(),
+                    call_site,
+                });
+            }
+        }
+        self.rounds.push(segments);
+    }
+
+    /// Translates the location of an error in the input of the current round into the original source.
+    fn locate(&self, mut error: PreprocessorError) -> PreprocessorError {
+        error.loc = self.translate(&error.loc);
+        error.expansions = self.get_expansions(&error.loc);
+        error
+    }
 }
 
 #[derive(pest_derive::Parser)]
@@ -61,127 +305,481 @@ fn parse_macro_decl(mut pairs: Pairs<Rule>) -> Result<MacroDecl> {
 fn parse_macro_call(mut pairs: Pairs<Rule>) -> Result<MacroCall> {
     let name = pairs.next().unwrap().as_str().trim().to_owned();
     let mut args = Vec::new();
+    let mut arg_offsets = Vec::new();
 
     for arg in pairs {
         let as_str = arg.as_str();
-        args.push(match arg.as_rule() {
-            Rule::marked_macro_param => as_str["```".len()..as_str.len() - "```".len()].trim().to_owned(),
-            Rule::simple_macro_param => as_str.to_owned(),
+        let start = arg.as_span().start();
+        match arg.as_rule() {
+            Rule::marked_macro_param => {
+                let marked = &as_str["```".len()..as_str.len() - "```".len()];
+                let trimmed = marked.trim_start();
+                args.push(trimmed.trim_end().to_owned());
+                arg_offsets.push(start + "```".len() + marked.len() - trimmed.len());
+            }
+            Rule::simple_macro_param => {
+                args.push(as_str.to_owned());
+                arg_offsets.push(start);
+            }
             x => unimplemented!("{:?}", x),
-        });
+        }
     }
 
     Ok(MacroCall {
         name,
         args,
+        arg_offsets,
     })
 }
 
-fn intrinsic_macro_decl_if(args: &[String], ctx: &PreprocessorContext) -> String {
-    if args.len() != 2 {
-        panic!("macro $IF takes 2 arguments");
+fn evaluate_condition(pair: Pair<Rule>, ctx: &PreprocessorContext) -> bool {
+    match pair.as_rule() {
+        Rule::condition_or => pair.into_inner().any(|operand| evaluate_condition(operand, ctx)),
+        Rule::condition_and => pair.into_inner().all(|operand| evaluate_condition(operand, ctx)),
+        Rule::condition_not => !evaluate_condition(pair.into_inner().next().unwrap(), ctx),
+        Rule::condition_flag => ctx.is_set(pair.as_str()),
+        Rule::condition_defined => ctx.is_defined(pair.into_inner().next().unwrap().as_str()),
+        Rule::condition_comparison => {
+            let mut pairs = pair.into_inner();
+            let name = pairs.next().unwrap().as_str();
+            let is_equal = pairs.next().unwrap().as_rule() == Rule::op_equal;
+            let expected = pairs.next().unwrap().as_str().trim_matches('"');
+            let matches = ctx.defines.get(name).map(|value| value == expected).unwrap_or(false);
+            matches == is_equal
+        }
+        x => unreachable!("{:?}", x),
     }
+}
 
-    if args[0] == "RTDBG" {
-        if ctx.use_rtdbg {
-            return args[1].clone();
+fn parse_condition(condition: &str, ctx: &PreprocessorContext) -> Result<bool> {
+    match SyntaxParser::parse(Rule::condition, condition) {
+        Ok(mut pairs) => {
+            let condition = pairs.next().unwrap().into_inner().next().unwrap();
+            Ok(evaluate_condition(condition, ctx))
         }
-        String::new()
-    } else {
-        panic!("not a boolean variable: {}", args[0])
+        Err(msg) => Err(anyhow!("invalid condition `{}`: {}", condition, msg.variant.message())),
     }
 }
 
-fn parse_all_macro_decls(code: &str) -> Result<Vec<MacroDecl>> {
-    let result = SyntaxParser::parse(Rule::program, code);
-    match result {
-        Ok(mut pairs) => match pairs.next() {
-            Some(pair) => Ok({
-                let pairs = pair.into_inner();
-                let mut macro_decls = Vec::new();
+fn intrinsic_macro_decl_if(args: &[String], ctx: &PreprocessorContext, previous_condition: &mut Option<bool>) -> Result<String> {
+    if args.len() != 2 {
+        return Err(anyhow!("macro $IF takes 2 arguments"));
+    }
 
-                macro_decls.push(MacroDecl {
-                    name: "$IF".to_owned(),
-                    mode: MacroDeclMode::Intrinsic(intrinsic_macro_decl_if),
-                });
+    let condition = parse_condition(&args[0], ctx)?;
+    *previous_condition = Some(condition);
+    Ok(if condition { args[1].clone() } else { String::new() })
+}
 
-                for next in pairs {
-                    match next.as_rule() {
-                        Rule::macro_decl => macro_decls.push(parse_macro_decl(next.into_inner())?),
-                        _ => (),
-                    }
-                }
-                macro_decls
-            }),
+fn intrinsic_macro_decl_ifnot(args: &[String], ctx: &PreprocessorContext, previous_condition: &mut Option<bool>) -> Result<String> {
+    if args.len() != 2 {
+        return Err(anyhow!("macro $IFNOT takes 2 arguments"));
+    }
+
+    // `$ELSE` is taken if the body of `$IFNOT` isn't
+    let condition = !parse_condition(&args[0], ctx)?;
+    *previous_condition = Some(condition);
+    Ok(if condition { args[1].clone() } else { String::new() })
+}
+
+fn intrinsic_macro_decl_else(args: &[String], _ctx: &PreprocessorContext, previous_condition: &mut Option<bool>) -> Result<String> {
+    if args.len() != 1 {
+        return Err(anyhow!("macro $ELSE takes 1 argument"));
+    }
+
+    match previous_condition.take() {
+        Some(condition) => Ok(if condition { String::new() } else { args[0].clone() }),
+        None => Err(anyhow!("macro $ELSE must directly follow $IF or $IFNOT")),
+    }
+}
+
+fn intrinsic_macro_decl_defined(args: &[String], ctx: &PreprocessorContext, previous_condition: &mut Option<bool>) -> Result<String> {
+    if args.len() != 1 {
+        return Err(anyhow!("macro $DEFINED takes 1 argument"));
+    }
+
+    *previous_condition = None;
+    Ok(ctx.is_defined(args[0].trim()).to_string())
+}
+
+fn parse_preprocessor_program(code: &str) -> Result<Pairs<'_, Rule>> {
+    match SyntaxParser::parse(Rule::program, code) {
+        Ok(mut pairs) => match pairs.next() {
+            Some(pair) => Ok(pair.into_inner()),
             None => Err(anyhow!("no input provided")),
         },
-        Err(msg) => Err(anyhow!("Preprocessor panic: {}", msg)),
+        Err(e) => {
+            let (start, end) = match e.location {
+                InputLocation::Pos(pos) => (pos, pos),
+                InputLocation::Span(span) => span,
+            };
+            Err(PreprocessorError {
+                message: format!("invalid macro syntax: {}", e.variant.message()),
+                loc: TokenLocation {
+                    start,
+                    end,
+                },
+                expansions: Vec::new(),
+            }
+            .into())
+        }
+    }
+}
+
+fn parse_user_macro_decls(code: &str) -> Result<Vec<MacroDecl>> {
+    let mut macro_decls: Vec<MacroDecl> = Vec::new();
+    for next in parse_preprocessor_program(code)? {
+        if next.as_rule() == Rule::macro_decl {
+            let span = next.as_span();
+            let decl = parse_macro_decl(next.into_inner())?;
+            if macro_decls.iter().any(|existing| existing.name == decl.name) {
+                return Err(PreprocessorError::new(format!("macro `{}` is declared more than once", decl.name), &span).into());
+            }
+            macro_decls.push(decl);
+        }
+    }
+    Ok(macro_decls)
+}
+
+/// Looks up the macros named by a `macro import` statement in the macros registered with the context.
+fn resolve_macro_import(import: Pair<Rule>, ctx: &PreprocessorContext) -> Result<Vec<MacroDecl>> {
+    let span = import.as_span();
+    let path = import.into_inner().nth(1).unwrap(); // skip "macro" keyword
+    let mut components: Vec<Pair<Rule>> = path.into_inner().collect();
+    let target = components.pop().unwrap();
+    let namespace = components.iter().map(|component| component.as_str()).collect::<Vec<_>>().join("::");
+
+    let decls = match ctx.macros.get(&namespace) {
+        Some(decls) => decls,
+        None => return Err(PreprocessorError::new(format!("no macros are declared in namespace `{}`", namespace), &span).into()),
+    };
+    match target.as_rule() {
+        Rule::macro_wildcard => Ok(decls.clone()),
+        Rule::macro_name => match decls.iter().find(|decl| decl.name == target.as_str()) {
+            Some(decl) => Ok(vec![decl.clone()]),
+            None => {
+                Err(PreprocessorError::new(format!("no such macro `{}` in namespace `{}`", target.as_str(), namespace), &span).into())
+            }
+        },
+        x => unreachable!("{:?}", x),
+    }
+}
+
+fn parse_all_macro_decls(code: &str, ctx: &PreprocessorContext) -> Result<Vec<MacroDecl>> {
+    let mut macro_decls = Vec::new();
+
+    let intrinsics: [(&str, IntrinsicMacroDecl); 4] = [
+        ("$IF", intrinsic_macro_decl_if),
+        ("$IFNOT", intrinsic_macro_decl_ifnot),
+        ("$ELSE", intrinsic_macro_decl_else),
+        ("$DEFINED", intrinsic_macro_decl_defined),
+    ];
+    for (name, intrinsic) in intrinsics {
+        macro_decls.push(MacroDecl {
+            name: name.to_owned(),
+            mode: MacroDeclMode::Intrinsic(intrinsic),
+        });
+    }
+
+    macro_decls.extend(parse_user_macro_decls(code)?);
+    for next in parse_preprocessor_program(code)? {
+        if next.as_rule() == Rule::macro_import {
+            for decl in resolve_macro_import(next, ctx)? {
+                // macros declared in the file take precedence over imported ones
+                if !macro_decls.iter().any(|existing| existing.name == decl.name) {
+                    macro_decls.push(decl);
+                }
+            }
+        }
+    }
+    Ok(macro_decls)
+}
+
+/// Substitutes the arguments of a macro call for the parameters in the body of a user defined macro. Only whole
+/// parameter names are replaced (so `#name` leaves `#names` alone), and the substituted arguments are never scanned for
+/// parameters themselves.
+fn substitute_macro_args(body: &str, params: &[String], args: &[String]) -> String {
+    let mut substituted = String::with_capacity(body.len());
+    let mut rest = body;
+    while let Some(index) = rest.find('#') {
+        substituted.push_str(&rest[..index]);
+        rest = &rest[index..];
+
+        let name_len = rest[1..].find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).map_or(rest.len(), |len| len + 1);
+        let name = &rest[..name_len];
+        match params.iter().position(|param| param == name) {
+            Some(i) => substituted.push_str(&args[i]),
+            None => substituted.push_str(name),
+        }
+        rest = &rest[name_len..];
     }
+    substituted.push_str(rest);
+    substituted
 }
 
-fn parse_program(pairs: Pairs<Rule>, macro_decls: &[MacroDecl], ctx: &PreprocessorContext) -> Result<String> {
+fn parse_program(pairs: Pairs<Rule>, macro_decls: &[MacroDecl], ctx: &PreprocessorContext) -> Result<(String, Vec<Segment>)> {
     let mut processed = String::new();
+    let mut segments = Vec::new();
+    let mut previous_condition = None;
     for next in pairs {
-        match next.as_rule() {
-            Rule::macro_decl => (),
+        let span = next.as_span();
+        let start = processed.len();
+        let origin = match next.as_rule() {
+            Rule::macro_decl | Rule::macro_import => continue,
             Rule::macro_call => {
                 let call = parse_macro_call(next.into_inner())?;
                 let decl = match macro_decls.iter().find(|decl| decl.name == call.name) {
                     Some(decl) => decl,
-                    None => panic!("no such macro `{}(...)`", call.name),
+                    None => return Err(PreprocessorError::new(format!("no such macro `{}(...)`", call.name), &span).into()),
                 };
 
                 match &decl.mode {
-                    MacroDeclMode::Intrinsic(intrinsic) => write!(&mut processed, "{}\n\n", intrinsic(&call.args, ctx))?,
+                    MacroDeclMode::Intrinsic(intrinsic) => {
+                        let expanded =
+                            intrinsic(&call.args, ctx, &mut previous_condition).map_err(|e| PreprocessorError::new(e.to_string(), &span))?;
+                        write!(&mut processed, "{}", expanded)?;
+
+                        // the selected argument of `$IF`, `$IFNOT` and `$ELSE` keeps its location in the input
+                        let selected = if expanded.is_empty() { None } else { call.args.iter().position(|arg| *arg == expanded) };
+                        if let Some(i) = selected {
+                            segments.push(Segment {
+                                start,
+                                end: processed.len(),
+                                origin: SegmentOrigin::Selected(call.arg_offsets[i]),
+                            });
+                            write!(&mut processed, "\n\n")?;
+                            continue;
+                        }
+                    }
                     MacroDeclMode::UserDefined {
                         args,
                         text,
                     } => {
+                        previous_condition = None;
                         if args.len() != call.args.len() {
-                            panic!("expecting {} args for macro `{}(....)`, but received {}", args.len(), call.name, call.args.len());
+                            return Err(PreprocessorError::new(
+                                format!("expecting {} args for macro `{}(...)`, but received {}", args.len(), call.name, call.args.len()),
+                                &span,
+                            )
+                            .into());
                         }
 
-                        let mut replaced = text[0..text.len() - "end macro".len()].trim().to_owned();
-                        for i in 0..args.len() {
-                            replaced = replaced.replace(&args[i], &call.args[i]);
-                        }
-
-                        write!(&mut processed, "{}\n\n", replaced)?
+                        let body = text[0..text.len() - "end macro".len()].trim();
+                        write!(&mut processed, "{}", substitute_macro_args(body, args, &call.args))?
                     }
                 }
+                SegmentOrigin::Expansion {
+                    name: call.name,
+                    call_site: TokenLocation {
+                        start: span.start(),
+                        end: span.end(),
+                    },
+                }
             }
             Rule::raw_text => {
-                write!(&mut processed, "{}\n\n", next.as_str())?;
+                previous_condition = None;
+                write!(&mut processed, "{}", next.as_str())?;
+                SegmentOrigin::Input(span.start())
             }
-            Rule::EOI => return Ok(processed),
+            Rule::EOI => break,
             x => unreachable!("{:#?}", x),
-        }
+        };
+        segments.push(Segment {
+            start,
+            end: processed.len(),
+            origin,
+        });
+        write!(&mut processed, "\n\n")?;
     }
 
-    Ok(processed)
+    Ok((processed, segments))
 }
 
-fn preprocess_round(code: &str, decls: &[MacroDecl], ctx: &PreprocessorContext) -> Result<String> {
-    let result = SyntaxParser::parse(Rule::program, code);
-    match result {
-        Ok(mut pairs) => match pairs.next() {
-            Some(pair) => Ok(parse_program(pair.into_inner(), decls, ctx)?),
-            None => Err(anyhow!("no input provided")),
-        },
-        Err(msg) => Err(anyhow!("Preprocessor panic: {}", msg)),
-    }
+fn preprocess_round(code: &str, decls: &[MacroDecl], ctx: &PreprocessorContext) -> Result<(String, Vec<Segment>)> {
+    parse_program(parse_preprocessor_program(code)?, decls, ctx)
 }
 
 pub fn preprocess(code: &str, ctx: &PreprocessorContext) -> Result<String> {
-    // Recursively apply preprocessing until all mecro invocations have been resolved.
+    Ok(preprocess_with_source_map(code, ctx)?.0)
+}
+
+/// Preprocesses `code` and returns a [`SourceMap`] that translates locations in the result back into `code`.
+/// Errors in the macros are reported as a [`PreprocessorError`] located in `code`.
+pub fn preprocess_with_source_map(code: &str, ctx: &PreprocessorContext) -> Result<(String, SourceMap)> {
+    // Recursively apply preprocessing until all macro invocations have been resolved.
     let mut current_code = code.to_owned();
-    let decls = parse_all_macro_decls(code)?;
-    for _ in 0..8 {
-        let next_code = preprocess_round(&current_code, &decls, ctx)?;
-        if current_code == next_code {
-            return Ok(next_code);
+    let mut source_map = SourceMap::new(code);
+    let decls = parse_all_macro_decls(code, ctx)?;
+    // the round after the last allowed one only checks that no macro calls are left
+    for round in 0..=MAX_EXPANSION_DEPTH {
+        let (next_code, segments) = match preprocess_round(&current_code, &decls, ctx) {
+            Ok(result) => result,
+            Err(e) => {
+                return Err(match e.downcast::<PreprocessorError>() {
+                    Ok(e) => source_map.locate(e).into(),
+                    Err(e) => e,
+                })
+            }
+        };
+        let has_expansions =
+            segments.iter().any(|segment| matches!(segment.origin, SegmentOrigin::Expansion { .. } | SegmentOrigin::Selected(_)));
+        if current_code != next_code {
+            source_map.push_round(segments);
+        }
+        if !has_expansions {
+            return Ok((next_code, source_map));
+        }
+        if round == MAX_EXPANSION_DEPTH {
+            break;
         }
         current_code = next_code;
     }
-    Err(anyhow!("Preprocessor panic: maximum recursion depth (8) reached"))
+
+    // a macro that expands to a call of itself would otherwise silently remain unexpanded
+    match source_map.expansions.last() {
+        Some(expansion) => Err(PreprocessorError {
+            message: format!("macro expansion exceeds the maximum depth of {}; is `{}(...)` recursive?", MAX_EXPANSION_DEPTH, expansion.name),
+            loc: expansion.call_site.clone(),
+            expansions: source_map.get_expansions(&expansion.call_site),
+        }
+        .into()),
+        // only conditional macros were expanded, which have no call site to report
+        None => Err(anyhow!("macro expansion exceeds the maximum depth of {}", MAX_EXPANSION_DEPTH)),
+    }
+}
+
+/// Preprocesses `code` and prefixes the result with a comment that lists the line and column of every macro call that
+/// was expanded, to show what the macros of a file expand to.
+pub fn expand(code: &str, ctx: &PreprocessorContext) -> Result<String> {
+    let (processed, source_map) = preprocess_with_source_map(code, ctx)?;
+    let mut expanded = String::new();
+    for expansion in &source_map.expansions {
+        if let Some((line, col)) = source_map.get_line_col(expansion.call_site.start) {
+            writeln!(&mut expanded, "// expanded `{}(...)` at {}:{}", expansion.name, line, col)?;
+        }
+    }
+    expanded.push_str(&processed);
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the line and column in `code` of the first occurrence of `token` in the preprocessed code.
+    fn original_line_col(code: &str, ctx: &PreprocessorContext, token: &str) -> (usize, usize) {
+        let (processed, source_map) = preprocess_with_source_map(code, ctx).unwrap();
+        let start = processed.find(token).unwrap();
+        let loc = source_map.translate(&TokenLocation {
+            start,
+            end: start + token.len(),
+        });
+        source_map.get_line_col(loc.start).unwrap()
+    }
+
+    fn error_line_col(code: &str, ctx: &PreprocessorContext) -> (usize, usize, Vec<String>) {
+        let error = preprocess_with_source_map(code, ctx).unwrap_err().downcast::<PreprocessorError>().unwrap();
+        let (line, col) = SourceMap::new(code).get_line_col(error.loc.start).unwrap();
+        (line, col, error.expansions)
+    }
+
+    #[test]
+    fn test_conditional_compilation() {
+        let code = "$IF(FAST && !$DEFINED(DEBUG), ```fast```)\n$ELSE(```slow```)\n$IFNOT(ARCH == x86_64, ```other```)\n";
+        let mut ctx = PreprocessorContext::for_target("aarch64-unknown-linux-gnu", false);
+        assert_eq!(preprocess(code, &ctx).unwrap().split_whitespace().collect::<Vec<_>>(), ["slow", "other"]);
+
+        ctx.define_flag("FAST");
+        assert_eq!(preprocess(code, &ctx).unwrap().split_whitespace().collect::<Vec<_>>(), ["fast", "other"]);
+
+        // a define that is set to false doesn't satisfy the condition, even though it's defined
+        ctx.define("FAST", "false");
+        assert_eq!(preprocess(code, &ctx).unwrap().split_whitespace().collect::<Vec<_>>(), ["slow", "other"]);
+    }
+
+    #[test]
+    fn test_macro_arg_substitution() {
+        let code = "macro $FIELD(#name, #names)\n    let #names_#name = #name + #names\nend macro\n$FIELD(a, #name)\n";
+        // only whole parameter names are replaced, and the substituted arguments aren't scanned for parameters
+        assert_eq!(preprocess(code, &PreprocessorContext::new()).unwrap().trim(), "let #names_a = a + #name");
+    }
+
+    #[test]
+    fn test_macro_imports() {
+        let mut ctx = PreprocessorContext::new();
+        ctx.register_macros("namespace core::test\n\nmacro $ONE()\n    1\nend macro\nmacro $TWO()\n    2\nend macro\n").unwrap();
+
+        let code = "namespace app\nmacro import core::test::$ONE\n$ONE()\n";
+        assert_eq!(preprocess(code, &ctx).unwrap().split_whitespace().collect::<Vec<_>>(), ["namespace", "app", "1"]);
+        let code = "macro import core::test::*\n$ONE() $TWO()\n";
+        assert_eq!(preprocess(code, &ctx).unwrap().split_whitespace().collect::<Vec<_>>(), ["1", "2"]);
+
+        // macros declared in the file take precedence over imported ones
+        let code = "macro import core::test::*\nmacro $ONE()\n    one\nend macro\n$ONE()\n";
+        assert_eq!(preprocess(code, &ctx).unwrap().trim(), "one");
+
+        let error = preprocess("macro import core::test::$THREE\n", &ctx).unwrap_err();
+        assert_eq!(error.to_string(), "no such macro `$THREE` in namespace `core::test`");
+        let error = preprocess("macro import core::other::*\n", &ctx).unwrap_err();
+        assert_eq!(error.to_string(), "no macros are declared in namespace `core::other`");
+    }
+
+    #[test]
+    fn test_recursive_macro() {
+        let code = "macro $LOOP()\n    $LOOP()\nend macro\nlet a = 1\n$LOOP()\n";
+        let error = preprocess(code, &PreprocessorContext::new()).unwrap_err().downcast::<PreprocessorError>().unwrap();
+        assert_eq!(
+            error.message,
+            format!("macro expansion exceeds the maximum depth of {}; is `$LOOP(...)` recursive?", MAX_EXPANSION_DEPTH)
+        );
+        assert_eq!(SourceMap::new(code).get_line_col(error.loc.start), Some((5, 1)));
+    }
+
+    #[test]
+    fn test_nested_conditionals() {
+        let ctx = PreprocessorContext::for_target("x86_64-unknown-linux-gnu", true);
+        let nested = |depth: usize| "$IF(DEBUG, ```".repeat(depth) + "let a = 1" + &"```)".repeat(depth);
+        // every round expands one level, so the innermost conditional finishes in the last allowed round
+        assert_eq!(preprocess(&nested(MAX_EXPANSION_DEPTH), &ctx).unwrap().trim(), "let a = 1");
+
+        let error = preprocess(&nested(MAX_EXPANSION_DEPTH + 1), &ctx).unwrap_err();
+        assert_eq!(error.to_string(), format!("macro expansion exceeds the maximum depth of {}", MAX_EXPANSION_DEPTH));
+    }
+
+    #[test]
+    fn test_location_in_conditional_region() {
+        let code = "let a = 1\n$IF(OS == linux, ```\n    let b = 2\n    let c = 3\n```)\n$ELSE(```let d = 4```)\nlet e = 5\n";
+        let ctx = PreprocessorContext::for_target("x86_64-unknown-linux-gnu", true);
+        assert_eq!(original_line_col(code, &ctx, "let c"), (4, 5));
+        assert_eq!(original_line_col(code, &ctx, "let e"), (7, 1));
+
+        let ctx = PreprocessorContext::for_target("x86_64-pc-windows-msvc", true);
+        assert_eq!(original_line_col(code, &ctx, "let d"), (6, 10));
+    }
+
+    #[test]
+    fn test_location_in_macro_expansion() {
+        let code = "macro $ANSWER(#name)\n    let #name = 42\nend macro\n\nlet a = 1\n  $ANSWER(b)\n";
+        let ctx = PreprocessorContext::new();
+        // locations within the expansion of a user defined macro are reported at the macro call
+        assert_eq!(original_line_col(code, &ctx, "let b"), (6, 3));
+        assert_eq!(original_line_col(code, &ctx, "let a"), (5, 1));
+    }
+
+    #[test]
+    fn test_error_location_in_conditional_region() {
+        let code = "let a = 1\n$IF(DEBUG, ```\n    let b = 2\n    $MISSING(b)\n```)\n";
+        let ctx = PreprocessorContext::for_target("x86_64-unknown-linux-gnu", true);
+        let (line, col, expansions) = error_line_col(code, &ctx);
+        assert_eq!((line, col), (4, 5));
+        assert!(expansions.is_empty());
+    }
+
+    #[test]
+    fn test_error_location_in_macro_expansion() {
+        let code = "macro $BROKEN(#x)\n    $MISSING(#x)\nend macro\n\nlet a = 1\n    $BROKEN(a)\n";
+        let (line, col, expansions) = error_line_col(code, &PreprocessorContext::new());
+        assert_eq!((line, col), (6, 5));
+        assert_eq!(expansions, vec!["$BROKEN".to_owned()]);
+    }
 }
@@ -1,5 +1,6 @@
 use super::ClassType;
 use crate::common::types::AnonymousStructField;
+use crate::parser::preprocessor::SourceMap;
 use crate::parser::Rule;
 use crate::{
     common::types::{BasicType, ComplexType},
@@ -132,10 +133,7 @@ impl QualifiedType {
 
     pub fn from_idents(idents: Pair<Rule>) -> QualifiedType {
         QualifiedType {
-            loc: TokenLocation {
-                start: idents.as_span().start(),
-                end: idents.as_span().end(),
-            },
+            loc: parser::span_to_location(&idents.as_span()),
             complex: { Self::parse_next(idents.into_inner()) },
         }
     }
@@ -153,6 +151,9 @@ pub struct KeidFile {
     pub fields: Vec<Let>,
     pub attributes: Vec<AttributeDecl>,
     pub enums: Vec<EnumDecl>,
+    /// The locations in this file already refer to the original source; the source map provides the macro expansions
+    /// and line numbers of those locations.
+    pub source_map: SourceMap,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -192,6 +193,7 @@ pub struct AttributeDecl {
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct ClassDecl {
+    pub is_public: bool,
     pub ty: ClassType,
     pub name: Vec<Token<Identifier>>,
     pub generics: Option<Vec<GenericDecl>>,
@@ -220,10 +222,12 @@ pub struct AnonymousStructTypeDecl {
 pub struct EnumElementDecl {
     pub name: Token<Identifier>,
     pub data: Option<AnonymousStructTypeDecl>,
+    pub discriminant: Option<Token<Expr>>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct EnumDecl {
+    pub is_public: bool,
     pub name: Vec<Token<Identifier>>,
     pub generics: Option<Vec<GenericDecl>>,
     pub elements: Vec<EnumElementDecl>,
@@ -311,6 +315,7 @@ pub struct Assign {
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Let {
+    pub is_public: bool,
     pub is_const: bool,
     pub name: Token<Identifier>,
     pub var_type: Option<QualifiedType>,
@@ -396,11 +401,19 @@ pub struct FixedBlock {
     pub block: Vec<Token<Statement>>,
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub struct CatchClause {
+    pub error_var: Option<Token<Identifier>>,
+    /// The class of errors caught by this clause; catches every error if omitted.
+    pub error_type: Option<QualifiedType>,
+    pub block: Vec<Token<Statement>>,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct TryCatch {
     pub try_block: Vec<Token<Statement>>,
-    pub error_var: Option<Token<Identifier>>,
-    pub catch_block: Vec<Token<Statement>>,
+    pub catch_clauses: Vec<CatchClause>,
+    pub finally_block: Option<Vec<Token<Statement>>>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -419,6 +432,8 @@ pub enum Statement {
     IndefiniteLoop(Vec<Token<Statement>>),
     Unreachable,
     Throw(Token<Expr>),
+    /// A bare `throw` within a catch clause, which rethrows the caught error.
+    Rethrow,
     TryCatch(TryCatch),
 }
 
@@ -474,6 +489,8 @@ pub struct Range {
 pub enum MemberType {
     Root,
     Class,
+    /// A member accessed with `?.`, which evaluates to `null` instead when the value it is accessed on is `null`.
+    NullSafeClass,
     Array,
 }
 
@@ -497,19 +514,43 @@ pub struct EnumWithDataExpr {
 }
 
 #[derive(Debug, Clone, PartialEq)]
-pub enum MatchExprBranchArg {
-    Catchall(TokenLocation),
-    Enum(Token<Identifier>),
-    EnumWithData {
+pub struct MatchPatternField {
+    pub name: Token<Identifier>,
+    /// The pattern that the field must match. If omitted, the field is bound to a local with the same name.
+    pub pattern: Option<Token<MatchPattern>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MatchPattern {
+    /// `_`, which matches any value.
+    Catchall,
+    /// `Variant` or `Variant { field, other: pattern }`.
+    /// A bare identifier that doesn't name a variant of the matched type is compared like a constant.
+    Enum {
         member: Token<Identifier>,
-        data_type: AnonymousStructTypeDecl,
+        fields: Option<Vec<MatchPatternField>>,
+    },
+    /// `start..end`, which matches integers in the half-open interval `[start, end)`.
+    Range {
+        start: Token<Expr>,
+        end: Token<Expr>,
+    },
+    /// `name @ pattern`, which binds the whole matched value to `name`.
+    Binding {
+        name: Token<Identifier>,
+        pattern: Box<Token<MatchPattern>>,
     },
+    /// `a | b`, which matches if any of the alternatives match.
+    Alternatives(Vec<Token<MatchPattern>>),
+    /// A literal, compared to the matched value by equality.
     Expr(Token<Expr>),
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct MatchExprBranch {
-    pub arg: MatchExprBranchArg,
+    pub pattern: Token<MatchPattern>,
+    /// The condition following `if`, which must also be true for the branch to be taken.
+    pub guard: Option<Token<Expr>>,
     pub statement: Token<Statement>,
 }
 
@@ -0,0 +1,151 @@
+use super::ast::*;
+use crate::{
+    common::{types::BasicType, Result},
+    compiler_error_loc,
+};
+
+/// A value that is known at compile time.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum StaticExpr {
+    Integer(i64),
+    String(String),
+    Bool(bool),
+}
+
+impl StaticExpr {
+    fn type_name(&self) -> &'static str {
+        match self {
+            StaticExpr::Integer(_) => "integer",
+            StaticExpr::String(_) => "string",
+            StaticExpr::Bool(_) => "bool",
+        }
+    }
+
+    /// Returns whether the constant can be stored in a value of type `ty` without being truncated. Only integers can be
+    /// out of range; whether the other constants are assignable to `ty` is checked like for any other value.
+    pub fn fits_in(&self, ty: &BasicType) -> bool {
+        let val = match self {
+            StaticExpr::Integer(val) => *val,
+            _ => return true,
+        };
+        match ty {
+            BasicType::UInt8 => u8::try_from(val).is_ok(),
+            BasicType::UInt16 => u16::try_from(val).is_ok(),
+            BasicType::UInt32 => u32::try_from(val).is_ok(),
+            BasicType::UInt64 | BasicType::USize => val >= 0,
+            BasicType::Int8 => i8::try_from(val).is_ok(),
+            BasicType::Int16 => i16::try_from(val).is_ok(),
+            BasicType::Int32 => i32::try_from(val).is_ok(),
+            _ => true,
+        }
+    }
+
+    /// Evaluates an expression made of literals, references to other constants, and operators applied to them.
+    /// `resolve` is called with the path of every referenced name, and returns the value of the constant it names, if any.
+    ///
+    /// If the expression cannot be evaluated at compile time (e.g. because it calls a function), [`None`] is returned.
+    /// An error is only returned if the expression is constant but invalid, such as an overflowing operation.
+    pub fn evaluate(expr: &Token<Expr>, resolve: &mut dyn FnMut(&Qualifier) -> Result<Option<StaticExpr>>) -> Result<Option<StaticExpr>> {
+        Ok(Some(match &expr.token {
+            Expr::BoolLit(val) => StaticExpr::Bool(*val),
+            Expr::SignedIntLit(val) => StaticExpr::Integer(*val),
+            Expr::StringLit(val) => StaticExpr::String(val.clone()),
+            Expr::Ident(ident) => match resolve(&Qualifier(vec![ident.clone()]))? {
+                Some(val) => val,
+                None => return Ok(None),
+            },
+            Expr::Member(member_expr) => {
+                if let ([member], None) = (&member_expr.members[..], &member_expr.prefix)
+                    && !matches!(member.value.token, Expr::Ident(_))
+                {
+                    return StaticExpr::evaluate(&member.value, resolve);
+                }
+
+                // only paths such as `namespace.CONSTANT` can name a constant
+                let mut path = member_expr.prefix.clone().map(|prefix| prefix.0).unwrap_or_default();
+                for member in &member_expr.members {
+                    match (&member.ty, &member.value.token) {
+                        (MemberType::Root | MemberType::Class, Expr::Ident(ident)) => path.push(ident.clone()),
+                        _ => return Ok(None),
+                    }
+                }
+                match resolve(&Qualifier(path))? {
+                    Some(val) => val,
+                    None => return Ok(None),
+                }
+            }
+            Expr::Unary(unary) => {
+                let val = match StaticExpr::evaluate(&unary.value, resolve)? {
+                    Some(val) => val,
+                    None => return Ok(None),
+                };
+                match (unary.op, val) {
+                    (Operator::Not, StaticExpr::Bool(val)) => StaticExpr::Bool(!val),
+                    (op, val) => {
+                        return Err(compiler_error_loc!(
+                            &expr.loc,
+                            "Operator `{:?}` cannot be applied to a constant of type `{}`",
+                            op,
+                            val.type_name()
+                        ))
+                    }
+                }
+            }
+            Expr::Logic(logic) => {
+                let (lhs, rhs) = match (StaticExpr::evaluate(&logic.lhs, resolve)?, StaticExpr::evaluate(&logic.rhs, resolve)?) {
+                    (Some(lhs), Some(rhs)) => (lhs, rhs),
+                    _ => return Ok(None),
+                };
+                StaticExpr::evaluate_logic(&expr.loc, lhs, logic.op, rhs)?
+            }
+            _ => return Ok(None),
+        }))
+    }
+
+    fn evaluate_logic(loc: &TokenLocation, lhs: StaticExpr, op: Operator, rhs: StaticExpr) -> Result<StaticExpr> {
+        let overflow = || compiler_error_loc!(loc, "Constant expression overflows");
+        Ok(match (lhs, rhs) {
+            (StaticExpr::Integer(lhs), StaticExpr::Integer(rhs)) => match op {
+                Operator::Add => StaticExpr::Integer(lhs.checked_add(rhs).ok_or_else(overflow)?),
+                Operator::Subtract => StaticExpr::Integer(lhs.checked_sub(rhs).ok_or_else(overflow)?),
+                Operator::Multiply => StaticExpr::Integer(lhs.checked_mul(rhs).ok_or_else(overflow)?),
+                Operator::Divide | Operator::Modulus if rhs == 0 => {
+                    return Err(compiler_error_loc!(loc, "Division by zero in constant expression"))
+                }
+                Operator::Divide => StaticExpr::Integer(lhs.checked_div(rhs).ok_or_else(overflow)?),
+                Operator::Modulus => StaticExpr::Integer(lhs.checked_rem(rhs).ok_or_else(overflow)?),
+                Operator::LeftShift => StaticExpr::Integer(lhs.checked_shl(u32::try_from(rhs).map_err(|_| overflow())?).ok_or_else(overflow)?),
+                Operator::RightShift => StaticExpr::Integer(lhs.checked_shr(u32::try_from(rhs).map_err(|_| overflow())?).ok_or_else(overflow)?),
+                Operator::Equals => StaticExpr::Bool(lhs == rhs),
+                Operator::NotEquals => StaticExpr::Bool(lhs != rhs),
+                Operator::LessThan => StaticExpr::Bool(lhs < rhs),
+                Operator::GreaterThan => StaticExpr::Bool(lhs > rhs),
+                Operator::LessThanOrEquals => StaticExpr::Bool(lhs <= rhs),
+                Operator::GreaterThanOrEquals => StaticExpr::Bool(lhs >= rhs),
+                op => return Err(compiler_error_loc!(loc, "Operator `{:?}` cannot be applied to constants of type `integer`", op)),
+            },
+            (StaticExpr::Bool(lhs), StaticExpr::Bool(rhs)) => match op {
+                Operator::BooleanAnd => StaticExpr::Bool(lhs && rhs),
+                Operator::BooleanOr => StaticExpr::Bool(lhs || rhs),
+                Operator::Equals => StaticExpr::Bool(lhs == rhs),
+                Operator::NotEquals => StaticExpr::Bool(lhs != rhs),
+                op => return Err(compiler_error_loc!(loc, "Operator `{:?}` cannot be applied to constants of type `bool`", op)),
+            },
+            (StaticExpr::String(lhs), StaticExpr::String(rhs)) => match op {
+                Operator::Add => StaticExpr::String(lhs + &rhs),
+                Operator::Equals => StaticExpr::Bool(lhs == rhs),
+                Operator::NotEquals => StaticExpr::Bool(lhs != rhs),
+                op => return Err(compiler_error_loc!(loc, "Operator `{:?}` cannot be applied to constants of type `string`", op)),
+            },
+            (lhs, rhs) => {
+                return Err(compiler_error_loc!(
+                    loc,
+                    "Cannot apply operator `{:?}` to constants of type `{}` and `{}`",
+                    op,
+                    lhs.type_name(),
+                    rhs.type_name()
+                ))
+            }
+        })
+    }
+}
@@ -5,6 +5,9 @@ use anyhow::anyhow;
 use bincode::{Decode, Encode};
 pub use parser::*;
 
+mod constant;
+pub use constant::*;
+
 pub mod ast;
 
 use self::ast::*;
@@ -199,17 +202,57 @@ pub struct TypedefImplNode {
     pub target_type: ComplexType,
 }
 
+/// Describes where a declaration can be accessed from.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Visibility {
+    /// The declaration was marked `public` and can be accessed from any namespace.
+    Public,
+    /// The declaration can only be accessed from within the given namespace, any namespace nested within it, or its parent
+    /// namespace, e.g. `std::fs` can access the private declarations of `std::fs::impl`.
+    Private(String),
+}
+
+impl Visibility {
+    pub fn new(is_public: bool, namespace: &str) -> Visibility {
+        if is_public {
+            Visibility::Public
+        } else {
+            Visibility::Private(namespace.to_owned())
+        }
+    }
+
+    pub fn is_accessible_from(&self, namespace: &str) -> bool {
+        match self {
+            Visibility::Public => true,
+            Visibility::Private(owner) => {
+                is_nested_within(namespace, owner) || matches!(owner.rsplit_once("::"), Some((parent, _)) if parent == namespace)
+            }
+        }
+    }
+}
+
+/// Returns whether `namespace` is `outer` or a namespace nested within it.
+fn is_nested_within(namespace: &str, outer: &str) -> bool {
+    namespace == outer || (namespace.starts_with(outer) && namespace[outer.len()..].starts_with("::"))
+}
+
 #[derive(Debug, Clone)]
 pub struct FieldNode {
     pub name: String,
     pub ty: ComplexType,
     pub initial_value: Option<Token<Expr>>,
+    pub visibility: Visibility,
+    /// Whether the field was declared with `const`, in which case it can only be assigned by its initializer.
+    pub is_const: bool,
+    /// The value of a `const` field whose initializer can be evaluated at compile time.
+    pub constant_value: Option<StaticExpr>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct ResolvedFieldNode {
     pub name: String,
     pub ty: ComplexType,
+    pub visibility: Visibility,
 }
 
 #[derive(Debug, Clone)]
@@ -238,6 +281,8 @@ pub enum ClassType {
 pub struct EnumElementNode {
     pub name: String,
     pub data: Option<Vec<AnonymousStructField>>,
+    /// The value stored in an instance of the enum to identify this variant.
+    pub discriminant: i64,
 }
 
 #[derive(Debug)]
@@ -252,6 +297,8 @@ pub struct EnumNode {
     pub generic_defs: Vec<GenericDefNode>,
     /// All of the elements defined by the enum.
     pub elements: Vec<EnumElementNode>,
+    /// Whether the enum can be referenced from outside of its namespace.
+    pub visibility: Visibility,
 }
 
 /// An implementation of a enum type, with only concrete types in its implementation.
@@ -305,6 +352,7 @@ impl GenericNode for EnumNode {
                     Some(data) => Some(data?),
                     None => None,
                 },
+                discriminant: element_decl.discriminant,
             });
         }
 
@@ -341,6 +389,10 @@ pub struct ClassNode {
     /// The code for the class' destructor, if one is present.
     pub destructor: Option<Vec<Token<Statement>>>,
     pub class_type: ClassType,
+    /// Whether the class can be referenced from outside of its namespace.
+    pub visibility: Visibility,
+    /// The location of the class name in its source file.
+    pub loc: TokenLocation,
 }
 
 impl GenericNode for ClassNode {
@@ -356,18 +408,39 @@ impl GenericNode for ClassNode {
             write!(&mut full_name, "<{}>", utils::iter_join(generic_args)).unwrap();
         }
 
+        let superclass = match &self.superclass {
+            Some(superclass) => Some(GenericIdentifier::from_name_with_args(
+                &superclass.name,
+                &superclass
+                    .generic_args
+                    .iter()
+                    .map(|arg| extract_type(type_provider, arg.clone(), &self.generic_defs, generic_args))
+                    .collect::<anyhow::Result<Vec<_>>>()?,
+            )),
+            None => None,
+        };
+
+        // the fields of a superclass are laid out before the fields declared by the class itself,
+        // so an instance of a class is always a valid instance of each of its superclasses
         let mut fields = Vec::new();
+        if self.class_type == ClassType::Class && let Some(superclass) = &superclass {
+            match type_provider.get_class_by_name(superclass) {
+                Some(superclass_impl) => fields.extend(superclass_impl.fields),
+                None => return Err(anyhow!("could not resolve superclass `{}`", superclass.to_string())),
+            }
+        }
         for field_decl in &self.fields {
             fields.push(ResolvedFieldNode {
                 name: field_decl.name.clone(),
                 ty: extract_type(type_provider, field_decl.ty.clone(), &self.generic_defs, generic_args)?,
+                visibility: field_decl.visibility.clone(),
             });
         }
 
         Ok(ResolvedClassNode {
             source_id: self.id,
             module_id: self.module_id,
-            superclass: self.superclass.clone(),
+            superclass,
             generic_impls: generic_args.to_vec(),
             full_name,
             fields,
@@ -388,9 +461,10 @@ pub struct ResolvedClassNode {
     /// The concrete types passed as the generic parameters to the function.
     pub generic_impls: Vec<ComplexType>,
 
+    /// The name and concrete generic arguments of the superclass, if any.
     pub superclass: Option<GenericIdentifier>,
 
-    /// The types of the fields declared in the class,
+    /// The types of the fields declared in the class, preceded by all of the fields inherited from its superclasses.
     pub fields: Vec<ResolvedFieldNode>,
     pub class_type: ClassType,
 }
@@ -442,6 +516,10 @@ pub struct FunctionNode {
 
     pub modifiers: Vec<FunctionModifier>,
 
+    /// Whether the function can be called from outside of its namespace.
+    /// Interface methods and interface implementations are always public.
+    pub visibility: Visibility,
+
     /// The name of the function as callable from within Keid, excluding generic arguments.
     /// For example, `std::io::File::open`, `core::collections::PositionalStorage::set_element`, etc
     pub base_name: String,
@@ -468,6 +546,9 @@ pub struct FunctionNode {
     pub body: Option<Vec<Token<Statement>>>,
 
     pub varargs: Varargs,
+
+    /// The location of the function name in its source file.
+    pub loc: TokenLocation,
 }
 
 impl GenericNode for FunctionNode {
@@ -585,3 +666,25 @@ impl ToString for ResolvedFunctionNode {
         format!("`{}({}): {}`", self.callable_name, param_str, self.return_type.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Visibility;
+
+    #[test]
+    fn private_declarations_are_accessible_from_nested_and_parent_namespaces() {
+        let visibility = Visibility::Private("std::fs::impl".to_owned());
+        assert!(visibility.is_accessible_from("std::fs::impl"));
+        assert!(visibility.is_accessible_from("std::fs::impl::linux"));
+        assert!(visibility.is_accessible_from("std::fs"));
+        assert!(!visibility.is_accessible_from("std"));
+        assert!(!visibility.is_accessible_from("std::io"));
+        assert!(!visibility.is_accessible_from("std::fs::implementation"));
+        assert!(!visibility.is_accessible_from("std::fsx"));
+    }
+
+    #[test]
+    fn public_declarations_are_accessible_from_any_namespace() {
+        assert!(Visibility::Public.is_accessible_from("std::io"));
+    }
+}
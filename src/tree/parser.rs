@@ -16,23 +16,6 @@ struct AstConverter<'a> {
 
 static mut TYPE_ID_NONCE: usize = 0;
 
-pub enum StaticExpr {
-    Integer(i64),
-    String(String),
-    Bool(bool),
-}
-
-impl StaticExpr {
-    pub fn parse(expr: &Token<Expr>) -> Result<StaticExpr> {
-        Ok(match &expr.token {
-            Expr::BoolLit(val) => StaticExpr::Bool(*val),
-            Expr::SignedIntLit(val) => StaticExpr::Integer(*val),
-            Expr::StringLit(val) => StaticExpr::String(val.clone()),
-            _ => return Err(compiler_error_loc!(&expr.loc, "Invalid dynamic expression (must be a static literal)")),
-        })
-    }
-}
-
 #[derive(Debug, Clone)]
 enum DeclParent {
     Class {
@@ -50,9 +33,10 @@ enum DeclParent {
 
 impl<'a> AstConverter<'a> {
     fn lookup_classlike(&self, resolved_name: &str, same_namespace: &str) -> Option<String> {
-        if self.lookup_items.iter().any(|item| item.name == resolved_name && !matches!(item.ty, LookupItemType::Typedef(_))) {
+        let is_classlike = |item: &LookupItem| !matches!(item.ty, LookupItemType::Typedef(_) | LookupItemType::Constant { .. });
+        if self.lookup_items.iter().any(|item| item.name == resolved_name && is_classlike(item)) {
             Some(resolved_name.to_owned())
-        } else if self.lookup_items.iter().any(|item| item.name == same_namespace && !matches!(item.ty, LookupItemType::Typedef(_))) {
+        } else if self.lookup_items.iter().any(|item| item.name == same_namespace && is_classlike(item)) {
             Some(same_namespace.to_owned())
         } else {
             None
@@ -60,7 +44,8 @@ impl<'a> AstConverter<'a> {
     }
 
     fn lookup_typedef(&self, resolved_name: &str, same_namespace: &str) -> Option<ComplexType> {
-        match self.lookup_items.iter().find(|item| item.name == resolved_name || item.name == same_namespace) {
+        let is_type = |item: &&LookupItem| !matches!(item.ty, LookupItemType::Constant { .. });
+        match self.lookup_items.iter().filter(is_type).find(|item| item.name == resolved_name || item.name == same_namespace) {
             Some(item) => match &item.ty {
                 LookupItemType::Typedef(ty) => Some(ty.clone()),
                 _ => None,
@@ -70,7 +55,7 @@ impl<'a> AstConverter<'a> {
     }
 
     fn get_all_type_names(&self) -> Vec<&String> {
-        self.lookup_items.iter().map(|item| &item.name).collect()
+        self.lookup_items.iter().filter(|item| !matches!(item.ty, LookupItemType::Constant { .. })).map(|item| &item.name).collect()
     }
 
     fn resolve_type(&self, ns: &str, original_type: &Qualifier, dst: &KeidModuleNode) -> Result<String> {
@@ -91,6 +76,47 @@ impl<'a> AstConverter<'a> {
         }
     }
 
+    /// Evaluates a constant expression that appears in namespace `ns`, in a file importing `imports`.
+    /// References to `const` globals are resolved across all modules.
+    fn evaluate_constant(&self, ns: &str, imports: &[String], expr: &Token<Expr>) -> Result<Option<StaticExpr>> {
+        self.evaluate_constant_with(ns, imports, expr, &mut Vec::new())
+    }
+
+    fn evaluate_constant_with(
+        &self,
+        ns: &str,
+        imports: &[String],
+        expr: &Token<Expr>,
+        evaluating: &mut Vec<String>,
+    ) -> Result<Option<StaticExpr>> {
+        StaticExpr::evaluate(expr, &mut |path| {
+            let mut candidates = vec![format!("{}::{}", ns, path.to_string())];
+            if path.0.len() > 1 {
+                for import in imports.iter().filter(|import| crate::func::utils::get_type_leaf(import) == path.0[0].token.0) {
+                    candidates.push(format!("{}::{}", import, Qualifier(path.0[1..].to_vec()).to_string()));
+                }
+            }
+            candidates.push(path.to_string());
+
+            for candidate in candidates {
+                let constant = self.lookup_items.iter().find_map(|item| match &item.ty {
+                    LookupItemType::Constant { value, imports } if item.name == candidate => Some((value, imports)),
+                    _ => None,
+                });
+                if let Some((value, imports)) = constant {
+                    if evaluating.contains(&candidate) {
+                        return Err(compiler_error_loc!(&path.get_location(), "Constant `{}` is defined in terms of itself", candidate));
+                    }
+                    evaluating.push(candidate.clone());
+                    let result = self.evaluate_constant_with(crate::func::utils::get_type_namespace(&candidate), imports, value, evaluating);
+                    evaluating.pop();
+                    return result;
+                }
+            }
+            Ok(None)
+        })
+    }
+
     fn get_type(
         &self,
         original_type: QualifiedType,
@@ -206,7 +232,10 @@ impl<'a> AstConverter<'a> {
                 modifiers: acc.modifiers,
                 name: vec![Token {
                     loc: acc.name.loc,
-                    token: Identifier(format!("__get_{}", acc.name.token.0)),
+                    token: Identifier(match acc.accessor_type {
+                        AccessorType::Getter => format!("__get_{}", acc.name.token.0),
+                        AccessorType::Setter(_) => format!("__set_{}", acc.name.token.0),
+                    }),
                 }],
                 generics: None,
                 return_type: match &acc.accessor_type {
@@ -269,13 +298,19 @@ impl<'a> AstConverter<'a> {
                 for attribute in func.attributes {
                     let attribute_type = self.resolve_type(ns, &attribute.attribute_type, dst)?;
                     if attribute_type == "core::runtime::ExternalFunction" {
-                        let static_expr = StaticExpr::parse(&attribute.params[0])?;
-                        match static_expr {
-                            StaticExpr::String(val) => break 'external_name val,
-                            _ => panic!(),
+                        let imports: Vec<String> = dst.imports.iter().map(|import| import.module.clone()).collect();
+                        let param = &attribute.params[0];
+                        match self.evaluate_constant(ns, &imports, param)? {
+                            Some(StaticExpr::String(val)) => break 'external_name val,
+                            Some(_) => return Err(compiler_error_loc!(&param.loc, "Expecting a string for the external function name")),
+                            None => return Err(compiler_error_loc!(&param.loc, "Attribute parameters must be constant expressions")),
                         }
                     } else {
-                        todo!("parsing attributes")
+                        return Err(compiler_error_loc!(
+                            &attribute.attribute_type.get_location(),
+                            "Unknown attribute `{}` on an external function",
+                            attribute_type
+                        ));
                     }
                 }
                 let external_name = base_name.split("::").last().unwrap().to_owned();
@@ -371,6 +406,9 @@ impl<'a> AstConverter<'a> {
         let return_type =
             func.return_type.map(|ty| self.get_type(ty, Some(&parent), dst, ns.to_owned())).unwrap_or(Ok(BasicType::Void.to_complex()))?;
 
+        // methods implementing an interface are as visible as the interface itself
+        let is_public = func.modifiers.contains(&FunctionModifier::Public) || matches!(class, Some(DeclParent::InterfaceImpl { .. }));
+
         let id = dst.functions.len();
         dst.functions.push(FunctionNode {
             module_id: self.module_id,
@@ -378,6 +416,7 @@ impl<'a> AstConverter<'a> {
             function_type,
             base_name,
             namespace_name: ns.to_owned(),
+            visibility: Visibility::new(is_public, ns),
             modifiers: func.modifiers,
             external_name,
             generic_defs: func_generic_defs,
@@ -385,27 +424,46 @@ impl<'a> AstConverter<'a> {
             return_type,
             body: func.body,
             varargs: func.varargs,
+            loc: Qualifier(func.name).get_location(),
         });
 
         Ok(id)
     }
 
     fn parse_field_decl(&self, field: Let, parent: Option<&DeclParent>, dst: &KeidModuleNode, ns: String) -> Result<FieldNode> {
+        let visibility = Visibility::new(field.is_public, &ns);
+        let constant_value = match (parent, field.is_const, &field.initial_value) {
+            (None, true, Some(initial_value)) => {
+                let imports: Vec<String> = dst.imports.iter().map(|import| import.module.clone()).collect();
+                self.evaluate_constant(&ns, &imports, initial_value)?
+            }
+            _ => None,
+        };
+        let ty = self.get_type(
+            match field.var_type {
+                Some(ty) => ty,
+                None => return Err(compiler_error_loc!(&field.name.loc, "Expecting field type")),
+            },
+            parent,
+            dst,
+            ns.clone(),
+        )?;
+        if let (Some(value @ StaticExpr::Integer(val)), ComplexType::Basic(basic_type)) = (&constant_value, &ty)
+            && !value.fits_in(basic_type)
+        {
+            let loc = &field.initial_value.as_ref().unwrap().loc;
+            return Err(compiler_error_loc!(loc, "Constant `{}` does not fit in `{}`", val, ty.to_string()));
+        }
         Ok(FieldNode {
             name: match parent {
                 Some(_) => field.name.token.0,
                 None => format!("{}::{}", ns, field.name.token.0),
             },
-            ty: self.get_type(
-                match field.var_type {
-                    Some(ty) => ty,
-                    None => return Err(compiler_error_loc!(&field.name.loc, "Expecting field type")),
-                },
-                parent,
-                dst,
-                ns,
-            )?,
+            ty,
             initial_value: field.initial_value,
+            visibility,
+            is_const: field.is_const,
+            constant_value,
         })
     }
 
@@ -527,8 +585,33 @@ impl<'a> AstConverter<'a> {
             associated_type_names: Vec::new(),
         };
 
-        let mut elements = Vec::new();
+        let imports: Vec<String> = dst.imports.iter().map(|import| import.module.clone()).collect();
+        let mut elements: Vec<EnumElementNode> = Vec::new();
+        let mut next_discriminant = 0;
         for element_decl in enm.elements {
+            // like in C, variants without an explicit discriminant follow the previous one
+            let discriminant = match &element_decl.discriminant {
+                Some(expr) => match self.evaluate_constant(ns, &imports, expr)? {
+                    Some(StaticExpr::Integer(val)) if i32::try_from(val).is_ok() => val,
+                    Some(StaticExpr::Integer(val)) => {
+                        return Err(compiler_error_loc!(&expr.loc, "Enum discriminant `{}` does not fit in 32 bits", val))
+                    }
+                    Some(_) => return Err(compiler_error_loc!(&expr.loc, "Enum discriminants must be integers")),
+                    None => return Err(compiler_error_loc!(&expr.loc, "Enum discriminants must be constant expressions")),
+                },
+                None => next_discriminant,
+            };
+            if let Some(other) = elements.iter().find(|element| element.discriminant == discriminant) {
+                return Err(compiler_error_loc!(
+                    &element_decl.name.loc,
+                    "Enum variant `{}` has the same discriminant as `{}` ({})",
+                    element_decl.name.token.0,
+                    other.name,
+                    discriminant
+                ));
+            }
+            next_discriminant = discriminant + 1;
+
             elements.push(EnumElementNode {
                 name: element_decl.name.token.0,
                 data: element_decl.data.map_or(Ok(None), |decl| {
@@ -548,6 +631,7 @@ impl<'a> AstConverter<'a> {
                         .collect::<Result<_>>()
                         .map(Some)
                 })?,
+                discriminant,
             });
         }
 
@@ -567,12 +651,14 @@ impl<'a> AstConverter<'a> {
             base_name,
             generic_defs,
             elements,
+            visibility: Visibility::new(enm.is_public, ns),
         });
 
         Ok(())
     }
 
     fn parse_class_decl(&self, ns: &str, class: ClassDecl, dst: &mut KeidModuleNode) -> Result<()> {
+        let loc = Qualifier(class.name.clone()).get_location();
         let base_name = Qualifier(class.name).to_string();
         let generic_defs: Vec<GenericDefNode> =
             class.generics.map(|decls| decls.into_iter().map(|decl| GenericDefNode::from_ast(&decl)).collect()).unwrap_or_default();
@@ -595,7 +681,16 @@ impl<'a> AstConverter<'a> {
                 })
                 .unwrap_or_else(|| Ok(Vec::new()))?;
             Some(match class.superclass_name {
-                Some(superclass_name) => GenericIdentifier::from_name_with_args(&superclass_name.to_string(), &interface_generics),
+                Some(superclass_name) => {
+                    if class.ty != ClassType::Class {
+                        return Err(compiler_error_loc!(
+                            &superclass_name.get_location(),
+                            "Only class types can extend a superclass; `{}` is not a class",
+                            base_name
+                        ));
+                    }
+                    GenericIdentifier::from_name_with_args(&self.resolve_type(ns, &superclass_name, dst)?, &interface_generics)
+                }
                 None => GenericIdentifier::from_name("core::object::Object"), // if no superclass was specified, the default is the Object class
             })
         };
@@ -621,6 +716,7 @@ impl<'a> AstConverter<'a> {
                 module_id: self.module_id,
                 id: dst.functions.len(),
                 modifiers: vec![FunctionModifier::Internal],
+                visibility: Visibility::Private(ns.to_owned()),
                 base_name: format!("{}::keid.destructor", base_name),
                 namespace_name: ns.to_owned(),
                 function_type: FunctionType::Instance,
@@ -633,14 +729,22 @@ impl<'a> AstConverter<'a> {
                 return_type: BasicType::Void.to_complex(),
                 body: None,
                 varargs: Varargs::None,
+                loc: loc.clone(),
             });
         }
 
         for method in class.methods {
             self.parse_func_decl(ns, method, Some(&decl_parent), dst)?;
         }
-        let accessors =
+        let accessors: Vec<AccessorNode> =
             class.accessors.into_iter().map(|acc| self.parse_accessor_decl(ns, acc, Some(&decl_parent), dst)).collect::<Result<_>>()?;
+
+        // the methods and accessors of an interface are always public, since any implementation must be callable through it
+        if class.ty == ClassType::Interface {
+            for function_id in (start..start + length).chain(accessors.iter().map(|acc| acc.function_id)) {
+                dst.functions[function_id].visibility = Visibility::Public;
+            }
+        }
         dst.classes.push(ClassNode {
             superclass,
             class_type: class.ty,
@@ -663,6 +767,8 @@ impl<'a> AstConverter<'a> {
             accessors,
             constructor: class.constructor,
             destructor: class.destructor,
+            visibility: Visibility::new(class.is_public, ns),
+            loc,
         });
 
         Ok(())
@@ -740,13 +846,18 @@ impl<'a> AstConverter<'a> {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum LookupItemType {
     Class,
     Typedef(ComplexType),
     Interface,
     Struct,
     Enum,
+    /// A `const` global, along with the modules imported by the file that declares it.
+    Constant {
+        value: Token<Expr>,
+        imports: Vec<String>,
+    },
 }
 
 #[derive(Debug)]
@@ -802,6 +913,19 @@ pub fn ast_to_type_list(program: &KeidFile) -> Vec<LookupItem> {
         items.push(LookupItem::new(&Qualifier(en.name.clone()).to_string(), LookupItemType::Enum, get_next_id()));
     }
 
+    for field in &program.fields {
+        if let (true, Some(initial_value)) = (field.is_const, &field.initial_value) {
+            items.push(LookupItem::new(
+                &format!("{}::{}", program.namespace.to_string(), field.name.token.0),
+                LookupItemType::Constant {
+                    value: initial_value.clone(),
+                    imports: program.imports.iter().map(|import| import.to_string()).collect(),
+                },
+                get_next_id(),
+            ));
+        }
+    }
+
     items
 }
 